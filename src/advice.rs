@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use crate::{
+    arguments::Operation,
+    compress::Compression,
+    profile::{OperationProfile, StageKind, format_rate},
+};
+
+/// Runs shorter than this are over before any stage can meaningfully limit
+/// them, so no advice is given.
+const MIN_ELAPSED: Duration = Duration::from_millis(500);
+
+/// A stage busy for at least this fraction of the wall time is considered to
+/// be the one limiting the run.
+const BOTTLENECK_SHARE: f64 = 0.5;
+
+/// An output is called out as slow when it sustained less than this fraction
+/// of the fastest other output's throughput.
+const SLOW_OUTPUT_RATIO: f64 = 0.5;
+
+/// Block sizes below this make per call overhead noticeable.
+const SMALL_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Number of reads after which per call overhead is worth mentioning.
+const MANY_READS: u64 = 1000;
+
+/// Look at the stage timings of a finished operation and suggest changes that
+/// would make the next run faster.
+pub fn advise(op: &Operation, profile: &OperationProfile) -> Vec<String> {
+    let mut advice = vec![];
+    if profile.elapsed < MIN_ELAPSED {
+        return advice;
+    }
+
+    let bottleneck = profile
+        .stages
+        .iter()
+        .max_by(|a, b| a.busy.cmp(&b.busy))
+        .filter(|stage| stage.share(profile.elapsed) >= BOTTLENECK_SHARE);
+
+    if let Some(stage) = bottleneck {
+        match stage.kind {
+            StageKind::Write => {
+                let fastest_other = profile
+                    .stages
+                    .iter()
+                    .filter(|other| other.kind == StageKind::Write && other.name != stage.name)
+                    .map(|other| other.rate())
+                    .fold(0.0, f64::max);
+                if fastest_other > 0.0 && stage.rate() < fastest_other * SLOW_OUTPUT_RATIO {
+                    advice.push(format!(
+                        "output {} sustained {} and limited the run while other outputs \
+                         reached {}; consider dropping it or moving it to its own operation",
+                        stage.name,
+                        format_rate(stage.rate()),
+                        format_rate(fastest_other),
+                    ));
+                } else {
+                    advice.push(format!(
+                        "output {} sustained {} and limited the run",
+                        stage.name,
+                        format_rate(stage.rate()),
+                    ));
+                }
+                match op.comp {
+                    Some(comp @ Compression::Zstd(_)) => advice.push(format!(
+                        "outputs are compressed with comp={comp} by their writers; a lower \
+                         level usually compresses much faster"
+                    )),
+                    Some(comp @ Compression::Gzip(_)) => advice.push(format!(
+                        "outputs are compressed with comp={comp} by their writers; comp=zstd \
+                         usually compresses much faster than gzip"
+                    )),
+                    None => {}
+                }
+            }
+            StageKind::Hash => {
+                let mut line = format!(
+                    "{} sustained {} and limited the run",
+                    stage.name,
+                    format_rate(stage.rate()),
+                );
+                if !stage.name.starts_with("hash=blake3") {
+                    line.push_str("; blake3 is usually several times faster than sha256 or md5");
+                }
+                advice.push(line);
+            }
+            StageKind::Scan => {
+                let mut line = format!(
+                    "{} sustained {} and limited the run",
+                    stage.name,
+                    format_rate(stage.rate()),
+                );
+                if stage.name == "scan" {
+                    line.push_str("; fewer or simpler scan= patterns would help");
+                }
+                advice.push(line);
+            }
+            StageKind::Read => {
+                advice.push(format!(
+                    "input {} sustained {} and limited the run",
+                    stage.name,
+                    format_rate(stage.rate()),
+                ));
+            }
+        }
+    }
+
+    let reads = profile
+        .stages
+        .iter()
+        .filter(|stage| stage.kind == StageKind::Read)
+        .map(|stage| stage.calls)
+        .sum::<u64>();
+    if op.block_size < SMALL_BLOCK_SIZE && reads >= MANY_READS {
+        advice.push(format!(
+            "bs={} needed {reads} reads; a larger block size such as bs=1M usually cuts \
+             per call overhead",
+            op.block_size,
+        ));
+    }
+
+    advice
+}
@@ -1,5 +1,50 @@
-use color_eyre::{Result, eyre::eyre};
-use std::{path::PathBuf, str::FromStr};
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    net::SocketAddrV4,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    catalog,
+    compress::{Compression, Decompression},
+    config::{Config, OPERATION},
+    container::{Container, Info},
+    convert::{Case, Conversion},
+    encrypt::{Decryption, Encryption},
+    engine::ErrorPolicy,
+    generate::Generator,
+    hash::HashAlgorithm,
+    history::Query,
+    keys,
+    log::Log,
+    multicast,
+    patch::Injection,
+    permissions::{Owner, Permissions, parse_mode},
+    pipe,
+    plan::PlanCheck,
+    print::Format,
+    priority::IoPriority,
+    progress::Status,
+    redact::Redaction,
+    rekey::Rekey,
+    render::Renderer,
+    report::parse_timestamp,
+    s3::{self, Object},
+    scan::Scan,
+    sink::is_block_device,
+    split::{Join, Layout},
+    template::Template,
+    trailer::TrailerMode,
+    value::{
+        parse_address, parse_amount, parse_bool, parse_duration, parse_number, parse_rate,
+        parse_size,
+    },
+    verify::Pace,
+    wave::{self, Wave},
+};
 
 // pdd if=boot.img of=/dev/sda1 of=/dev/sdb1 of=/dev/sdc1 \
 //  -- if=root.img of=/dev/sda2 of=/dev/sdb2 of=/dev/sdc2 \
@@ -8,30 +53,379 @@ use std::{path::PathBuf, str::FromStr};
 //  -- if=stderr.log os=localhost:9001 redir=1 \
 //  1>stdout.log 2>stderr.log
 
-const SEPARATOR: &'static str = "--";
+const SEPARATOR: &str = "--";
+
+/// Operands that apply to the whole run rather than an operation.
+const GLOBAL_OPERANDS: [&str; 3] = ["status", "log", "stats"];
 
 #[derive(Clone, Default)]
 pub struct Arguments {
     pub operations: Vec<Operation>,
+
+    /// Print a per stage timing breakdown after each operation (`--profile`)
+    ///
+    /// (default = false)
+    pub profile: bool,
+
+    /// Don't print suggestions after each operation (`--no-advice`)
+    ///
+    /// (default = false)
+    pub no_advice: bool,
+
+    /// What to print while copying (`status=default|progress|none`)
+    ///
+    /// (default = default)
+    pub status: Status,
+
+    /// How messages and the end of run results are printed (`log=text|json`)
+    ///
+    /// (default = text)
+    pub log: Log,
+
+    /// Report memory, open files, tasks and output queues this often, and
+    /// warn if they keep growing (`stats=INTERVAL`)
+    ///
+    /// (default = none)
+    pub stats: Option<Duration>,
+
+    /// How progress is drawn while copying
+    /// (`--progress plain|bars|tui|json|quiet`, `--tui` for the dashboard);
+    /// implies `status=progress` unless `status=none` is given
+    ///
+    /// (default = plain, or json with `log=json`)
+    pub progress: Option<Renderer>,
+
+    /// Write an HTML report of the whole run to this file (`--report FILE`)
+    ///
+    /// (default = none)
+    pub report: Option<PathBuf>,
+
+    /// Append per output results to this CSV file (`--csv FILE`)
+    ///
+    /// (default = none)
+    pub csv: Option<PathBuf>,
+
+    /// Print chosen fields of each operation's summary to stdout once it is
+    /// done, one line each (`--print="{bytes_out} {duration_s}"`)
+    ///
+    /// (default = none)
+    pub print: Option<Format>,
+
+    /// Append a record of every operation to this history file
+    /// (`--history FILE`)
+    ///
+    /// (default = none)
+    pub history: Option<PathBuf>,
+
+    /// Search a history file instead of copying
+    /// (`pdd history FILE [--since 7d] [--failed] ...`)
+    ///
+    /// (default = none)
+    pub query: Option<Query>,
+
+    /// Catalog of known-good image digests for `verify-catalog=` and
+    /// `pdd catalog` (`--catalog FILE`)
+    ///
+    /// (default = $PDD_CATALOG, else pdd/catalog in the config directory)
+    pub catalog: Option<PathBuf>,
+
+    /// Keep the catalog instead of copying
+    /// (`pdd catalog add NAME FILE [--hash ALGO]` or `pdd catalog list`)
+    ///
+    /// (default = none)
+    pub catalog_command: Option<catalog::Command>,
+
+    /// Change who can decrypt an age file instead of copying
+    /// (`pdd rekey FILE --identity KEY --add RECIPIENT` or `--to RECIPIENT`)
+    ///
+    /// (default = none)
+    pub rekey: Option<Rekey>,
+
+    /// Check a copy against a `verify-plan=` file instead of copying
+    /// (`pdd verify-plan PLAN FILE [--seek BYTES] [--json]`)
+    ///
+    /// (default = none)
+    pub plan_check: Option<PlanCheck>,
+
+    /// Describe and check a `container=pddstream` output instead of
+    /// copying (`pdd info FILE [--json]`)
+    ///
+    /// (default = none)
+    pub info: Option<Info>,
+
+    /// Copy through disk devices as given instead of their raw counterparts,
+    /// e.g. `/dev/disk2` rather than `/dev/rdisk2` on macOS (`--no-rdisk`)
+    ///
+    /// (default = false)
+    pub no_rdisk: bool,
+
+    /// Run the built in self-test instead of copying (`pdd self-test`)
+    ///
+    /// (default = false)
+    pub self_test: bool,
 }
 
 #[derive(Clone)]
 pub struct Operation {
-    /// Path to the input file
-    pub input_file: PathBuf,
+    /// Where the data comes from
+    pub input: Input,
 
     /// Paths to the outputs
     pub outputs: Vec<Output>,
 
-    /// Block size
+    /// Size of the blocks read from the input (`bs=`, or `ibs=`)
     ///
     /// (default = 1024)
     pub block_size: u64,
 
-    /// Number of blocks
+    /// Size of the blocks written to the outputs (`bs=`, or `obs=`). When it
+    /// differs from the input block size, what is read is gathered into
+    /// blocks of exactly this size, the last one possibly short.
+    ///
+    /// (default = 1024)
+    pub output_block_size: u64,
+
+    /// Number of input blocks, or bytes with a `B` suffix (`count=1GiB`).
+    /// Every read counts as a block, however short, unless
+    /// `iflag=fullblock` is given.
     ///
     /// (default = 0|ALL)
-    pub count: u64,
+    pub count: Amount,
+
+    /// Stop copying after this much wall time
+    ///
+    /// (default = none)
+    pub duration: Option<Duration>,
+
+    /// Stop copying once the input has been idle for this long
+    ///
+    /// (default = none)
+    pub idle_timeout: Option<Duration>,
+
+    /// Cap on reading the input, in bytes per second
+    ///
+    /// (default = none)
+    pub limit: Option<u64>,
+
+    /// Per output, a cap on writing it in bytes per second. A limited output
+    /// still holds back the others once its queue fills, like any slow
+    /// output.
+    ///
+    /// (default = none)
+    pub output_limits: Vec<Option<u64>>,
+
+    /// Per output, the size of the writes it takes: what it is given is
+    /// gathered or cut into frames of exactly this size, the last one
+    /// possibly short (`oframe=SIZE`)
+    ///
+    /// (default = as given, or whole sectors for disks that need them and
+    /// outputs written directly)
+    pub output_frames: Vec<Option<u64>>,
+
+    /// Per output, what happens when writing it fails
+    ///
+    /// (default = abort)
+    pub on_error: Vec<ErrorPolicy>,
+
+    /// Per output, the mode and owner file outputs are given
+    /// (`mode=0600 owner=backup`)
+    ///
+    /// (default = as created)
+    pub permissions: Vec<Permissions>,
+
+    /// Input blocks, or bytes, to skip before reading (`skip=`, or `iseek=`
+    /// as in GNU dd)
+    ///
+    /// (default = 0)
+    pub skip: Amount,
+
+    /// Output blocks, or bytes, to seek into each output before writing
+    /// (`seek=`, or `oseek=`)
+    ///
+    /// (default = 0)
+    pub seek: Amount,
+
+    /// Unique data written into each output at fixed offsets
+    ///
+    /// (default = none)
+    pub injections: Vec<Injection>,
+
+    /// Patch files applied to the stream before it reaches the outputs
+    ///
+    /// (default = none)
+    pub patches: Vec<PathBuf>,
+
+    /// Regions wiped from the stream before it reaches the outputs
+    ///
+    /// (default = none)
+    pub redactions: Vec<Redaction>,
+
+    /// Byte patterns searched for in the stream
+    ///
+    /// (default = none)
+    pub scans: Vec<Scan>,
+
+    /// Directory recognised files are carved out of the stream into
+    ///
+    /// (default = none)
+    pub carve: Option<PathBuf>,
+
+    /// Conversion flags
+    ///
+    /// (default = none)
+    pub conv: Conv,
+
+    /// Input flags
+    ///
+    /// (default = none)
+    pub iflag: Iflag,
+
+    /// Output flags
+    ///
+    /// (default = none)
+    pub oflag: Oflag,
+
+    /// Compression applied to every output except hashes
+    ///
+    /// (default = none)
+    pub comp: Option<Compression>,
+
+    /// Decompression of the input; counts and progress are in decompressed
+    /// bytes
+    ///
+    /// (default = auto)
+    pub decomp: Decompression,
+
+    /// Encryption applied to every output except hashes, after compression
+    ///
+    /// (default = none)
+    pub enc: Option<Encryption>,
+
+    /// Decryption of the input, before decompression
+    ///
+    /// (default = none)
+    pub dec: Option<Decryption>,
+
+    /// Container every output except hashes is written in, after
+    /// compression and encryption (`container=pddstream`)
+    ///
+    /// (default = none)
+    pub container: Option<Container>,
+
+    /// Directory a download is kept in as it streams through, and read from
+    /// by later runs with the same URL (`cache=DIR`)
+    ///
+    /// (default = none)
+    pub cache: Option<PathBuf>,
+
+    /// Map of the regions of a failing input read so far and those that
+    /// couldn't be; if it exists, only those are read again (`rescue=MAP`)
+    ///
+    /// (default = none)
+    pub rescue: Option<PathBuf>,
+
+    /// Block map of the input, so only the blocks it maps are written to
+    /// the outputs, each checked against its checksum (`ibmap=FILE`)
+    ///
+    /// (default = none)
+    pub ibmap: Option<PathBuf>,
+
+    /// Write a block map of the stream, in the format of bmaptool, for
+    /// flashing just the blocks that hold data later (`obmap=FILE`)
+    ///
+    /// (default = none)
+    pub obmap: Option<PathBuf>,
+
+    /// Leave blocks of all zeros out of the block map of a stream that
+    /// isn't a file it can ask for the allocated blocks of, for an image
+    /// whose zero blocks don't have to be written (`obmap-sparse=1`)
+    ///
+    /// (default = false)
+    pub obmap_sparse: bool,
+
+    /// Refuse to write anything unless the input file has the digest of
+    /// this catalog entry (`verify-catalog=NAME`)
+    ///
+    /// (default = none)
+    pub verify_catalog: Option<String>,
+
+    /// Read every output back after writing and compare it
+    ///
+    /// (default = false)
+    pub verify: bool,
+
+    /// Cap on reading the outputs back, in bytes per second, apart from
+    /// `limit=`, so verifying doesn't starve whatever now uses the device
+    /// (`verify-rate=RATE`)
+    ///
+    /// (default = none)
+    pub verify_rate: Option<u64>,
+
+    /// I/O priority to read the outputs back at, apart from that of the
+    /// copy (`verify-priority=idle|best-effort:N|realtime:N`)
+    ///
+    /// (default = none)
+    pub verify_priority: Option<IoPriority>,
+
+    /// Times to copy the chunks of an output that didn't read back right
+    /// again from the input, and read just them back, before giving up on
+    /// it; only a file input copied as it is can be (`verify-retries=N`)
+    ///
+    /// (default = 2)
+    pub verify_retries: u32,
+
+    /// Write the digests of what was sent to the first output pdd can't
+    /// read back itself, such as `omcast=`, for receivers to check their
+    /// copies against with `pdd verify-plan` (`verify-plan=FILE`)
+    ///
+    /// (default = none)
+    pub verify_plan: Option<PathBuf>,
+
+    /// Checkpoint file recording how far each output got, so a rerun picks
+    /// up from there
+    ///
+    /// (default = none)
+    pub resume: Option<PathBuf>,
+
+    /// How the stream is laid out over the outputs (`mode=mirror|split|join`)
+    ///
+    /// (default = mirror)
+    pub layout: Layout,
+
+    /// Bytes per chunk with `mode=split`, or per shard of each output
+    /// without it (`split=4G`)
+    ///
+    /// (default = bs with mode=split, else none)
+    pub split: Option<u64>,
+
+    /// What is done with integrity trailers, at the end of a file input and
+    /// of file outputs (`trailer=strip|add|keep`)
+    ///
+    /// (default = strip)
+    pub trailer: TrailerMode,
+
+    /// Move the backup GPT of each file output to its end once written, for
+    /// disk images restored to a disk of another size
+    ///
+    /// (default = false)
+    pub fix_gpt: bool,
+
+    /// Give each file output new disk, partition and filesystem identifiers
+    /// once written, so a clone can be attached next to its original
+    ///
+    /// (default = false)
+    pub new_ids: bool,
+
+    /// Most outputs written at once; with more, the input is copied to
+    /// each wave of them in turn (`wave=N`)
+    ///
+    /// (default = as many as the limit on open files allows, up to 256)
+    pub wave_size: Option<usize>,
+
+    /// The wave of outputs this is, of an operation split into waves
+    ///
+    /// (default = none)
+    pub wave: Option<Wave>,
 
     /// True if the input file is redirected output, e.g. stdout.
     ///
@@ -39,146 +433,1699 @@ pub struct Operation {
     pub is_redirected: bool,
 }
 
+impl Operation {
+    /// Byte offset into the input where reading starts
+    pub fn skip_bytes(&self) -> Result<u64> {
+        self.skip.bytes(self.block_size).ok_or_else(|| {
+            eyre!(
+                "skip={} with ibs={} is too large",
+                self.skip,
+                self.block_size
+            )
+        })
+    }
+
+    /// Byte offset into each output where writing starts
+    pub fn seek_bytes(&self) -> Result<u64> {
+        self.seek.bytes(self.output_block_size).ok_or_else(|| {
+            eyre!(
+                "seek={} with obs={} is too large",
+                self.seek,
+                self.output_block_size
+            )
+        })
+    }
+}
+
+/// How much a `count=`, `skip=` or `seek=` operand covers: a number of
+/// blocks, or of bytes with a `B` suffix, e.g. `count=1GiB` or `skip=446B`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Amount {
+    Blocks(u64),
+    Bytes(u64),
+}
+
+impl Default for Amount {
+    fn default() -> Self {
+        Amount::Blocks(0)
+    }
+}
+
+impl Amount {
+    pub fn is_zero(self) -> bool {
+        matches!(self, Amount::Blocks(0) | Amount::Bytes(0))
+    }
+
+    /// In bytes, with blocks of `block_size`, or `None` if that overflows.
+    pub fn bytes(self, block_size: u64) -> Option<u64> {
+        match self {
+            Amount::Blocks(blocks) => blocks.checked_mul(block_size),
+            Amount::Bytes(bytes) => Some(bytes),
+        }
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Amount::Blocks(blocks) => write!(f, "{blocks}"),
+            Amount::Bytes(bytes) => write!(f, "{bytes}B"),
+        }
+    }
+}
+
+/// dd style conversion flags (`conv=FLAG[,FLAG...]`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Conv {
+    /// Don't truncate regular file outputs
+    pub notrunc: bool,
+
+    /// Pad short reads with zeros to the block size
+    pub sync: bool,
+
+    /// Substitute zero blocks for blocks that can't be read
+    pub noerror: bool,
+
+    /// Sync data and metadata of file outputs before finishing
+    pub fsync: bool,
+
+    /// Sync the data of file outputs before finishing
+    pub fdatasync: bool,
+
+    /// Seek over all zero blocks of file outputs instead of writing them
+    pub sparse: bool,
+
+    /// Like `sparse`, but also punch holes so data that was already there
+    /// reads back as zeros
+    pub punch: bool,
+
+    /// Read each block of file outputs first and only write the ones that
+    /// differ, so re-flashing a device rewrites just what changed
+    pub delta: bool,
+
+    /// Swap every pair of bytes, for images of big endian tapes and disks
+    pub swab: bool,
+
+    /// Make ASCII letters upper case
+    pub ucase: bool,
+
+    /// Make ASCII letters lower case
+    pub lcase: bool,
+}
+
+impl Conv {
+    /// The conversions of the stream the flags ask for.
+    pub fn conversion(&self) -> Conversion {
+        let case = match (self.ucase, self.lcase) {
+            (true, _) => Some(Case::Upper),
+            (_, true) => Some(Case::Lower),
+            _ => None,
+        };
+        Conversion {
+            swab: self.swab,
+            case,
+        }
+    }
+}
+
+impl FromStr for Conv {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut conv = Conv::default();
+        for flag in s.split(',') {
+            match flag {
+                "notrunc" => conv.notrunc = true,
+                "sync" => conv.sync = true,
+                "noerror" => conv.noerror = true,
+                "fsync" => conv.fsync = true,
+                "fdatasync" => conv.fdatasync = true,
+                "sparse" => conv.sparse = true,
+                "punch" => conv.punch = true,
+                "delta" => conv.delta = true,
+                "swab" => conv.swab = true,
+                "ucase" => conv.ucase = true,
+                "lcase" => conv.lcase = true,
+                _ => {
+                    return Err(eyre!("Unknown conversion flag {flag}")
+                        .with_note(|| format!("input conv={s}"))
+                        .with_suggestion(|| {
+                            "expected a comma separated list of notrunc, sync, noerror, \
+                             fsync, fdatasync, sparse, punch, delta, swab, ucase, lcase"
+                        }));
+                }
+            }
+        }
+        Ok(conv)
+    }
+}
+
+/// dd style input flags (`iflag=FLAG[,FLAG...]`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Iflag {
+    /// Keep reading until each block is full or the input ends, so `count`
+    /// counts full blocks even when reading from a pipe
+    pub fullblock: bool,
+
+    /// Read around the page cache
+    pub direct: bool,
+}
+
+impl FromStr for Iflag {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut iflag = Iflag::default();
+        for flag in s.split(',') {
+            match flag {
+                "fullblock" => iflag.fullblock = true,
+                "direct" => iflag.direct = true,
+                _ => {
+                    return Err(eyre!("Unknown input flag {flag}")
+                        .with_note(|| format!("input iflag={s}"))
+                        .with_suggestion(
+                            || "expected a comma separated list of fullblock, direct",
+                        ));
+                }
+            }
+        }
+        Ok(iflag)
+    }
+}
+
+/// dd style output flags (`oflag=FLAG[,FLAG...]`), applied to file and
+/// device outputs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Oflag {
+    /// Write around the page cache, so errors and throughput are those of
+    /// the device
+    pub direct: bool,
+
+    /// Return from each write only once its data is on the device (O_DSYNC)
+    pub dsync: bool,
+
+    /// Like dsync, and the metadata as well (O_SYNC)
+    pub sync: bool,
+
+    /// Zero whatever is left of a device after the image, so nothing that
+    /// was there before can be read back past it
+    pub wipe_tail: bool,
+}
+
+impl FromStr for Oflag {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut oflag = Oflag::default();
+        for flag in s.split(',') {
+            match flag {
+                "direct" => oflag.direct = true,
+                "dsync" => oflag.dsync = true,
+                "sync" => oflag.sync = true,
+                "wipe-tail" => oflag.wipe_tail = true,
+                _ => {
+                    return Err(eyre!("Unknown output flag {flag}")
+                        .with_note(|| format!("input oflag={s}"))
+                        .with_suggestion(
+                            || "expected a comma separated list of direct, dsync, sync, wipe-tail",
+                        ));
+                }
+            }
+        }
+        Ok(oflag)
+    }
+}
+
+#[derive(Clone)]
+pub enum Input {
+    File(PathBuf),
+    Stdin,
+
+    /// Connect to this host and port and read what it sends
+    Socket(String, u16),
+
+    /// Wait for a connection on this address and port and read what it
+    /// sends, e.g. another pdd's `os=` output, whose pddstream is unpacked
+    /// and fails to read if it ends before its end
+    Listen(String, u16),
+
+    /// Download this URL
+    Http(String),
+
+    /// Join this multicast group and read what an `omcast=` output sends it
+    Multicast(SocketAddrV4),
+
+    /// Shards put back together
+    Join(Join),
+
+    /// What an earlier operation of the run writes to `of=pipe:NAME`
+    Pipe(String),
+
+    /// Data made up as it is read
+    Generated {
+        generator: Generator,
+
+        /// Bytes to make up before ending, if not endless
+        len: Option<u64>,
+    },
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::File(path) => write!(f, "if={}", path.display()),
+            Input::Stdin => write!(f, "if=-"),
+            Input::Socket(hostname, port) => write!(f, "is={hostname}:{port}"),
+            Input::Listen(address, port) => write!(f, "listen={address}:{port}"),
+            Input::Http(url) => write!(f, "ihttp={url}"),
+            Input::Multicast(group) => write!(f, "imcast={group}"),
+            Input::Generated { generator, .. } => write!(f, "if={generator}"),
+            Input::Join(join) => write!(f, "{join}"),
+            Input::Pipe(name) => write!(f, "if=pipe:{name}"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Output {
     File(PathBuf),
+
+    /// A file named by a template, made into [`Output::File`] before copying
+    Auto(Template),
+    Stdout,
+
+    /// Connect to this host and port and send the stream as a pddstream,
+    /// which a `listen=` or `is=` pdd unpacks; `of=-` piped into netcat sends
+    /// it raw
     Socket(String, u16),
-    Http { method: String, url: String },
+
+    /// Send to this multicast group, for `imcast=` inputs to receive
+    Multicast(SocketAddrV4),
+    Http {
+        method: String,
+        url: String,
+    },
+    Hash {
+        algorithm: HashAlgorithm,
+        sidecar: Option<PathBuf>,
+    },
+
+    /// An object uploaded in parts of `part_size` bytes
+    S3 {
+        object: Object,
+        part_size: u64,
+    },
+
+    /// For a later operation of the run to read as `if=pipe:NAME`
+    Pipe(String),
+}
+
+impl Output {
+    /// True if what was written can only be read back elsewhere, the kinds
+    /// `verify-plan=` describes.
+    pub fn is_remote(&self) -> bool {
+        !matches!(
+            self,
+            Output::File(_) | Output::Auto(_) | Output::Hash { .. } | Output::Pipe(_)
+        )
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::File(path) => write!(f, "of={}", path.display()),
+            Output::Auto(template) => write!(f, "of=auto:{template}"),
+            Output::Stdout => write!(f, "of=-"),
+            Output::Socket(hostname, port) => write!(f, "os={hostname}:{port}"),
+            Output::Multicast(group) => write!(f, "omcast={group}"),
+            Output::Http { method, url } => write!(f, "ohttp={method};{url}"),
+            Output::Hash {
+                algorithm,
+                sidecar: None,
+            } => write!(f, "hash={algorithm}"),
+            Output::Hash {
+                algorithm,
+                sidecar: Some(path),
+            } => write!(f, "hash={algorithm}:{}", path.display()),
+            Output::S3 { object, .. } => write!(f, "os3={object}"),
+            Output::Pipe(name) => write!(f, "of=pipe:{name}"),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct OperationBuilder {
-    pub input_file: Option<PathBuf>,
+    pub input: Option<Input>,
     pub outputs: Vec<Output>,
     pub is_redirected: bool,
     pub block_size: u64,
-    pub count: u64,
+    pub input_block_size: Option<u64>,
+    pub output_block_size: Option<u64>,
+    pub count: Amount,
+    pub duration: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub limit: Option<u64>,
+    pub output_limits: Vec<Option<u64>>,
+    pub output_frames: Vec<Option<u64>>,
+    pub on_error: ErrorPolicy,
+    pub output_errors: Vec<Option<ErrorPolicy>>,
+    pub permissions: Vec<Permissions>,
+    pub skip: Amount,
+    pub seek: Amount,
+    pub injections: Vec<Injection>,
+    pub patches: Vec<PathBuf>,
+    pub redactions: Vec<Redaction>,
+    pub scans: Vec<Scan>,
+    pub carve: Option<PathBuf>,
+    pub conv: Conv,
+    pub iflag: Iflag,
+    pub oflag: Oflag,
+    pub comp: Option<Compression>,
+    pub decomp: Decompression,
+    pub enc: Option<Encryption>,
+    pub dec: Option<Decryption>,
+    pub container: Option<Container>,
+    pub cache: Option<PathBuf>,
+    pub rescue: Option<PathBuf>,
+    pub ibmap: Option<PathBuf>,
+    pub obmap: Option<PathBuf>,
+    pub obmap_sparse: bool,
+    pub verify_catalog: Option<String>,
+    pub verify: bool,
+    pub verify_rate: Option<u64>,
+    pub verify_priority: Option<IoPriority>,
+    pub verify_retries: u32,
+    pub verify_plan: Option<PathBuf>,
+    pub resume: Option<PathBuf>,
+    pub layout: Layout,
+    pub split: Option<u64>,
+    pub trailer: TrailerMode,
+    pub fix_gpt: bool,
+    pub new_ids: bool,
+    pub wave_size: Option<usize>,
+
+    /// Part size of `os3=` outputs, if not derived from `bs=`
+    pub s3_part: Option<u64>,
+
+    /// Every `if=` file given, the shards with `mode=join`
+    pub input_files: Vec<PathBuf>,
 }
 
 impl Default for OperationBuilder {
     fn default() -> Self {
         Self {
-            input_file: None,
+            input: None,
             outputs: vec![],
             is_redirected: false,
             block_size: 1024,
-            count: 0,
+            input_block_size: None,
+            output_block_size: None,
+            count: Amount::default(),
+            duration: None,
+            idle_timeout: None,
+            limit: None,
+            output_limits: vec![],
+            output_frames: vec![],
+            on_error: ErrorPolicy::default(),
+            output_errors: vec![],
+            permissions: vec![],
+            skip: Amount::default(),
+            seek: Amount::default(),
+            injections: vec![],
+            patches: vec![],
+            redactions: vec![],
+            scans: vec![],
+            carve: None,
+            conv: Conv::default(),
+            iflag: Iflag::default(),
+            oflag: Oflag::default(),
+            comp: None,
+            decomp: Decompression::default(),
+            enc: None,
+            dec: None,
+            container: None,
+            cache: None,
+            rescue: None,
+            ibmap: None,
+            obmap: None,
+            obmap_sparse: false,
+            verify_catalog: None,
+            verify: false,
+            verify_rate: None,
+            verify_priority: None,
+            verify_retries: 2,
+            verify_plan: None,
+            resume: None,
+            layout: Layout::default(),
+            split: None,
+            trailer: TrailerMode::default(),
+            fix_gpt: false,
+            new_ids: false,
+            wave_size: None,
+            s3_part: None,
+            input_files: vec![],
         }
     }
 }
 
 impl OperationBuilder {
     pub fn input_file(&mut self, path: PathBuf) {
-        let _ = self.input_file.replace(path);
+        self.input_files.push(path.clone());
+        let _ = self.input.replace(Input::File(path));
     }
 
-    pub fn output_file(&mut self, path: PathBuf) {
-        self.outputs.push(Output::File(path))
+    pub fn input_generated(&mut self, generator: Generator) {
+        let _ = self.input.replace(Input::Generated {
+            generator,
+            len: None,
+        });
     }
 
-    pub fn output_socket(&mut self, hostname: &str, port: u16) {
-        self.outputs
-            .push(Output::Socket(hostname.to_string(), port))
+    pub fn input_stdin(&mut self) {
+        let _ = self.input.replace(Input::Stdin);
     }
 
-    pub fn output_http(&mut self, method: &str, url: &str) {
-        self.outputs.push(Output::Http {
-            method: method.to_string(),
-            url: url.to_string(),
-        })
+    pub fn input_pipe(&mut self, name: &str) {
+        let _ = self.input.replace(Input::Pipe(name.to_string()));
     }
 
-    pub fn block_size(&mut self, bs: u64) {
-        self.block_size = bs
+    /// Read from `hostname:port`, or from the first connection made to
+    /// `port` if `hostname` is empty.
+    pub fn input_socket(&mut self, hostname: &str, port: u16) {
+        let input = match hostname {
+            "" => Input::Listen("0.0.0.0".to_string(), port),
+            _ => Input::Socket(hostname.to_string(), port),
+        };
+        let _ = self.input.replace(input);
     }
 
-    pub fn count(&mut self, c: u64) {
-        self.count = c
+    /// Read from the first connection made to `address:port`.
+    pub fn input_listen(&mut self, address: &str, port: u16) {
+        let _ = self.input.replace(Input::Listen(address.to_string(), port));
     }
 
-    pub fn is_redirected(&mut self) {
-        self.is_redirected = !self.is_redirected;
+    pub fn input_http(&mut self, url: &str) {
+        let _ = self.input.replace(Input::Http(url.to_string()));
     }
 
-    pub fn build(self) -> Result<Operation> {
-        // There must be an input file
-        let Some(input_file) = self.input_file else {
-            return Err(eyre!("Operation is missing input file"));
-        };
+    pub fn input_multicast(&mut self, group: SocketAddrV4) {
+        let _ = self.input.replace(Input::Multicast(group));
+    }
 
-        if self.outputs.is_empty() {
+    pub fn output_file(&mut self, path: PathBuf) {
+        self.outputs.push(Output::File(path))
+    }
+
+    pub fn output_auto(&mut self, template: Template) {
+        self.outputs.push(Output::Auto(template))
+    }
+
+    pub fn output_stdout(&mut self) {
+        self.outputs.push(Output::Stdout)
+    }
+
+    pub fn output_pipe(&mut self, name: &str) {
+        self.outputs.push(Output::Pipe(name.to_string()))
+    }
+
+    pub fn output_socket(&mut self, hostname: &str, port: u16) {
+        self.outputs
+            .push(Output::Socket(hostname.to_string(), port))
+    }
+
+    pub fn output_multicast(&mut self, group: SocketAddrV4) {
+        self.outputs.push(Output::Multicast(group))
+    }
+
+    pub fn output_http(&mut self, method: &str, url: &str) {
+        self.outputs.push(Output::Http {
+            method: method.to_string(),
+            url: url.to_string(),
+        })
+    }
+
+    /// Part sizes are only known once every operand is given.
+    pub fn output_s3(&mut self, object: Object) {
+        self.outputs.push(Output::S3 {
+            object,
+            part_size: 0,
+        })
+    }
+
+    pub fn output_hash(&mut self, algorithm: HashAlgorithm, sidecar: Option<PathBuf>) {
+        self.outputs.push(Output::Hash { algorithm, sidecar })
+    }
+
+    pub fn block_size(&mut self, bs: u64) {
+        self.block_size = bs
+    }
+
+    /// Read blocks of this size, whatever `bs=` says.
+    pub fn input_block_size(&mut self, ibs: u64) {
+        let _ = self.input_block_size.replace(ibs);
+    }
+
+    /// Write blocks of this size, whatever `bs=` says.
+    pub fn output_block_size(&mut self, obs: u64) {
+        let _ = self.output_block_size.replace(obs);
+    }
+
+    pub fn count(&mut self, c: Amount) {
+        self.count = c
+    }
+
+    pub fn limit(&mut self, rate: u64) {
+        let _ = self.limit.replace(rate);
+    }
+
+    /// Limit the output given last.
+    pub fn output_limit(&mut self, rate: u64) -> Result<()> {
+        if self.outputs.is_empty() {
+            return Err(eyre!("olimit= must follow the output it limits")
+                .with_note(|| format!("input olimit={rate}")));
+        }
+        self.output_limits.resize(self.outputs.len(), None);
+        let _ = self.output_limits[self.outputs.len() - 1].replace(rate);
+        Ok(())
+    }
+
+    /// Write the output given last in frames of `size`.
+    pub fn output_frame(&mut self, size: u64) -> Result<()> {
+        if self.outputs.is_empty() {
+            return Err(eyre!("oframe= must follow the output it frames")
+                .with_note(|| format!("input oframe={size}")));
+        }
+        if size == 0 {
+            return Err(eyre!("oframe= must be greater than zero"));
+        }
+        self.output_frames.resize(self.outputs.len(), None);
+        let _ = self.output_frames[self.outputs.len() - 1].replace(size);
+        Ok(())
+    }
+
+    /// Set the policy of the output given last, or of every output without
+    /// one of its own if no output has been given yet.
+    pub fn on_error(&mut self, policy: ErrorPolicy) {
+        if self.outputs.is_empty() {
+            self.on_error = policy;
+            return;
+        }
+        self.output_errors.resize(self.outputs.len(), None);
+        let _ = self.output_errors[self.outputs.len() - 1].replace(policy);
+    }
+
+    /// Give the file output given last this mode when creating it.
+    pub fn mode(&mut self, mode: u32) -> Result<()> {
+        self.last_permissions("mode", &format!("{mode:04o}"))?.mode = Some(mode);
+        Ok(())
+    }
+
+    /// Give the file output given last this owner.
+    pub fn owner(&mut self, owner: Owner) -> Result<()> {
+        self.last_permissions("owner", &owner.to_string())?.owner = Some(owner);
+        Ok(())
+    }
+
+    fn last_permissions(&mut self, key: &str, value: &str) -> Result<&mut Permissions> {
+        if !matches!(self.outputs.last(), Some(Output::File(_))) {
+            return Err(eyre!("{key}= must follow the file output it applies to")
+                .with_note(|| format!("input {key}={value}")));
+        }
+        self.permissions
+            .resize(self.outputs.len(), Permissions::default());
+        Ok(&mut self.permissions[self.outputs.len() - 1])
+    }
+
+    pub fn skip(&mut self, n: Amount) {
+        self.skip = n
+    }
+
+    pub fn duration(&mut self, duration: Duration) {
+        let _ = self.duration.replace(duration);
+    }
+
+    pub fn idle_timeout(&mut self, idle_timeout: Duration) {
+        let _ = self.idle_timeout.replace(idle_timeout);
+    }
+
+    pub fn seek(&mut self, n: Amount) {
+        self.seek = n
+    }
+
+    pub fn inject(&mut self, injection: Injection) {
+        self.injections.push(injection)
+    }
+
+    pub fn patch(&mut self, path: PathBuf) {
+        self.patches.push(path)
+    }
+
+    pub fn redact(&mut self, redaction: Redaction) {
+        self.redactions.push(redaction)
+    }
+
+    pub fn scan(&mut self, scan: Scan) {
+        self.scans.push(scan)
+    }
+
+    pub fn carve(&mut self, dir: PathBuf) {
+        let _ = self.carve.replace(dir);
+    }
+
+    /// Flags of repeated `conv=` operands add up.
+    pub fn conv(&mut self, conv: Conv) {
+        self.conv.notrunc |= conv.notrunc;
+        self.conv.sync |= conv.sync;
+        self.conv.noerror |= conv.noerror;
+        self.conv.fsync |= conv.fsync;
+        self.conv.fdatasync |= conv.fdatasync;
+        self.conv.sparse |= conv.sparse;
+        self.conv.punch |= conv.punch;
+        self.conv.delta |= conv.delta;
+        self.conv.swab |= conv.swab;
+        self.conv.ucase |= conv.ucase;
+        self.conv.lcase |= conv.lcase;
+    }
+
+    /// Flags of repeated `iflag=` operands add up.
+    pub fn iflag(&mut self, iflag: Iflag) {
+        self.iflag.fullblock |= iflag.fullblock;
+        self.iflag.direct |= iflag.direct;
+    }
+
+    /// Flags of repeated `oflag=` operands add up.
+    pub fn oflag(&mut self, oflag: Oflag) {
+        self.oflag.direct |= oflag.direct;
+        self.oflag.dsync |= oflag.dsync;
+        self.oflag.sync |= oflag.sync;
+        self.oflag.wipe_tail |= oflag.wipe_tail;
+    }
+
+    pub fn comp(&mut self, comp: Compression) {
+        let _ = self.comp.replace(comp);
+    }
+
+    pub fn decomp(&mut self, decomp: Decompression) {
+        self.decomp = decomp
+    }
+
+    /// Recipients of repeated `enc=` operands add up.
+    pub fn enc(&mut self, enc: Encryption) {
+        match &mut self.enc {
+            Some(encryption) => encryption.add(enc),
+            None => self.enc = Some(enc),
+        }
+    }
+
+    pub fn dec(&mut self, dec: Decryption) {
+        let _ = self.dec.replace(dec);
+    }
+
+    pub fn container(&mut self, container: Container) {
+        let _ = self.container.replace(container);
+    }
+
+    pub fn cache(&mut self, dir: PathBuf) {
+        let _ = self.cache.replace(dir);
+    }
+
+    pub fn rescue(&mut self, map: PathBuf) {
+        let _ = self.rescue.replace(map);
+    }
+
+    pub fn ibmap(&mut self, path: PathBuf) {
+        let _ = self.ibmap.replace(path);
+    }
+
+    pub fn obmap(&mut self, path: PathBuf) {
+        let _ = self.obmap.replace(path);
+    }
+
+    pub fn obmap_sparse(&mut self, sparse: bool) {
+        self.obmap_sparse = sparse
+    }
+
+    pub fn verify_catalog(&mut self, name: String) {
+        let _ = self.verify_catalog.replace(name);
+    }
+
+    pub fn verify(&mut self, verify: bool) {
+        self.verify = verify
+    }
+
+    pub fn verify_rate(&mut self, rate: u64) {
+        let _ = self.verify_rate.replace(rate);
+    }
+
+    pub fn verify_priority(&mut self, priority: IoPriority) {
+        let _ = self.verify_priority.replace(priority);
+    }
+
+    pub fn verify_retries(&mut self, retries: u32) {
+        self.verify_retries = retries
+    }
+
+    pub fn verify_plan(&mut self, path: PathBuf) {
+        let _ = self.verify_plan.replace(path);
+    }
+
+    pub fn resume(&mut self, path: PathBuf) {
+        let _ = self.resume.replace(path);
+    }
+
+    pub fn layout(&mut self, layout: Layout) {
+        self.layout = layout
+    }
+
+    pub fn split(&mut self, size: u64) {
+        let _ = self.split.replace(size);
+    }
+
+    pub fn trailer(&mut self, trailer: TrailerMode) {
+        self.trailer = trailer
+    }
+
+    pub fn fix_gpt(&mut self, fix_gpt: bool) {
+        self.fix_gpt = fix_gpt
+    }
+
+    pub fn new_ids(&mut self, new_ids: bool) {
+        self.new_ids = new_ids
+    }
+
+    pub fn wave_size(&mut self, size: usize) {
+        let _ = self.wave_size.replace(size);
+    }
+
+    pub fn s3_part(&mut self, size: u64) {
+        let _ = self.s3_part.replace(size);
+    }
+
+    pub fn is_redirected(&mut self) {
+        self.is_redirected = !self.is_redirected;
+    }
+
+    /// True if no operand has been given for this operation yet.
+    pub fn is_empty(&self) -> bool {
+        self.input.is_none() && self.outputs.is_empty()
+    }
+
+    pub fn build(self) -> Result<Operation> {
+        // Without an input file the operation reads from stdin
+        let mut input = self.input.unwrap_or(Input::Stdin);
+        if self.layout == Layout::Join {
+            if self.input_files.is_empty() {
+                return Err(eyre!("mode=join needs the shards as if= files"));
+            }
+            // Several shards took turns; one is the start of a series.
+            let chunk = (self.input_files.len() > 1).then(|| {
+                self.split
+                    .unwrap_or(self.input_block_size.unwrap_or(self.block_size))
+            });
+            input = Input::Join(Join {
+                shards: self.input_files,
+                chunk,
+            });
+        }
+
+        if self.outputs.is_empty() {
             return Err(eyre!("Operation must have at least one output"));
         }
 
+        let stdouts = self
+            .outputs
+            .iter()
+            .filter(|output| matches!(output, Output::Stdout))
+            .count();
+        if stdouts > 1 {
+            return Err(eyre!("Operation can only write to stdout once"));
+        }
+
+        let block_size = self.input_block_size.unwrap_or(self.block_size);
+        let output_block_size = self.output_block_size.unwrap_or(self.block_size);
+        if block_size == 0 || output_block_size == 0 {
+            return Err(eyre!("Block size must be greater than zero"));
+        }
+
+        if self.cache.is_some() && !matches!(input, Input::Http(_)) {
+            return Err(eyre!("cache= only keeps downloads")
+                .with_note(|| format!("input {input}"))
+                .with_suggestion(|| "use it with an ihttp= input"));
+        }
+
+        // A rescue reads the drive as it is, and its second pass writes what
+        // it gets where it belongs in the outputs.
+        let mut decomp = self.decomp;
+        let mut trailer = self.trailer;
+        if let Some(map) = &self.rescue {
+            if !matches!(input, Input::File(_)) {
+                return Err(eyre!("rescue= only reads files and devices")
+                    .with_note(|| format!("input {input}"))
+                    .with_suggestion(|| "give the failing drive as if=, e.g. if=/dev/sdb"));
+            }
+            if let Some(output) = self
+                .outputs
+                .iter()
+                .find(|output| !matches!(output, Output::File(_) | Output::Auto(_)))
+            {
+                return Err(eyre!("rescue= only writes files and devices")
+                    .with_note(|| format!("output {output}"))
+                    .with_note(|| "what a second pass rescues is written into the outputs"));
+            }
+            let invalid = |what: &str| {
+                eyre!("rescue= can't be used with {what}")
+                    .with_note(|| format!("rescue={}", map.display()))
+                    .with_note(|| "a rescue copies the input as it is")
+            };
+            if self.layout != Layout::Mirror {
+                return Err(invalid("split or join"));
+            }
+            if self.resume.is_some() {
+                return Err(invalid("resume=, it keeps its own map"));
+            }
+            if self.comp.is_some() || !matches!(decomp, Decompression::Auto | Decompression::None) {
+                return Err(invalid("comp= or decomp="));
+            }
+            if self.enc.is_some() || self.dec.is_some() {
+                return Err(invalid("enc= or dec="));
+            }
+            if !self.conv.conversion().is_none()
+                || !self.injections.is_empty()
+                || !self.patches.is_empty()
+                || !self.redactions.is_empty()
+            {
+                return Err(invalid("conversions, injections, patches or redactions"));
+            }
+            if trailer == TrailerMode::Add {
+                return Err(invalid("trailer=add"));
+            }
+            decomp = Decompression::None;
+            trailer = TrailerMode::Keep;
+        }
+
+        // Flashing with a block map writes each mapped range where it
+        // belongs in the outputs, and reads past the rest of the image.
+        if let Some(map) = &self.ibmap {
+            if !matches!(input, Input::File(_) | Input::Stdin) {
+                return Err(eyre!("ibmap= reads an image file")
+                    .with_note(|| format!("input {input}"))
+                    .with_suggestion(|| "give the image as if=, e.g. if=image.img.zst"));
+            }
+            if let Some(output) = self
+                .outputs
+                .iter()
+                .find(|output| !matches!(output, Output::File(_) | Output::Auto(_)))
+            {
+                return Err(eyre!("ibmap= only writes files and devices")
+                    .with_note(|| format!("output {output}"))
+                    .with_note(|| "the blocks it leaves out are skipped over in the outputs"));
+            }
+            let invalid = |what: &str| {
+                eyre!("ibmap= can't be used with {what}")
+                    .with_note(|| format!("ibmap={}", map.display()))
+                    .with_note(|| "the map says where each range of the image goes")
+            };
+            if self.layout != Layout::Mirror || self.split.is_some() {
+                return Err(invalid("split or join"));
+            }
+            if self.resume.is_some() || self.rescue.is_some() {
+                return Err(invalid("resume= or rescue="));
+            }
+            if !self.skip.is_zero() || !self.count.is_zero() {
+                return Err(invalid("skip= or count="));
+            }
+            if self.comp.is_some() || self.enc.is_some() || self.dec.is_some() {
+                return Err(invalid("comp=, enc= or dec="));
+            }
+            if !self.conv.conversion().is_none()
+                || !self.injections.is_empty()
+                || !self.patches.is_empty()
+                || !self.redactions.is_empty()
+            {
+                return Err(invalid("conversions, injections, patches or redactions"));
+            }
+            if trailer == TrailerMode::Add || self.verify || self.obmap.is_some() {
+                return Err(invalid("trailer=add, verify= or obmap="));
+            }
+        }
+
+        if self.conv.ucase && self.conv.lcase {
+            return Err(eyre!("conv=ucase and conv=lcase can't be used together"));
+        }
+
+        let invalid = |what: &str| eyre!("{what} can't be used with split or join");
+        let targets: Vec<&Output> = self
+            .outputs
+            .iter()
+            .filter(|output| !matches!(output, Output::Hash { .. }))
+            .collect();
+        match self.layout {
+            Layout::Split if targets.len() < 2 => {
+                return Err(eyre!(
+                    "mode=split needs at least two outputs to split across"
+                ));
+            }
+            Layout::Mirror if self.split.is_some() => {
+                if let Some(output) = targets
+                    .iter()
+                    .find(|output| !matches!(output, Output::File(_) | Output::Auto(_)))
+                {
+                    return Err(eyre!("split= can only write shards of file outputs")
+                        .with_note(|| format!("output {output}")));
+                }
+                if self.verify {
+                    return Err(invalid("verify="));
+                }
+                if !self.seek.is_zero() {
+                    return Err(invalid("seek="));
+                }
+            }
+            _ => {}
+        }
+        if (self.layout != Layout::Mirror || self.split.is_some()) && self.resume.is_some() {
+            return Err(invalid("resume="));
+        }
+        let piped = matches!(input, Input::Pipe(_))
+            || self
+                .outputs
+                .iter()
+                .any(|output| matches!(output, Output::Pipe(_)));
+        if piped && (self.resume.is_some() || self.rescue.is_some()) {
+            return Err(eyre!("resume= and rescue= can't be used with pipes")
+                .with_note(|| "what went through a pipe is gone once it has been read"));
+        }
+        if self.split == Some(0) {
+            return Err(eyre!("split= must be greater than zero"));
+        }
+        if self.wave_size == Some(0) {
+            return Err(eyre!("wave= must be greater than zero"));
+        }
+        // Only files and devices can be read back; anything else would be
+        // reported as verified without having been.
+        if self.verify
+            && let Some(output) = targets
+                .iter()
+                .find(|output| !matches!(output, Output::File(_) | Output::Auto(_)))
+        {
+            let remote = output.is_remote();
+            return Err(
+                eyre!("verify=1 can't read {output} back").with_suggestion(move || {
+                    if remote {
+                        "check the copy where it ends up with verify-plan=, or leave out verify=1"
+                    } else {
+                        "leave out verify=1, or write to a file and copy that on"
+                    }
+                }),
+            );
+        }
+        if !self.verify && (self.verify_rate.is_some() || self.verify_priority.is_some()) {
+            return Err(
+                eyre!("verify-rate= and verify-priority= pace reading the outputs back")
+                    .with_suggestion(|| "add verify=1 to read them back"),
+            );
+        }
+        if self.verify_plan.is_some() && !self.outputs.iter().any(Output::is_remote) {
+            return Err(
+                eyre!("verify-plan= is for outputs read back on another machine")
+                    .with_suggestion(|| "add an omcast=, os= or of=- output, or use verify=1"),
+            );
+        }
+
+        if self.trailer == TrailerMode::Add {
+            let invalid = |what: &str| eyre!("trailer=add can't be used with {what}");
+            if self.layout != Layout::Mirror || self.split.is_some() {
+                return Err(invalid("split or join"));
+            }
+            if self.resume.is_some() {
+                return Err(invalid("resume="));
+            }
+            // The trailer covers the whole file, from its start, and has to
+            // be where it ends.
+            if !self.seek.is_zero() {
+                return Err(invalid("seek="));
+            }
+            if self.conv.notrunc {
+                return Err(invalid("conv=notrunc")
+                    .with_note(|| "a longer file would go on past the trailer"));
+            }
+            // Only a regular file is looked for a trailer at the end of, and
+            // a device goes on past the end of the image.
+            if let Some(output) = targets
+                .iter()
+                .find(|output| matches!(output, Output::File(path) if is_block_device(path)))
+            {
+                return Err(eyre!("trailer=add only adds trailers to files")
+                    .with_note(|| format!("output {output} is a device"))
+                    .with_suggestion(|| "keep a checksum with hash=sha256:FILE instead"));
+            }
+            if !targets
+                .iter()
+                .any(|output| matches!(output, Output::File(_) | Output::Auto(_)))
+            {
+                return Err(eyre!(
+                    "trailer=add needs a file output to add the trailer to"
+                ));
+            }
+        }
+
+        if self.obmap_sparse && self.obmap.is_none() {
+            return Err(eyre!("obmap-sparse=1 needs obmap= to write the map to"));
+        }
+
+        // Frames are read back through pdd info, not verify=, and a stream
+        // is framed from its start, in one piece.
+        if let Some(container) = self.container {
+            let invalid = |what: &str| eyre!("container={container} can't be used with {what}");
+            if self.layout != Layout::Mirror || self.split.is_some() {
+                return Err(invalid("split or join"));
+            }
+            if self.verify || self.verify_plan.is_some() {
+                return Err(invalid("verify= or verify-plan=")
+                    .with_suggestion(|| "check each output with pdd info FILE instead"));
+            }
+            if self.resume.is_some() {
+                return Err(invalid("resume="));
+            }
+            if self.ibmap.is_some() {
+                return Err(invalid("ibmap="));
+            }
+            if !self.seek.is_zero() {
+                return Err(invalid("seek="));
+            }
+            if self.fix_gpt || self.new_ids {
+                return Err(invalid("fix-gpt= or new-ids="));
+            }
+        }
+
+        // Both change the disk as written once the copy is done, which only
+        // works on a whole, plain copy, and would leave a trailer behind
+        // that no longer matches.
+        for (operand, enabled) in [("fix-gpt=1", self.fix_gpt), ("new-ids=1", self.new_ids)] {
+            if !enabled {
+                continue;
+            }
+            let invalid = |what: &str| eyre!("{operand} can't be used with {what}");
+            if self.layout != Layout::Mirror || self.split.is_some() {
+                return Err(invalid("split or join"));
+            }
+            if self.comp.is_some() {
+                return Err(invalid("comp="));
+            }
+            if self.enc.is_some() {
+                return Err(invalid("enc="));
+            }
+            if self.trailer == TrailerMode::Add {
+                return Err(invalid("trailer=add"));
+            }
+        }
+
+        if let Some(size) = self.s3_part
+            && size < s3::MIN_PART
+        {
+            return Err(eyre!("s3-part= must be at least 5M")
+                .with_note(|| format!("input s3-part={size}"))
+                .with_suggestion(|| "object storage takes no smaller parts but the last"));
+        }
+        // A part per block, unless blocks are smaller than what is worth a
+        // request.
+        let part = self
+            .s3_part
+            .unwrap_or(output_block_size.max(s3::DEFAULT_PART));
+        let mut outputs = self.outputs;
+        for output in &mut outputs {
+            if let Output::S3 { part_size, .. } = output {
+                *part_size = part;
+            }
+        }
+
+        let hashed = outputs
+            .iter()
+            .any(|output| matches!(output, Output::Hash { .. }));
+        if let Some(output) = outputs
+            .iter()
+            .find(|output| matches!(output, Output::Auto(template) if template.needs_hash()))
+            && !hashed
+        {
+            return Err(eyre!("{{hash}} needs a hash= output to take the digest of")
+                .with_note(|| format!("output {output}"))
+                .with_suggestion(|| "add e.g. hash=sha256"));
+        }
+
+        let mut output_limits = self.output_limits;
+        output_limits.resize(outputs.len(), None);
+        let mut output_frames = self.output_frames;
+        output_frames.resize(outputs.len(), None);
+        let mut output_errors = self.output_errors;
+        output_errors.resize(outputs.len(), None);
+        let mut permissions = self.permissions;
+        permissions.resize(outputs.len(), Permissions::default());
+        let on_error = output_errors
+            .into_iter()
+            .map(|policy| policy.unwrap_or(self.on_error))
+            .collect();
+
         Ok(Operation {
-            input_file: input_file.clone(),
-            outputs: self.outputs,
-            block_size: self.block_size,
+            input,
+            outputs,
+            block_size,
+            output_block_size,
             is_redirected: self.is_redirected,
             count: self.count,
+            duration: self.duration,
+            idle_timeout: self.idle_timeout,
+            limit: self.limit,
+            output_limits,
+            output_frames,
+            on_error,
+            permissions,
+            skip: self.skip,
+            seek: self.seek,
+            injections: self.injections,
+            patches: self.patches,
+            redactions: self.redactions,
+            scans: self.scans,
+            carve: self.carve,
+            conv: self.conv,
+            iflag: self.iflag,
+            oflag: self.oflag,
+            comp: self.comp,
+            decomp,
+            enc: self.enc,
+            dec: self.dec,
+            container: self.container,
+            cache: self.cache,
+            rescue: self.rescue,
+            ibmap: self.ibmap,
+            obmap: self.obmap,
+            obmap_sparse: self.obmap_sparse,
+            verify_catalog: self.verify_catalog,
+            verify: self.verify,
+            verify_rate: self.verify_rate,
+            verify_priority: self.verify_priority,
+            verify_retries: self.verify_retries,
+            verify_plan: self.verify_plan,
+            resume: self.resume,
+            layout: self.layout,
+            split: self.split,
+            trailer,
+            fix_gpt: self.fix_gpt,
+            new_ids: self.new_ids,
+            wave_size: self.wave_size,
+            wave: None,
         })
     }
 }
 
 impl Arguments {
+    /// The renderer to draw progress with, if progress is drawn at all.
+    /// With `log=json` progress is part of the event stream unless
+    /// `status=none` is given.
+    pub fn renderer(&self) -> Option<Renderer> {
+        let json = (self.log == Log::Json).then_some(Renderer::Json);
+        match self.status {
+            Status::None => None,
+            Status::Progress => Some(self.progress.or(json).unwrap_or_default()),
+            Status::Default => self.progress.or(json),
+        }
+    }
+
+    /// The report format given with `log=` on the command line, found
+    /// before the arguments are parsed so that errors in them are reported
+    /// in it too.
+    pub fn log() -> Log {
+        std::env::args()
+            .skip(1)
+            .filter_map(|arg| arg.strip_prefix("log=")?.parse().ok())
+            .next_back()
+            .unwrap_or_default()
+    }
+
     pub fn parse() -> Result<Self> {
         let mut args = Self::default();
         let mut op = OperationBuilder::default();
-        for arg in std::env::args() {
+        let mut argv = std::env::args().skip(1).peekable();
+        if argv.next_if_eq("self-test").is_some() {
+            if let Some(arg) = argv.next() {
+                return Err(eyre!(
+                    "Invalid command line argument, self-test takes no arguments, got {arg}"
+                ));
+            }
+            args.self_test = true;
+            return Ok(args);
+        }
+        if argv.next_if_eq("history").is_some() {
+            args.query = Some(parse_query(argv)?);
+            return Ok(args);
+        }
+        if argv.next_if_eq("catalog").is_some() {
+            let (command, path) = parse_catalog(argv)?;
+            args.catalog_command = Some(command);
+            args.catalog = path;
+            return Ok(args);
+        }
+        if argv.next_if_eq("rekey").is_some() {
+            args.rekey = Some(parse_rekey(argv)?);
+            return Ok(args);
+        }
+        if argv.next_if_eq("verify-plan").is_some() {
+            args.plan_check = Some(parse_plan_check(argv)?);
+            return Ok(args);
+        }
+        if argv.next_if_eq("info").is_some() {
+            args.info = Some(parse_info(argv)?);
+            return Ok(args);
+        }
+        while let Some(arg) = argv.next() {
             if arg == SEPARATOR {
                 let this = std::mem::take(&mut op).build()?;
                 args.operations.push(this);
                 continue;
             }
 
-            let Some((lhs, rhs)) = arg.split_once('=') else {
-                return Err(eyre!(
-                    "Invalid command line argument, expected key=value pair, got {arg}"
-                ));
-            };
-            let (lhs, rhs) = (lhs.trim(), rhs.trim());
-            match lhs {
-                "if" => op.input_file(PathBuf::from_str(rhs)?),
-                "of" => op.output_file(PathBuf::from_str(rhs)?),
-                "os" => {
-                    let Some((mut hostname, port_str)) = rhs.split_once(':') else {
-                        return Err(eyre!(
-                            "Invalid command line argument, expected os=hostname:port, got {arg}"
-                        ));
-                    };
-                    let port = port_str.parse()?;
-                    if hostname.is_empty() {
-                        hostname = "localhost";
+            if let Some((flag, mut value)) = parse_flag(&arg, &mut argv) {
+                match flag {
+                    "profile" => args.profile = true,
+                    "no-advice" => args.no_advice = true,
+                    "no-rdisk" => args.no_rdisk = true,
+                    "report" => args.report = Some(PathBuf::from(value()?)),
+                    "csv" => args.csv = Some(PathBuf::from(value()?)),
+                    "print" => args.print = Some(value()?.parse()?),
+                    "history" => args.history = Some(PathBuf::from(value()?)),
+                    "catalog" => args.catalog = Some(PathBuf::from(value()?)),
+                    "progress" => args.progress = Some(value()?.parse()?),
+                    "tui" => args.progress = Some(Renderer::Tui),
+                    "config" => {
+                        if !op.is_empty() {
+                            return Err(eyre!(
+                                "Invalid command line argument, --config in the middle of an operation"
+                            )
+                            .with_suggestion(|| "end the operation before it with --"));
+                        }
+                        args.load_config(Path::new(&value()?))?;
                     }
-                    op.output_socket(hostname, port);
+                    _ => return Err(eyre!("Invalid command line argument, unknown flag {arg}")),
                 }
-                "ohttp" => {
-                    let Some((method, url)) = rhs.split_once(';') else {
-                        return Err(eyre!(
-                            "Invalid command line argument, expected ohttp=[METHOD];[URL], got {rhs}"
-                        ));
-                    };
-                }
-                "bs" => {
-                    let block_size: u64 = rhs.parse()?;
-                    op.block_size(block_size);
+                continue;
+            }
+
+            args.operand(&mut op, &arg)?;
+        }
+        if !op.is_empty() {
+            args.operations.push(op.build()?);
+        }
+
+        if args.operations.is_empty() {
+            return Err(eyre!("No outputs given"));
+        }
+        args.operations = wave::split(args.operations)?;
+        pipe::check(&args.operations)?;
+        if let Some(format) = &args.print
+            && args.operations.iter().any(|op| {
+                op.outputs
+                    .iter()
+                    .any(|output| matches!(output, Output::Stdout))
+            })
+        {
+            return Err(eyre!("--print can't be used with of=-")
+                .with_note(|| format!("input --print={format}"))
+                .with_note(|| "what it prints would end up in the output"));
+        }
+
+        Ok(args)
+    }
+
+    /// Add the operations of the config file at `path`, each built and
+    /// checked as if given on the command line. Errors name the line of the
+    /// key, or of the `[[operation]]` header for the operation as a whole.
+    fn load_config(&mut self, path: &Path) -> Result<()> {
+        let config = Config::load(path)?;
+        let at = |line: usize| {
+            move |e: color_eyre::Report| {
+                e.with_note(|| format!("config {} line {line}", path.display()))
+            }
+        };
+        let mut globals = OperationBuilder::default();
+        for entry in &config.globals {
+            if !GLOBAL_OPERANDS.contains(&entry.key.as_str()) {
+                return Err(eyre!(
+                    "Invalid config file, {} outside of an operation",
+                    entry.key
+                ))
+                .map_err(at(entry.line))
+                .with_suggestion(|| {
+                    format!(
+                        "put it under a {OPERATION} header, only {} apply to the whole run",
+                        GLOBAL_OPERANDS.join(", ")
+                    )
+                });
+            }
+            for operand in entry.operands() {
+                self.operand(&mut globals, &operand)
+                    .map_err(at(entry.line))?;
+            }
+        }
+        for (line, entries) in &config.operations {
+            let mut op = OperationBuilder::default();
+            for entry in entries {
+                for operand in entry.operands() {
+                    self.operand(&mut op, &operand).map_err(at(entry.line))?;
                 }
-                "count" | "c" => {
-                    let count: u64 = rhs.parse()?;
-                    op.count(count);
+            }
+            if op.is_empty() {
+                return Err(eyre!("Invalid config file, empty operation")).map_err(at(*line));
+            }
+            self.operations.push(op.build().map_err(at(*line))?);
+        }
+        Ok(())
+    }
+
+    /// Apply one `key=value` operand to `op`, or to the whole run for the
+    /// ones that aren't per operation.
+    fn operand(&mut self, op: &mut OperationBuilder, arg: &str) -> Result<()> {
+        let Some((lhs, rhs)) = arg.split_once('=') else {
+            return Err(eyre!(
+                "Invalid command line argument, expected key=value pair, got {arg}"
+            ));
+        };
+        let (lhs, rhs) = (lhs.trim(), rhs.trim());
+        match lhs {
+            "if" if rhs == "-" => op.input_stdin(),
+            "if" if rhs.starts_with("pipe:") => op.input_pipe(pipe_name(rhs)?),
+            "if" if Generator::is_generator(rhs) => op.input_generated(rhs.parse()?),
+            "if" => op.input_file(PathBuf::from_str(rhs)?),
+            "is" => {
+                let (hostname, port) = parse_address(lhs, rhs)?;
+                op.input_socket(hostname, port);
+            }
+            "listen" => {
+                let (address, port) = parse_address(lhs, rhs)?;
+                let address = match address {
+                    "" => "0.0.0.0",
+                    address => address.trim_start_matches('[').trim_end_matches(']'),
+                };
+                op.input_listen(address, port);
+            }
+            "ihttp" => op.input_http(rhs),
+            "imcast" => op.input_multicast(multicast::parse_group(lhs, rhs)?),
+            "of" if rhs == "-" => op.output_stdout(),
+            "of" if rhs.starts_with("pipe:") => op.output_pipe(pipe_name(rhs)?),
+            "of" if rhs.starts_with("auto:") => op.output_auto(rhs["auto:".len()..].parse()?),
+            "of" => op.output_file(PathBuf::from_str(rhs)?),
+            "os" => {
+                let (mut hostname, port) = parse_address(lhs, rhs)?;
+                if hostname.is_empty() {
+                    hostname = "localhost";
                 }
-                "redir" => op.is_redirected(),
-                _ => {
+                op.output_socket(hostname, port);
+            }
+            "omcast" => op.output_multicast(multicast::parse_group(lhs, rhs)?),
+            "ohttp" => {
+                let Some((method, url)) = rhs.split_once(';') else {
                     return Err(eyre!(
-                        "Invalid command line argument, unexpected input {arg}"
+                        "Invalid command line argument, expected ohttp=[METHOD];[URL], got {rhs}"
                     ));
-                }
+                };
+                op.output_http(method, url);
+            }
+            "os3" => op.output_s3(rhs.parse()?),
+            "hash" => {
+                let (algorithm, sidecar) = match rhs.split_once(':') {
+                    Some((algorithm, path)) => (algorithm, Some(PathBuf::from_str(path)?)),
+                    None => (rhs, None),
+                };
+                op.output_hash(algorithm.parse()?, sidecar);
+            }
+            "inject" => op.inject(rhs.parse()?),
+            "patch" => op.patch(PathBuf::from_str(rhs)?),
+            "redact" => op.redact(Redaction::from_str(rhs)?),
+            "scan" => op.scan(Scan::from_str(rhs)?),
+            "carve" => op.carve(PathBuf::from_str(rhs)?),
+            "conv" => op.conv(Conv::from_str(rhs)?),
+            "iflag" => op.iflag(Iflag::from_str(rhs)?),
+            "oflag" => op.oflag(Oflag::from_str(rhs)?),
+            "comp" => op.comp(Compression::from_str(rhs)?),
+            "decomp" => op.decomp(Decompression::from_str(rhs)?),
+            "enc" => op.enc(Encryption::from_str(rhs)?),
+            "dec" => op.dec(Decryption::from_str(rhs)?),
+            "container" => op.container(rhs.parse()?),
+            "verify" => op.verify(parse_bool(lhs, rhs)?),
+            "verify-catalog" => op.verify_catalog(rhs.to_string()),
+            "verify-rate" => op.verify_rate(parse_rate(lhs, rhs)?),
+            "verify-priority" => op.verify_priority(rhs.parse()?),
+            "verify-retries" => op.verify_retries(parse_number(lhs, rhs)?),
+            "verify-plan" => op.verify_plan(PathBuf::from(rhs)),
+            "cache" => op.cache(PathBuf::from_str(rhs)?),
+            "rescue" => op.rescue(PathBuf::from_str(rhs)?),
+            "ibmap" => op.ibmap(PathBuf::from_str(rhs)?),
+            "obmap" => op.obmap(PathBuf::from_str(rhs)?),
+            "obmap-sparse" => op.obmap_sparse(parse_bool(lhs, rhs)?),
+            "resume" => op.resume(PathBuf::from_str(rhs)?),
+            "bs" => op.block_size(parse_size(lhs, rhs)?),
+            "ibs" => op.input_block_size(parse_size(lhs, rhs)?),
+            "obs" => op.output_block_size(parse_size(lhs, rhs)?),
+            "count" | "c" => op.count(parse_amount(lhs, rhs)?),
+            "duration" => op.duration(parse_duration(lhs, rhs)?),
+            "idle-timeout" => op.idle_timeout(parse_duration(lhs, rhs)?),
+            "limit" => op.limit(parse_rate(lhs, rhs)?),
+            "olimit" => op.output_limit(parse_rate(lhs, rhs)?)?,
+            "oframe" => op.output_frame(parse_size(lhs, rhs)?)?,
+            "onerror" => op.on_error(ErrorPolicy::from_str(rhs)?),
+            "mode" if matches!(rhs, "mirror" | "split" | "join") => op.layout(rhs.parse()?),
+            "split" => op.split(parse_size(lhs, rhs)?),
+            "trailer" => op.trailer(rhs.parse()?),
+            "fix-gpt" => op.fix_gpt(parse_bool(lhs, rhs)?),
+            "new-ids" => op.new_ids(parse_bool(lhs, rhs)?),
+            "wave" => op.wave_size(parse_number(lhs, rhs)?),
+            "s3-part" => op.s3_part(parse_size(lhs, rhs)?),
+            "mode" => op.mode(parse_mode(rhs)?)?,
+            "owner" => op.owner(rhs.parse()?)?,
+            "skip" | "iseek" => op.skip(parse_amount(lhs, rhs)?),
+            "seek" | "oseek" => op.seek(parse_amount(lhs, rhs)?),
+            "redir" => op.is_redirected(),
+            "status" => self.status = rhs.parse()?,
+            "log" => self.log = rhs.parse()?,
+            "stats" => self.stats = Some(parse_duration(lhs, rhs)?),
+            _ => {
+                return Err(eyre!(
+                    "Invalid command line argument, unexpected input {arg}"
+                ));
             }
         }
-        if op.input_file.is_some() {
-            args.operations.push(op.build()?);
+        Ok(())
+    }
+}
+
+/// The name of `pipe:NAME`, which other operands can't be mistaken for.
+fn pipe_name(value: &str) -> Result<&str> {
+    let name = &value["pipe:".len()..];
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        return Err(eyre!("Invalid pipe name")
+            .with_note(|| format!("pipe {value}"))
+            .with_suggestion(|| "use letters, digits, -, _ and ., e.g. pipe:stage1"));
+    }
+    Ok(name)
+}
+
+/// The name of `arg` if it is a `--flag`, and what takes its value: the rest
+/// of `--flag=VALUE`, or else the next argument.
+fn parse_flag<'a, I: Iterator<Item = String>>(
+    arg: &'a str,
+    argv: &'a mut I,
+) -> Option<(&'a str, impl FnMut() -> Result<String> + 'a)> {
+    let flag = arg.strip_prefix("--")?;
+    let (flag, inline) = match flag.split_once('=') {
+        Some((flag, value)) => (flag, Some(value)),
+        None => (flag, None),
+    };
+    let value = move || {
+        inline
+            .map(str::to_string)
+            .or_else(|| argv.next())
+            .ok_or_else(|| eyre!("Invalid command line argument, --{flag} needs a value"))
+    };
+    Some((flag, value))
+}
+
+/// The flags of `pdd history FILE`.
+fn parse_query(mut argv: impl Iterator<Item = String>) -> Result<Query> {
+    let mut query = Query::default();
+    let mut path = None;
+    while let Some(arg) = argv.next() {
+        let Some((flag, mut value)) = parse_flag(&arg, &mut argv) else {
+            if path.replace(PathBuf::from(&arg)).is_some() {
+                return Err(eyre!(
+                    "Invalid command line argument, history takes one file, got {arg}"
+                ));
+            }
+            continue;
+        };
+        match flag {
+            "since" => {
+                let since = value()?;
+                let time = match parse_timestamp(&since) {
+                    Some(time) => time,
+                    None => SystemTime::now()
+                        .checked_sub(parse_duration("--since", &since).map_err(|e| {
+                            e.with_suggestion(|| "or a date, e.g. --since 2026-09-01")
+                        })?)
+                        .unwrap_or(UNIX_EPOCH),
+                };
+                query.since = Some(time);
+            }
+            "failed" => query.failed = true,
+            "input" => query.input = Some(value()?),
+            "output" => query.output = Some(value()?),
+            "hash" => query.hash = Some(value()?),
+            "json" => query.json = true,
+            _ => return Err(eyre!("Invalid command line argument, unknown flag {arg}")),
         }
+    }
+    query.path = path.ok_or_else(|| {
+        eyre!("Invalid command line argument, history needs the file to search")
+            .with_suggestion(|| "e.g. pdd history runs.jsonl --since 7d --failed")
+    })?;
+    Ok(query)
+}
 
-        Ok(args)
+/// The arguments of `pdd catalog add NAME FILE` and `pdd catalog list`,
+/// and the catalog given with `--catalog`.
+fn parse_catalog(
+    mut argv: impl Iterator<Item = String>,
+) -> Result<(catalog::Command, Option<PathBuf>)> {
+    let usage = || "e.g. pdd catalog add golden-v3 golden.img, or pdd catalog list";
+    let mut words = vec![];
+    let mut algorithm = HashAlgorithm::Sha256;
+    let mut path = None;
+    while let Some(arg) = argv.next() {
+        let Some((flag, mut value)) = parse_flag(&arg, &mut argv) else {
+            words.push(arg);
+            continue;
+        };
+        match flag {
+            "hash" => algorithm = value()?.parse()?,
+            "catalog" => path = Some(PathBuf::from(value()?)),
+            _ => return Err(eyre!("Invalid command line argument, unknown flag {arg}")),
+        }
+    }
+    let command = match words.as_slice() {
+        [list] if list == "list" => catalog::Command::List,
+        [add, name, image] if add == "add" => catalog::Command::Add {
+            name: name.clone(),
+            path: PathBuf::from(image),
+            algorithm,
+        },
+        [] => {
+            return Err(
+                eyre!("Invalid command line argument, catalog needs a command")
+                    .with_suggestion(usage),
+            );
+        }
+        words => {
+            return Err(eyre!(
+                "Invalid command line argument, unknown catalog command {}",
+                words.join(" ")
+            )
+            .with_suggestion(usage));
+        }
+    };
+    Ok((command, path))
+}
+
+/// The arguments of `pdd verify-plan PLAN FILE`, with where the stream
+/// starts in the file and how to read it back.
+fn parse_plan_check(mut argv: impl Iterator<Item = String>) -> Result<PlanCheck> {
+    let usage = || "e.g. pdd verify-plan image.plan /dev/sdb --json";
+    let mut files = vec![];
+    let mut seek = 0;
+    let mut json = false;
+    let mut pace = Pace::default();
+    while let Some(arg) = argv.next() {
+        let Some((flag, mut value)) = parse_flag(&arg, &mut argv) else {
+            files.push(PathBuf::from(arg));
+            continue;
+        };
+        match flag {
+            "seek" => seek = parse_size("--seek", &value()?)?,
+            "json" => json = true,
+            "rate" => pace.rate = Some(parse_rate("--rate", &value()?)?),
+            "priority" => pace.priority = Some(value()?.parse()?),
+            _ => return Err(eyre!("Invalid command line argument, unknown flag {arg}")),
+        }
+    }
+    let [plan, target] = <[PathBuf; 2]>::try_from(files).map_err(|_| {
+        eyre!("Invalid command line argument, verify-plan needs the plan and the copy to check")
+            .with_suggestion(usage)
+    })?;
+    Ok(PlanCheck {
+        plan,
+        target,
+        seek,
+        json,
+        pace,
+    })
+}
+
+/// The arguments of `pdd info FILE [--json]`.
+fn parse_info(mut argv: impl Iterator<Item = String>) -> Result<Info> {
+    let mut path = None;
+    let mut json = false;
+    while let Some(arg) = argv.next() {
+        let Some((flag, _)) = parse_flag(&arg, &mut argv) else {
+            if path.replace(PathBuf::from(&arg)).is_some() {
+                return Err(eyre!(
+                    "Invalid command line argument, info takes one file, got {arg}"
+                ));
+            }
+            continue;
+        };
+        match flag {
+            "json" => json = true,
+            _ => return Err(eyre!("Invalid command line argument, unknown flag {arg}")),
+        }
+    }
+    let path = path.ok_or_else(|| {
+        eyre!("Invalid command line argument, info needs the file to describe")
+            .with_suggestion(|| "e.g. pdd info backup.pdds --json")
+    })?;
+    Ok(Info { path, json })
+}
+
+/// The arguments of `pdd rekey FILE --identity KEY`, with the recipients to
+/// `--add` or change `--to`, each an age key source like `enc=age:` takes.
+fn parse_rekey(mut argv: impl Iterator<Item = String>) -> Result<Rekey> {
+    let usage = || "e.g. pdd rekey backup.age --identity key.txt --add age1ql3z7hjy...";
+    let mut files = vec![];
+    let mut identity = None;
+    let mut add = vec![];
+    let mut to = vec![];
+    while let Some(arg) = argv.next() {
+        let Some((flag, mut value)) = parse_flag(&arg, &mut argv) else {
+            files.push(PathBuf::from(arg));
+            continue;
+        };
+        match flag {
+            "identity" => identity = Some(keys::provider(&value()?)?),
+            "add" => add.push(keys::provider(&value()?)?),
+            "to" => to.push(keys::provider(&value()?)?),
+            _ => return Err(eyre!("Invalid command line argument, unknown flag {arg}")),
+        }
     }
+    let [path] = <[PathBuf; 1]>::try_from(files).map_err(|_| {
+        eyre!("Invalid command line argument, rekey needs one age file").with_suggestion(usage)
+    })?;
+    let identity = identity.ok_or_else(|| {
+        eyre!("Invalid command line argument, rekey needs the --identity that can decrypt the file")
+            .with_suggestion(usage)
+    })?;
+    Ok(Rekey {
+        path,
+        identity,
+        add,
+        to,
+    })
 }
@@ -0,0 +1,489 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{compress, hash::to_hex, rescue::write_at, sink::Sink};
+
+/// Size of the blocks pdd maps, the one bmaptool uses too.
+pub const BLOCK_SIZE: u64 = 4096;
+
+/// Bytes read from the image at a time while flashing.
+const CHUNK: usize = 1 << 20;
+
+/// A block map of an image in the format of bmaptool (`obmap=FILE` and
+/// `ibmap=FILE`): the ranges of blocks that hold data, each with the
+/// SHA-256 of its bytes, so the rest needn't be written when flashing it.
+///
+/// Like bmaptool, pdd maps the blocks an image file has allocated, zeros or
+/// not, so only its holes are left out. The blocks of a stream that isn't
+/// such a file are all mapped, unless `obmap-sparse=1` leaves out those of
+/// all zeros. Either way a device flashed with the map keeps whatever it
+/// held under the blocks left out.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub ranges: Vec<Range>,
+}
+
+/// A run of mapped blocks, `first` to `last` inclusive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub first: u64,
+    pub last: u64,
+
+    /// Hex SHA-256 of the bytes of the blocks, if the map has it
+    pub sha256: Option<String>,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// The trimmed text of the first `<tag>...</tag>` in `text`.
+fn element<'a>(text: &'a str, tag: &str) -> Option<&'a str> {
+    let start = text.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + text[start..].find(&format!("</{tag}>"))?;
+    Some(text[start..end].trim())
+}
+
+/// The value of `name="..."` in the attributes `text` of a tag.
+fn attribute<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let start = text.find(&format!("{name}=\""))? + name.len() + 2;
+    let end = start + text[start..].find('"')?;
+    Some(&text[start..end])
+}
+
+impl Bmap {
+    /// Blocks in the image, the last of them possibly short.
+    pub fn blocks(&self) -> u64 {
+        self.image_size.div_ceil(self.block_size)
+    }
+
+    pub fn mapped_blocks(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| range.last - range.first + 1)
+            .sum()
+    }
+
+    /// Offset and length in the image of the bytes of `range`.
+    pub fn extent(&self, range: &Range) -> (u64, u64) {
+        let start = range.first * self.block_size;
+        let end = ((range.last + 1) * self.block_size).min(self.image_size);
+        (start, end - start)
+    }
+
+    /// Read a map as bmaptool writes it, version 2, or 1 without range
+    /// checksums, checking the checksum of the file itself if it has one.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let version = text
+            .find("<bmap")
+            .and_then(|start| attribute(&text[start..], "version"))
+            .ok_or_else(|| invalid("not a bmap file"))?;
+        let major = version.split('.').next().unwrap_or_default();
+        if major != "1" && major != "2" {
+            return Err(invalid(format!(
+                "version {version} of the bmap format, pdd reads versions 1 and 2"
+            )));
+        }
+        let number = |tag: &str| -> io::Result<u64> {
+            element(text, tag)
+                .ok_or_else(|| invalid(format!("the bmap has no {tag}")))?
+                .parse()
+                .map_err(|_| invalid(format!("the {tag} of the bmap isn't a number")))
+        };
+        let image_size = number("ImageSize")?;
+        let block_size = number("BlockSize")?;
+        if block_size == 0 {
+            return Err(invalid("the BlockSize of the bmap is zero"));
+        }
+        let checksum = element(text, "ChecksumType").unwrap_or("sha1");
+
+        if let Some(expected) = element(text, "BmapFileChecksum")
+            && checksum == "sha256"
+        {
+            // Taken with the checksum itself all zeros.
+            let zeroed = text.replacen(expected, &"0".repeat(expected.len()), 1);
+            if to_hex(&Sha256::digest(zeroed.as_bytes())) != expected {
+                return Err(invalid(
+                    "the bmap doesn't match its BmapFileChecksum, so it was altered",
+                ));
+            }
+        }
+
+        let mut bmap = Bmap {
+            image_size,
+            block_size,
+            ranges: vec![],
+        };
+        let map = element(text, "BlockMap").unwrap_or_default();
+        for piece in map.split("<Range").skip(1) {
+            let (attributes, rest) = piece
+                .split_once('>')
+                .ok_or_else(|| invalid("a Range of the bmap isn't closed"))?;
+            let blocks = rest
+                .split_once("</Range>")
+                .ok_or_else(|| invalid("a Range of the bmap isn't closed"))?
+                .0
+                .trim();
+            let parse = |n: &str| {
+                n.trim()
+                    .parse::<u64>()
+                    .map_err(|_| invalid(format!("bmap Range {blocks} isn't block numbers")))
+            };
+            let (first, last) = match blocks.split_once('-') {
+                Some((first, last)) => (parse(first)?, parse(last)?),
+                None => (parse(blocks)?, parse(blocks)?),
+            };
+            let sha256 = attribute(attributes, "chksum").map(str::to_string);
+            if sha256.is_some() && checksum != "sha256" {
+                return Err(invalid(format!(
+                    "the bmap has {checksum} checksums, pdd checks sha256 ones"
+                )));
+            }
+            let after = bmap.ranges.last().map_or(0, |range| range.last + 1);
+            if first < after || last < first || last >= bmap.blocks() {
+                return Err(invalid(format!(
+                    "bmap Range {blocks} is out of order or past the end of the image"
+                )));
+            }
+            bmap.ranges.push(Range {
+                first,
+                last,
+                sha256,
+            });
+        }
+        Ok(bmap)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        std::fs::read_to_string(path)
+            .and_then(|text| Bmap::parse(&text))
+            .map_err(|e| {
+                eyre!("Failed to read block map")
+                    .with_error(|| e)
+                    .with_note(|| format!("ibmap={}", path.display()))
+            })
+    }
+
+    /// The map in bmaptool's format, version 2.0.
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" ?>\n");
+        xml.push_str(
+            "<!-- Block map of an image, written by pdd: the ranges of blocks that hold\n     \
+             data, which are all that has to be written to flash it. -->\n\n",
+        );
+        xml.push_str("<bmap version=\"2.0\">\n");
+        xml.push_str(&format!(
+            "    <ImageSize> {} </ImageSize>\n",
+            self.image_size
+        ));
+        xml.push_str(&format!(
+            "    <BlockSize> {} </BlockSize>\n",
+            self.block_size
+        ));
+        xml.push_str(&format!(
+            "    <BlocksCount> {} </BlocksCount>\n",
+            self.blocks()
+        ));
+        xml.push_str(&format!(
+            "    <MappedBlocksCount> {} </MappedBlocksCount>\n",
+            self.mapped_blocks()
+        ));
+        xml.push_str("    <ChecksumType> sha256 </ChecksumType>\n");
+        let placeholder = "0".repeat(64);
+        xml.push_str(&format!(
+            "    <BmapFileChecksum> {placeholder} </BmapFileChecksum>\n"
+        ));
+        xml.push_str("    <BlockMap>\n");
+        for range in &self.ranges {
+            let blocks = if range.first == range.last {
+                range.first.to_string()
+            } else {
+                format!("{}-{}", range.first, range.last)
+            };
+            match &range.sha256 {
+                Some(sha256) => xml.push_str(&format!(
+                    "        <Range chksum=\"{sha256}\"> {blocks} </Range>\n"
+                )),
+                None => xml.push_str(&format!("        <Range> {blocks} </Range>\n")),
+            }
+        }
+        xml.push_str("    </BlockMap>\n</bmap>\n");
+        let checksum = to_hex(&Sha256::digest(xml.as_bytes()));
+        xml.replacen(&placeholder, &checksum, 1)
+    }
+}
+
+/// Which blocks of a stream a [`BmapSink`] maps.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mapping {
+    /// Those in the extents of the stream, offsets and lengths in order,
+    /// that the file they were read from has allocated
+    Allocated(Vec<(u64, u64)>),
+
+    /// Every block
+    All,
+
+    /// Blocks that aren't all zeros (`obmap-sparse=1`)
+    NonZero,
+}
+
+/// Extents of the image file `file` from `skip` on that it has allocated,
+/// as offsets from `skip` and lengths, found with `SEEK_DATA` and
+/// `SEEK_HOLE` as bmaptool does; `None` where they can't be asked for.
+#[cfg(target_os = "linux")]
+pub fn allocated(file: &File, skip: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::fd::AsRawFd;
+
+    let seek = |offset: u64, whence| {
+        // SAFETY: the descriptor is valid for the lifetime of `file`; its
+        // position is moved, which nothing else reading it shares.
+        let at = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+        match at {
+            -1 => Err(io::Error::last_os_error()),
+            at => Ok(at as u64),
+        }
+    };
+    let len = file.metadata()?.len();
+    let mut extents = vec![];
+    let mut offset = skip;
+    while offset < len {
+        let start = match seek(offset, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // Holes to the end.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let end = seek(start, libc::SEEK_HOLE)?.min(len);
+        extents.push((start - skip, end - start));
+        offset = end;
+    }
+    Ok(Some(extents))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allocated(_file: &File, _skip: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+/// Sink mapping the blocks of the stream that hold data into a [`Bmap`],
+/// written to `path` once the stream ends (`obmap=FILE`).
+pub struct BmapSink {
+    path: PathBuf,
+    bmap: Bmap,
+    mapping: Mapping,
+
+    /// Index of the first extent of [`Mapping::Allocated`] that doesn't end
+    /// before the block being mapped
+    extent: usize,
+
+    /// The start of a block, until the rest of it arrives
+    partial: Vec<u8>,
+
+    /// Bytes mapped so far
+    position: u64,
+
+    /// First block and digest so far of the run of blocks being mapped
+    run: Option<(u64, Sha256)>,
+}
+
+impl BmapSink {
+    pub fn new(path: &Path, mapping: Mapping) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            bmap: Bmap {
+                image_size: 0,
+                block_size: BLOCK_SIZE,
+                ranges: vec![],
+            },
+            mapping,
+            extent: 0,
+            partial: vec![],
+            position: 0,
+            run: None,
+        }
+    }
+
+    fn block(&mut self, data: &[u8]) {
+        let index = self.position / BLOCK_SIZE;
+        let start = self.position;
+        self.position += data.len() as u64;
+        let mapped = match &self.mapping {
+            Mapping::Allocated(extents) => {
+                while extents
+                    .get(self.extent)
+                    .is_some_and(|&(offset, len)| offset + len <= start)
+                {
+                    self.extent += 1;
+                }
+                extents
+                    .get(self.extent)
+                    .is_some_and(|&(offset, _)| offset < self.position)
+            }
+            Mapping::All => true,
+            Mapping::NonZero => data.iter().any(|&b| b != 0),
+        };
+        if !mapped {
+            self.end_run(index);
+            return;
+        }
+        self.run
+            .get_or_insert_with(|| (index, Sha256::new()))
+            .1
+            .update(data);
+    }
+
+    /// Enter the run being mapped, if any, as ending before `index`.
+    fn end_run(&mut self, index: u64) {
+        if let Some((first, hasher)) = self.run.take() {
+            self.bmap.ranges.push(Range {
+                first,
+                last: index - 1,
+                sha256: Some(to_hex(&hasher.finalize())),
+            });
+        }
+    }
+}
+
+impl Write for BmapSink {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len();
+        let block_size = BLOCK_SIZE as usize;
+        if !self.partial.is_empty() {
+            let take = (block_size - self.partial.len()).min(buf.len());
+            self.partial.extend_from_slice(&buf[..take]);
+            buf = &buf[take..];
+            if self.partial.len() < block_size {
+                return Ok(n);
+            }
+            let block = std::mem::take(&mut self.partial);
+            self.block(&block);
+        }
+        let mut blocks = buf.chunks_exact(block_size);
+        for block in &mut blocks {
+            self.block(block);
+        }
+        self.partial.extend_from_slice(blocks.remainder());
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for BmapSink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        if !self.partial.is_empty() {
+            let block = std::mem::take(&mut self.partial);
+            self.block(&block);
+        }
+        self.end_run(self.position.div_ceil(BLOCK_SIZE));
+        self.bmap.image_size = self.position;
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.bmap.to_xml())?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(None)
+    }
+
+    /// No map for part of an image.
+    fn abandon(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What flashing the mapped blocks of an image came to.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Flashed {
+    /// Bytes written to each output
+    pub written: u64,
+    pub ranges: usize,
+}
+
+/// Write the mapped ranges of the image `reader` streams to `outputs`, each
+/// holding it from `seek`, checking each range against its checksum. The
+/// blocks between them are read past.
+pub fn flash(
+    bmap: &Bmap,
+    mut reader: &mut dyn Read,
+    outputs: &[File],
+    seek: u64,
+    interrupt: &AtomicBool,
+) -> Result<Flashed> {
+    let mut flashed = Flashed::default();
+    let mut buffer = vec![0u8; CHUNK];
+    let mut position = 0u64;
+    let mut read = |buffer: &mut [u8], position: u64| -> Result<()> {
+        match compress::read_full(&mut reader, buffer) {
+            Ok(n) if n == buffer.len() => Ok(()),
+            Ok(n) => Err(eyre!("The image is shorter than its block map")
+                .with_note(|| format!("it ends at {}", position + n as u64))
+                .with_note(|| format!("the map says {}", bmap.image_size))),
+            Err(e) => Err(eyre!("Failed to read input")
+                .with_error(|| e)
+                .with_note(|| format!("offset {position}"))),
+        }
+    };
+    for range in &bmap.ranges {
+        let (start, len) = bmap.extent(range);
+        while position < start {
+            let n = (start - position).min(CHUNK as u64) as usize;
+            read(&mut buffer[..n], position)?;
+            position += n as u64;
+        }
+        let mut hasher = Sha256::new();
+        let end = start + len;
+        while position < end {
+            if interrupt.load(Ordering::Relaxed) {
+                return Err(eyre!("Interrupted while flashing").with_note(|| {
+                    format!("{} bytes of the mapped ones written", flashed.written)
+                }));
+            }
+            let n = (end - position).min(CHUNK as u64) as usize;
+            read(&mut buffer[..n], position)?;
+            hasher.update(&buffer[..n]);
+            for output in outputs {
+                write_at(output, &buffer[..n], seek + position).map_err(|e| {
+                    eyre!("Failed to write output")
+                        .with_error(|| e)
+                        .with_note(|| format!("offset {}", seek + position))
+                })?;
+            }
+            position += n as u64;
+            flashed.written += n as u64;
+        }
+        if let Some(expected) = &range.sha256
+            && to_hex(&hasher.finalize()) != *expected
+        {
+            return Err(eyre!(
+                "Blocks {}-{} of the image don't match its block map",
+                range.first,
+                range.last
+            )
+            .with_note(|| "they have been written, so the outputs can't be used")
+            .with_suggestion(|| "check the image and the map go together"));
+        }
+        flashed.ranges += 1;
+    }
+    for output in outputs {
+        // A file output is as long as the image, holes and all.
+        let end = seek + bmap.image_size;
+        let metadata = output.metadata()?;
+        if metadata.is_file() && metadata.len() < end {
+            output.set_len(end)?;
+        }
+        output
+            .sync_all()
+            .map_err(|e| eyre!("Failed to sync output").with_error(|| e))?;
+    }
+    Ok(flashed)
+}
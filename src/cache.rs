@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    diagnostic::{Code, Diagnostic},
+    hash::to_hex,
+    report::format_timestamp,
+};
+
+/// A cache of downloads (`cache=DIR`): a run with an `ihttp=` input keeps a
+/// copy of the download as it streams through, and later runs with the same
+/// URL read that copy instead, as long as the server still has it with the
+/// same `ETag` or `Last-Modified` and it still matches the blake3 digest it
+/// was kept with, which is checked before any of it is read.
+///
+/// Each URL has two files named after the blake3 of the URL: `KEY` holds the
+/// download as served, before any decryption or decompression, and
+/// `KEY.json` the URL, length, digest and what the server said identifies
+/// that version of it. A copy is only kept once the whole download has been
+/// read, so a run stopped early by `count=` or an error leaves nothing
+/// behind.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// What `KEY.json` says about a kept download.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    length: u64,
+    blake3: String,
+    kept: String,
+    #[serde(default, flatten)]
+    version: Version,
+}
+
+/// What a server says identifies the version of a resource it serves, for
+/// telling whether a kept copy is still the same one.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Version {
+    /// The `ETag` and `Last-Modified` headers of `response`.
+    pub fn of<B>(response: &ureq::http::Response<B>) -> Self {
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header("etag"),
+            last_modified: header("last-modified"),
+        }
+    }
+
+    fn is_known(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+}
+
+impl Cache {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn data(&self, url: &str) -> PathBuf {
+        self.dir
+            .join(blake3::hash(url.as_bytes()).to_hex().as_str())
+    }
+
+    fn metadata(&self, url: &str) -> PathBuf {
+        self.data(url).with_extension("json")
+    }
+
+    fn entry(&self, url: &str) -> Option<Entry> {
+        let text = std::fs::read_to_string(self.metadata(url)).ok()?;
+        serde_json::from_str::<Entry>(&text)
+            .ok()
+            .filter(|entry| entry.url == url)
+    }
+
+    /// True if a copy of `url` has been kept.
+    pub fn contains(&self, url: &str) -> bool {
+        self.entry(url).is_some()
+    }
+
+    /// Throw the kept copy of `url` away unless the server still serves the
+    /// same version of it, asked with a HEAD request. A copy kept without a
+    /// version can't be told apart from a newer one and is thrown away too;
+    /// one that can't be asked about is kept, saying so.
+    pub fn revalidate(&self, url: &str) {
+        let Some(entry) = self.entry(url) else {
+            return;
+        };
+        let stale = |why: &str| {
+            self.remove(url);
+            Diagnostic::new(
+                Code::CacheStale,
+                format!("{why}, so it is downloaded again"),
+            )
+            .subject(format!("ihttp={url}"))
+            .emit();
+        };
+        if !entry.version.is_known() {
+            return stale("the kept copy has no ETag or Last-Modified to check");
+        }
+        match ureq::head(url).call() {
+            Ok(response) if Version::of(&response) == entry.version => {}
+            Ok(_) => stale("the server has a different version than the kept copy"),
+            Err(e) => Diagnostic::new(
+                Code::CacheUnchecked,
+                format!("couldn't check the kept copy is still current ({e}), reading it anyway"),
+            )
+            .subject(format!("ihttp={url}"))
+            .emit(),
+        }
+    }
+
+    fn remove(&self, url: &str) {
+        let _ = std::fs::remove_file(self.metadata(url));
+        let _ = std::fs::remove_file(self.data(url));
+    }
+
+    /// The kept copy of `url`, if there is one, once all of it has been
+    /// checked against its digest; one that no longer matches is thrown
+    /// away, for the download to be made again.
+    pub fn open(&self, url: &str) -> io::Result<Option<File>> {
+        let Some(entry) = self.entry(url) else {
+            return Ok(None);
+        };
+        let mut file = match File::open(self.data(url)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut hasher = blake3::Hasher::new();
+        let length = io::copy(&mut file, &mut hasher)?;
+        let digest = to_hex(hasher.finalize().as_bytes());
+        if digest != entry.blake3 || length != entry.length {
+            self.remove(url);
+            Diagnostic::new(
+                Code::CacheStale,
+                format!(
+                    "the kept copy doesn't match what was kept, blake3 {digest} instead of {}, \
+                     so it is downloaded again",
+                    entry.blake3
+                ),
+            )
+            .subject(format!("ihttp={url}"))
+            .emit();
+            return Ok(None);
+        }
+        file.rewind()?;
+        Ok(Some(file))
+    }
+
+    /// Read `inner`, the download of `url` from its start, keeping a copy
+    /// along with the `version` the server served.
+    pub fn fill<R: Read>(&self, url: &str, inner: R, version: Version) -> io::Result<CacheFill<R>> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.data(url);
+        let partial = path.with_extension(format!("partial.{}", std::process::id()));
+        Ok(CacheFill {
+            inner,
+            file: Some(File::create(&partial)?),
+            hasher: blake3::Hasher::new(),
+            length: 0,
+            url: url.to_string(),
+            version,
+            partial,
+            path,
+            metadata: self.metadata(url),
+        })
+    }
+}
+
+/// Passes a download on while writing it to the cache, which it enters once
+/// the download has been read to its end.
+pub struct CacheFill<R> {
+    inner: R,
+
+    /// Taken once the copy is kept, or given up on
+    file: Option<File>,
+    hasher: blake3::Hasher,
+    length: u64,
+    url: String,
+    version: Version,
+    partial: PathBuf,
+    path: PathBuf,
+    metadata: PathBuf,
+}
+
+impl<R> CacheFill<R> {
+    /// Give up on keeping a copy, without failing the download.
+    fn abandon(&mut self, e: io::Error) {
+        self.file = None;
+        let _ = std::fs::remove_file(&self.partial);
+        Diagnostic::new(Code::CacheNotKept, format!("failed to keep a copy: {e}"))
+            .subject(format!("ihttp={}", self.url))
+            .emit();
+    }
+
+    /// Enter the finished copy in the cache.
+    fn keep(&mut self, mut file: File) -> io::Result<()> {
+        file.flush()?;
+        file.sync_all()?;
+        std::fs::rename(&self.partial, &self.path)?;
+        let entry = Entry {
+            url: self.url.clone(),
+            length: self.length,
+            blake3: to_hex(self.hasher.finalize().as_bytes()),
+            kept: format_timestamp(SystemTime::now()),
+            version: self.version.clone(),
+        };
+        let json = serde_json::to_string_pretty(&entry)?;
+        let tmp = self.metadata.with_extension("json.tmp");
+        std::fs::write(&tmp, json + "\n")?;
+        std::fs::rename(&tmp, &self.metadata)
+    }
+}
+
+impl<R: Read> Read for CacheFill<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(file) = &mut self.file {
+            if n > 0 {
+                self.hasher.update(&buf[..n]);
+                self.length += n as u64;
+                if let Err(e) = file.write_all(&buf[..n]) {
+                    self.abandon(e);
+                }
+            } else if !buf.is_empty()
+                && let Some(file) = self.file.take()
+                && let Err(e) = self.keep(file)
+            {
+                self.abandon(e);
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<R> Drop for CacheFill<R> {
+    /// A download that wasn't read to its end isn't kept.
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.partial);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use regex::bytes::Regex;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::sink::Sink;
+
+/// A file type recognised by its header and footer signatures.
+struct Signature {
+    kind: FileKind,
+    header: &'static [u8],
+    footer: &'static [u8],
+
+    /// Bytes of trailer following the footer that still belong to the file
+    trailer: usize,
+
+    /// Candidates are cut off at this size if no footer turns up
+    max_len: u64,
+}
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        kind: FileKind::Jpeg,
+        header: b"\xff\xd8\xff",
+        footer: b"\xff\xd9",
+        trailer: 0,
+        max_len: 32 << 20,
+    },
+    Signature {
+        kind: FileKind::Png,
+        header: b"\x89PNG\r\n\x1a\n",
+        footer: b"IEND\xae\x42\x60\x82",
+        trailer: 0,
+        max_len: 64 << 20,
+    },
+    // The end of central directory record is 22 bytes including its
+    // signature; a trailing archive comment is not carved.
+    Signature {
+        kind: FileKind::Zip,
+        header: b"PK\x03\x04",
+        footer: b"PK\x05\x06",
+        trailer: 18,
+        max_len: 1 << 30,
+    },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Jpeg,
+    Png,
+    Zip,
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileKind::Jpeg => write!(f, "jpg"),
+            FileKind::Png => write!(f, "png"),
+            FileKind::Zip => write!(f, "zip"),
+        }
+    }
+}
+
+/// One candidate region written out by `carve=`.
+#[derive(Clone, Debug)]
+pub struct Carved {
+    pub kind: FileKind,
+
+    /// Offset of the header from the start of the input
+    pub offset: u64,
+
+    pub len: u64,
+    pub path: PathBuf,
+
+    /// False if the region was cut off before a footer was seen
+    pub complete: bool,
+}
+
+/// A candidate being written out.
+struct Carving {
+    /// Index into [`SIGNATURES`]
+    signature: usize,
+    file: File,
+    carved: Carved,
+}
+
+/// Sink writing every region that looks like a known file type to its own
+/// file in a directory (`carve=DIR`), with a `manifest.csv` listing them.
+pub struct CarveSink {
+    dir: PathBuf,
+    headers: Regex,
+    footers: Vec<Regex>,
+    window: Vec<u8>,
+    /// Offset of `window` from the start of the input
+    position: u64,
+    current: Option<Carving>,
+    carved: Arc<Mutex<Vec<Carved>>>,
+}
+
+impl CarveSink {
+    /// `position` is the offset of the first block from the start of the
+    /// input.
+    pub fn new(dir: &Path, position: u64, carved: Arc<Mutex<Vec<Carved>>>) -> Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|e| {
+            eyre!("Failed to create carve directory")
+                .with_error(|| e)
+                .with_note(|| format!("carve {}", dir.display()))
+        })?;
+        let literal = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .map(|b| format!("\\x{b:02x}"))
+                .collect::<String>()
+        };
+        let headers = SIGNATURES
+            .iter()
+            .map(|signature| literal(signature.header))
+            .collect::<Vec<_>>()
+            .join("|");
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            headers: Regex::new(&format!("(?-u){headers}"))?,
+            footers: SIGNATURES
+                .iter()
+                .map(|signature| Regex::new(&format!("(?-u){}", literal(signature.footer))))
+                .collect::<Result<_, _>>()?,
+            window: vec![],
+            position,
+            current: None,
+            carved,
+        })
+    }
+
+    /// Work through `window` until more data is needed.
+    fn process(&mut self, last: bool) -> io::Result<()> {
+        loop {
+            let Some(current) = &mut self.current else {
+                let Some(found) = self.headers.find(&self.window) else {
+                    // A header could still start in the last few bytes.
+                    let longest = SIGNATURES.iter().map(|s| s.header.len()).max().unwrap_or(1);
+                    let keep = if last { 0 } else { longest - 1 };
+                    self.consume(self.window.len().saturating_sub(keep));
+                    return Ok(());
+                };
+                self.consume(found.start());
+                let signature = SIGNATURES
+                    .iter()
+                    .position(|signature| self.window.starts_with(signature.header))
+                    .expect("matched a header");
+                self.start(signature)?;
+                continue;
+            };
+
+            let signature = &SIGNATURES[current.signature];
+            let len = self.window.len();
+            let room =
+                usize::try_from(signature.max_len - current.carved.len).unwrap_or(usize::MAX);
+            // Search past the header so it can't be taken for the footer.
+            let from = if current.carved.len == 0 {
+                signature.header.len().min(len)
+            } else {
+                0
+            };
+            let end = self.footers[current.signature]
+                .find(&self.window[from..])
+                .map(|found| from + found.end() + signature.trailer);
+            if let Some(end) = end
+                && end <= len
+            {
+                if end <= room {
+                    self.write(end)?;
+                    self.close(true);
+                } else {
+                    self.write(room)?;
+                    self.close(false);
+                }
+                continue;
+            }
+            if last {
+                self.write(len.min(room))?;
+                self.close(false);
+                continue;
+            }
+            // Hold back what could be the start of the footer and trailer
+            // until the next block arrives.
+            let held = match end {
+                Some(end) => len - (end - signature.footer.len() - signature.trailer),
+                None => signature.footer.len() + signature.trailer - 1,
+            };
+            let n = len.saturating_sub(held);
+            if n >= room {
+                self.write(room)?;
+                self.close(false);
+                continue;
+            }
+            self.write(n)?;
+            return Ok(());
+        }
+    }
+
+    fn start(&mut self, signature: usize) -> io::Result<()> {
+        let index = self.carved.lock().unwrap().len() + 1;
+        let kind = SIGNATURES[signature].kind;
+        let path = self
+            .dir
+            .join(format!("{index:06}-{}.{}", self.position, kind));
+        let file = File::create(&path)?;
+        self.current = Some(Carving {
+            signature,
+            file,
+            carved: Carved {
+                kind,
+                offset: self.position,
+                len: 0,
+                path,
+                complete: false,
+            },
+        });
+        Ok(())
+    }
+
+    /// Write the first `n` bytes of `window` to the current candidate.
+    fn write(&mut self, n: usize) -> io::Result<()> {
+        if let Some(current) = &mut self.current {
+            current.file.write_all(&self.window[..n])?;
+            current.carved.len += n as u64;
+        }
+        self.consume(n);
+        Ok(())
+    }
+
+    fn close(&mut self, complete: bool) {
+        if let Some(mut current) = self.current.take() {
+            current.carved.complete = complete;
+            self.carved.lock().unwrap().push(current.carved);
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.window.drain(..n);
+        self.position += n as u64;
+    }
+
+    fn write_manifest(&self) -> io::Result<()> {
+        let mut manifest = String::from("file,type,offset,length,complete\n");
+        for carved in self.carved.lock().unwrap().iter() {
+            let name = carved
+                .path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            manifest.push_str(&format!(
+                "{name},{},{},{},{}\n",
+                carved.kind, carved.offset, carved.len, carved.complete
+            ));
+        }
+        std::fs::write(self.dir.join("manifest.csv"), manifest)
+    }
+}
+
+impl Write for CarveSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.window.extend_from_slice(buf);
+        self.process(false)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for CarveSink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.process(true)?;
+        self.write_manifest()?;
+        Ok(None)
+    }
+}
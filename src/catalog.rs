@@ -0,0 +1,179 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::hash::{self, HashAlgorithm};
+
+/// A catalog of known-good images: the digest each image name, usually
+/// with its version, is expected to have, so `verify-catalog=NAME` can
+/// refuse to flash a corrupted or tampered copy.
+///
+/// The file has one entry per line, `NAME ALGORITHM:DIGEST`, and `#`
+/// comments:
+///
+/// ```text
+/// # golden images
+/// ubuntu-24.04.1 sha256:e240e4b801f7bb68c20d1356b60968ad0c33a41d00d828e74ceb3364a0317be9
+/// kiosk-v7 blake3:9a2c...
+/// ```
+///
+/// It is kept with `pdd catalog add NAME FILE` and `pdd catalog list`.
+#[derive(Clone, Debug, Default)]
+pub struct Catalog {
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub algorithm: HashAlgorithm,
+
+    /// Lower case hex
+    pub digest: String,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}:{}", self.name, self.algorithm, self.digest)
+    }
+}
+
+/// What `pdd catalog` does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Digest a file and enter it under a name, replacing an entry of the
+    /// same name
+    Add {
+        name: String,
+        path: PathBuf,
+        algorithm: HashAlgorithm,
+    },
+
+    /// Print every entry
+    List,
+}
+
+impl Catalog {
+    /// `$PDD_CATALOG`, or `pdd/catalog` in the user's config directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let var = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty());
+        if let Some(path) = var("PDD_CATALOG") {
+            return Some(PathBuf::from(path));
+        }
+        let config = var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config.join("pdd").join("catalog"))
+    }
+
+    /// Catalog at `path`, empty if there is no such file yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(eyre!("Failed to read catalog")
+                    .with_error(|| e)
+                    .with_note(|| format!("catalog {}", path.display())));
+            }
+        };
+        let mut entries = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                eyre!("Invalid catalog entry")
+                    .with_note(|| format!("catalog {} line {}: {line}", path.display(), number + 1))
+                    .with_suggestion(
+                        || "expected NAME ALGORITHM:DIGEST, e.g. golden-v3 sha256:3a7b...",
+                    )
+            };
+            let Some((name, hash)) = line.split_once(char::is_whitespace) else {
+                return Err(invalid());
+            };
+            let Some((algorithm, digest)) = hash.trim().split_once(':') else {
+                return Err(invalid());
+            };
+            if digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            entries.push(Entry {
+                name: name.to_string(),
+                algorithm: algorithm.parse().map_err(|_| invalid())?,
+                digest: digest.to_ascii_lowercase(),
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+}
+
+/// Enter `entry` into the catalog at `path`, in place of the line of an
+/// entry of the same name, keeping every other line and comment as is.
+fn add(path: &Path, entry: &Entry) -> Result<()> {
+    let context = |e: std::io::Error| {
+        eyre!("Failed to write catalog")
+            .with_error(|| e)
+            .with_note(|| format!("catalog {}", path.display()))
+    };
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(context(e)),
+    };
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let same = |line: &String| line.split_whitespace().next() == Some(entry.name.as_str());
+    match lines.iter_mut().find(|line| same(line)) {
+        Some(line) => *line = entry.to_string(),
+        None => lines.push(entry.to_string()),
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).map_err(context)?;
+    }
+    std::fs::write(path, lines.join("\n") + "\n").map_err(context)
+}
+
+/// Digest the file at `path` as the catalog does, all of it as stored.
+pub fn digest_file(algorithm: HashAlgorithm, path: &Path) -> std::io::Result<String> {
+    hash::digest(algorithm, std::fs::File::open(path)?)
+}
+
+/// Run `pdd catalog` on the catalog at `path`.
+pub fn run(command: &Command, path: &Path) -> Result<()> {
+    // Read even to add to, so a broken catalog isn't added to.
+    let catalog = Catalog::load(path)?;
+    match command {
+        Command::Add {
+            name,
+            path: image,
+            algorithm,
+        } => {
+            let digest = digest_file(*algorithm, image).map_err(|e| {
+                eyre!("Failed to read image")
+                    .with_error(|| e)
+                    .with_note(|| format!("image {}", image.display()))
+            })?;
+            let entry = Entry {
+                name: name.clone(),
+                algorithm: *algorithm,
+                digest,
+            };
+            add(path, &entry)?;
+            println!("{entry}");
+            Ok(())
+        }
+        Command::List => {
+            for entry in &catalog.entries {
+                println!("{entry}");
+            }
+            Ok(())
+        }
+    }
+}
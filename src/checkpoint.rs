@@ -0,0 +1,264 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    diagnostic::{Code, Diagnostic},
+    progress::Counter,
+    sink,
+};
+
+/// How often the checkpoint of a running copy is saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How far every output of an operation got, saved by `resume=FILE` so an
+/// interrupted copy can carry on from there.
+///
+/// The input, block size, skip and seek identify the operation; a checkpoint
+/// only resumes the operation that wrote it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub input: String,
+    pub block_size: u64,
+
+    /// Byte offsets into the input and outputs the operation started at
+    pub skip: u64,
+    pub seek: u64,
+
+    pub outputs: Vec<OutputCheckpoint>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputCheckpoint {
+    pub name: String,
+
+    /// Bytes of the stream written without error, counted from `seek`
+    pub written: u64,
+
+    /// True once the output has received the whole stream
+    pub complete: bool,
+}
+
+impl Checkpoint {
+    /// Read the checkpoint at `path`, or `None` if there isn't one yet.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let context = |e: &dyn std::fmt::Display| {
+            eyre!("Failed to read checkpoint")
+                .with_note(|| e.to_string())
+                .with_note(|| format!("resume={}", path.display()))
+        };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(context(&e)),
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| context(&e).with_suggestion(|| "delete the file to start over"))
+    }
+
+    /// Write the checkpoint to `path`, replacing the old one in a single
+    /// rename so it is never left half written, and syncing it so a crash
+    /// doesn't take the new one or the rename with it.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let context = |e: std::io::Error| {
+            eyre!("Failed to save checkpoint")
+                .with_error(|| e)
+                .with_note(|| format!("resume={}", path.display()))
+        };
+        let json = serde_json::to_string_pretty(self)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp).map_err(context)?;
+        file.write_all((json + "\n").as_bytes()).map_err(context)?;
+        file.sync_all().map_err(context)?;
+        std::fs::rename(&tmp, path).map_err(context)?;
+        sync_dir(path).map_err(context)
+    }
+
+    /// Offset into the stream every unfinished output has safely reached,
+    /// rounded down to a whole block.
+    pub fn resume_offset(&self) -> u64 {
+        let written = self
+            .outputs
+            .iter()
+            .filter(|output| !output.complete)
+            .map(|output| output.written)
+            .min()
+            .unwrap_or(0);
+        written - written % self.block_size
+    }
+
+    /// True if there is nothing left to copy.
+    pub fn is_complete(&self) -> bool {
+        self.outputs.iter().all(|output| output.complete)
+    }
+
+    /// Record that output `index` has written `acked` bytes since `offset`.
+    pub fn update(&mut self, index: usize, offset: u64, acked: u64) {
+        self.outputs[index].written = offset + acked;
+    }
+}
+
+/// Sync the directory holding `path`, so a rename into it is on disk.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// An output the saver records the progress of.
+pub struct Acked {
+    /// Its index into the checkpoint
+    pub index: usize,
+
+    /// Bytes written to it since the offset the copy started at
+    pub counter: Counter,
+
+    /// The file to sync before its count is recorded, so the checkpoint never
+    /// gets ahead of what is on disk. Outputs that aren't files are recorded
+    /// as written.
+    pub file: Option<PathBuf>,
+}
+
+/// Keeps a checkpoint file up to date while a copy runs.
+pub struct Saver {
+    checkpoint: Arc<Mutex<Checkpoint>>,
+    task: JoinHandle<()>,
+}
+
+impl Saver {
+    /// Save `checkpoint` to `path` every [`SAVE_INTERVAL`], with how far
+    /// each of the `acked` outputs got since `offset`.
+    pub fn spawn(checkpoint: Checkpoint, path: PathBuf, offset: u64, acked: Vec<Acked>) -> Self {
+        let checkpoint = Arc::new(Mutex::new(checkpoint));
+        let acked = Arc::new(acked);
+        let task = tokio::spawn({
+            let checkpoint = checkpoint.clone();
+            async move {
+                let mut interval = tokio::time::interval(SAVE_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let (checkpoint, path, acked) =
+                        (checkpoint.clone(), path.clone(), acked.clone());
+                    let saved = tokio::task::spawn_blocking(move || {
+                        save_synced(&checkpoint, &path, offset, &acked)
+                    });
+                    let _ = saved.await;
+                }
+            }
+        });
+        Self { checkpoint, task }
+    }
+
+    /// Stop saving and hand back the checkpoint for its final update.
+    ///
+    /// The outputs are to be synced before that update is saved.
+    pub fn finish(self) -> Checkpoint {
+        self.task.abort();
+        self.checkpoint.lock().unwrap().clone()
+    }
+}
+
+/// Sync the outputs, then save how far they had got before the sync.
+fn save_synced(checkpoint: &Mutex<Checkpoint>, path: &Path, offset: u64, acked: &[Acked]) {
+    let subject = format!("resume={}", path.display());
+    let mut checkpoint = checkpoint.lock().unwrap();
+    for output in acked {
+        let written = output.counter.get();
+        if let Some(file) = &output.file
+            && let Err(e) = sink::sync(file)
+        {
+            // Keep what it had reached at the last sync that worked.
+            Diagnostic::new(Code::SyncFailed, format!("failed to sync: {e}"))
+                .subject(file.display())
+                .emit();
+            continue;
+        }
+        checkpoint.update(output.index, offset, written);
+    }
+    if let Err(e) = checkpoint.save(path) {
+        Diagnostic::new(Code::CheckpointNotSaved, format!("{e:#}"))
+            .subject(&subject)
+            .emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(outputs: &[(u64, bool)]) -> Checkpoint {
+        Checkpoint {
+            input: "if=disk.img".to_string(),
+            block_size: 4096,
+            skip: 0,
+            seek: 512,
+            outputs: outputs
+                .iter()
+                .enumerate()
+                .map(|(index, &(written, complete))| OutputCheckpoint {
+                    name: format!("of=out{index}.img"),
+                    written,
+                    complete,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn resumes_from_the_slowest_unfinished_output() {
+        // The complete output is ahead of, and the unfinished ones behind,
+        // where the copy picks up again.
+        let checkpoint = checkpoint(&[(1 << 20, true), (3 * 4096 + 100, false), (5 * 4096, false)]);
+        assert_eq!(checkpoint.resume_offset(), 3 * 4096);
+        assert!(!checkpoint.is_complete());
+    }
+
+    #[test]
+    fn resume_offset_is_rounded_down_to_a_block() {
+        assert_eq!(checkpoint(&[(4095, false)]).resume_offset(), 0);
+        assert_eq!(checkpoint(&[(4096, false)]).resume_offset(), 4096);
+        assert_eq!(checkpoint(&[(8191, false)]).resume_offset(), 4096);
+    }
+
+    #[test]
+    fn complete_once_every_output_is() {
+        let mut checkpoint = checkpoint(&[(8192, true), (4096, false)]);
+        assert!(!checkpoint.is_complete());
+        checkpoint.update(1, 4096, 4096);
+        assert_eq!(checkpoint.outputs[1].written, 8192);
+        checkpoint.outputs[1].complete = true;
+        assert!(checkpoint.is_complete());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path = std::env::temp_dir().join(format!("pdd-checkpoint-{}", std::process::id()));
+        let saved = checkpoint(&[(10_000, false), (20_000, true)]);
+        saved.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), Some(saved));
+
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), None);
+    }
+}
@@ -0,0 +1,305 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::sink::{Shared, Sink};
+
+/// Compression applied to the outputs of an operation (`comp=ALGO[:LEVEL]`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard, levels 1 to 22
+    Zstd(i32),
+
+    /// gzip, levels 0 to 9
+    Gzip(u32),
+}
+
+impl Compression {
+    pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+    pub const DEFAULT_GZIP_LEVEL: u32 = 6;
+}
+
+impl FromStr for Compression {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            eyre!("Invalid compression")
+                .with_note(|| format!("input comp={s}"))
+                .with_suggestion(|| "expected comp=zstd[:1-22] or comp=gzip[:0-9]")
+        };
+        let (algorithm, level) = match s.split_once(':') {
+            Some((algorithm, level)) => (algorithm, Some(level)),
+            None => (s, None),
+        };
+        match (algorithm, level) {
+            ("zstd", None) => Ok(Compression::Zstd(Self::DEFAULT_ZSTD_LEVEL)),
+            ("zstd", Some(level)) => match level.parse() {
+                Ok(level @ 1..=22) => Ok(Compression::Zstd(level)),
+                _ => Err(invalid()),
+            },
+            ("gzip" | "gz", None) => Ok(Compression::Gzip(Self::DEFAULT_GZIP_LEVEL)),
+            ("gzip" | "gz", Some(level)) => match level.parse() {
+                Ok(level @ 0..=9) => Ok(Compression::Gzip(level)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Zstd(level) => write!(f, "zstd:{level}"),
+            Compression::Gzip(level) => write!(f, "gzip:{level}"),
+        }
+    }
+}
+
+enum Encoder {
+    Zstd(zstd::Encoder<'static, Shared>),
+    Gzip(GzEncoder<Shared>),
+}
+
+/// Sink wrapper compressing the stream on its way to `inner`.
+pub struct CompressSink {
+    /// Taken when finished, since finishing the encoder consumes it
+    encoder: Option<Encoder>,
+
+    /// What the encoder writes to, for abandoning it without the encoder
+    /// ending the stream
+    inner: Shared,
+}
+
+impl CompressSink {
+    pub fn new(inner: Box<dyn Sink>, compression: Compression) -> io::Result<Self> {
+        let inner = Shared::new(inner);
+        let encoder = match compression {
+            Compression::Zstd(level) => Encoder::Zstd(zstd::Encoder::new(inner.clone(), level)?),
+            Compression::Gzip(level) => Encoder::Gzip(GzEncoder::new(
+                inner.clone(),
+                flate2::Compression::new(level),
+            )),
+        };
+        Ok(Self {
+            encoder: Some(encoder),
+            inner,
+        })
+    }
+
+    fn encoder(&mut self) -> io::Result<&mut dyn Write> {
+        match &mut self.encoder {
+            Some(Encoder::Zstd(encoder)) => Ok(encoder),
+            Some(Encoder::Gzip(encoder)) => Ok(encoder),
+            None => Err(io::Error::other("compressed output already finished")),
+        }
+    }
+}
+
+impl Write for CompressSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder()?.flush()
+    }
+}
+
+impl Sink for CompressSink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        let mut inner = match self.encoder.take() {
+            Some(Encoder::Zstd(encoder)) => encoder.finish()?,
+            Some(Encoder::Gzip(encoder)) => encoder.finish()?,
+            None => return Ok(None),
+        };
+        inner.finish()
+    }
+
+    /// Leave the compressed stream without its end, which a gzip encoder
+    /// would otherwise write as it is dropped.
+    fn abandon(&mut self) -> io::Result<()> {
+        let inner = self.inner.take();
+        self.encoder = None;
+        match inner {
+            Some(mut inner) => inner.abandon(),
+            None => Ok(()),
+        }
+    }
+}
+
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+/// How the input of an operation is decompressed (`decomp=`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Decompression {
+    /// Decompress if the input starts with zstd or gzip magic bytes, or its
+    /// name ends in `.zst` or `.gz`
+    #[default]
+    Auto,
+
+    /// Copy the input as is
+    None,
+
+    Zstd,
+    Gzip,
+}
+
+impl Decompression {
+    /// Settle `Auto` given the input's path, if any, and its first bytes.
+    pub fn resolve(self, path: Option<&Path>, magic: &[u8]) -> Self {
+        if self != Decompression::Auto {
+            return self;
+        }
+        if magic.starts_with(ZSTD_MAGIC) {
+            return Decompression::Zstd;
+        }
+        if magic.starts_with(GZIP_MAGIC) {
+            return Decompression::Gzip;
+        }
+        match path.and_then(Path::extension).and_then(|e| e.to_str()) {
+            Some("zst" | "zstd") => Decompression::Zstd,
+            Some("gz") => Decompression::Gzip,
+            _ => Decompression::None,
+        }
+    }
+
+    /// Wrap `inner` in the matching decoder. `Auto` must be resolved first.
+    pub fn reader(self, inner: impl Read + Send + 'static) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Decompression::Auto | Decompression::None => Box::new(inner),
+            Decompression::Zstd => Box::new(FullReads(zstd::Decoder::new(inner)?)),
+            // Concatenated members, as written by `pigz` or `cat a.gz b.gz`,
+            // are all decompressed.
+            Decompression::Gzip => Box::new(FullReads(MultiGzDecoder::new(inner))),
+        })
+    }
+}
+
+impl FromStr for Decompression {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Decompression::Auto),
+            "none" | "off" => Ok(Decompression::None),
+            "zstd" => Ok(Decompression::Zstd),
+            "gzip" | "gz" => Ok(Decompression::Gzip),
+            _ => Err(eyre!("Invalid decompression")
+                .with_note(|| format!("input decomp={s}"))
+                .with_suggestion(|| "expected decomp=auto, none, zstd or gzip")),
+        }
+    }
+}
+
+impl fmt::Display for Decompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decompression::Auto => write!(f, "auto"),
+            Decompression::None => write!(f, "none"),
+            Decompression::Zstd => write!(f, "zstd"),
+            Decompression::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+/// Read up to `buf.len()` bytes, stopping early only at the end of `reader`.
+pub fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Decoders often return short reads; filling every read
+/// keeps the blocks handed to the outputs at the block size.
+struct FullReads<R>(R);
+
+impl<R: Read> Read for FullReads<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read_full(&mut self.0, buf)
+    }
+}
+
+/// A stream with the bytes sniffed from its start put back in front of it.
+/// They are read along with the stream's own first read, so a stream read
+/// as it is doesn't start with a block only as long as the magic bytes.
+pub struct Sniffed<R> {
+    magic: Vec<u8>,
+    inner: R,
+}
+
+impl<R> Sniffed<R> {
+    pub fn new(magic: &[u8], inner: R) -> Self {
+        Self {
+            magic: magic.to_vec(),
+            inner,
+        }
+    }
+}
+
+impl<R: Read> Read for Sniffed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.magic.is_empty() {
+            return self.inner.read(buf);
+        }
+        let n = self.magic.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.magic[..n]);
+        self.magic.drain(..n);
+        if !self.magic.is_empty() || n == buf.len() {
+            return Ok(n);
+        }
+        // An error is left for the next read to come across again, since
+        // the magic bytes have been handed on already.
+        Ok(n + self.inner.read(&mut buf[n..]).unwrap_or(0))
+    }
+}
+
+/// Reader deciding on and applying decompression when it is first read, so
+/// that waiting for the first bytes of a pipe happens on the reading thread.
+pub struct Decompressor<R> {
+    inner: Option<R>,
+    path: Option<Box<Path>>,
+    decomp: Decompression,
+    reader: Option<Box<dyn Read + Send>>,
+}
+
+impl<R: Read + Send + 'static> Decompressor<R> {
+    pub fn new(inner: R, path: Option<&Path>, decomp: Decompression) -> Self {
+        Self {
+            inner: Some(inner),
+            path: path.map(Into::into),
+            decomp,
+            reader: None,
+        }
+    }
+}
+
+impl<R: Read + Send + 'static> Read for Decompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(mut inner) = self.inner.take() {
+            let mut magic = [0u8; 4];
+            let n = read_full(&mut inner, &mut magic)?;
+            let decomp = self.decomp.resolve(self.path.as_deref(), &magic[..n]);
+            let inner = Sniffed::new(&magic[..n], inner);
+            self.reader = Some(decomp.reader(inner)?);
+        }
+        match &mut self.reader {
+            Some(reader) => reader.read(buf),
+            None => Err(io::Error::other("input failed to open")),
+        }
+    }
+}
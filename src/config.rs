@@ -0,0 +1,406 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{iter::Peekable, path::Path, str::Chars};
+
+/// Header of the table each operation of a config file is declared in.
+pub const OPERATION: &str = "[[operation]]";
+
+/// A run declared in a file (`--config jobs.toml`) rather than on the
+/// command line:
+///
+/// ```toml
+/// status = "progress"
+///
+/// [[operation]]
+/// if = "boot.img"
+/// of = ["/dev/sda1", "/dev/sdb1"]
+/// bs = "1M"
+/// conv = "sync,noerror"
+/// verify = true
+///
+/// [[operation]]
+/// if = "root.img"
+/// of = ["/dev/sda2", "/dev/sdb2"]
+/// limit = "50M"
+/// ```
+///
+/// Every key is an operand of the same name, an array standing for the
+/// operand given once per element. Keys before the first `[[operation]]`
+/// apply to the whole run. This is the part of TOML needed for that: bare or
+/// quoted keys, strings, integers, booleans, arrays of those and comments.
+/// It is read here rather than with a TOML library so that every key keeps
+/// the line it is on, for the errors of its operands to point at.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Keys before the first table
+    pub globals: Vec<Entry>,
+
+    /// Each `[[operation]]` table, with the line its header is on
+    pub operations: Vec<(usize, Vec<Entry>)>,
+}
+
+/// One `key = value` of a config file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Line the key is on, counting from 1
+    pub line: usize,
+
+    pub key: String,
+
+    /// The value, one per element for an array
+    pub values: Vec<String>,
+}
+
+impl Entry {
+    /// The command line operands the entry stands for, e.g. `of=/dev/sda1`.
+    pub fn operands(&self) -> impl Iterator<Item = String> + '_ {
+        self.values
+            .iter()
+            .map(move |value| format!("{}={value}", self.key))
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            eyre!("Failed to read config file")
+                .with_error(|| e)
+                .with_note(|| format!("config {}", path.display()))
+        })?;
+        Self::parse(&text).map_err(|e| e.with_note(|| format!("config {}", path.display())))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let lines: Vec<&str> = text.lines().collect();
+        let mut parser = Parser {
+            chars: text.chars().peekable(),
+            line: 1,
+        };
+        let mut config = Config::default();
+        parser.statements(&mut config).map_err(|(line, what)| {
+            let text = lines.get(line - 1).copied().unwrap_or_default().trim();
+            eyre!("Invalid config file, {what}")
+                .with_note(|| format!("line {line}: {text}"))
+                .with_suggestion(|| {
+                    "expected key = value lines under [[operation]] headers, \
+                     e.g. of = [\"/dev/sda\", \"/dev/sdb\"]"
+                })
+        })?;
+        Ok(config)
+    }
+}
+
+/// Why parsing stopped, and on which line.
+type ParseError = (usize, String);
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+
+    /// Line of the next character
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn error<T>(&self, what: impl Into<String>) -> Result<T, ParseError> {
+        Err((self.line, what.into()))
+    }
+
+    /// Skip spaces and, with `newlines`, blank lines and comments too.
+    fn skip(&mut self, newlines: bool) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                ' ' | '\t' | '\r' => {}
+                '\n' if newlines => {}
+                '#' if newlines => {
+                    while self.chars.peek().is_some_and(|&c| c != '\n') {
+                        self.next();
+                    }
+                    continue;
+                }
+                _ => return,
+            }
+            self.next();
+        }
+    }
+
+    /// Nothing but a comment may follow on the line.
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip(false);
+        match self.chars.peek() {
+            None | Some('\n') | Some('#') => Ok(()),
+            Some(&c) => self.error(format!("unexpected {c:?} after the value")),
+        }
+    }
+
+    fn statements(&mut self, config: &mut Config) -> Result<(), ParseError> {
+        loop {
+            self.skip(true);
+            let Some(&c) = self.chars.peek() else {
+                return Ok(());
+            };
+            if c == '[' {
+                let line = self.line;
+                let mut header = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if c == '\n' || c == '#' {
+                        break;
+                    }
+                    header.push(c);
+                    self.next();
+                }
+                if header.trim() != OPERATION {
+                    return self.error(format!("unknown table {}", header.trim()));
+                }
+                config.operations.push((line, vec![]));
+                continue;
+            }
+            let line = self.line;
+            let key = self.key()?;
+            self.skip(false);
+            if self.next() != Some('=') {
+                return self.error(format!("expected = after {key}"));
+            }
+            self.skip(false);
+            let values = self.value()?;
+            self.end_of_line()?;
+            let entries = match config.operations.last_mut() {
+                Some((_, entries)) => entries,
+                None => &mut config.globals,
+            };
+            if entries.iter().any(|entry| entry.key == key) {
+                return Err((line, format!("{key} given twice, use an array instead")));
+            }
+            entries.push(Entry { line, key, values });
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ParseError> {
+        if let Some('"' | '\'') = self.chars.peek() {
+            return self.string();
+        }
+        let mut key = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                break;
+            }
+            key.push(c);
+            self.next();
+        }
+        if key.is_empty() {
+            return self.error("expected a key");
+        }
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<Vec<String>, ParseError> {
+        if self.chars.peek() != Some(&'[') {
+            return Ok(vec![self.scalar()?]);
+        }
+        self.next();
+        let mut values = vec![];
+        loop {
+            self.skip(true);
+            if self.chars.peek() == Some(&']') {
+                self.next();
+                return Ok(values);
+            }
+            values.push(self.scalar()?);
+            self.skip(true);
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(values),
+                _ => return self.error("expected , or ] in the array"),
+            }
+        }
+    }
+
+    /// A string, an integer or a boolean, as the text of an operand.
+    fn scalar(&mut self) -> Result<String, ParseError> {
+        if let Some('"' | '\'') = self.chars.peek() {
+            return self.string();
+        }
+        let mut token = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() || matches!(c, ',' | ']' | '#') {
+                break;
+            }
+            token.push(c);
+            self.next();
+        }
+        let digits = token.strip_prefix(['+', '-']).unwrap_or(&token);
+        if token == "true" || token == "false" {
+            Ok(token)
+        } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit() || c == '_') {
+            Ok(token.replace(['_', '+'], ""))
+        } else if token.is_empty() {
+            self.error("expected a value")
+        } else {
+            self.error(format!(
+                "{token} isn't a string, integer or boolean, quote it"
+            ))
+        }
+    }
+
+    /// A `"basic"` string with escapes, or a `'literal'` one without.
+    fn string(&mut self) -> Result<String, ParseError> {
+        let line = self.line;
+        let quote = self.next().expect("a quote was peeked");
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err((line, "unterminated string".into())),
+                Some(c) if c == quote => return Ok(string),
+                Some('\\') if quote == '"' => match self.next() {
+                    Some('\\') => string.push('\\'),
+                    Some('"') => string.push('"'),
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('r') => string.push('\r'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('u') => string.push(self.unicode(4)?),
+                    Some('U') => string.push(self.unicode(8)?),
+                    Some(c) => return self.error(format!("unknown escape \\{c}")),
+                    None => return Err((line, "unterminated string".into())),
+                },
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    /// The character of a `\uXXXX` or `\UXXXXXXXX` escape, with `digits` hex
+    /// digits.
+    fn unicode(&mut self, digits: usize) -> Result<char, ParseError> {
+        let mut hex = String::new();
+        while hex.len() < digits
+            && let Some(c) = self.chars.peek().copied().filter(char::is_ascii_hexdigit)
+        {
+            hex.push(c);
+            self.next();
+        }
+        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            Some(c) if hex.len() == digits => Ok(c),
+            _ => self.error(format!(
+                "\\u{hex} isn't a character, expected {digits} hex digits"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(line: usize, key: &str, values: &[&str]) -> Entry {
+        Entry {
+            line,
+            key: key.to_string(),
+            values: values.iter().map(|value| value.to_string()).collect(),
+        }
+    }
+
+    /// The line parsing `text` stops at, and why.
+    fn error(text: &str) -> ParseError {
+        let mut parser = Parser {
+            chars: text.chars().peekable(),
+            line: 1,
+        };
+        parser.statements(&mut Config::default()).unwrap_err()
+    }
+
+    #[test]
+    fn globals_and_operations() {
+        let config = Config::parse(
+            "# a run\nstatus = \"progress\"\n\n[[operation]]\nif = \"a.img\" # the input\nbs = 4096\n\n[[operation]]  # second\nverify = true\n",
+        )
+        .unwrap();
+        assert_eq!(config.globals, [entry(2, "status", &["progress"])]);
+        assert_eq!(config.operations.len(), 2);
+        assert_eq!(config.operations[0].0, 4);
+        assert_eq!(
+            config.operations[0].1,
+            [entry(5, "if", &["a.img"]), entry(6, "bs", &["4096"])]
+        );
+        assert_eq!(
+            config.operations[1],
+            (8, vec![entry(9, "verify", &["true"])])
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        let config = Config::parse(
+            "\"of\" = 'C:\\images\\a.img'\n'if' = \"has # no comment\"\nkey-with_dash = \"\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.globals,
+            [
+                entry(1, "of", &["C:\\images\\a.img"]),
+                entry(2, "if", &["has # no comment"]),
+                entry(3, "key-with_dash", &[""]),
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        let config = Config::parse(r#"a = "q\"b\\s\n\t\r\b\f\u00e9\U0001F600""#).unwrap();
+        assert_eq!(
+            config.globals[0].values,
+            ["q\"b\\s\n\t\r\u{8}\u{c}\u{e9}\u{1f600}"]
+        );
+        // Literal strings take backslashes as they are.
+        let config = Config::parse(r"a = '\n\u00e9'").unwrap();
+        assert_eq!(config.globals[0].values, [r"\n\u00e9"]);
+    }
+
+    #[test]
+    fn arrays_and_scalars() {
+        let config = Config::parse(
+            "of = [\n  \"/dev/sda\",  # first\n  '/dev/sdb',\n]\nempty = []\nn = [+1_000, -2, true, false]\nafter = 1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.globals,
+            [
+                entry(1, "of", &["/dev/sda", "/dev/sdb"]),
+                entry(5, "empty", &[]),
+                entry(6, "n", &["1000", "-2", "true", "false"]),
+                entry(7, "after", &["1"]),
+            ]
+        );
+        let operands: Vec<String> = config.globals[0].operands().collect();
+        assert_eq!(operands, ["of=/dev/sda", "of=/dev/sdb"]);
+    }
+
+    #[test]
+    fn errors_point_at_their_line() {
+        let cases = [
+            ("a = 1\nb = \"open\n", "unterminated string", 2),
+            ("a = 1\n\n[[job]]\n", "unknown table [[job]]", 3),
+            ("a = 1\na = 2\n", "a given twice", 2),
+            ("a = 1 2\n", "unexpected '2' after the value", 1),
+            ("a = 1M\n", "1M isn't a string, integer or boolean", 1),
+            ("a = 1\n= 2\n", "expected a key", 2),
+            ("a 1\n", "expected = after a", 1),
+            ("a = [1 2]\n", "expected , or ] in the array", 1),
+            ("a = \"\\x\"\n", "unknown escape \\x", 1),
+            ("a = \"\\u00\"\n", "expected 4 hex digits", 1),
+            ("a = \"\\uD800\"\n", "\\uD800 isn't a character", 1),
+            ("a =\n", "expected a value", 1),
+        ];
+        for (text, what, line) in cases {
+            let e = error(text);
+            assert!(e.1.contains(what), "{text:?}: {e:?}");
+            assert_eq!(e.0, line, "{text:?}: {e:?}");
+        }
+    }
+}
@@ -0,0 +1,683 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use crate::{
+    compress::{Sniffed, read_full},
+    hash::to_hex,
+    input,
+    report::format_timestamp,
+    sink::Sink,
+};
+
+/// First bytes of every pddstream.
+const MAGIC: &[u8; 8] = b"PDDSTRM\0";
+
+/// Last bytes of every pddstream that was finished.
+const END_MAGIC: &[u8; 8] = b"PDDSTEND";
+
+/// Layout version of the container.
+const VERSION: u32 = 1;
+
+/// Bytes of the stream in each frame but the last.
+const FRAME_SIZE: usize = 1 << 20;
+
+/// Largest frame read, so a damaged length can't make pdd take all the
+/// memory there is.
+const MAX_FRAME: u32 = 64 << 20;
+
+/// Most metadata a header is read with.
+const MAX_METADATA: u32 = 64 * 1024;
+
+/// Bits of the header flags.
+const COMPRESSED: u32 = 1;
+const ENCRYPTED: u32 = 1 << 1;
+
+/// Container the outputs but hashes are written in (`container=pddstream`).
+///
+/// A pddstream cuts the stream into frames that are each checked as they
+/// are read back, so a copy kept in a file or sent over the network says
+/// itself whether it is whole:
+///
+/// ```text
+/// header | frame | frame ... | end | footer
+///
+/// header: "PDDSTRM\0" | version (u32) | flags (u32) | metadata length (u32) | metadata
+/// frame:  length (u32) | blake3 (32) | data
+/// end:    0 (u32) | frames (u64) | length (u64) | blake3 (32) | offset (u64) of each frame
+/// footer: offset of the end (u64) | "PDDSTEND"
+/// ```
+///
+/// with the integers little endian and offsets from the start of the
+/// container. The metadata is `key=value` lines like a trailer's, the flags
+/// say whether the stream was compressed (1) or encrypted (2) before it was
+/// framed, and the end has the totals and a table of the frames, with the
+/// digest of the whole stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Pddstream,
+}
+
+impl FromStr for Container {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pddstream" => Ok(Container::Pddstream),
+            _ => Err(eyre!("Invalid container")
+                .with_note(|| format!("input container={s}"))
+                .with_suggestion(|| "expected container=pddstream")),
+        }
+    }
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Container::Pddstream => write!(f, "pddstream"),
+        }
+    }
+}
+
+/// The header of a pddstream.
+#[derive(Clone, Debug, Default)]
+pub struct Header {
+    pub version: u32,
+
+    /// True if the stream was compressed before it was framed
+    pub compressed: bool,
+
+    /// True if the stream was encrypted before it was framed
+    pub encrypted: bool,
+
+    /// `key=value` pairs, e.g. when it was made and from what
+    pub metadata: Vec<(String, String)>,
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pddstream version {}", self.version)?;
+        if self.compressed {
+            write!(f, ", compressed")?;
+        }
+        if self.encrypted {
+            write!(f, ", encrypted")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the end of a pddstream says the stream held.
+#[derive(Clone, Copy, Debug)]
+pub struct Totals {
+    pub frames: u64,
+
+    /// Bytes of the stream, in all frames
+    pub length: u64,
+
+    /// BLAKE3 of those bytes
+    pub digest: [u8; 32],
+}
+
+impl fmt::Display for Totals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in {} frames, blake3 {}",
+            self.length,
+            self.frames,
+            to_hex(&self.digest)
+        )
+    }
+}
+
+/// Sink wrapper writing the stream to `inner` as a pddstream. The end is
+/// only written once the stream is finished, so one that was abandoned
+/// can't be taken for a whole one.
+pub struct ContainerSink {
+    inner: Box<dyn Sink>,
+
+    /// Data of the frame being filled
+    frame: Vec<u8>,
+    hasher: blake3::Hasher,
+
+    /// Bytes of the stream so far
+    length: u64,
+
+    /// Bytes written to `inner` so far
+    offset: u64,
+
+    /// Offset of each frame written
+    table: Vec<u64>,
+}
+
+impl ContainerSink {
+    /// Write the header to `inner`, recording `source` as where the stream
+    /// came from and whether it was compressed or encrypted on the way.
+    pub fn new(
+        mut inner: Box<dyn Sink>,
+        source: &str,
+        compressed: bool,
+        encrypted: bool,
+    ) -> io::Result<Self> {
+        let metadata = format!(
+            "created={}\nsource={}\npdd={}\nframe={FRAME_SIZE}\n",
+            format_timestamp(SystemTime::now()),
+            source.replace('\n', " "),
+            env!("CARGO_PKG_VERSION"),
+        );
+        let mut flags = 0;
+        if compressed {
+            flags |= COMPRESSED;
+        }
+        if encrypted {
+            flags |= ENCRYPTED;
+        }
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&flags.to_le_bytes());
+        header.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        header.extend_from_slice(metadata.as_bytes());
+        inner.write_all(&header)?;
+        Ok(Self {
+            inner,
+            frame: Vec::with_capacity(FRAME_SIZE),
+            hasher: blake3::Hasher::new(),
+            length: 0,
+            offset: header.len() as u64,
+            table: vec![],
+        })
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let mut head = [0u8; 4 + 32];
+        head[..4].copy_from_slice(&(self.frame.len() as u32).to_le_bytes());
+        head[4..].copy_from_slice(blake3::hash(&self.frame).as_bytes());
+        self.inner.write_all(&head)?;
+        self.inner.write_all(&self.frame)?;
+        self.table.push(self.offset);
+        self.offset += (head.len() + self.frame.len()) as u64;
+        self.frame.clear();
+        Ok(())
+    }
+}
+
+impl Write for ContainerSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let n = rest.len().min(FRAME_SIZE - self.frame.len());
+            self.frame.extend_from_slice(&rest[..n]);
+            self.hasher.update(&rest[..n]);
+            self.length += n as u64;
+            rest = &rest[n..];
+            if self.frame.len() == FRAME_SIZE {
+                self.write_frame()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Frames are written once full; a short one can only be the last.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for ContainerSink {
+    /// Write the last frame, the end and the footer.
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        if !self.frame.is_empty() {
+            self.write_frame()?;
+        }
+        let mut end = 0u32.to_le_bytes().to_vec();
+        end.extend_from_slice(&(self.table.len() as u64).to_le_bytes());
+        end.extend_from_slice(&self.length.to_le_bytes());
+        end.extend_from_slice(self.hasher.finalize().as_bytes());
+        for offset in &self.table {
+            end.extend_from_slice(&offset.to_le_bytes());
+        }
+        end.extend_from_slice(&self.offset.to_le_bytes());
+        end.extend_from_slice(END_MAGIC);
+        self.inner.write_all(&end)?;
+        self.inner.finish()
+    }
+
+    /// Left without an end, for whatever reads it to fail.
+    fn abandon(&mut self) -> io::Result<()> {
+        self.inner.abandon()
+    }
+}
+
+/// True if the file at `path` is a pddstream. Only regular files and block
+/// devices are looked at, as the bytes read from a FIFO would be lost to it;
+/// a stream is found out about by [`unpack`] as it is read.
+pub fn detect(path: &Path) -> bool {
+    if !input::rereadable(path) {
+        return false;
+    }
+    let mut magic = [0u8; 8];
+    File::open(path)
+        .and_then(|mut file| read_full(&mut file, &mut magic))
+        .is_ok_and(|n| n == magic.len() && magic == *MAGIC)
+}
+
+/// Read `inner` unpacked if it is a pddstream, and as it is if not.
+pub fn unpack<R: Read + Send + 'static>(inner: R) -> Unpack<R> {
+    Unpack {
+        inner: Some(inner),
+        reader: None,
+    }
+}
+
+/// Reader finding out whether its input is a pddstream when it is first
+/// read, so that waiting for the first bytes of a pipe happens on the
+/// reading thread.
+pub struct Unpack<R> {
+    inner: Option<R>,
+    reader: Option<Box<dyn Read + Send>>,
+}
+
+impl<R: Read + Send + 'static> Read for Unpack<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(mut inner) = self.inner.take() {
+            let mut magic = [0u8; 8];
+            let n = read_full(&mut inner, &mut magic)?;
+            let inner = Sniffed::new(&magic[..n], inner);
+            self.reader = Some(if magic[..n] == *MAGIC {
+                Box::new(Unpacker::open(inner)?)
+            } else {
+                Box::new(inner)
+            });
+        }
+        match &mut self.reader {
+            Some(reader) => reader.read(buf),
+            None => Err(io::Error::other("input failed to open")),
+        }
+    }
+}
+
+fn invalid(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// Reads the stream out of a pddstream, checking each frame against its
+/// digest as it comes, and the frames against the end once they are all
+/// read; reading fails where they don't match.
+pub struct Unpacker<R> {
+    inner: R,
+    header: Header,
+
+    /// Data of the frame being read, from `position`
+    frame: Vec<u8>,
+    position: usize,
+    hasher: blake3::Hasher,
+
+    /// Bytes of the stream read so far
+    length: u64,
+
+    /// Bytes of the container read so far
+    offset: u64,
+
+    /// Offset of each frame read
+    table: Vec<u64>,
+
+    /// Set once the end was read, and matched
+    totals: Option<Totals>,
+}
+
+impl<R: Read> Unpacker<R> {
+    /// Read the header of the pddstream `inner`, which is at its start.
+    pub fn open(inner: R) -> io::Result<Self> {
+        let mut unpacker = Self {
+            inner,
+            header: Header::default(),
+            frame: vec![],
+            position: 0,
+            hasher: blake3::Hasher::new(),
+            length: 0,
+            offset: 0,
+            table: vec![],
+            totals: None,
+        };
+        let mut fixed = [0u8; 8 + 4 + 4 + 4];
+        unpacker.fill(&mut fixed)?;
+        if fixed[..8] != *MAGIC {
+            return Err(invalid("the input isn't a pddstream".to_string()));
+        }
+        let version = u32::from_le_bytes(fixed[8..12].try_into().expect("4 bytes"));
+        if version != VERSION {
+            return Err(invalid(format!(
+                "the pddstream is of an unknown version, {version}"
+            )));
+        }
+        let flags = u32::from_le_bytes(fixed[12..16].try_into().expect("4 bytes"));
+        let metadata_len = u32::from_le_bytes(fixed[16..20].try_into().expect("4 bytes"));
+        if metadata_len > MAX_METADATA {
+            return Err(invalid(format!(
+                "the pddstream header has {metadata_len} bytes of metadata"
+            )));
+        }
+        let mut metadata = vec![0u8; metadata_len as usize];
+        unpacker.fill(&mut metadata)?;
+        unpacker.header = Header {
+            version,
+            compressed: flags & COMPRESSED != 0,
+            encrypted: flags & ENCRYPTED != 0,
+            metadata: String::from_utf8_lossy(&metadata)
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+        Ok(unpacker)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The totals, once the whole stream was read and matched them.
+    pub fn totals(&self) -> Option<&Totals> {
+        self.totals.as_ref()
+    }
+
+    /// Frames read so far, each matching its digest.
+    pub fn frames(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Read exactly `buf.len()` bytes of the container.
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        if read_full(&mut self.inner, buf)? < buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the pddstream ends before its end, the copy that wrote it didn't finish",
+            ));
+        }
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        self.fill(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Read the next frame, or the end; `false` once the stream is over.
+    fn next_frame(&mut self) -> io::Result<bool> {
+        if self.totals.is_some() {
+            return Ok(false);
+        }
+        let start = self.offset;
+        let mut head = [0u8; 4];
+        self.fill(&mut head)?;
+        let len = u32::from_le_bytes(head);
+        if len == 0 {
+            self.end(start)?;
+            return Ok(false);
+        }
+        let number = self.table.len() + 1;
+        if len > MAX_FRAME {
+            return Err(invalid(format!(
+                "frame {number} at offset {start} says it has {len} bytes"
+            )));
+        }
+        let mut digest = [0u8; 32];
+        self.fill(&mut digest)?;
+        let mut frame = std::mem::take(&mut self.frame);
+        frame.resize(len as usize, 0);
+        self.fill(&mut frame)?;
+        if blake3::hash(&frame).as_bytes() != &digest {
+            return Err(invalid(format!(
+                "frame {number} at offset {start} doesn't match its hash"
+            )));
+        }
+        self.hasher.update(&frame);
+        self.length += u64::from(len);
+        self.table.push(start);
+        self.frame = frame;
+        self.position = 0;
+        Ok(true)
+    }
+
+    /// Read the end, which starts at `start`, and the footer after it, and
+    /// check them against the frames.
+    fn end(&mut self, start: u64) -> io::Result<()> {
+        let frames = self.read_u64()?;
+        let length = self.read_u64()?;
+        let mut digest = [0u8; 32];
+        self.fill(&mut digest)?;
+        if frames != self.table.len() as u64 || length != self.length {
+            return Err(invalid(format!(
+                "the pddstream says it has {length} bytes in {frames} frames, but has {} in {}",
+                self.length,
+                self.table.len()
+            )));
+        }
+        let actual = self.hasher.finalize();
+        if actual.as_bytes() != &digest {
+            return Err(invalid(format!(
+                "the pddstream doesn't match its hash, blake3 {} instead of {}",
+                actual.to_hex(),
+                to_hex(&digest)
+            )));
+        }
+        for index in 0..self.table.len() {
+            if self.read_u64()? != self.table[index] {
+                return Err(invalid(format!(
+                    "the frame table of the pddstream doesn't match frame {}",
+                    index + 1
+                )));
+            }
+        }
+        let end = self.read_u64()?;
+        let mut magic = [0u8; 8];
+        self.fill(&mut magic)?;
+        if end != start || magic != *END_MAGIC {
+            return Err(invalid(
+                "the pddstream doesn't end in its footer".to_string(),
+            ));
+        }
+        self.totals = Some(Totals {
+            frames,
+            length,
+            digest,
+        });
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Unpacker<R> {
+    /// Fills `buf` across frames, so blocks keep the block size.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.position == self.frame.len() && !self.next_frame()? {
+                break;
+            }
+            let n = (buf.len() - filled).min(self.frame.len() - self.position);
+            buf[filled..filled + n].copy_from_slice(&self.frame[self.position..self.position + n]);
+            self.position += n;
+            filled += n;
+        }
+        Ok(filled)
+    }
+}
+
+/// What `pdd info FILE [--json]` is asked.
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub path: PathBuf,
+
+    /// Print what was found as JSON instead of text
+    pub json: bool,
+}
+
+/// Describe the pddstream at `info.path`, reading all of it to check every
+/// frame and its end.
+pub fn info(info: &Info) -> Result<()> {
+    let path = &info.path;
+    let context = |e: io::Error| {
+        eyre!("Failed to read the pddstream")
+            .with_error(|| e)
+            .with_note(|| format!("file {}", path.display()))
+    };
+    if !detect(path) {
+        // Tell a file that isn't one from one that can't be read.
+        File::open(path).map_err(context)?;
+        return Err(eyre!("Not a pddstream")
+            .with_note(|| format!("file {}", path.display()))
+            .with_suggestion(|| "pdd info reads outputs written with container=pddstream"));
+    }
+    let file = File::open(path).map_err(context)?;
+    let mut unpacker = Unpacker::open(BufReader::new(file)).map_err(context)?;
+    let checked = io::copy(&mut unpacker, &mut io::sink());
+    let header = unpacker.header();
+    let totals = unpacker.totals();
+    if info.json {
+        let metadata: serde_json::Map<String, serde_json::Value> = header
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone().into()))
+            .collect();
+        println!(
+            "{}",
+            serde_json::json!({
+                "path": path,
+                "version": header.version,
+                "compressed": header.compressed,
+                "encrypted": header.encrypted,
+                "metadata": metadata,
+                "frames": totals.map_or(unpacker.frames() as u64, |totals| totals.frames),
+                "length": totals.map(|totals| totals.length),
+                "digest": totals.map(|totals| to_hex(&totals.digest)),
+                "error": checked.as_ref().err().map(|e| e.to_string()),
+            })
+        );
+    } else {
+        println!("{}: {header}", path.display());
+        for (key, value) in &header.metadata {
+            println!("  {key} {value}");
+        }
+        match totals {
+            Some(totals) => println!("  {totals}, every frame matching"),
+            None => println!("  {} frames matching before it failed", unpacker.frames()),
+        }
+    }
+    checked.map_err(|e| {
+        eyre!("The pddstream is damaged")
+            .with_error(|| e)
+            .with_note(|| format!("file {}", path.display()))
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pdd-container-{}-{name}", std::process::id()))
+    }
+
+    /// Two and a half frames.
+    fn stream() -> Vec<u8> {
+        (0..FRAME_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn pack(path: &Path, data: &[u8], finish: bool) -> Vec<u8> {
+        let file = Box::new(File::create(path).unwrap());
+        let mut sink = ContainerSink::new(file, "if=a", true, false).unwrap();
+        sink.write_all(data).unwrap();
+        if finish {
+            sink.finish().unwrap();
+        } else {
+            sink.abandon().unwrap();
+        }
+        std::fs::read(path).unwrap()
+    }
+
+    fn read(data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        unpack(io::Cursor::new(data)).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        let path = temp("round-trip");
+        let packed = pack(&path, &stream(), true);
+        assert!(detect(&path));
+        assert_eq!(read(packed.clone()).unwrap(), stream());
+
+        let mut unpacker = Unpacker::open(io::Cursor::new(packed)).unwrap();
+        io::copy(&mut unpacker, &mut io::sink()).unwrap();
+        assert!(unpacker.header().compressed && !unpacker.header().encrypted);
+        assert!(
+            unpacker
+                .header()
+                .metadata
+                .contains(&("source".to_string(), "if=a".to_string()))
+        );
+        let totals = unpacker.totals().expect("the end was read");
+        assert_eq!((totals.frames, totals.length), (3, stream().len() as u64));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn other_input_is_read_as_it_is() {
+        assert_eq!(read(b"PDD".to_vec()).unwrap(), b"PDD");
+        assert_eq!(read(stream()).unwrap(), stream());
+    }
+
+    #[test]
+    fn a_corrupt_header_is_refused() {
+        let path = temp("header");
+        let packed = pack(&path, b"data", true);
+        let mut version = packed.clone();
+        version[8] ^= 1;
+        let mut metadata_len = packed.clone();
+        metadata_len[19] = 0xff;
+        let short = packed[..12].to_vec();
+        for (what, data) in [
+            ("version", version),
+            ("metadata length", metadata_len),
+            ("short", short),
+        ] {
+            let Err(e) = Unpacker::open(io::Cursor::new(data.clone())) else {
+                panic!("{what} was taken for a header");
+            };
+            assert!(
+                matches!(
+                    e.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ),
+                "{what}: {e}"
+            );
+            assert!(read(data).is_err(), "{what}");
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_damaged_or_unfinished_stream_fails_to_read() {
+        let path = temp("damaged");
+        let mut packed = pack(&path, &stream(), true);
+        let at = packed.len() / 2;
+        packed[at] ^= 1;
+        assert_eq!(read(packed).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let unfinished = pack(&path, &stream(), false);
+        let e = read(unfinished).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
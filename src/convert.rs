@@ -0,0 +1,145 @@
+/// dd's classic byte conversions of the stream (`conv=swab,ucase,lcase`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Conversion {
+    /// Swap every pair of bytes
+    pub swab: bool,
+
+    /// Change the case of ASCII letters
+    pub case: Option<Case>,
+}
+
+impl Conversion {
+    /// True if the stream is left as is.
+    pub fn is_none(&self) -> bool {
+        !self.swab && self.case.is_none()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Case {
+    Upper,
+    Lower,
+}
+
+/// Applies a [`Conversion`] to the stream block by block.
+///
+/// Pairs are swapped across the whole stream rather than each block, like
+/// GNU dd does: the last byte of an odd length block is held back and swapped
+/// with the first of the next, so short reads from a pipe don't shift the
+/// pairs. A byte still held back when the stream ends is passed on as is.
+pub struct Converter {
+    conversion: Conversion,
+
+    /// Byte held back from the last block
+    held: Option<u8>,
+    block: Vec<u8>,
+}
+
+impl Converter {
+    pub fn new(conversion: Conversion) -> Self {
+        Self {
+            conversion,
+            held: None,
+            block: vec![],
+        }
+    }
+
+    /// Convert `block`, returning the block to pass on and whether it starts
+    /// with the byte held back from the one before.
+    pub fn convert(&mut self, block: &[u8]) -> (bool, &mut [u8]) {
+        self.block.clear();
+        let held = self.held.take();
+        self.block.extend(held);
+        self.block.extend_from_slice(block);
+        match self.conversion.case {
+            Some(Case::Upper) => self.block.make_ascii_uppercase(),
+            Some(Case::Lower) => self.block.make_ascii_lowercase(),
+            None => {}
+        }
+        if self.conversion.swab {
+            if self.block.len() % 2 == 1 {
+                self.held = self.block.pop();
+            }
+            for pair in self.block.chunks_exact_mut(2) {
+                pair.swap(0, 1);
+            }
+        }
+        (held.is_some(), &mut self.block)
+    }
+
+    /// The byte still held back once the stream has ended.
+    pub fn finish(&mut self) -> Option<u8> {
+        self.held.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SWAB: Conversion = Conversion {
+        swab: true,
+        case: None,
+    };
+
+    /// `stream` converted in blocks of the given lengths, as the outputs get
+    /// it, with what is held back at the end.
+    fn convert(conversion: Conversion, stream: &[u8], blocks: &[usize]) -> Vec<u8> {
+        let mut converter = Converter::new(conversion);
+        let mut out = vec![];
+        let mut rest = stream;
+        for &len in blocks {
+            let (block, tail) = rest.split_at(len);
+            out.extend_from_slice(converter.convert(block).1);
+            rest = tail;
+        }
+        assert!(rest.is_empty());
+        out.extend(converter.finish());
+        out
+    }
+
+    #[test]
+    fn swab_carries_a_byte_across_odd_blocks() {
+        let mut converter = Converter::new(SWAB);
+        let (held, block) = converter.convert(b"abc");
+        assert!(!held);
+        assert_eq!(block, b"ba");
+        let (held, block) = converter.convert(b"def");
+        assert!(held);
+        assert_eq!(block, b"dcfe");
+        assert_eq!(converter.finish(), None);
+    }
+
+    #[test]
+    fn swab_passes_a_last_odd_byte_on_as_is() {
+        let mut converter = Converter::new(SWAB);
+        assert_eq!(converter.convert(b"abcde").1, b"badc");
+        assert_eq!(converter.convert(b"").1, b"");
+        assert_eq!(converter.finish(), Some(b'e'));
+        assert_eq!(converter.finish(), None);
+    }
+
+    #[test]
+    fn swab_is_the_same_however_the_stream_is_read() {
+        let stream = b"0123456789abcdefg";
+        let whole = convert(SWAB, stream, &[stream.len()]);
+        assert_eq!(whole, b"1032547698badcfeg");
+        for blocks in [
+            &[1, 1, 1, 14][..],
+            &[3, 3, 3, 3, 5],
+            &[2, 5, 1, 9],
+            &[7, 0, 10],
+        ] {
+            assert_eq!(convert(SWAB, stream, blocks), whole, "blocks {blocks:?}");
+        }
+    }
+
+    #[test]
+    fn swab_with_case() {
+        let conversion = Conversion {
+            swab: true,
+            case: Some(Case::Upper),
+        };
+        assert_eq!(convert(conversion, b"abcde", &[3, 2]), b"BADCE");
+    }
+}
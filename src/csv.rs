@@ -0,0 +1,73 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use crate::report::{Report, format_timestamp};
+
+const HEADER: &str = "input,output,serial,model,start,end,bytes,verify,hash,records_in,records_out";
+
+/// Append one row per output of every operation to the CSV file at `path`,
+/// writing the header first if the file is new or empty.
+pub fn append(path: &Path, reports: &[Report]) -> Result<()> {
+    let context = |e: std::io::Error| {
+        eyre!("Failed to write CSV results")
+            .with_error(|| e)
+            .with_note(|| format!("csv {}", path.display()))
+    };
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(context)?;
+    let mut out = String::new();
+    if file.metadata().map_err(context)?.len() == 0 {
+        out.push_str(HEADER);
+        out.push('\n');
+    }
+
+    for report in reports {
+        let summary = &report.summary;
+        let hash = summary
+            .digests()
+            .map(|(name, digest)| format!("{}:{digest}", name.trim_start_matches("hash=")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        for output in summary
+            .outputs
+            .iter()
+            .filter(|output| output.digest.is_none())
+        {
+            let fields = [
+                summary.input.clone(),
+                output.name.clone(),
+                output.identity.serial.clone().unwrap_or_default(),
+                output.identity.model.clone().unwrap_or_default(),
+                format_timestamp(summary.started),
+                format_timestamp(summary.started + output.elapsed),
+                output.records.bytes.to_string(),
+                output
+                    .verification
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_default(),
+                hash.clone(),
+                summary.records_in.to_string(),
+                output.records.to_string(),
+            ];
+            let row: Vec<String> = fields.iter().map(|f| field(f)).collect();
+            out.push_str(&row.join(","));
+            out.push('\n');
+        }
+    }
+
+    file.write_all(out.as_bytes()).map_err(context)
+}
+
+/// Quote a field if it contains anything CSV treats specially.
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
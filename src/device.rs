@@ -0,0 +1,450 @@
+use color_eyre::Result;
+use std::path::{Path, PathBuf};
+
+/// Identity of the hardware behind an output, if it is a block device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub serial: Option<String>,
+    pub model: Option<String>,
+}
+
+/// Look up the serial number and model of the device at `path`.
+///
+/// Anything that isn't a block device, or a platform without support,
+/// yields an empty identity.
+pub fn identify(path: &Path) -> DeviceIdentity {
+    imp::identify(path).unwrap_or_default()
+}
+
+/// The path to copy through for the device at `path`.
+///
+/// On macOS the buffered `/dev/diskN` nodes are many times slower than the
+/// raw `/dev/rdiskN` ones, so those are used instead. Anything else is
+/// returned unchanged.
+pub fn raw_path(path: &Path) -> PathBuf {
+    imp::raw_path(path).unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Get the device at `path` ready to be written to. On macOS its volumes are
+/// unmounted first, since the disk can't be opened for writing while they
+/// are mounted.
+pub fn prepare_write(path: &Path) -> Result<()> {
+    imp::prepare_write(path)
+}
+
+/// Sector size of the device at `path`, if every read and write of it has to
+/// be a multiple of that size, as with raw disks on macOS and every disk on
+/// FreeBSD.
+pub fn sector_size(path: &Path) -> Option<u64> {
+    imp::sector_size(path)
+}
+
+/// Size in bytes of the file or device at `path`, if it can be told.
+pub fn size(path: &Path) -> Option<u64> {
+    let metadata = std::fs::metadata(path).ok()?;
+    if metadata.is_file() {
+        return Some(metadata.len());
+    }
+    imp::media_size(path)
+}
+
+/// Request number of a BSD `_IOR(group, number, type)` ioctl, one that reads
+/// `size` bytes back, as macOS and FreeBSD number those of `<sys/disk.h>`.
+#[cfg(any(test, target_os = "macos", target_os = "freebsd"))]
+const fn ior(group: u8, number: u8, size: usize) -> libc::c_ulong {
+    const IOC_OUT: libc::c_ulong = 0x4000_0000;
+    const IOCPARM_MASK: libc::c_ulong = 0x1fff;
+    IOC_OUT
+        | ((size as libc::c_ulong & IOCPARM_MASK) << 16)
+        | ((group as libc::c_ulong) << 8)
+        | number as libc::c_ulong
+}
+
+/// The serial number in a NUL-terminated, space-padded field, as disks
+/// report it.
+#[cfg(any(test, target_os = "linux", target_os = "freebsd"))]
+fn serial(field: &[u8]) -> Option<String> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    let serial = String::from_utf8_lossy(&field[..end]);
+    let serial = serial.trim();
+    (!serial.is_empty()).then(|| serial.to_string())
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        fs,
+        os::unix::fs::FileTypeExt,
+        path::{Path, PathBuf},
+    };
+
+    use color_eyre::Result;
+
+    use super::{DeviceIdentity, serial};
+
+    pub fn raw_path(_path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    pub fn prepare_write(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Block devices go through the page cache, which takes care of
+    /// alignment.
+    pub fn sector_size(_path: &Path) -> Option<u64> {
+        None
+    }
+
+    /// Seeking to the end of a block device gives its size.
+    pub fn media_size(path: &Path) -> Option<u64> {
+        use std::io::{Seek, SeekFrom};
+
+        let mut file = fs::File::open(path).ok()?;
+        if !file.metadata().ok()?.file_type().is_block_device() {
+            return None;
+        }
+        file.seek(SeekFrom::End(0)).ok()
+    }
+
+    pub fn identify(path: &Path) -> Option<DeviceIdentity> {
+        let path = fs::canonicalize(path).ok()?;
+        if !fs::metadata(&path).ok()?.file_type().is_block_device() {
+            return None;
+        }
+        let sys = disk_sysfs_dir(&path)?;
+        let device = sys.join("device");
+        Some(DeviceIdentity {
+            serial: read_trimmed(&device.join("serial"))
+                .or_else(|| read_trimmed(&sys.join("serial")))
+                .or_else(|| read_vpd_serial(&device.join("vpd_pg80"))),
+            model: read_trimmed(&device.join("model")),
+        })
+    }
+
+    /// `/sys/class/block/<disk>` for a disk or partition node, resolving
+    /// partitions to the disk they live on.
+    fn disk_sysfs_dir(path: &Path) -> Option<PathBuf> {
+        let name = path.file_name()?;
+        let sys = fs::canonicalize(Path::new("/sys/class/block").join(name)).ok()?;
+        if sys.join("partition").exists() {
+            sys.parent().map(Path::to_path_buf)
+        } else {
+            Some(sys)
+        }
+    }
+
+    fn read_trimmed(path: &Path) -> Option<String> {
+        let value = fs::read_to_string(path).ok()?;
+        let value = value.trim();
+        (!value.is_empty()).then(|| value.to_string())
+    }
+
+    /// SCSI VPD page 0x80 (unit serial number): a 4 byte header followed by
+    /// the ASCII serial.
+    fn read_vpd_serial(path: &Path) -> Option<String> {
+        serial(fs::read(path).ok()?.get(4..)?)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use color_eyre::{Result, Section, eyre::eyre};
+    use std::{
+        fs::File,
+        os::{fd::AsRawFd, unix::fs::FileTypeExt},
+        path::{Path, PathBuf},
+        process::Command,
+    };
+
+    use super::{DeviceIdentity, ior};
+
+    /// `DKIOCGETBLOCKSIZE` from `<sys/disk.h>`, `_IOR('d', 24, uint32_t)`
+    const DKIOCGETBLOCKSIZE: libc::c_ulong = ior(b'd', 24, 4);
+
+    /// `DKIOCGETBLOCKCOUNT` from `<sys/disk.h>`, `_IOR('d', 25, uint64_t)`
+    const DKIOCGETBLOCKCOUNT: libc::c_ulong = ior(b'd', 25, 8);
+
+    /// Split `/dev/diskNsM` or `/dev/rdiskNsM` into whether it is raw, the
+    /// disk number and the partition suffix.
+    fn parse_disk(path: &Path) -> Option<(bool, &str, &str)> {
+        let name = path.strip_prefix("/dev").ok()?.to_str()?;
+        let (raw, rest) = match name.strip_prefix("rdisk") {
+            Some(rest) => (true, rest),
+            None => (false, name.strip_prefix("disk")?),
+        };
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (number, partition) = rest.split_at(split);
+        (!number.is_empty() && (partition.is_empty() || partition.starts_with('s')))
+            .then_some((raw, number, partition))
+    }
+
+    pub fn raw_path(path: &Path) -> Option<PathBuf> {
+        match parse_disk(path)? {
+            (false, number, partition) => {
+                let raw = Path::new("/dev").join(format!("rdisk{number}{partition}"));
+                raw.exists().then_some(raw)
+            }
+            (true, ..) => None,
+        }
+    }
+
+    pub fn prepare_write(path: &Path) -> Result<()> {
+        let Some((_, number, partition)) = parse_disk(path) else {
+            return Ok(());
+        };
+        // A partition only needs its own volume unmounted; writing to the
+        // whole disk needs all of them gone.
+        let (verb, disk) = if partition.is_empty() {
+            ("unmountDisk", format!("/dev/disk{number}"))
+        } else {
+            ("unmount", format!("/dev/disk{number}{partition}"))
+        };
+        let output = Command::new("diskutil")
+            .args([verb, &disk])
+            .output()
+            .map_err(|e| {
+                eyre!("Failed to run diskutil")
+                    .with_error(|| e)
+                    .with_note(|| format!("diskutil {verb} {disk}"))
+            })?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(eyre!("Failed to unmount {disk} before writing to it")
+                .with_note(|| stderr)
+                .with_suggestion(|| {
+                    format!("close whatever is using it or run diskutil {verb} force {disk}")
+                }));
+        }
+        Ok(())
+    }
+
+    pub fn sector_size(path: &Path) -> Option<u64> {
+        let file = File::open(path).ok()?;
+        if !file.metadata().ok()?.file_type().is_char_device() {
+            return None;
+        }
+        let mut size: u32 = 0;
+        // SAFETY: the descriptor is valid for the lifetime of `file`, and
+        // DKIOCGETBLOCKSIZE writes a single u32 through the pointer.
+        let rc = unsafe { libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKSIZE, &mut size) };
+        (rc == 0 && size > 0).then_some(u64::from(size))
+    }
+
+    pub fn media_size(path: &Path) -> Option<u64> {
+        let file = File::open(path).ok()?;
+        let file_type = file.metadata().ok()?.file_type();
+        if !file_type.is_char_device() && !file_type.is_block_device() {
+            return None;
+        }
+        let (mut size, mut count): (u32, u64) = (0, 0);
+        // SAFETY: the descriptor is valid for the lifetime of `file`, and
+        // each ioctl writes a single integer of the given type.
+        let rc = unsafe {
+            libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKSIZE, &mut size)
+                | libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKCOUNT, &mut count)
+        };
+        (rc == 0).then(|| u64::from(size) * count)
+    }
+
+    pub fn identify(_path: &Path) -> Option<DeviceIdentity> {
+        None
+    }
+}
+
+/// FreeBSD has no block devices: disks are GEOM providers, character devices
+/// that only take whole sectors, and are described by `<sys/disk.h>` ioctls.
+#[cfg(target_os = "freebsd")]
+mod imp {
+    use color_eyre::Result;
+    use std::{
+        fs::File,
+        os::{fd::AsRawFd, unix::fs::FileTypeExt},
+        path::{Path, PathBuf},
+    };
+
+    use super::{DeviceIdentity, ior, serial};
+
+    /// `DIOCGSECTORSIZE`, `_IOR('d', 128, u_int)`
+    const DIOCGSECTORSIZE: libc::c_ulong = ior(b'd', 128, 4);
+
+    /// `DIOCGMEDIASIZE`, `_IOR('d', 129, off_t)`
+    const DIOCGMEDIASIZE: libc::c_ulong = ior(b'd', 129, 8);
+
+    /// `DIOCGIDENT`, `_IOR('d', 137, char[DISK_IDENT_SIZE])`
+    const DIOCGIDENT: libc::c_ulong = ior(b'd', 137, DISK_IDENT_SIZE);
+    const DISK_IDENT_SIZE: usize = 256;
+
+    /// Open `path` if it is a disk, i.e. answers `DIOCGSECTORSIZE`.
+    fn open_disk(path: &Path) -> Option<(File, u64)> {
+        let file = File::open(path).ok()?;
+        if !file.metadata().ok()?.file_type().is_char_device() {
+            return None;
+        }
+        let mut size: libc::c_uint = 0;
+        // SAFETY: the descriptor is valid for the lifetime of `file`, and
+        // DIOCGSECTORSIZE writes a single u_int through the pointer.
+        let rc = unsafe { libc::ioctl(file.as_raw_fd(), DIOCGSECTORSIZE, &mut size) };
+        (rc == 0 && size > 0).then_some((file, u64::from(size)))
+    }
+
+    pub fn raw_path(_path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    pub fn prepare_write(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn sector_size(path: &Path) -> Option<u64> {
+        open_disk(path).map(|(_, size)| size)
+    }
+
+    pub fn media_size(path: &Path) -> Option<u64> {
+        let (file, _) = open_disk(path)?;
+        let mut size: libc::off_t = 0;
+        // SAFETY: the descriptor is valid for the lifetime of `file`, and
+        // DIOCGMEDIASIZE writes a single off_t through the pointer.
+        let rc = unsafe { libc::ioctl(file.as_raw_fd(), DIOCGMEDIASIZE, &mut size) };
+        (rc == 0).then(|| u64::try_from(size).ok()).flatten()
+    }
+
+    /// GEOM only knows the serial number, as the disk's ident.
+    pub fn identify(path: &Path) -> Option<DeviceIdentity> {
+        let (file, _) = open_disk(path)?;
+        let mut ident = [0u8; DISK_IDENT_SIZE];
+        // SAFETY: the descriptor is valid for the lifetime of `file`, and
+        // DIOCGIDENT writes at most DISK_IDENT_SIZE bytes into the buffer.
+        let rc = unsafe { libc::ioctl(file.as_raw_fd(), DIOCGIDENT, ident.as_mut_ptr()) };
+        if rc != 0 {
+            return None;
+        }
+        Some(DeviceIdentity {
+            serial: serial(&ident),
+            model: None,
+        })
+    }
+}
+
+/// OpenBSD and NetBSD describe disks with a disklabel (`DIOCGDINFO`) rather
+/// than the media size ioctls, which pdd doesn't read, so it can't tell the
+/// size or sector size of their raw disks and refuses to write to them.
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+mod imp {
+    use color_eyre::{Result, Section, eyre::eyre};
+    use std::{
+        os::unix::fs::FileTypeExt,
+        path::{Path, PathBuf},
+    };
+
+    use super::DeviceIdentity;
+
+    pub fn raw_path(_path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    pub fn prepare_write(path: &Path) -> Result<()> {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return Ok(());
+        };
+        let file_type = metadata.file_type();
+        if !file_type.is_char_device() && !file_type.is_block_device() {
+            return Ok(());
+        }
+        Err(eyre!("Writing to disks isn't supported on this system")
+            .with_note(|| {
+                format!(
+                    "{} is a disk, and pdd can't read its disklabel",
+                    path.display()
+                )
+            })
+            .with_suggestion(|| "write the image with dd(1) and a bs= of whole sectors"))
+    }
+
+    pub fn sector_size(_path: &Path) -> Option<u64> {
+        None
+    }
+
+    pub fn media_size(_path: &Path) -> Option<u64> {
+        None
+    }
+
+    pub fn identify(_path: &Path) -> Option<DeviceIdentity> {
+        None
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+)))]
+mod imp {
+    use color_eyre::Result;
+    use std::path::{Path, PathBuf};
+
+    use super::DeviceIdentity;
+
+    pub fn raw_path(_path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    pub fn prepare_write(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn sector_size(_path: &Path) -> Option<u64> {
+        None
+    }
+
+    pub fn media_size(_path: &Path) -> Option<u64> {
+        None
+    }
+
+    pub fn identify(_path: &Path) -> Option<DeviceIdentity> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ior_matches_sys_disk() {
+        // macOS
+        assert_eq!(ior(b'd', 24, 4), 0x4004_6418);
+        assert_eq!(ior(b'd', 25, 8), 0x4008_6419);
+        // FreeBSD
+        assert_eq!(ior(b'd', 128, 4), 0x4004_6480);
+        assert_eq!(ior(b'd', 129, 8), 0x4008_6481);
+        assert_eq!(ior(b'd', 137, 256), 0x4100_6489);
+    }
+
+    #[test]
+    fn serial_is_cut_at_nul_and_trimmed() {
+        assert_eq!(serial(b"WD-1234\0junk").as_deref(), Some("WD-1234"));
+        assert_eq!(serial(b"  S3Z9NB0K  \0\0").as_deref(), Some("S3Z9NB0K"));
+        assert_eq!(serial(b"ABC").as_deref(), Some("ABC"));
+        assert_eq!(serial(b"   \0ABC"), None);
+        assert_eq!(serial(b""), None);
+    }
+
+    #[test]
+    fn size_of_files_and_others() {
+        let dir = std::env::temp_dir().join(format!("pdd-device-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("image");
+        std::fs::write(&file, [0u8; 1536]).unwrap();
+        assert_eq!(size(&file), Some(1536));
+        assert_eq!(sector_size(&file), None);
+        assert_eq!(size(&dir.join("missing")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        #[cfg(unix)]
+        assert_eq!(size(Path::new("/dev/null")), None);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set when diagnostics are printed as JSON.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Print diagnostics as one JSON object per line instead of text, e.g. to
+/// go with `--progress json` or `log=json`.
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+/// Conditions worth reacting to, each with a stable code so scripts don't
+/// have to match messages. Codes are never reused or renumbered; warnings
+/// are `PDD-W`, errors `PDD-E`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    /// An output received fewer bytes than were read
+    OutputShort,
+
+    /// An unreadable input block was replaced with zeros (`conv=noerror`)
+    ReadErrorZeroed,
+
+    /// Direct I/O was asked for but isn't possible, so the page cache is used
+    DirectUnavailable,
+
+    /// `oflag=sync` or `dsync` isn't supported on this platform
+    SyncUnsupported,
+
+    /// An output couldn't be synced after an interrupt
+    SyncFailed,
+
+    /// The `resume=` checkpoint couldn't be saved
+    CheckpointNotSaved,
+
+    /// The connection of a download dropped and it was picked up again
+    InputResumed,
+
+    /// A filesystem kept its identifier, there being no safe way to change
+    /// it in place (`new-ids=1`)
+    IdKept,
+
+    /// Memory, open files or tasks keep growing over a long run (`stats=`)
+    ResourceGrowth,
+
+    /// Writing to an output failed and it was given up on
+    WriteFailed,
+
+    /// Flushing or finishing an output failed
+    FinishFailed,
+
+    /// Reading an output back found data that differs
+    VerifyMismatch,
+
+    /// Reading an output back found it shorter than what was written
+    VerifyShort,
+
+    /// An output couldn't be read back
+    VerifyFailed,
+
+    /// The backup GPT of an output couldn't be moved to its end
+    /// (`fix-gpt=1`)
+    GptNotFixed,
+
+    /// The identifiers of an output couldn't be replaced (`new-ids=1`)
+    IdsNotChanged,
+
+    /// A download couldn't be kept in the cache, though the copy went on
+    /// (`cache=`)
+    CacheNotKept,
+
+    /// Verification couldn't be given the I/O priority asked for, so it ran
+    /// at the one the copy had (`verify-priority=`)
+    PriorityNotSet,
+
+    /// A kept download had changed on the server or no longer matched its
+    /// digest, so it was thrown away and downloaded again (`cache=`)
+    CacheStale,
+
+    /// A kept download couldn't be checked against the server, so it was
+    /// read as it was kept (`cache=`)
+    CacheUnchecked,
+
+    /// The input's integrity trailer was checked and left out of the copy,
+    /// which has none (`trailer=strip`)
+    TrailerStripped,
+}
+
+impl Code {
+    /// The stable code, e.g. `PDD-W001`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::OutputShort => "PDD-W001",
+            Code::ReadErrorZeroed => "PDD-W002",
+            Code::DirectUnavailable => "PDD-W003",
+            Code::SyncUnsupported => "PDD-W004",
+            Code::SyncFailed => "PDD-W005",
+            Code::CheckpointNotSaved => "PDD-W006",
+            Code::InputResumed => "PDD-W007",
+            Code::IdKept => "PDD-W008",
+            Code::ResourceGrowth => "PDD-W009",
+            Code::WriteFailed => "PDD-E010",
+            Code::FinishFailed => "PDD-E011",
+            Code::VerifyMismatch => "PDD-E014",
+            Code::VerifyShort => "PDD-E015",
+            Code::VerifyFailed => "PDD-E016",
+            Code::GptNotFixed => "PDD-E017",
+            Code::IdsNotChanged => "PDD-E018",
+            Code::CacheNotKept => "PDD-W019",
+            Code::PriorityNotSet => "PDD-W020",
+            Code::CacheStale => "PDD-W021",
+            Code::CacheUnchecked => "PDD-W022",
+            Code::TrailerStripped => "PDD-W023",
+        }
+    }
+
+    pub fn is_error(self) -> bool {
+        self.as_str().starts_with("PDD-E")
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One warning or error, printed to stderr with [`Diagnostic::emit`].
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub code: Code,
+
+    /// The operand it is about, e.g. `of=/dev/sda`
+    pub subject: Option<String>,
+
+    pub message: String,
+
+    /// Byte offset it happened at, if there is one
+    pub offset: Option<u64>,
+}
+
+impl Diagnostic {
+    pub fn new(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            subject: None,
+            message: message.into(),
+            offset: None,
+        }
+    }
+
+    pub fn subject(mut self, subject: impl fmt::Display) -> Self {
+        self.subject = Some(subject.to_string());
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Print as text, `warning PDD-W002: if=disk.img: ...`, or as JSON.
+    pub fn emit(&self) {
+        let severity = if self.code.is_error() {
+            "error"
+        } else {
+            "warning"
+        };
+        if JSON.load(Ordering::Relaxed) {
+            crate::log::event(serde_json::json!({
+                "type": "diagnostic",
+                "severity": severity,
+                "code": self.code.as_str(),
+                "subject": self.subject,
+                "message": self.message,
+                "offset": self.offset,
+            }));
+            return;
+        }
+        match &self.subject {
+            Some(subject) => eprintln!("{severity} {}: {subject}: {}", self.code, self.message),
+            None => eprintln!("{severity} {}: {}", self.code, self.message),
+        }
+    }
+}
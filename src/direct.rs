@@ -0,0 +1,212 @@
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::diagnostic::{Code, Diagnostic};
+
+/// Alignment of the buffers handed to the kernel for direct I/O, enough for
+/// disks with 4K sectors.
+pub const BUFFER_ALIGNMENT: usize = 4096;
+
+/// Multiple of which the length of a direct read or write has to be. Disks
+/// with bigger sectors refuse such I/O, which is then done through the page
+/// cache after all.
+pub const LENGTH_ALIGNMENT: usize = 512;
+
+/// Ask for I/O on `file` to bypass the page cache, or stop asking.
+/// `Ok(false)` if the platform has no way to.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn set_direct(file: &File, direct: bool) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    // SAFETY: the descriptor is valid for the lifetime of `file`, and
+    // F_GETFL and F_SETFL only read and set its status flags.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if direct {
+        flags | libc::O_DIRECT
+    } else {
+        flags & !libc::O_DIRECT
+    };
+    // SAFETY: as above.
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(true)
+}
+
+/// macOS has no O_DIRECT; F_NOCACHE keeps the data out of the cache and
+/// needs no alignment.
+#[cfg(target_os = "macos")]
+pub fn set_direct(file: &File, direct: bool) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor is valid for the lifetime of `file`, and
+    // F_NOCACHE only sets a flag on it.
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, libc::c_int::from(direct)) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(true)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "macos")))]
+pub fn set_direct(_file: &File, _direct: bool) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Memory whose contents start at a [`BUFFER_ALIGNMENT`] boundary.
+#[derive(Default)]
+pub struct AlignedBuf {
+    raw: Vec<u8>,
+}
+
+impl AlignedBuf {
+    /// `len` aligned bytes. What was there before is kept unless the buffer
+    /// has to grow.
+    pub fn get(&mut self, len: usize) -> &mut [u8] {
+        if self.raw.len() < len + BUFFER_ALIGNMENT {
+            self.raw = vec![0; len + BUFFER_ALIGNMENT];
+        }
+        let start = self.raw.as_ptr().align_offset(BUFFER_ALIGNMENT);
+        &mut self.raw[start..start + len]
+    }
+}
+
+/// True for the error a kernel gives for direct I/O it won't do, e.g. at an
+/// unaligned offset or on a filesystem without support for it.
+pub fn is_refused(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::InvalidInput
+}
+
+/// An input file read around the page cache (`iflag=direct`).
+///
+/// Reads go through an aligned buffer in multiples of
+/// [`LENGTH_ALIGNMENT`]; whatever a read brings in beyond what was asked
+/// for is handed out by the next one. If the kernel refuses a direct read,
+/// the rest of the file is read through the cache.
+pub struct DirectReader {
+    file: File,
+    buffer: AlignedBuf,
+    /// Read ahead of the caller, `buffer[start..end]`
+    start: usize,
+    end: usize,
+    direct: bool,
+}
+
+impl DirectReader {
+    /// Wrap `file` and switch it to direct I/O if the platform and the
+    /// filesystem allow it.
+    pub fn new(file: File) -> io::Result<Self> {
+        let direct = match set_direct(&file, true) {
+            Ok(direct) => direct,
+            Err(e) if is_refused(&e) => false,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            file,
+            buffer: AlignedBuf::default(),
+            start: 0,
+            end: 0,
+            direct,
+        })
+    }
+
+    /// False once reads go through the page cache.
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    /// Read ahead by at least `len` bytes, unless the file ends first.
+    fn fill(&mut self, len: usize) -> io::Result<()> {
+        let len = len.next_multiple_of(LENGTH_ALIGNMENT);
+        match self.file.read(self.buffer.get(len)) {
+            Ok(n) => (self.start, self.end) = (0, n),
+            Err(e) if is_refused(&e) => {
+                let message = format!("direct read refused ({e}), using the page cache");
+                Diagnostic::new(Code::DirectUnavailable, message)
+                    .offset(self.file.stream_position()?)
+                    .emit();
+                set_direct(&self.file, false)?;
+                self.direct = false;
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+}
+
+impl Read for DirectReader {
+    /// Reads fill `buf` where the file allows, so blocks that aren't a
+    /// whole number of sectors long don't come out short.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            if self.start == self.end {
+                if !self.direct {
+                    return match self.file.read(&mut buf[done..]) {
+                        Ok(n) => Ok(done + n),
+                        Err(_) if done > 0 => Ok(done),
+                        Err(e) => Err(e),
+                    };
+                }
+                match self.fill(buf.len() - done) {
+                    Ok(()) if self.start == self.end && self.direct => break,
+                    Ok(()) => continue,
+                    Err(_) if done > 0 => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            let n = (buf.len() - done).min(self.end - self.start);
+            let ahead = &self.buffer.get(self.end)[self.start..self.start + n];
+            buf[done..done + n].copy_from_slice(ahead);
+            self.start += n;
+            done += n;
+        }
+        Ok(done)
+    }
+}
+
+impl Seek for DirectReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // The file is ahead of the caller by what was read ahead.
+        let ahead = (self.end - self.start) as i64;
+        let pos = match pos {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - ahead),
+            pos => pos,
+        };
+        (self.start, self.end) = (0, 0);
+        self.file.seek(pos)
+    }
+}
+
+// The reads and seeks are blocking, so they are made to look async by
+// telling the runtime the worker is busy. This needs the multi-threaded
+// runtime, which the pdd binary runs on.
+impl AsyncRead for DirectReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = tokio::task::block_in_place(|| self.read(buf.initialize_unfilled()))?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for DirectReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        tokio::task::block_in_place(|| self.seek(position)).map(|_| ())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(self.file.stream_position())
+    }
+}
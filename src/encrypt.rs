@@ -0,0 +1,256 @@
+use age::{
+    Decryptor, Encryptor,
+    stream::{StreamReader, StreamWriter},
+};
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::{
+    keys::{self, KeyProvider},
+    sink::{Shared, Sink},
+};
+
+/// Encryption applied to the outputs of an operation (`enc=age:SOURCE`),
+/// after compression, so what leaves for a socket, object storage or a file
+/// is encrypted in flight and at rest, and authenticated frame by frame
+/// (see [`FRAME_SIZE`]).
+#[derive(Clone)]
+pub enum Encryption {
+    /// An [age](https://age-encryption.org) file any of the keys from these
+    /// providers can decrypt
+    Age(Vec<Arc<dyn KeyProvider>>),
+}
+
+impl Encryption {
+    /// Also encrypt to the keys of `other`, for repeated `enc=`.
+    pub fn add(&mut self, other: Encryption) {
+        let (Encryption::Age(providers), Encryption::Age(more)) = (self, other);
+        providers.extend(more);
+    }
+
+    /// Ask every provider for its keys, once for all the outputs.
+    pub fn recipients(&self) -> io::Result<Vec<Box<dyn age::Recipient + Send>>> {
+        let Encryption::Age(providers) = self;
+        let mut recipients = vec![];
+        for provider in providers {
+            let keys = provider.keys()?;
+            // age only lets a passphrase encrypt a file on its own.
+            if keys.passphrase.is_some() && providers.len() > 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("the passphrase of {provider} can't be used along with other keys"),
+                ));
+            }
+            recipients.extend(keys.recipients());
+        }
+        Ok(recipients)
+    }
+}
+
+impl FromStr for Encryption {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some(source) = s.strip_prefix("age:") else {
+            return Err(eyre!("Invalid encryption")
+                .with_note(|| format!("input enc={s}"))
+                .with_suggestion(|| "expected enc=age:RECIPIENT, e.g. enc=age:age1ql3z7hjy..."));
+        };
+        let provider = keys::provider(source).with_note(|| format!("input enc={s}"))?;
+        Ok(Encryption::Age(vec![provider]))
+    }
+}
+
+impl fmt::Display for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encryption::Age(providers) => {
+                let providers: Vec<String> = providers.iter().map(ToString::to_string).collect();
+                write!(f, "age:{}", providers.join(","))
+            }
+        }
+    }
+}
+
+/// Decryption applied to the input of an operation (`dec=age:SOURCE`),
+/// before decompression.
+#[derive(Clone)]
+pub enum Decryption {
+    /// An age file, decrypted with the keys from this provider, e.g. a file
+    /// written by `age-keygen`
+    Age(Arc<dyn KeyProvider>),
+}
+
+impl Decryption {
+    /// Wrap `inner` in a reader decrypting it, which reads the header once
+    /// first read from.
+    pub fn reader<R: Read + Send + 'static>(&self, inner: R) -> io::Result<Decrypter<R>> {
+        let Decryption::Age(provider) = self;
+        let identities = provider.keys()?.identities();
+        if identities.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{provider} holds only public keys, which can't decrypt"),
+            ));
+        }
+        Ok(Decrypter {
+            inner: Some(inner),
+            identities,
+            reader: None,
+            position: 0,
+        })
+    }
+}
+
+impl FromStr for Decryption {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("age:") {
+            Some(source) if !source.is_empty() => Ok(Decryption::Age(
+                keys::provider(source).with_note(|| format!("input dec={s}"))?,
+            )),
+            _ => Err(eyre!("Invalid decryption")
+                .with_note(|| format!("input dec={s}"))
+                .with_suggestion(|| "expected dec=age:IDENTITY_FILE, e.g. dec=age:key.txt")),
+        }
+    }
+}
+
+impl fmt::Display for Decryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decryption::Age(provider) => write!(f, "age:{provider}"),
+        }
+    }
+}
+
+/// Bytes of plaintext in each frame of an age payload.
+///
+/// age encrypts the payload in frames of this size, each sealed with
+/// ChaCha20-Poly1305 under a nonce made of its sequence number and whether
+/// it is the last frame, so a frame that was altered, moved or dropped, or
+/// a stream cut short of its last frame, fails to authenticate as soon as
+/// that frame is reached rather than once all of it has been read.
+pub const FRAME_SIZE: u64 = 64 * 1024;
+
+/// Reader decrypting an age file, reading its header when first read so
+/// that waiting for the first bytes of a pipe happens on the reading thread.
+/// Nothing of a frame is passed on before it has been authenticated.
+pub struct Decrypter<R: Read> {
+    inner: Option<R>,
+    identities: Vec<Box<dyn age::Identity + Send + Sync>>,
+    reader: Option<StreamReader<R>>,
+
+    /// Plaintext bytes passed on so far, which tell the frame being read
+    position: u64,
+}
+
+impl<R: Read + Send + 'static> Read for Decrypter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(inner) = self.inner.take() {
+            let decryptor = Decryptor::new(inner)
+                .map_err(|e| io::Error::other(format!("not an age file, {e}")))?;
+            let identities = self.identities.iter().map(|identity| &**identity as _);
+            let reader = decryptor
+                .decrypt(identities)
+                .map_err(|e| io::Error::other(format!("failed to decrypt, {e}")))?;
+            self.reader = Some(reader);
+        }
+        let Some(reader) = &mut self.reader else {
+            return Err(io::Error::other("input failed to decrypt"));
+        };
+        match reader.read(buf) {
+            Ok(n) => {
+                self.position += n as u64;
+                Ok(n)
+            }
+            // Errors of the input itself pass through as they are.
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                let frame = self.position / FRAME_SIZE;
+                Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "frame {frame} of the encrypted input, from byte {} of its plaintext, \
+                         failed to authenticate, so it was corrupted, reordered or cut short \
+                         ({e})",
+                        frame * FRAME_SIZE
+                    ),
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Sink wrapper encrypting the stream on its way to `inner`.
+pub struct EncryptSink {
+    /// Taken when finished, since finishing the stream consumes it
+    writer: Option<StreamWriter<Shared>>,
+
+    /// What the stream writes to, for abandoning it without the last chunk
+    inner: Shared,
+}
+
+impl EncryptSink {
+    /// Write the header to `inner` and encrypt what follows to `recipients`,
+    /// from [`Encryption::recipients`].
+    pub fn new(
+        inner: Box<dyn Sink>,
+        recipients: &[Box<dyn age::Recipient + Send>],
+    ) -> io::Result<Self> {
+        let encryptor = Encryptor::with_recipients(
+            recipients
+                .iter()
+                .map(|recipient| &**recipient as &dyn age::Recipient),
+        )
+        .map_err(|e| io::Error::other(e.to_string()))?;
+        let inner = Shared::new(inner);
+        Ok(Self {
+            writer: Some(encryptor.wrap_output(inner.clone())?),
+            inner,
+        })
+    }
+
+    fn writer(&mut self) -> io::Result<&mut StreamWriter<Shared>> {
+        self.writer
+            .as_mut()
+            .ok_or_else(|| io::Error::other("encrypted output already finished"))
+    }
+}
+
+impl Write for EncryptSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer()?.flush()
+    }
+}
+
+impl Sink for EncryptSink {
+    /// Write the last chunk, which says the stream ended where it did.
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        match self.writer.take() {
+            Some(writer) => writer.finish()?.finish(),
+            None => Ok(None),
+        }
+    }
+
+    /// Leave the stream without its last chunk, so decrypting what was
+    /// written fails rather than taking it for the whole stream.
+    fn abandon(&mut self) -> io::Result<()> {
+        let inner = self.inner.take();
+        self.writer = None;
+        match inner {
+            Some(mut inner) => inner.abandon(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,1259 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    fs::File,
+    future::Future,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    task::JoinHandle,
+};
+
+use crate::{
+    convert::{Conversion, Converter},
+    diagnostic::{Code, Diagnostic},
+    hash::{HashAlgorithm, Hasher},
+    lifecycle::{Lifecycle, State},
+    log,
+    offload::{self, Offload},
+    patch::{self, Patch},
+    profile::{StageKind, StageProfile},
+    progress::{Counter, Progress},
+    redact::{Redaction, Redactor},
+    rescue,
+    sink::Sink,
+    summary::Records,
+    throttle::Throttle,
+};
+
+/// Most bytes the kernel is asked to copy at once when the copy is left to
+/// it, so progress is seen and an interrupt stops it soon.
+const OFFLOAD_CHUNK: usize = 8 * 1024 * 1024;
+
+/// Blocks queued per output before the reader waits for it to catch up.
+const CHANNEL_DEPTH: usize = 64;
+
+/// Wait before the first retry of a failed write; it doubles with every
+/// further retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What a sink does when writing to it fails (`onerror=`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop reading; the other sinks still get what was already read
+    #[default]
+    Abort,
+
+    /// Drop the sink and keep the others going
+    Skip,
+
+    /// Retry the write this many times with backoff, then drop the sink
+    Retry(u32),
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            eyre!("Invalid error policy")
+                .with_note(|| format!("input onerror={s}"))
+                .with_suggestion(|| "expected onerror=abort, onerror=skip or onerror=retry:N")
+        };
+        match s.split_once(':') {
+            None if s == "abort" => Ok(ErrorPolicy::Abort),
+            None if s == "skip" => Ok(ErrorPolicy::Skip),
+            Some(("retry", retries)) => retries
+                .parse()
+                .map(ErrorPolicy::Retry)
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for ErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorPolicy::Abort => write!(f, "abort"),
+            ErrorPolicy::Skip => write!(f, "skip"),
+            ErrorPolicy::Retry(retries) => write!(f, "retry:{retries}"),
+        }
+    }
+}
+
+/// A reader that can also seek, e.g. a file.
+pub trait SeekRead: AsyncRead + AsyncSeek + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncSeek + Send + Unpin> SeekRead for T {}
+
+/// What a copy reads from.
+pub enum Source {
+    /// Read front to back only, e.g. a pipe
+    Stream(Box<dyn AsyncRead + Send + Unpin>),
+
+    /// Can be seeked past blocks that fail to read
+    Seekable(Box<dyn SeekRead>),
+}
+
+impl Source {
+    pub fn stream(reader: impl AsyncRead + Send + Unpin + 'static) -> Self {
+        Source::Stream(Box::new(reader))
+    }
+
+    pub fn seekable(reader: impl SeekRead + 'static) -> Self {
+        Source::Seekable(Box::new(reader))
+    }
+
+    /// Read a blocking reader such as stdin on a thread of its own, `chunk`
+    /// bytes at a time. Unlike tokio's blocking pool, a read still pending
+    /// when the copy stops doesn't hold up the exit of the process.
+    pub fn threaded(reader: impl Read + Send + 'static, chunk: usize) -> Self {
+        let (tx, rx) = mpsc::channel(1);
+        std::thread::spawn(move || {
+            let mut reader = reader;
+            loop {
+                let mut buffer = vec![0u8; chunk];
+                let result = match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        buffer.truncate(n);
+                        Ok(buffer)
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = result.is_err();
+                if tx.blocking_send(result).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Source::stream(ThreadedReader {
+            rx,
+            pending: vec![],
+            offset: 0,
+        })
+    }
+
+    async fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Source::Stream(reader) => reader.read(buffer).await,
+            Source::Seekable(reader) => reader.read(buffer).await,
+        }
+    }
+}
+
+/// The receiving end of [`Source::threaded`].
+struct ThreadedReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl AsyncRead for ThreadedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.offset == self.pending.len() {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.pending = chunk;
+                    self.offset = 0;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(self.pending.len() - self.offset);
+        let offset = self.offset;
+        buf.put_slice(&self.pending[offset..offset + n]);
+        self.offset += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Copies one source to any number of sinks.
+///
+/// The source is read block by block and every block is handed to every
+/// sink; each sink gets its own bounded queue and thread, so the slowest one
+/// throttles the reader.
+pub struct CopyEngine {
+    source: Source,
+    source_name: String,
+    sinks: Vec<(StageProfile, Box<dyn Sink>, ErrorPolicy)>,
+    block_size: usize,
+    output_block_size: Option<usize>,
+    count: u64,
+    count_bytes: Option<u64>,
+    duration: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    position: u64,
+    patches: Vec<Patch>,
+    redactions: Vec<Redaction>,
+    conversion: Conversion,
+    pad: bool,
+    noerror: bool,
+    fullblock: bool,
+    limit: Option<u64>,
+    expected: Option<u64>,
+    digest: Option<(HashAlgorithm, Hasher, String)>,
+    split: Option<u64>,
+    offload: Option<File>,
+    rescue: Option<File>,
+    interrupt: Arc<AtomicBool>,
+    lifecycle: Option<Arc<Lifecycle>>,
+}
+
+impl CopyEngine {
+    /// `name` identifies the source in progress and profiles, e.g.
+    /// `if=disk.img`.
+    pub fn new(source: Source, name: impl Into<String>) -> Self {
+        Self {
+            source,
+            source_name: name.into(),
+            sinks: vec![],
+            block_size: 1024,
+            output_block_size: None,
+            count: 0,
+            count_bytes: None,
+            duration: None,
+            idle_timeout: None,
+            position: 0,
+            patches: vec![],
+            redactions: vec![],
+            conversion: Conversion::default(),
+            pad: false,
+            noerror: false,
+            fullblock: false,
+            limit: None,
+            expected: None,
+            digest: None,
+            split: None,
+            offload: None,
+            rescue: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            lifecycle: None,
+        }
+    }
+
+    /// Add a sink fed every block of the source.
+    pub fn add_sink(
+        &mut self,
+        kind: StageKind,
+        name: impl Into<String>,
+        sink: Box<dyn Sink>,
+        on_error: ErrorPolicy,
+    ) {
+        self.sinks
+            .push((StageProfile::new(kind, name), sink, on_error));
+    }
+
+    /// Size of the blocks read from the source
+    ///
+    /// (default = 1024)
+    pub fn block_size(&mut self, block_size: usize) {
+        self.block_size = block_size
+    }
+
+    /// Gather what is read into blocks of exactly this size before handing
+    /// them to the sinks, the last one possibly short, like dd given `ibs=`
+    /// and `obs=`
+    ///
+    /// (default = none, blocks are passed on as read)
+    pub fn output_block_size(&mut self, output_block_size: usize) {
+        let _ = self.output_block_size.replace(output_block_size);
+    }
+
+    /// Number of blocks to copy. Each read counts as a block, however short,
+    /// unless [`CopyEngine::fullblock`] is set.
+    ///
+    /// (default = 0|ALL)
+    pub fn count(&mut self, count: u64) {
+        self.count = count
+    }
+
+    /// Number of bytes to copy, stopping part way through a block if need be
+    /// (`count=1GiB`)
+    ///
+    /// (default = all)
+    pub fn count_bytes(&mut self, bytes: u64) {
+        let _ = self.count_bytes.replace(bytes);
+    }
+
+    /// Stop copying after this much wall time, even while waiting for the
+    /// source
+    ///
+    /// (default = none)
+    pub fn duration(&mut self, duration: Duration) {
+        let _ = self.duration.replace(duration);
+    }
+
+    /// Stop copying once the source has produced nothing for this long; the
+    /// copy still counts as complete
+    ///
+    /// (default = none)
+    pub fn idle_timeout(&mut self, idle_timeout: Duration) {
+        let _ = self.idle_timeout.replace(idle_timeout);
+    }
+
+    /// Offset of the source from the start of the input, which patch and
+    /// redaction offsets are relative to
+    ///
+    /// (default = 0)
+    pub fn position(&mut self, position: u64) {
+        self.position = position
+    }
+
+    pub fn patches(&mut self, patches: Vec<Patch>) {
+        self.patches = patches
+    }
+
+    pub fn redactions(&mut self, redactions: Vec<Redaction>) {
+        self.redactions = redactions
+    }
+
+    /// Swap byte pairs or change the case of every block after it is read,
+    /// and after padding, like `conv=swab,ucase` or `conv=lcase`
+    ///
+    /// (default = none)
+    pub fn conversion(&mut self, conversion: Conversion) {
+        self.conversion = conversion
+    }
+
+    /// Pad short reads with zeros to the block size, like `conv=sync`
+    ///
+    /// (default = false)
+    pub fn pad(&mut self, pad: bool) {
+        self.pad = pad
+    }
+
+    /// Substitute a zero block for every block that fails to read and carry
+    /// on past it, like `conv=noerror`. Only seekable sources can be moved
+    /// past a bad block; errors on streams still stop the copy.
+    ///
+    /// (default = false)
+    pub fn noerror(&mut self, noerror: bool) {
+        self.noerror = noerror
+    }
+
+    /// Keep reading until a block is full or the source ends, like
+    /// `iflag=fullblock`, so short reads from pipes don't cut blocks short
+    ///
+    /// (default = false)
+    pub fn fullblock(&mut self, fullblock: bool) {
+        self.fullblock = fullblock
+    }
+
+    /// Cap reading from the source at this many bytes per second, which
+    /// holds back every sink with it
+    ///
+    /// (default = none)
+    pub fn limit(&mut self, rate: u64) {
+        let _ = self.limit.replace(rate);
+    }
+
+    /// Number of bytes the source is expected to yield, for progress
+    ///
+    /// (default = unknown)
+    pub fn expect(&mut self, bytes: u64) {
+        let _ = self.expected.replace(bytes);
+    }
+
+    /// Digest of the whole source, as it is read, that the copy must have:
+    /// otherwise every output is abandoned and the copy fails, like
+    /// `verify-catalog=`. For copies that read the source from its start to
+    /// its end.
+    ///
+    /// (default = none)
+    pub fn digest(&mut self, algorithm: HashAlgorithm, expected: String) {
+        let _ = self
+            .digest
+            .replace((algorithm, Hasher::new(algorithm), expected));
+    }
+
+    /// Deal the stream out across the [`StageKind::Write`] sinks in turn,
+    /// this many bytes to each, instead of giving each all of it, like
+    /// `mode=split`. Other sinks, like hashes, still get everything.
+    ///
+    /// (default = none)
+    pub fn split(&mut self, chunk: u64) {
+        let _ = self.split.replace(chunk);
+    }
+
+    /// The source as a file, so a copy with nothing done to the stream and
+    /// one sink writing a regular file as is can be left to the kernel: a
+    /// reflink where the whole file goes to an empty one on the same copy on
+    /// write filesystem, else `copy_file_range`, else `sendfile`. Where none
+    /// of them can, the source is read as usual.
+    ///
+    /// (default = none)
+    pub fn offload(&mut self, file: File) {
+        let _ = self.offload.replace(file);
+    }
+
+    /// The source as a file, to read a block that fails to read again from,
+    /// in smaller and smaller reads, so only what really can't be read is
+    /// replaced with zeros, like `rescue=`. Implies [`CopyEngine::noerror`]
+    /// for seekable sources.
+    ///
+    /// (default = none)
+    pub fn rescue(&mut self, file: File) {
+        let _ = self.rescue.replace(file);
+    }
+
+    /// Flag that stops reading when set; blocks already read are still
+    /// written.
+    pub fn interrupt(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = interrupt
+    }
+
+    /// The operation the copy is part of, moved to [`State::Flushing`] once
+    /// reading stops
+    pub fn lifecycle(&mut self, lifecycle: Arc<Lifecycle>) {
+        let _ = self.lifecycle.replace(lifecycle);
+    }
+
+    fn flushing(&self) -> Result<()> {
+        match &self.lifecycle {
+            Some(lifecycle) => lifecycle.advance(State::Flushing),
+            None => Ok(()),
+        }
+    }
+
+    /// Start copying in the background.
+    pub fn start(self) -> RunningCopy {
+        let mut progress = Progress::new();
+        if let Some(bytes) = self.expected {
+            progress.expect(bytes);
+        }
+        let counters: Vec<(Counter, Counter)> = self
+            .sinks
+            .iter()
+            .map(|(profile, ..)| progress.add_output(profile.name.clone()))
+            .collect();
+        let acked: Vec<Counter> = counters.iter().map(|_| Counter::default()).collect();
+        let progress = Arc::new(progress);
+        let task = tokio::spawn(self.run(progress.clone(), counters, acked.clone()));
+        RunningCopy {
+            progress,
+            acked,
+            task,
+        }
+    }
+
+    async fn run(
+        mut self,
+        progress: Arc<Progress>,
+        counters: Vec<(Counter, Counter)>,
+        acked: Vec<Counter>,
+    ) -> Result<CopyResult> {
+        let start = Instant::now();
+        if let Some(source) = self.offload.take()
+            && let Some(result) =
+                self.run_offloaded(&source, start, &progress, &counters[0], &acked[0])?
+        {
+            return Ok(result);
+        }
+        let mut senders = vec![];
+        let mut writers = vec![];
+        let abort = Arc::new(AtomicBool::new(false));
+        // Set before the senders are dropped if reading failed or the copy
+        // was stopped, leaving every output short.
+        let stopped = Arc::new(AtomicBool::new(false));
+        let dealer = self.split.map(|chunk| Dealer {
+            chunk,
+            position: 0,
+            striped: self
+                .sinks
+                .iter()
+                .enumerate()
+                .filter(|(_, (profile, ..))| profile.kind == StageKind::Write)
+                .map(|(index, _)| index)
+                .collect(),
+        });
+        for (((profile, sink, policy), (written, errors)), acked) in
+            self.sinks.drain(..).zip(counters).zip(acked)
+        {
+            let (tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+            senders.push(tx);
+            let mut writer = OutputWriter {
+                sink,
+                rx,
+                profile,
+                written,
+                errors,
+                acked,
+                policy,
+                abort: abort.clone(),
+                error: None,
+                records: Records::default(),
+                block_size: self.output_block_size.unwrap_or(self.block_size),
+            };
+            let stopped = stopped.clone();
+            writers.push(tokio::task::spawn_blocking(move || {
+                while let Some(block) = writer.rx.blocking_recv() {
+                    writer.write_block(&block);
+                }
+                // An output that failed didn't get the rest either.
+                let digest = if stopped.load(Ordering::Relaxed) || writer.error.is_some() {
+                    writer.abandon();
+                    None
+                } else {
+                    writer.finish()
+                };
+                OutputResult {
+                    name: writer.profile.name.clone(),
+                    records: writer.records,
+                    elapsed: start.elapsed(),
+                    digest,
+                    error: writer.error,
+                    profile: writer.profile,
+                }
+            }));
+        }
+
+        progress.watch_queues(&senders);
+        let mut outlet = Outlet {
+            senders,
+            dealer,
+            reblock: self.output_block_size.map(|size| Reblock {
+                size,
+                pending: Vec::with_capacity(size),
+            }),
+        };
+
+        let mut reader = StageProfile::new(StageKind::Read, self.source_name.clone());
+        let read = progress.input();
+        let input_errors = progress.input_errors();
+        let mut records_in = Records::default();
+        let mut buffer = vec![0u8; self.block_size];
+        let mut count = 0;
+        // Bytes of `buffer` already read towards the next block
+        let mut filled = 0;
+        let mut read_errors = vec![];
+        let mut unreadable = vec![];
+        let deadline = self
+            .duration
+            .map(|duration| tokio::time::Instant::from_std(start) + duration);
+        let mut idle = false;
+        let mut throttle = self.limit.map(Throttle::new);
+        let mut redactor = (!self.redactions.is_empty())
+            .then(|| Redactor::new(std::mem::take(&mut self.redactions), self.position));
+        let mut converter = (!self.conversion.is_none()).then(|| Converter::new(self.conversion));
+        let mut digest = self.digest.take();
+        let read: Result<()> = async {
+            loop {
+                if self.count > 0 && count >= self.count {
+                    break;
+                }
+                if self
+                    .count_bytes
+                    .is_some_and(|bytes| records_in.bytes >= bytes)
+                {
+                    break;
+                }
+                if self.interrupt.load(Ordering::Relaxed) || abort.load(Ordering::Relaxed) {
+                    stopped.store(true, Ordering::Relaxed);
+                    break;
+                }
+                let position = self.position + records_in.bytes;
+                // A byte count ends the block being read where it is reached.
+                let end = match self.count_bytes {
+                    Some(bytes) => {
+                        let left = bytes - records_in.bytes - filled as u64;
+                        filled
+                            + usize::try_from(left)
+                                .map_or(self.block_size, |left| left.min(self.block_size - filled))
+                    }
+                    None => self.block_size,
+                };
+                let read_block = self.source.read(&mut buffer[filled..end]);
+                let idle_limit = self
+                    .idle_timeout
+                    .map(|idle| tokio::time::Instant::now() + idle);
+                let limit = match (deadline, idle_limit) {
+                    (Some(deadline), Some(idle_limit)) => Some(deadline.min(idle_limit)),
+                    (deadline, idle_limit) => deadline.or(idle_limit),
+                };
+                // With a partly filled block, ending the copy still passes on
+                // what has been read so far before stopping.
+                let mut last = false;
+                let result = match limit {
+                    Some(limit) => {
+                        match reader
+                            .time_async(tokio::time::timeout_at(limit, read_block))
+                            .await
+                        {
+                            Ok(result) => result,
+                            Err(_) => {
+                                idle = idle_limit == Some(limit);
+                                Ok(0)
+                            }
+                        }
+                    }
+                    None => reader.time_async(read_block).await,
+                };
+                let n = match result {
+                    Ok(0) if filled == 0 => break,
+                    Ok(0) => {
+                        last = true;
+                        0
+                    }
+                    Ok(n) => n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => match (&mut self.source, &self.rescue) {
+                        (Source::Seekable(source), Some(file)) => {
+                            let at = position + filled as u64;
+                            let len = end - filled;
+                            let file = file.try_clone()?;
+                            let salvage = tokio::task::spawn_blocking(move || {
+                                rescue::salvage(&file, len, at)
+                            })
+                            .await??;
+                            let n = salvage.data.len();
+                            buffer[filled..filled + n].copy_from_slice(&salvage.data);
+                            source.seek(SeekFrom::Start(at + n as u64)).await?;
+                            let lost: u64 = salvage.unreadable.iter().map(|(_, len)| len).sum();
+                            Diagnostic::new(
+                                Code::ReadErrorZeroed,
+                                format!("read error: {e}, {lost} of {len} bytes unreadable"),
+                            )
+                            .subject(&self.source_name)
+                            .offset(at)
+                            .emit();
+                            input_errors.add(1);
+                            unreadable.extend(salvage.unreadable);
+                            if n == 0 {
+                                if filled == 0 {
+                                    break;
+                                }
+                                last = true;
+                            }
+                            n
+                        }
+                        (Source::Seekable(source), None) if self.noerror => {
+                            let at = position + filled as u64;
+                            Diagnostic::new(Code::ReadErrorZeroed, format!("read error: {e}"))
+                                .subject(&self.source_name)
+                                .offset(at)
+                                .emit();
+                            let rest = end - filled;
+                            source.seek(SeekFrom::Current(rest as i64)).await?;
+                            read_errors.push(at);
+                            input_errors.add(1);
+                            buffer[filled..end].fill(0);
+                            rest
+                        }
+                        _ => return Err(e.into()),
+                    },
+                };
+                reader.add_bytes(n);
+                read.add(n);
+                filled += n;
+                if let Some(throttle) = &mut throttle {
+                    let wait = throttle.take(n);
+                    if !wait.is_zero() {
+                        let until = tokio::time::Instant::now() + wait;
+                        tokio::time::sleep_until(deadline.map_or(until, |d| d.min(until))).await;
+                    }
+                }
+                if self.fullblock && !last && filled < end {
+                    continue;
+                }
+                let mut n = std::mem::take(&mut filled);
+                count = count.saturating_add(1);
+                // The record counts what was read; padding only reaches the
+                // outputs.
+                records_in.record(n, self.block_size);
+                if let Some((_, hasher, _)) = &mut digest {
+                    hasher.update(&buffer[..n]);
+                }
+                if self.pad && n < self.block_size {
+                    buffer[n..].fill(0);
+                    n = self.block_size;
+                }
+                let (block, start) = match &mut converter {
+                    Some(converter) => {
+                        let (held, block) = converter.convert(&buffer[..n]);
+                        (block, position - held as u64)
+                    }
+                    None => (&mut buffer[..n], position),
+                };
+                patch::apply(&self.patches, start, block);
+                match &mut redactor {
+                    Some(redactor) => {
+                        if let Some(block) = redactor.push(block)? {
+                            outlet.send(&block).await;
+                        }
+                    }
+                    None => outlet.send(block).await,
+                }
+                if last {
+                    break;
+                }
+            }
+            if let Some(byte) = converter.as_mut().and_then(Converter::finish) {
+                let mut block = [byte];
+                patch::apply(
+                    &self.patches,
+                    self.position + records_in.bytes - 1,
+                    &mut block,
+                );
+                match &mut redactor {
+                    Some(redactor) => {
+                        if let Some(block) = redactor.push(&block)? {
+                            outlet.send(&block).await;
+                        }
+                    }
+                    None => outlet.send(&block).await,
+                }
+            }
+            if let Some(block) = redactor.as_mut().and_then(Redactor::finish) {
+                outlet.send(&block).await;
+            }
+            // Failing here leaves the outputs abandoned rather than finished.
+            if let Some((algorithm, hasher, expected)) = &mut digest
+                && !stopped.load(Ordering::Relaxed)
+            {
+                let digest = hasher.hex();
+                if digest != *expected {
+                    return Err(eyre!(
+                        "{} no longer has the digest it was checked to have, abandoning the outputs",
+                        self.source_name
+                    )
+                    .with_note(|| format!("expected {algorithm}:{expected}"))
+                    .with_note(|| format!("read {algorithm}:{digest}")));
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        // However reading stopped, dropping the senders closes the channels
+        // so the writers finish once they have drained every block, and each
+        // is joined before the copy ends.
+        let flushing = self.flushing();
+        if read.is_err() {
+            stopped.store(true, Ordering::Relaxed);
+        }
+        outlet.finish().await;
+        let mut outputs = vec![];
+        let mut panicked = None;
+        for writer in writers {
+            match writer.await {
+                Ok(output) => outputs.push(output),
+                Err(e) => {
+                    let _ = panicked.get_or_insert(e);
+                }
+            }
+        }
+        read?;
+        flushing?;
+        if let Some(e) = panicked {
+            return Err(e.into());
+        }
+        Ok(CopyResult {
+            records_in,
+            read: reader,
+            outputs,
+            elapsed: start.elapsed(),
+            interrupted: self.interrupt.load(Ordering::Relaxed),
+            aborted: abort.load(Ordering::Relaxed),
+            idle,
+            expired: deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline),
+            read_errors,
+            unreadable: rescue::merge(unreadable),
+            redacted: redactor
+                .map(|redactor| redactor.redacted())
+                .unwrap_or_default(),
+            offload: None,
+        })
+    }
+
+    /// True if every byte of the source goes to one sink as it is.
+    fn is_plain(&self) -> bool {
+        self.sinks.len() == 1
+            && self.sinks[0].0.kind == StageKind::Write
+            && self.output_block_size.is_none()
+            && self.duration.is_none()
+            && self.idle_timeout.is_none()
+            && self.patches.is_empty()
+            && self.redactions.is_empty()
+            && self.conversion.is_none()
+            && !self.pad
+            && !self.noerror
+            && self.rescue.is_none()
+            && self.limit.is_none()
+            && self.split.is_none()
+            && self.digest.is_none()
+    }
+
+    /// Have the kernel copy `source` to the only sink, if the copy is plain
+    /// and the sink writes a regular file; `None` if it can't, before
+    /// anything has been copied.
+    fn run_offloaded(
+        &mut self,
+        source: &File,
+        start: Instant,
+        progress: &Progress,
+        (written, errors): &(Counter, Counter),
+        acked: &Counter,
+    ) -> Result<Option<CopyResult>> {
+        if !self.is_plain() {
+            return Ok(None);
+        }
+        let limit = match (self.count, self.count_bytes) {
+            (_, Some(bytes)) => Some(bytes),
+            (0, None) => None,
+            (count, None) => Some(count.saturating_mul(self.block_size as u64)),
+        };
+        let (profile, sink, policy) = &mut self.sinks[0];
+        let Some(mut file) = sink.plain_file() else {
+            return Ok(None);
+        };
+        if !file.metadata()?.is_file() {
+            return Ok(None);
+        }
+        let read = progress.input();
+        let mut reader = StageProfile::new(StageKind::Read, self.source_name.clone());
+        let mut copied = 0u64;
+        let mut error = None;
+        let mut stopped = false;
+        // A whole file into an empty one can share its data outright.
+        let mut method = None;
+        if self.position == 0
+            && limit.is_none()
+            && file.metadata()?.len() == 0
+            && file.stream_position()? == 0
+            && profile.time(|| tokio::task::block_in_place(|| offload::reflink(source, file)))?
+        {
+            copied = file.seek(SeekFrom::End(0))?;
+            method = Some(Offload::Reflink);
+        }
+        let mut methods = [Offload::CopyFileRange, Offload::Sendfile].into_iter();
+        let mut current = methods.next();
+        let chunk = self.block_size.max(OFFLOAD_CHUNK);
+        while method != Some(Offload::Reflink)
+            && let Some(attempt) = current
+        {
+            if self.interrupt.load(Ordering::Relaxed) {
+                stopped = true;
+                break;
+            }
+            let left = limit.map_or(u64::MAX, |limit| limit - copied);
+            if left == 0 {
+                break;
+            }
+            let len = usize::try_from(left).map_or(chunk, |left| left.min(chunk));
+            let offset = self.position + copied;
+            match profile.time(|| {
+                tokio::task::block_in_place(|| offload::copy(attempt, source, offset, file, len))
+            }) {
+                Ok(0) => break,
+                Ok(n) => {
+                    copied += n as u64;
+                    reader.add_bytes(n);
+                    profile.add_bytes(n);
+                    read.add(n);
+                    written.add(n);
+                    acked.add(n);
+                    method = Some(attempt);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::Unsupported && method.is_none() => {
+                    current = methods.next();
+                }
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        let Some(method) = method.or(error.as_ref().and(current)) else {
+            return Ok(None);
+        };
+        if method == Offload::Reflink {
+            reader.add_bytes(copied as usize);
+            profile.add_bytes(copied as usize);
+            read.add(copied as usize);
+            written.add(copied as usize);
+            acked.add(copied as usize);
+        }
+
+        let block_size = self.block_size as u64;
+        let records = Records {
+            full: copied / block_size,
+            partial: u64::from(!copied.is_multiple_of(block_size)),
+            bytes: copied,
+        };
+        let aborted = error.is_some() && *policy == ErrorPolicy::Abort;
+        let error = error.map(|e| {
+            errors.add(1);
+            Diagnostic::new(Code::WriteFailed, format!("failed to write block: {e}"))
+                .subject(&profile.name)
+                .offset(copied)
+                .emit();
+            e.to_string()
+        });
+        let (profile, sink, policy) = self.sinks.remove(0);
+        let mut writer = OutputWriter {
+            sink,
+            rx: mpsc::channel(1).1,
+            profile,
+            written: written.clone(),
+            errors: errors.clone(),
+            acked: acked.clone(),
+            policy,
+            abort: Arc::new(AtomicBool::new(aborted)),
+            error,
+            records,
+            block_size: self.block_size,
+        };
+        self.flushing()?;
+        let digest = if stopped || writer.error.is_some() {
+            writer.abandon();
+            None
+        } else {
+            writer.finish()
+        };
+        Ok(Some(CopyResult {
+            records_in: records,
+            read: reader,
+            outputs: vec![OutputResult {
+                name: writer.profile.name.clone(),
+                records: writer.records,
+                elapsed: start.elapsed(),
+                digest,
+                error: writer.error,
+                profile: writer.profile,
+            }],
+            elapsed: start.elapsed(),
+            interrupted: self.interrupt.load(Ordering::Relaxed),
+            aborted,
+            idle: false,
+            expired: false,
+            read_errors: vec![],
+            unreadable: vec![],
+            redacted: (0, 0),
+            offload: Some(method),
+        }))
+    }
+}
+
+/// A copy started with [`CopyEngine::start`]; resolves to its results.
+pub struct RunningCopy {
+    progress: Arc<Progress>,
+    acked: Vec<Counter>,
+    task: JoinHandle<Result<CopyResult>>,
+}
+
+impl RunningCopy {
+    /// Live counters of the copy, e.g. for a progress line or timeline.
+    pub fn progress(&self) -> &Arc<Progress> {
+        &self.progress
+    }
+
+    /// Per sink, in the order they were added, the bytes written before
+    /// its first write error: how far the sink is known to be intact.
+    pub fn acked(&self) -> &[Counter] {
+        &self.acked
+    }
+}
+
+impl Future for RunningCopy {
+    type Output = Result<CopyResult>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task)
+            .poll(cx)
+            .map(|result| result.map_err(Into::into).and_then(|result| result))
+    }
+}
+
+/// What a finished copy did.
+pub struct CopyResult {
+    pub records_in: Records,
+
+    /// Profile of reading the source
+    pub read: StageProfile,
+
+    /// One result per sink, in the order they were added
+    pub outputs: Vec<OutputResult>,
+
+    pub elapsed: Duration,
+
+    /// True if the interrupt flag stopped the copy early
+    pub interrupted: bool,
+
+    /// True if a sink with [`ErrorPolicy::Abort`] failed and stopped the copy
+    pub aborted: bool,
+
+    /// True if the copy stopped because its duration ran out
+    pub expired: bool,
+
+    /// True if the copy stopped because the source was idle for too long
+    pub idle: bool,
+
+    /// Offsets of blocks replaced with zeros because they failed to read
+    pub read_errors: Vec<u64>,
+
+    /// Offsets and lengths of what failed to read however small the reads
+    /// and was replaced with zeros, with [`CopyEngine::rescue`]
+    pub unreadable: Vec<(u64, u64)>,
+
+    /// Regions and bytes redacted from the stream
+    pub redacted: (usize, u64),
+
+    /// How the kernel did the copy, if it was left to it
+    pub offload: Option<Offload>,
+}
+
+/// What a finished copy did for one sink.
+pub struct OutputResult {
+    pub name: String,
+    pub records: Records,
+
+    /// Time from the start of the copy until the sink was finished
+    pub elapsed: Duration,
+
+    /// What the sink computed over the stream, e.g. a digest
+    pub digest: Option<String>,
+
+    /// Why the sink stopped receiving blocks, if a write failed
+    pub error: Option<String>,
+
+    pub profile: StageProfile,
+}
+
+/// Queue a block on every output, waiting for room on each. Striped
+/// outputs get only their share of it.
+async fn send(senders: &[Sender<Arc<[u8]>>], dealer: Option<&mut Dealer>, block: &[u8]) {
+    let mut shares = dealer.map(|dealer| dealer.deal(block)).unwrap_or_default();
+    let block: Arc<[u8]> = Arc::from(block);
+    for (index, tx) in senders.iter().enumerate() {
+        let block = match shares.iter_mut().find(|(striped, _)| *striped == index) {
+            Some((_, share)) if share.is_empty() => continue,
+            Some((_, share)) => Arc::from(std::mem::take(share)),
+            None => block.clone(),
+        };
+        // A writer only goes away early if it panicked, which awaiting it
+        // reports.
+        let _ = tx.send(block).await;
+    }
+}
+
+/// Where the reader hands on the stream: every sink's queue, through the
+/// dealer if the stream is split, gathered into whole output blocks first if
+/// re-blocking.
+struct Outlet {
+    senders: Vec<Sender<Arc<[u8]>>>,
+    dealer: Option<Dealer>,
+    reblock: Option<Reblock>,
+}
+
+/// Output block being gathered.
+struct Reblock {
+    size: usize,
+    pending: Vec<u8>,
+}
+
+impl Outlet {
+    async fn send(&mut self, block: &[u8]) {
+        let Some(reblock) = &mut self.reblock else {
+            return send(&self.senders, self.dealer.as_mut(), block).await;
+        };
+        let mut rest = block;
+        while !rest.is_empty() {
+            // Whole blocks go straight on when nothing is waiting for more.
+            if reblock.pending.is_empty() && rest.len() >= reblock.size {
+                let (block, after) = rest.split_at(reblock.size);
+                send(&self.senders, self.dealer.as_mut(), block).await;
+                rest = after;
+                continue;
+            }
+            let n = (reblock.size - reblock.pending.len()).min(rest.len());
+            reblock.pending.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            if reblock.pending.len() == reblock.size {
+                send(&self.senders, self.dealer.as_mut(), &reblock.pending).await;
+                reblock.pending.clear();
+            }
+        }
+    }
+
+    /// Pass on the short last block, if any, and close the queues.
+    async fn finish(mut self) {
+        if let Some(reblock) = &self.reblock
+            && !reblock.pending.is_empty()
+        {
+            send(&self.senders, self.dealer.as_mut(), &reblock.pending).await;
+        }
+    }
+}
+
+/// Splits the stream into chunks dealt out to the striped sinks in turn.
+struct Dealer {
+    chunk: u64,
+
+    /// Offset into the stream of the next block
+    position: u64,
+
+    /// Indices of the sinks taking turns
+    striped: Vec<usize>,
+}
+
+impl Dealer {
+    /// The part of `block` each striped sink gets, as (sink, bytes).
+    fn deal(&mut self, block: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        let mut shares: Vec<(usize, Vec<u8>)> =
+            self.striped.iter().map(|&index| (index, vec![])).collect();
+        if shares.is_empty() {
+            return shares;
+        }
+        let mut rest = block;
+        while !rest.is_empty() {
+            let turn = (self.position / self.chunk) as usize % shares.len();
+            let left = self.chunk - self.position % self.chunk;
+            let n = rest.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+            shares[turn].1.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            self.position += n as u64;
+        }
+        shares
+    }
+}
+
+/// One sink of a copy, fed blocks by the reader.
+struct OutputWriter {
+    sink: Box<dyn Sink>,
+    rx: Receiver<Arc<[u8]>>,
+    profile: StageProfile,
+    written: Counter,
+
+    /// Writes that failed, including ones that were retried
+    errors: Counter,
+    acked: Counter,
+    policy: ErrorPolicy,
+    /// Set to stop the reader when a sink with [`ErrorPolicy::Abort`] fails
+    abort: Arc<AtomicBool>,
+    /// Set by the first write that fails for good, after which the sink gets
+    /// no more blocks
+    error: Option<String>,
+    records: Records,
+    block_size: usize,
+}
+
+impl OutputWriter {
+    fn write_block(&mut self, block: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        let sink = &mut self.sink;
+        let retries = match self.policy {
+            ErrorPolicy::Retry(retries) => retries,
+            ErrorPolicy::Abort | ErrorPolicy::Skip => 0,
+        };
+        match self
+            .profile
+            .time(|| write_retrying(sink.as_mut(), block, retries, &self.errors))
+        {
+            Ok(()) => {
+                if log::is_json() {
+                    log::event(serde_json::json!({
+                        "type": "block",
+                        "output": self.profile.name,
+                        "offset": self.records.bytes,
+                        "bytes": block.len(),
+                    }));
+                }
+                self.profile.add_bytes(block.len());
+                self.written.add(block.len());
+                self.acked.add(block.len());
+                self.records.record(block.len(), self.block_size);
+            }
+            Err(e) => {
+                self.errors.add(1);
+                let error = match self.policy {
+                    ErrorPolicy::Retry(retries) => format!("{e}, after {retries} retries"),
+                    ErrorPolicy::Abort | ErrorPolicy::Skip => e.to_string(),
+                };
+                Diagnostic::new(Code::WriteFailed, format!("failed to write block: {error}"))
+                    .subject(&self.profile.name)
+                    .offset(self.records.bytes)
+                    .emit();
+                if self.policy == ErrorPolicy::Abort {
+                    self.abort.store(true, Ordering::Relaxed);
+                }
+                self.error = Some(error);
+            }
+        }
+    }
+
+    /// Give up on the sink after the stream stopped short, see
+    /// [`Sink::abandon`].
+    fn abandon(&mut self) {
+        if let Err(e) = self.sink.abandon() {
+            self.errors.add(1);
+            Diagnostic::new(Code::FinishFailed, format!("failed to finish: {e}"))
+                .subject(&self.profile.name)
+                .emit();
+            let _ = self.error.get_or_insert_with(|| e.to_string());
+        }
+    }
+
+    /// Flush the sink after the last block, returning its digest if it has
+    /// one.
+    fn finish(&mut self) -> Option<String> {
+        match self.sink.finish() {
+            Ok(digest) => digest,
+            Err(e) => {
+                self.errors.add(1);
+                Diagnostic::new(Code::FinishFailed, format!("failed to finish: {e}"))
+                    .subject(&self.profile.name)
+                    .emit();
+                let _ = self.error.get_or_insert_with(|| e.to_string());
+                None
+            }
+        }
+    }
+}
+
+/// Write all of `block`, retrying a failed write up to `retries` times from
+/// where it stopped. Each retried write is counted in `errors`.
+fn write_retrying(
+    sink: &mut dyn Sink,
+    block: &[u8],
+    retries: u32,
+    errors: &Counter,
+) -> io::Result<()> {
+    let mut done = 0;
+    let mut attempt = 0;
+    while done < block.len() {
+        match sink.write(&block[done..]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => done += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) if attempt < retries => {
+                errors.add(1);
+                std::thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt.min(6)));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    io::{self, Read},
+    str::FromStr,
+};
+
+use crate::hash::to_hex;
+
+/// Data made up by pdd instead of read from somewhere (`if=zero:`,
+/// `if=random:`, `if=pattern:HEXBYTES`), e.g. to wipe a device or make test
+/// data without /dev/zero or /dev/urandom. It goes on until `count=` is
+/// reached or, without one, the smallest output device is full; with neither
+/// it is refused, unless `duration=` ends it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Generator {
+    Zero,
+    Random,
+
+    /// These bytes over and over, starting at the first byte of the input
+    Pattern(Vec<u8>),
+}
+
+impl Generator {
+    /// True if an `if=` operand names a generator rather than a file.
+    pub fn is_generator(s: &str) -> bool {
+        ["zero:", "random:", "pattern:"]
+            .iter()
+            .any(|prefix| s.starts_with(prefix))
+    }
+
+    /// Read `len` generated bytes, or endlessly.
+    pub fn reader(&self, len: Option<u64>) -> GeneratorReader {
+        GeneratorReader {
+            generator: self.clone(),
+            offset: 0,
+            len,
+        }
+    }
+}
+
+impl FromStr for Generator {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            eyre!("Invalid generated input")
+                .with_note(|| format!("input if={s}"))
+                .with_suggestion(|| "expected if=zero:, if=random: or if=pattern:HEXBYTES")
+        };
+        match s.split_once(':').ok_or_else(invalid)? {
+            ("zero", "") => Ok(Generator::Zero),
+            ("random", "") => Ok(Generator::Random),
+            ("pattern", hex) => {
+                let hex = hex.strip_prefix("0x").unwrap_or(hex);
+                if hex.is_empty() || !hex.len().is_multiple_of(2) {
+                    return Err(invalid());
+                }
+                let bytes = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .map_err(|_| invalid())?;
+                Ok(Generator::Pattern(bytes))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Generator::Zero => write!(f, "zero:"),
+            Generator::Random => write!(f, "random:"),
+            Generator::Pattern(bytes) => write!(f, "pattern:{}", to_hex(bytes)),
+        }
+    }
+}
+
+/// Fills every read completely, so each block is a full one.
+pub struct GeneratorReader {
+    generator: Generator,
+
+    /// Bytes generated so far, where the pattern continues from
+    offset: u64,
+
+    len: Option<u64>,
+}
+
+impl Read for GeneratorReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let buf = match self.len {
+            Some(len) => {
+                let left = len.saturating_sub(self.offset).min(buf.len() as u64);
+                &mut buf[..left as usize]
+            }
+            None => buf,
+        };
+        match &self.generator {
+            Generator::Zero => buf.fill(0),
+            Generator::Random => getrandom::fill(buf).map_err(io::Error::other)?,
+            Generator::Pattern(bytes) => {
+                let start = (self.offset % bytes.len() as u64) as usize;
+                let pattern = bytes.iter().cycle().skip(start);
+                for (byte, value) in buf.iter_mut().zip(pattern) {
+                    *byte = *value;
+                }
+            }
+        }
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,471 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+};
+
+use crate::device;
+
+/// First bytes of a GPT header.
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// Sector sizes a GPT is looked for with, its header being the second
+/// sector.
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+/// Offset of the protective MBR's first partition record, and the type of
+/// one covering a GPT disk.
+pub const MBR_PARTITION: usize = 446;
+pub const MBR_PROTECTIVE: u8 = 0xee;
+
+/// Where the backup GPT of an output was moved to (`fix-gpt=1`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    /// LBA of the backup header as the image had it
+    pub from: u64,
+
+    /// LBA of the backup header now, the last sector of the output
+    pub to: u64,
+}
+
+impl fmt::Display for Relocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GPT backup header moved from LBA {} to {}",
+            self.from, self.to
+        )
+    }
+}
+
+/// What `fix-gpt=1` did to an output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GptFix {
+    Relocated(Relocation),
+
+    /// The backup was already at the end
+    Unchanged,
+
+    /// The table couldn't be fixed, and why
+    Failed(String),
+}
+
+impl GptFix {
+    pub fn is_ok(&self) -> bool {
+        !matches!(self, GptFix::Failed(_))
+    }
+}
+
+impl fmt::Display for GptFix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GptFix::Relocated(relocation) => write!(f, "{relocation}"),
+            GptFix::Unchanged => write!(f, "GPT backup header already at the end"),
+            GptFix::Failed(e) => write!(f, "GPT not fixed, {e}"),
+        }
+    }
+}
+
+/// Fix the GPT of the output at `path` with [`relocate`].
+pub fn fix(path: &Path) -> GptFix {
+    match relocate(path) {
+        Ok(Some(relocation)) => GptFix::Relocated(relocation),
+        Ok(None) => GptFix::Unchanged,
+        Err(e) => GptFix::Failed(e.to_string()),
+    }
+}
+
+/// Move the backup GPT header and partition array of the disk image written
+/// to `path` to the end of it, and make the primary header, the usable
+/// range and the protective MBR agree, with every CRC updated. `Ok(None)` if
+/// the backup is already where it belongs.
+///
+/// An image written to a bigger disk leaves its backup in the middle of it,
+/// and one cut short for a smaller disk, at [`image_end`], loses it; either
+/// way the disk reads as having a corrupt table until this is done.
+/// Partitions that end past the new end are refused rather than cut off.
+pub fn relocate(path: &Path) -> io::Result<Option<Relocation>> {
+    let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+    let size = device::size(path).ok_or_else(|| invalid("its size can't be told".into()))?;
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let (sector, mut primary) =
+        primary(&mut file)?.ok_or_else(|| invalid("no GPT found".into()))?;
+    let header_size = u32_at(&primary, 12) as usize;
+
+    let from = u64_at(&primary, 32);
+    let to = size / sector - 1;
+    if from == to {
+        return Ok(None);
+    }
+    let first_usable = u64_at(&primary, 40);
+    let (entries, entries_len, entry_size) = entries(&mut file, sector, &primary)?;
+    let entries_sectors = entries.len() as u64 / sector;
+    let backup_entries_lba = to
+        .checked_sub(entries_sectors)
+        .filter(|&lba| lba > first_usable)
+        .ok_or_else(|| invalid("the output is too small for a GPT".into()))?;
+    let last_usable = backup_entries_lba - 1;
+    for (index, entry) in entries[..entries_len].chunks(entry_size).enumerate() {
+        // Unused entries have no type.
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        if u64_at(entry, 40) > last_usable {
+            return Err(invalid(format!(
+                "partition {} ends past the end of the output",
+                index + 1
+            )));
+        }
+    }
+
+    put_u64(&mut primary, 32, to);
+    put_u64(&mut primary, 48, last_usable);
+    let crc = header_crc(&primary[..header_size]);
+    put_u32(&mut primary, 16, crc);
+
+    let mut backup = primary.clone();
+    put_u64(&mut backup, 24, to);
+    put_u64(&mut backup, 32, 1);
+    put_u64(&mut backup, 72, backup_entries_lba);
+    let crc = header_crc(&backup[..header_size]);
+    put_u32(&mut backup, 16, crc);
+
+    write_at(&mut file, backup_entries_lba * sector, &entries)?;
+    write_at(&mut file, to * sector, &backup)?;
+    write_at(&mut file, sector, &primary)?;
+
+    // The old backup, if it is still on the output, is now in unused space
+    // where it could be mistaken for the real one.
+    if from < backup_entries_lba {
+        let mut old = vec![0u8; sector as usize];
+        if read_at(&mut file, from * sector, &mut old)? && old.starts_with(SIGNATURE) {
+            write_at(&mut file, from * sector, &vec![0u8; sector as usize])?;
+        }
+    }
+
+    // The protective MBR covers the whole disk, or as much of it as its
+    // 32 bit size can.
+    let mut mbr = vec![0u8; sector as usize];
+    if read_at(&mut file, 0, &mut mbr)?
+        && mbr[510..512] == [0x55, 0xaa]
+        && mbr[MBR_PARTITION + 4] == MBR_PROTECTIVE
+    {
+        let sectors = u32::try_from(to).unwrap_or(u32::MAX);
+        mbr[MBR_PARTITION + 12..MBR_PARTITION + 16].copy_from_slice(&sectors.to_le_bytes());
+        write_at(&mut file, 0, &mbr)?;
+    }
+    file.sync_all()?;
+    Ok(Some(Relocation { from, to }))
+}
+
+/// Bytes at the start of the GPT disk image at `path` that a disk has to
+/// hold for [`relocate`] to fit the table on it: its partitions and room for
+/// the backup array and header after them. `Ok(None)` if it has no GPT.
+pub fn image_end(path: &Path) -> io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let Some((sector, primary)) = primary(&mut file)? else {
+        return Ok(None);
+    };
+    let (entries, entries_len, entry_size) = entries(&mut file, sector, &primary)?;
+    let last = entries[..entries_len]
+        .chunks(entry_size)
+        .filter(|entry| entry[..16].iter().any(|&b| b != 0))
+        .map(|entry| u64_at(entry, 40) + 1)
+        .fold(u64_at(&primary, 40), u64::max);
+    let backup_sectors = entries.len() as u64 / sector + 1;
+    Ok(Some((last + backup_sectors) * sector))
+}
+
+/// Number of a partition, counting from 1, and the bytes it spans.
+pub type Partition = (usize, Range<u64>);
+
+/// Give the GPT disk on `file` a new disk GUID and each of its partitions
+/// a new unique GUID, the `PARTUUID` of Linux, in the primary and the backup
+/// table alike. Returns the partitions; `Ok(None)` if there is no GPT.
+pub fn new_guids(file: &mut File) -> io::Result<Option<Vec<Partition>>> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let Some((sector, mut primary)) = primary(file)? else {
+        return Ok(None);
+    };
+    let (mut entries, entries_len, entry_size) = entries(file, sector, &primary)?;
+    let mut ranges = vec![];
+    for (index, entry) in entries[..entries_len].chunks_mut(entry_size).enumerate() {
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        entry[16..32].copy_from_slice(&random_guid()?);
+        ranges.push((
+            index + 1,
+            u64_at(entry, 32) * sector..(u64_at(entry, 40) + 1) * sector,
+        ));
+    }
+    let entries_crc = crc32(&entries[..entries_len]);
+    let disk_guid = random_guid()?;
+
+    // The backup first, which has to be sound before anything is written.
+    let backup_lba = u64_at(&primary, 32);
+    let header_size = u32_at(&primary, 12) as usize;
+    let mut backup = vec![0u8; sector as usize];
+    if !read_at(file, backup_lba * sector, &mut backup)?
+        || !backup.starts_with(SIGNATURE)
+        || header_crc(&backup[..header_size]) != u32_at(&backup, 16)
+    {
+        return Err(invalid(
+            "the backup GPT header is missing or corrupt, fix-gpt=1 puts it back",
+        ));
+    }
+    for (header, lba) in [(&mut backup, backup_lba), (&mut primary, 1)] {
+        header[56..72].copy_from_slice(&disk_guid);
+        put_u32(header, 88, entries_crc);
+        let crc = header_crc(&header[..header_size]);
+        put_u32(header, 16, crc);
+        write_at(file, u64_at(header, 72) * sector, &entries)?;
+        write_at(file, lba * sector, header)?;
+    }
+    Ok(Some(ranges))
+}
+
+/// A random (version 4) GUID, in the mixed endian layout of GPT.
+fn random_guid() -> io::Result<[u8; 16]> {
+    let mut guid = [0u8; 16];
+    getrandom::fill(&mut guid).map_err(io::Error::other)?;
+    // The version is the high nibble of the third field, stored little
+    // endian, and the variant the top bits of the fourth.
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    Ok(guid)
+}
+
+/// The sector size and sound primary header of the GPT on `file`, if it
+/// has one.
+fn primary(file: &mut File) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+    for sector in SECTOR_SIZES {
+        let mut header = vec![0u8; sector as usize];
+        if !read_at(file, sector, &mut header)? || !header.starts_with(SIGNATURE) {
+            continue;
+        }
+        let header_size = u32_at(&header, 12) as usize;
+        if !(92..=sector as usize).contains(&header_size) {
+            return Err(invalid(format!("GPT header size {header_size} is invalid")));
+        }
+        if header_crc(&header[..header_size]) != u32_at(&header, 16) {
+            return Err(invalid("the primary GPT header is corrupt".into()));
+        }
+        return Ok(Some((sector, header)));
+    }
+    Ok(None)
+}
+
+/// The partition array `header` points to, in whole sectors, with the
+/// length of its entries and the size of each.
+fn entries(file: &mut File, sector: u64, header: &[u8]) -> io::Result<(Vec<u8>, usize, usize)> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let lba = u64_at(header, 72);
+    let count = u32_at(header, 80) as u64;
+    let entry_size = u32_at(header, 84) as u64;
+    if entry_size < 128 || count * entry_size > 1 << 20 {
+        return Err(invalid("the GPT partition array is invalid"));
+    }
+    let len = count * entry_size;
+    let mut entries = vec![0u8; (len.div_ceil(sector) * sector) as usize];
+    if !read_at(file, lba * sector, &mut entries)? {
+        return Err(invalid("the GPT partition array is cut off"));
+    }
+    if crc32(&entries[..len as usize]) != u32_at(header, 88) {
+        return Err(invalid("the GPT partition array is corrupt"));
+    }
+    Ok((entries, len as usize, entry_size as usize))
+}
+
+/// Fill `buf` from `offset`; `Ok(false)` if the file ends first.
+pub fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn write_at(file: &mut File, offset: u64, buf: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+pub fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().expect("4 bytes"))
+}
+
+pub fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().expect("8 bytes"))
+}
+
+fn put_u32(buf: &mut [u8], at: usize, value: u32) {
+    buf[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], at: usize, value: u64) {
+    buf[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// CRC of a header, taken with its own CRC field as zero.
+fn header_crc(header: &[u8]) -> u32 {
+    let mut header = header.to_vec();
+    put_u32(&mut header, 16, 0);
+    crc32(&header)
+}
+
+/// The CRC-32 GPT uses, the same as gzip's.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: u64 = 512;
+
+    /// A GPT disk image `sectors` long with one partition over `partition`,
+    /// its backup at the end.
+    fn image(name: &str, sectors: u64, partition: Range<u64>) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("pdd-gpt-{}-{name}", std::process::id()));
+        let mut image = vec![0u8; (sectors * SECTOR) as usize];
+        let mbr = &mut image[..512];
+        mbr[MBR_PARTITION + 4] = MBR_PROTECTIVE;
+        mbr[MBR_PARTITION + 12..MBR_PARTITION + 16]
+            .copy_from_slice(&((sectors - 1) as u32).to_le_bytes());
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        // 128 entries of 128 bytes, 32 sectors.
+        let mut entries = vec![0u8; 128 * 128];
+        entries[..16].copy_from_slice(&[0xaf; 16]);
+        entries[16..32].copy_from_slice(&[0x11; 16]);
+        put_u64(&mut entries, 32, partition.start);
+        put_u64(&mut entries, 40, partition.end - 1);
+        let backup = sectors - 1;
+        let mut header = vec![0u8; SECTOR as usize];
+        header[..8].copy_from_slice(SIGNATURE);
+        put_u32(&mut header, 8, 0x0001_0000);
+        put_u32(&mut header, 12, 92);
+        put_u64(&mut header, 24, 1);
+        put_u64(&mut header, 32, backup);
+        put_u64(&mut header, 40, 34);
+        put_u64(&mut header, 48, backup - 33);
+        header[56..72].copy_from_slice(&[0x22; 16]);
+        put_u64(&mut header, 72, 2);
+        put_u32(&mut header, 80, 128);
+        put_u32(&mut header, 84, 128);
+        put_u32(&mut header, 88, crc32(&entries));
+        let mut backup_header = header.clone();
+        put_u64(&mut backup_header, 24, backup);
+        put_u64(&mut backup_header, 32, 1);
+        put_u64(&mut backup_header, 72, backup - 32);
+        for header in [&mut header, &mut backup_header] {
+            let crc = header_crc(&header[..92]);
+            put_u32(header, 16, crc);
+        }
+        let at = |lba: u64| (lba * SECTOR) as usize;
+        image[at(1)..at(2)].copy_from_slice(&header);
+        image[at(2)..at(34)].copy_from_slice(&entries);
+        image[at(backup - 32)..at(backup)].copy_from_slice(&entries);
+        image[at(backup)..at(backup + 1)].copy_from_slice(&backup_header);
+        std::fs::write(&path, image).unwrap();
+        path
+    }
+
+    /// The primary and backup headers of the image at `path`, both checked
+    /// against their CRCs and those of their arrays.
+    fn headers(path: &Path) -> (Vec<u8>, Vec<u8>) {
+        let mut file = File::open(path).unwrap();
+        let (sector, primary) = primary(&mut file).unwrap().unwrap();
+        entries(&mut file, sector, &primary).unwrap();
+        let mut backup = vec![0u8; sector as usize];
+        assert!(read_at(&mut file, u64_at(&primary, 32) * sector, &mut backup).unwrap());
+        assert!(backup.starts_with(SIGNATURE));
+        assert_eq!(header_crc(&backup[..92]), u32_at(&backup, 16));
+        entries(&mut file, sector, &backup).unwrap();
+        (primary, backup)
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn relocates_to_the_end_of_a_bigger_disk() {
+        let path = image("bigger", 1000, 34..900);
+        // Written to a disk of 2000 sectors.
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(2000 * SECTOR)
+            .unwrap();
+        let relocation = relocate(&path).unwrap();
+        assert_eq!(
+            relocation,
+            Some(Relocation {
+                from: 999,
+                to: 1999
+            })
+        );
+
+        let (primary, backup) = headers(&path);
+        assert_eq!(u64_at(&primary, 32), 1999);
+        assert_eq!(u64_at(&primary, 48), 1999 - 33);
+        assert_eq!(u64_at(&backup, 24), 1999);
+        assert_eq!(u64_at(&backup, 32), 1);
+        assert_eq!(u64_at(&backup, 72), 1999 - 32);
+        assert_eq!(primary[56..72], backup[56..72]);
+
+        // The old backup header is gone, the protective MBR covers the disk.
+        let image = std::fs::read(&path).unwrap();
+        assert!(!image[(999 * SECTOR) as usize..].starts_with(SIGNATURE));
+        assert_eq!(u32_at(&image, MBR_PARTITION + 12), 1999);
+        assert_eq!(relocate(&path).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn relocates_onto_a_smaller_disk_the_partitions_fit_on() {
+        let path = image("smaller", 2000, 34..900);
+        let end = image_end(&path).unwrap().unwrap();
+        assert_eq!(end, (900 + 32 + 1) * SECTOR);
+        // Cut short as the copy is with fix-gpt=1.
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(end)
+            .unwrap();
+        let to = end / SECTOR - 1;
+        assert_eq!(
+            relocate(&path).unwrap(),
+            Some(Relocation { from: 1999, to })
+        );
+        let (primary, _) = headers(&path);
+        assert_eq!(u64_at(&primary, 48), 899);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_partitions_past_the_end() {
+        let path = image("refused", 2000, 34..1900);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(1000 * SECTOR)
+            .unwrap();
+        let e = relocate(&path).unwrap_err();
+        assert_eq!(e.to_string(), "partition 1 ends past the end of the output");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::sink::Sink;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+    Blake3,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "md5" => Ok(HashAlgorithm::Md5),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(eyre!("Invalid hash algorithm")
+                .with_note(|| format!("input hash={s}"))
+                .with_suggestion(|| "expected one of md5, sha256, blake3")),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Md5 => write!(f, "md5"),
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+/// Running digest of a stream in one of the [`HashAlgorithm`]s
+pub enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    fn finalize(&mut self) -> Vec<u8> {
+        match self {
+            Hasher::Md5(h) => h.finalize_reset().to_vec(),
+            Hasher::Sha256(h) => h.finalize_reset().to_vec(),
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        }
+    }
+
+    /// Lower case hex digest of everything so far.
+    pub fn hex(&mut self) -> String {
+        to_hex(&self.finalize())
+    }
+}
+
+/// Output that digests the stream instead of storing it (`hash=`).
+pub struct HashSink {
+    hasher: Hasher,
+
+    /// `sha256sum` style file the digest is written to, if any
+    sidecar: Option<PathBuf>,
+
+    /// Name of the input, used in the sidecar file
+    input: String,
+}
+
+impl HashSink {
+    pub fn new(algorithm: HashAlgorithm, sidecar: Option<PathBuf>, input: String) -> Self {
+        Self {
+            hasher: Hasher::new(algorithm),
+            sidecar,
+            input,
+        }
+    }
+}
+
+impl Write for HashSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for HashSink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        let digest = to_hex(&self.hasher.finalize());
+        if let Some(sidecar) = &self.sidecar {
+            std::fs::write(sidecar, format!("{digest}  {}\n", self.input))?;
+        }
+        Ok(Some(digest))
+    }
+
+    /// No digest or sidecar for part of a stream.
+    fn abandon(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hex digest of everything `reader` yields.
+pub fn digest(algorithm: HashAlgorithm, mut reader: impl io::Read) -> io::Result<String> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(to_hex(&hasher.finalize())),
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,183 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    diagnostic::{Code, Diagnostic},
+    log,
+    profile::format_bytes,
+    progress::Progress,
+};
+
+/// Growth of the resident set over the first stats before it is warned
+/// about, as a factor and at least this many bytes.
+const RSS_GROWTH: u64 = 2;
+const RSS_SLACK: u64 = 64 << 20;
+
+/// Open files and tasks more than at the first stats that are warned
+/// about. A copy opens a fixed number of either, however long it runs.
+const FD_SLACK: u64 = 64;
+const TASK_SLACK: u64 = 64;
+
+/// Resource usage of the process at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Resident set size in bytes, where it can be told
+    pub rss: Option<u64>,
+
+    /// Open file descriptors, where they can be counted
+    pub fds: Option<u64>,
+
+    /// Tokio tasks alive
+    pub tasks: u64,
+}
+
+impl Usage {
+    pub fn now() -> Self {
+        Self {
+            rss: rss(),
+            fds: fds(),
+            tasks: tokio::runtime::Handle::current()
+                .metrics()
+                .num_alive_tasks() as u64,
+        }
+    }
+}
+
+/// Periodically reports resource usage of a long run (`stats=INTERVAL`) and
+/// warns when it keeps growing, so a leak shows long before the process
+/// runs out of memory or file descriptors.
+///
+/// The first report is the baseline, past the start when every output is
+/// open and queues are full. Memory is warned about once it doubles, and then
+/// each time it doubles again; open files and tasks once there are
+/// [`FD_SLACK`] or [`TASK_SLACK`] more than the last warning.
+pub struct Health {
+    current: Arc<Mutex<Option<Arc<Progress>>>>,
+    task: JoinHandle<()>,
+}
+
+impl Health {
+    pub fn spawn(interval: Duration) -> Self {
+        let current: Arc<Mutex<Option<Arc<Progress>>>> = Arc::default();
+        let task = tokio::spawn({
+            let current = current.clone();
+            async move {
+                let mut interval = tokio::time::interval(interval);
+                interval.tick().await;
+                let mut marks: Option<Usage> = None;
+                loop {
+                    interval.tick().await;
+                    let usage = Usage::now();
+                    let progress = current.lock().unwrap().clone();
+                    report(&usage, progress.as_deref());
+                    match &mut marks {
+                        Some(marks) => check(&usage, marks),
+                        None => marks = Some(usage),
+                    }
+                }
+            }
+        });
+        Self { current, task }
+    }
+
+    /// Report the queues of `progress` until the next copy takes over.
+    pub fn watch(&self, progress: Arc<Progress>) {
+        let _ = self.current.lock().unwrap().replace(progress);
+    }
+}
+
+impl Drop for Health {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Print `usage` and the output queues of the copy in flight.
+fn report(usage: &Usage, progress: Option<&Progress>) {
+    let queues = progress.map(Progress::queued).unwrap_or_default();
+    if log::is_json() {
+        let queues: Vec<_> = queues
+            .iter()
+            .map(|(name, queued, depth)| {
+                serde_json::json!({ "name": name, "queued": queued, "depth": depth })
+            })
+            .collect();
+        log::event(serde_json::json!({
+            "type": "stats",
+            "rss": usage.rss,
+            "fds": usage.fds,
+            "tasks": usage.tasks,
+            "queues": queues,
+        }));
+        return;
+    }
+    let mut line = String::new();
+    if let Some(rss) = usage.rss {
+        line.push_str(&format!("rss {}, ", format_bytes(rss as f64)));
+    }
+    if let Some(fds) = usage.fds {
+        line.push_str(&format!("{fds} open files, "));
+    }
+    line.push_str(&format!("{} tasks", usage.tasks));
+    for (name, queued, depth) in &queues {
+        line.push_str(&format!(", {name} {queued}/{depth} queued"));
+    }
+    log::message(Some("stats"), &line);
+}
+
+/// Warn about whatever grew past its mark since the baseline, and raise the
+/// mark so it is only warned about again once it grows that much more.
+fn check(usage: &Usage, marks: &mut Usage) {
+    let grew = |what: String| Diagnostic::new(Code::ResourceGrowth, what).emit();
+    if let (Some(rss), Some(mark)) = (usage.rss, &mut marks.rss)
+        && rss > (*mark * RSS_GROWTH).max(*mark + RSS_SLACK)
+    {
+        grew(format!(
+            "memory grew to {} from {}, a leak?",
+            format_bytes(rss as f64),
+            format_bytes(*mark as f64)
+        ));
+        *mark = rss;
+    }
+    if let (Some(fds), Some(mark)) = (usage.fds, &mut marks.fds)
+        && fds > *mark + FD_SLACK
+    {
+        grew(format!("open files grew to {fds} from {mark}, a leak?"));
+        *mark = fds;
+    }
+    if usage.tasks > marks.tasks + TASK_SLACK {
+        grew(format!(
+            "tasks grew to {} from {}, a leak?",
+            usage.tasks, marks.tasks
+        ));
+        marks.tasks = usage.tasks;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn rss() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions.
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page).ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rss() -> Option<u64> {
+    None
+}
+
+fn fds() -> Option<u64> {
+    let dir = if cfg!(target_os = "linux") {
+        "/proc/self/fd"
+    } else {
+        "/dev/fd"
+    };
+    // Reading the directory takes a descriptor of its own.
+    let count = std::fs::read_dir(dir).ok()?.count() as u64;
+    Some(count.saturating_sub(1))
+}
@@ -0,0 +1,226 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use serde_json::Value;
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    arguments::Operation,
+    report::{Report, format_timestamp, parse_timestamp},
+};
+
+/// Append a record of every operation to the history file at `path`
+/// (`--history FILE`), one JSON object per line: when it finished, whether
+/// it succeeded, the command line it was run with, and its summary, with the
+/// bytes, durations and digests of each output.
+pub fn append(path: &Path, reports: &[Report]) -> Result<()> {
+    let records = reports.iter().map(|report| {
+        let summary = &report.summary;
+        let mut record = serde_json::json!({
+            "finished": format_timestamp(summary.started + summary.elapsed),
+            "ok": summary.is_ok(),
+            "command": command(),
+        });
+        if let (Some(record), Value::Object(summary)) = (record.as_object_mut(), summary.to_json())
+        {
+            record.extend(summary);
+        }
+        record
+    });
+    write(path, records)
+}
+
+/// Append a record of an operation that failed before it had a summary,
+/// e.g. because an output couldn't be opened.
+pub fn append_failure(path: &Path, op: &Operation, error: &color_eyre::Report) -> Result<()> {
+    let outputs: Vec<Value> = op
+        .outputs
+        .iter()
+        .map(|output| serde_json::json!({ "name": output.to_string() }))
+        .collect();
+    let record = serde_json::json!({
+        "finished": format_timestamp(SystemTime::now()),
+        "ok": false,
+        "command": command(),
+        "input": op.input.to_string(),
+        "outputs": outputs,
+        "error": error
+            .chain()
+            .map(|cause| cause.to_string())
+            .collect::<Vec<_>>()
+            .join(": "),
+    });
+    write(path, [record].into_iter())
+}
+
+fn command() -> Vec<String> {
+    std::env::args().collect()
+}
+
+fn write(path: &Path, records: impl Iterator<Item = Value>) -> Result<()> {
+    let context = |e: std::io::Error| {
+        eyre!("Failed to write history")
+            .with_error(|| e)
+            .with_note(|| format!("history {}", path.display()))
+    };
+    let mut out = String::new();
+    for record in records {
+        out.push_str(&record.to_string());
+        out.push('\n');
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(context)?;
+    file.write_all(out.as_bytes()).map_err(context)
+}
+
+/// What `pdd history FILE` looks for in a history file. Every condition
+/// given has to hold.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    pub path: PathBuf,
+
+    /// Finished at or after this time (`--since 7d` or `--since 2026-09-01`)
+    pub since: Option<SystemTime>,
+
+    /// Failed (`--failed`)
+    pub failed: bool,
+
+    /// Input containing this text (`--input golden.img`)
+    pub input: Option<String>,
+
+    /// An output whose name, serial number or model contains this text
+    /// (`--output S4EWNX0R`)
+    pub output: Option<String>,
+
+    /// A digest starting with this, of a hash output or input trailer
+    /// (`--hash 3a7bd3e2`)
+    pub hash: Option<String>,
+
+    /// Print matching records as they are stored (`--json`)
+    pub json: bool,
+}
+
+impl Query {
+    fn matches(&self, record: &Value) -> bool {
+        let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+        let outputs = record["outputs"].as_array().cloned().unwrap_or_default();
+        if let Some(since) = self.since
+            && parse_timestamp(&text(&record["finished"])).is_none_or(|time| time < since)
+        {
+            return false;
+        }
+        if self.failed && record["ok"].as_bool() != Some(false) {
+            return false;
+        }
+        if let Some(input) = &self.input
+            && !text(&record["input"]).contains(input.as_str())
+        {
+            return false;
+        }
+        if let Some(wanted) = &self.output
+            && !outputs.iter().any(|output| {
+                ["name", "serial", "model"]
+                    .iter()
+                    .any(|key| text(&output[key]).contains(wanted.as_str()))
+            })
+        {
+            return false;
+        }
+        if let Some(hash) = &self.hash {
+            let hash = hash.to_ascii_lowercase();
+            let mut digests = outputs
+                .iter()
+                .map(|output| text(&output["digest"]))
+                .chain([text(&record["trailer"]["blake3"])]);
+            if !digests.any(|digest| !digest.is_empty() && digest.starts_with(&hash)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Print the records of the history file that match `query`, oldest first.
+pub fn query(query: &Query) -> Result<()> {
+    let file = std::fs::File::open(&query.path).map_err(|e| {
+        eyre!("Failed to read history")
+            .with_error(|| e)
+            .with_note(|| format!("history {}", query.path.display()))
+            .with_suggestion(|| "record one with --history FILE")
+    })?;
+    let mut stdout = std::io::stdout().lock();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Value = serde_json::from_str(&line).map_err(|e| {
+            eyre!("Invalid history record")
+                .with_error(|| e)
+                .with_note(|| format!("history {} line {}", query.path.display(), number + 1))
+        })?;
+        if !query.matches(&record) {
+            continue;
+        }
+        if query.json {
+            writeln!(stdout, "{line}")?;
+        } else {
+            writeln!(stdout, "{}", describe(&record))?;
+        }
+    }
+    Ok(())
+}
+
+/// One line about a record: when, how it went, from where to where and
+/// what the digests were.
+fn describe(record: &Value) -> String {
+    let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+    let outcome = if record["ok"].as_bool() == Some(true) {
+        "ok"
+    } else {
+        "failed"
+    };
+    let mut targets = vec![];
+    let mut digests = vec![];
+    for output in record["outputs"].as_array().into_iter().flatten() {
+        let name = text(&output["name"]);
+        if let Some(digest) = output["digest"].as_str() {
+            digests.push(format!("{}:{digest}", name.trim_start_matches("hash=")));
+            continue;
+        }
+        let mut target = name;
+        if let Some(serial) = output["serial"].as_str() {
+            target.push_str(&format!(" (serial {serial})"));
+        }
+        if let Some(error) = output["error"].as_str() {
+            target.push_str(&format!(" failed, {error}"));
+        }
+        targets.push(target);
+    }
+    if let Some(error) = record["error"].as_str() {
+        return format!(
+            "{} failed {} -> {}, {error}",
+            text(&record["finished"]),
+            text(&record["input"]),
+            targets.join(", "),
+        );
+    }
+    let mut line = format!(
+        "{} {outcome:<6} {} -> {}, {} bytes in {:.1} s",
+        text(&record["finished"]),
+        text(&record["input"]),
+        targets.join(", "),
+        record["bytes_in"].as_u64().unwrap_or_default(),
+        record["elapsed"].as_f64().unwrap_or_default(),
+    );
+    for digest in digests {
+        line.push_str(&format!(", {digest}"));
+    }
+    line
+}
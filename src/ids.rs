@@ -0,0 +1,950 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    ops::Range,
+    path::Path,
+};
+
+use crate::{
+    device,
+    gpt::{self, MBR_PARTITION, MBR_PROTECTIVE, read_at, u32_at, write_at},
+};
+
+/// MBR partition types of extended partitions, whose logical partitions
+/// are left alone.
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// ext2/3/4 superblock fields, from the start of the superblock.
+const EXT_SUPERBLOCK: u64 = 1024;
+const EXT_MAGIC: u16 = 0xef53;
+const EXT_UUID: usize = 0x68;
+const EXT_CHECKSUM_SEED: usize = 0x270;
+const EXT_CHECKSUM: usize = 0x3fc;
+const EXT_SPARSE_SUPER2: u32 = 0x200;
+const EXT_CSUM_SEED: u32 = 0x2000;
+const EXT_SPARSE_SUPER: u32 = 0x1;
+const EXT_GDT_CSUM: u32 = 0x10;
+const EXT_METADATA_CSUM: u32 = 0x400;
+
+/// XFS superblock fields (`struct xfs_dsb`), big endian, from the start of
+/// each allocation group.
+const XFS_BLOCKSIZE: usize = 4;
+const XFS_UUID: usize = 32;
+const XFS_LOGSTART: usize = 48;
+const XFS_AGBLOCKS: usize = 84;
+const XFS_AGCOUNT: usize = 88;
+const XFS_LOGBLOCKS: usize = 96;
+const XFS_VERSIONNUM: usize = 100;
+const XFS_SECTSIZE: usize = 102;
+const XFS_AGBLKLOG: usize = 124;
+const XFS_FEATURES_INCOMPAT: usize = 216;
+const XFS_CRC: usize = 224;
+const XFS_META_UUID: usize = 248;
+const XFS_INCOMPAT_META_UUID: u32 = 1 << 2;
+
+/// XFS log record header fields (`struct xlog_rec_header`), big endian but
+/// for the checksum, at the start of a 512 byte block of the log.
+const XLOG_BLOCK: u64 = 512;
+const XLOG_MAGIC: u32 = 0xfeed_babe;
+const XLOG_VERSION: usize = 8;
+const XLOG_LEN: usize = 12;
+const XLOG_LSN: usize = 16;
+const XLOG_CRC: usize = 32;
+const XLOG_NUM_LOGOPS: usize = 40;
+const XLOG_FS_UUID: usize = 304;
+const XLOG_SIZE: usize = 320;
+const XLOG_VERSION_2: u32 = 2;
+
+/// Bytes of a record header its checksum covers: the struct padded to 8
+/// bytes, or not, as on i386.
+const XLOG_HEADER_SIZES: [usize; 2] = [328, 324];
+
+/// Bytes of each extended header block, and of the record a header block
+/// holds the cycles of.
+const XLOG_EXT_HEADER: usize = 260;
+const XLOG_CYCLE_SIZE: u32 = 32 * 1024;
+
+/// Flag of the operation in the record a clean unmount leaves last.
+const XLOG_UNMOUNT_TRANS: u8 = 0x08;
+
+/// Page sizes a swap area is looked for with; its signature ends the first
+/// page.
+const SWAP_PAGE_SIZES: [u64; 4] = [4096, 8192, 16384, 65536];
+const SWAP_UUID: u64 = 1024 + 12;
+
+/// What `new-ids=1` changed on an output.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stamped {
+    /// Identifiers that were replaced, e.g. `ext4 UUID of partition 1`
+    pub changed: Vec<String>,
+
+    /// Filesystems whose identifiers were left as they are, and why
+    pub skipped: Vec<String>,
+}
+
+/// The result of `new-ids=1` on an output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NewIds {
+    Stamped(Stamped),
+
+    /// Changing them failed part way or not at all, and why
+    Failed(String),
+}
+
+impl NewIds {
+    pub fn is_ok(&self) -> bool {
+        !matches!(self, NewIds::Failed(_))
+    }
+}
+
+impl fmt::Display for NewIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NewIds::Stamped(stamped) if stamped.changed.is_empty() => {
+                write!(f, "no IDs found to change")?;
+                for skipped in &stamped.skipped {
+                    write!(f, ", {skipped}")?;
+                }
+                Ok(())
+            }
+            NewIds::Stamped(stamped) => {
+                write!(f, "new {}", stamped.changed.join(", "))?;
+                for skipped in &stamped.skipped {
+                    write!(f, ", {skipped}")?;
+                }
+                Ok(())
+            }
+            NewIds::Failed(e) => write!(f, "IDs not changed, {e}"),
+        }
+    }
+}
+
+/// Give the clone written to `path` identifiers of its own with
+/// [`stamp`].
+pub fn renew(path: &Path) -> NewIds {
+    match stamp(path) {
+        Ok(stamped) => NewIds::Stamped(stamped),
+        Err(e) => NewIds::Failed(e.to_string()),
+    }
+}
+
+/// Replace the identifiers a clone shares with the disk it was made from,
+/// so both can be attached to one machine without mounts by UUID or
+/// PARTUUID picking the wrong one: the GPT disk and partition GUIDs or the
+/// MBR disk signature, and the UUID or serial number of each ext2/3/4, swap,
+/// version 5 XFS, FAT and NTFS filesystem found on a partition or the whole
+/// output.
+///
+/// Filesystems that stamp their UUID into every block, Btrfs and version 4
+/// XFS, are skipped with the tool that can change it named instead.
+pub fn stamp(path: &Path) -> io::Result<Stamped> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let size = device::size(path).ok_or_else(|| invalid("its size can't be told"))?;
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut stamped = Stamped::default();
+
+    let regions = match gpt::new_guids(&mut file)? {
+        Some(partitions) => {
+            stamped.changed.push("GPT disk GUID".into());
+            stamped
+                .changed
+                .push(format!("{} partition GUIDs", partitions.len()));
+            partitions
+                .into_iter()
+                .map(|(number, range)| (format!("partition {number}"), range))
+                .collect()
+        }
+        None if filesystem(&mut file, 0)?.is_none() && is_mbr(&mut file)? => {
+            let mut signature = [0u8; 4];
+            while signature == [0; 4] {
+                getrandom::fill(&mut signature).map_err(io::Error::other)?;
+            }
+            write_at(&mut file, 440, &signature)?;
+            stamped.changed.push("MBR disk signature".into());
+            mbr_partitions(&mut file)?
+        }
+        None => vec![("the whole output".to_string(), 0..size)],
+    };
+
+    for (name, range) in regions {
+        let Some(kind) = filesystem(&mut file, range.start)? else {
+            continue;
+        };
+        let skipped = match kind {
+            Filesystem::Ext => ext(&mut file, &range)?,
+            Filesystem::Swap => {
+                write_at(&mut file, range.start + SWAP_UUID, &random_uuid()?)?;
+                None
+            }
+            Filesystem::Fat { serial, backup } => fat(&mut file, &range, serial, backup)?,
+            Filesystem::Ntfs => ntfs(&mut file, &range)?,
+            Filesystem::Xfs => xfs(&mut file, &range)?,
+            Filesystem::Btrfs => Some("use btrfstune -u"),
+        };
+        match skipped {
+            None => stamped
+                .changed
+                .push(format!("{kind} {} of {name}", kind.id())),
+            Some(why) => stamped
+                .skipped
+                .push(format!("{kind} on {name} left as it is, {why}")),
+        }
+    }
+    file.sync_all()?;
+    Ok(stamped)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Filesystem {
+    Ext,
+    Swap,
+
+    /// With the offsets of the volume serial number and of the backup boot
+    /// sector, if there is one
+    Fat {
+        serial: usize,
+        backup: Option<u64>,
+    },
+
+    Ntfs,
+    Xfs,
+    Btrfs,
+}
+
+impl Filesystem {
+    /// What its identifier is called.
+    fn id(self) -> &'static str {
+        match self {
+            Filesystem::Fat { .. } | Filesystem::Ntfs => "serial number",
+            _ => "UUID",
+        }
+    }
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filesystem::Ext => write!(f, "ext"),
+            Filesystem::Swap => write!(f, "swap"),
+            Filesystem::Fat { .. } => write!(f, "FAT"),
+            Filesystem::Ntfs => write!(f, "NTFS"),
+            Filesystem::Xfs => write!(f, "XFS"),
+            Filesystem::Btrfs => write!(f, "Btrfs"),
+        }
+    }
+}
+
+/// The filesystem starting at `offset`, if it is one pdd knows.
+fn filesystem(file: &mut File, offset: u64) -> io::Result<Option<Filesystem>> {
+    let mut boot = [0u8; 512];
+    if !read_at(file, offset, &mut boot)? {
+        return Ok(None);
+    }
+    if &boot[..4] == b"XFSB" {
+        return Ok(Some(Filesystem::Xfs));
+    }
+    if &boot[3..11] == b"NTFS    " {
+        return Ok(Some(Filesystem::Ntfs));
+    }
+    let sector = u16::from_le_bytes([boot[11], boot[12]]);
+    if boot[510..512] == [0x55, 0xaa] && matches!(sector, 512 | 1024 | 2048 | 4096) {
+        if &boot[0x52..0x57] == b"FAT32" && boot[0x42] == 0x29 {
+            let backup = u16::from_le_bytes([boot[0x32], boot[0x33]]);
+            return Ok(Some(Filesystem::Fat {
+                serial: 0x43,
+                backup: (backup != 0 && backup != 0xffff)
+                    .then_some(u64::from(backup) * u64::from(sector)),
+            }));
+        }
+        if &boot[0x36..0x39] == b"FAT" && boot[0x26] == 0x29 {
+            return Ok(Some(Filesystem::Fat {
+                serial: 0x27,
+                backup: None,
+            }));
+        }
+    }
+    let mut superblock = [0u8; 1024];
+    if read_at(file, offset + EXT_SUPERBLOCK, &mut superblock)?
+        && u16_at(&superblock, 0x38) == EXT_MAGIC
+    {
+        return Ok(Some(Filesystem::Ext));
+    }
+    let mut magic = [0u8; 8];
+    if read_at(file, offset + 0x10040, &mut magic)? && &magic == b"_BHRfS_M" {
+        return Ok(Some(Filesystem::Btrfs));
+    }
+    for page in SWAP_PAGE_SIZES {
+        let mut magic = [0u8; 10];
+        if read_at(file, offset + page - 10, &mut magic)? && &magic == b"SWAPSPACE2" {
+            return Ok(Some(Filesystem::Swap));
+        }
+    }
+    Ok(None)
+}
+
+/// True if sector 0 is an MBR with partitions, and not a protective one.
+fn is_mbr(file: &mut File) -> io::Result<bool> {
+    let mut mbr = [0u8; 512];
+    Ok(read_at(file, 0, &mut mbr)?
+        && mbr[510..512] == [0x55, 0xaa]
+        && (0..4).any(|index| {
+            let kind = mbr[MBR_PARTITION + index * 16 + 4];
+            kind != 0 && kind != MBR_PROTECTIVE
+        }))
+}
+
+/// The primary partitions of the MBR as byte ranges.
+fn mbr_partitions(file: &mut File) -> io::Result<Vec<(String, Range<u64>)>> {
+    let mut mbr = [0u8; 512];
+    read_at(file, 0, &mut mbr)?;
+    let mut partitions = vec![];
+    for index in 0..4 {
+        let entry = &mbr[MBR_PARTITION + index * 16..MBR_PARTITION + (index + 1) * 16];
+        if entry[4] == 0 || MBR_EXTENDED.contains(&entry[4]) {
+            continue;
+        }
+        let start = u64::from(u32_at(entry, 8)) * 512;
+        let len = u64::from(u32_at(entry, 12)) * 512;
+        partitions.push((format!("partition {}", index + 1), start..start + len));
+    }
+    Ok(partitions)
+}
+
+/// A new UUID for an ext filesystem, carried over to every backup
+/// superblock. Filesystems with metadata checksums keep checksumming with
+/// the seed of the old one, stored in the superblock, as `tune2fs -U` does.
+///
+/// Each of these returns why the filesystem was left alone, if it was.
+fn ext(file: &mut File, range: &Range<u64>) -> io::Result<Option<&'static str>> {
+    let mut primary = [0u8; 1024];
+    read_at(file, range.start + EXT_SUPERBLOCK, &mut primary)?;
+    let ro_compat = u32_at(&primary, 0x64);
+    let metadata_csum = ro_compat & EXT_METADATA_CSUM != 0;
+    if ro_compat & EXT_GDT_CSUM != 0 && !metadata_csum {
+        return Ok(Some(
+            "its group descriptor checksums need it, use tune2fs -U random",
+        ));
+    }
+    let seed = crc32c(!0, &primary[EXT_UUID..EXT_UUID + 16]);
+    let uuid = random_uuid()?;
+
+    let block_size = 1024u64 << u32_at(&primary, 0x18).min(16);
+    let first_data_block = u64::from(u32_at(&primary, 0x14));
+    let per_group = u64::from(u32_at(&primary, 0x20)).max(1);
+    let mut blocks = u64::from(u32_at(&primary, 0x04));
+    if u32_at(&primary, 0x60) & 0x80 != 0 {
+        blocks |= u64::from(u32_at(&primary, 0x150)) << 32;
+    }
+    let groups = blocks.saturating_sub(first_data_block).div_ceil(per_group);
+    let compat = u32_at(&primary, 0x5c);
+    let backups: Vec<u64> = if compat & EXT_SPARSE_SUPER2 != 0 {
+        [u32_at(&primary, 0x24c), u32_at(&primary, 0x250)]
+            .into_iter()
+            .map(u64::from)
+            .filter(|&group| group != 0)
+            .collect()
+    } else if ro_compat & EXT_SPARSE_SUPER != 0 {
+        (1..groups)
+            .filter(|&group| group == 1 || [3, 5, 7].iter().any(|&base| is_power(group, base)))
+            .collect()
+    } else {
+        (1..groups).collect()
+    };
+
+    let mut offsets = vec![range.start + EXT_SUPERBLOCK];
+    offsets.extend(
+        backups
+            .into_iter()
+            .map(|group| range.start + (first_data_block + group * per_group) * block_size)
+            .filter(|&offset| offset + 1024 <= range.end),
+    );
+    for offset in offsets {
+        let mut superblock = [0u8; 1024];
+        if !read_at(file, offset, &mut superblock)? || u16_at(&superblock, 0x38) != EXT_MAGIC {
+            continue;
+        }
+        if metadata_csum && u32_at(&superblock, 0x60) & EXT_CSUM_SEED == 0 {
+            let incompat = u32_at(&superblock, 0x60) | EXT_CSUM_SEED;
+            superblock[0x60..0x64].copy_from_slice(&incompat.to_le_bytes());
+            superblock[EXT_CHECKSUM_SEED..EXT_CHECKSUM_SEED + 4]
+                .copy_from_slice(&seed.to_le_bytes());
+        }
+        superblock[EXT_UUID..EXT_UUID + 16].copy_from_slice(&uuid);
+        if metadata_csum {
+            let checksum = crc32c(!0, &superblock[..EXT_CHECKSUM]);
+            superblock[EXT_CHECKSUM..].copy_from_slice(&checksum.to_le_bytes());
+        }
+        write_at(file, offset, &superblock)?;
+    }
+    Ok(None)
+}
+
+/// A new volume serial number in the boot sector and its backup.
+fn fat(
+    file: &mut File,
+    range: &Range<u64>,
+    serial: usize,
+    backup: Option<u64>,
+) -> io::Result<Option<&'static str>> {
+    let mut id = [0u8; 4];
+    getrandom::fill(&mut id).map_err(io::Error::other)?;
+    write_at(file, range.start + serial as u64, &id)?;
+    if let Some(backup) = backup {
+        let mut boot = [0u8; 512];
+        if read_at(file, range.start + backup, &mut boot)? && boot[510..512] == [0x55, 0xaa] {
+            write_at(file, range.start + backup + serial as u64, &id)?;
+        }
+    }
+    Ok(None)
+}
+
+/// A new volume serial number in the boot sector and in its backup, the
+/// sector after the last one of the volume.
+fn ntfs(file: &mut File, range: &Range<u64>) -> io::Result<Option<&'static str>> {
+    let mut boot = [0u8; 512];
+    read_at(file, range.start, &mut boot)?;
+    let mut serial = [0u8; 8];
+    getrandom::fill(&mut serial).map_err(io::Error::other)?;
+    write_at(file, range.start + 0x48, &serial)?;
+    let sector = u64::from(u16_at(&boot, 0x0b));
+    let sectors = u64::from_le_bytes(boot[0x28..0x30].try_into().expect("8 bytes"));
+    if let Some(backup) = sectors
+        .checked_mul(sector)
+        .and_then(|len| range.start.checked_add(len))
+        .filter(|&backup| backup + 512 <= range.end)
+    {
+        let mut copy = [0u8; 512];
+        if read_at(file, backup, &mut copy)? && copy[3..11] == boot[3..11] {
+            write_at(file, backup + 0x48, &serial)?;
+        }
+    }
+    Ok(None)
+}
+
+/// A new UUID for a version 5 XFS, as `xfs_admin -U generate` gives it: the
+/// old one stays on as the metadata UUID its blocks are stamped with, and
+/// the superblock of every allocation group and the header of every record
+/// of the log, which the kernel matches against it, get the new one. The log
+/// has to be clean, so none of it is replayed with the old one.
+fn xfs(file: &mut File, range: &Range<u64>) -> io::Result<Option<&'static str>> {
+    let mut primary = [0u8; 512];
+    read_at(file, range.start, &mut primary)?;
+    if be16_at(&primary, XFS_VERSIONNUM) & 0xf != 5 {
+        return Ok(Some(
+            "a version 4 XFS stamps its UUID into every block, use xfs_admin -U generate",
+        ));
+    }
+    let sector = usize::from(be16_at(&primary, XFS_SECTSIZE));
+    let block_size = u64::from(be32_at(&primary, XFS_BLOCKSIZE));
+    let ag_blocks = u64::from(be32_at(&primary, XFS_AGBLOCKS));
+    let ag_count = u64::from(be32_at(&primary, XFS_AGCOUNT));
+    let ag_block_log = u32::from(primary[XFS_AGBLKLOG]);
+    if !sector.is_power_of_two()
+        || !(512..=32768).contains(&sector)
+        || !block_size.is_power_of_two()
+        || !(512..=65536).contains(&block_size)
+        || ag_block_log > 31
+    {
+        return Ok(Some(
+            "its superblock doesn't make sense, use xfs_repair first",
+        ));
+    }
+    let mut superblock = vec![0u8; sector];
+    read_at(file, range.start, &mut superblock)?;
+    if xfs_checksum(&superblock, XFS_CRC) != u32_at(&superblock, XFS_CRC) {
+        return Ok(Some(
+            "its superblock checksum doesn't match, use xfs_repair first",
+        ));
+    }
+    let old: [u8; 16] = superblock[XFS_UUID..XFS_UUID + 16]
+        .try_into()
+        .expect("16 bytes");
+
+    // Log blocks are numbered by allocation group and block within it.
+    let log_start = be64_at(&superblock, XFS_LOGSTART);
+    if log_start == 0 {
+        return Ok(Some(
+            "its log is on another device, use xfs_admin -U generate",
+        ));
+    }
+    let log_block =
+        (log_start >> ag_block_log) * ag_blocks + (log_start & ((1 << ag_block_log) - 1));
+    let log = Log {
+        start: range.start + log_block * block_size,
+        len: u64::from(be32_at(&superblock, XFS_LOGBLOCKS)) * block_size,
+    };
+    if log.len < XLOG_BLOCK || log.start + log.len > range.end {
+        return Ok(Some("its log doesn't fit in it, use xfs_repair first"));
+    }
+    let Some(records) = log.clean_records(file, &old)? else {
+        return Ok(Some(
+            "its log isn't clean, mount and unmount it or run xfs_repair first",
+        ));
+    };
+
+    let uuid = random_uuid()?;
+    for block in records {
+        let mut record = log
+            .record(file, block, &old)?
+            .expect("the record was there a moment ago");
+        record.header[XLOG_FS_UUID..XLOG_FS_UUID + 16].copy_from_slice(&uuid);
+        let checksum = record.checksum();
+        record.header[XLOG_CRC..XLOG_CRC + 4].copy_from_slice(&checksum.to_le_bytes());
+        write_at(file, log.start + block * XLOG_BLOCK, &record.header)?;
+    }
+    for ag in 0..ag_count {
+        let offset = range.start + ag * ag_blocks * block_size;
+        if offset + sector as u64 > range.end {
+            break;
+        }
+        let mut superblock = vec![0u8; sector];
+        if !read_at(file, offset, &mut superblock)? || &superblock[..4] != b"XFSB" {
+            continue;
+        }
+        let incompat = be32_at(&superblock, XFS_FEATURES_INCOMPAT);
+        if incompat & XFS_INCOMPAT_META_UUID == 0 {
+            superblock[XFS_META_UUID..XFS_META_UUID + 16].copy_from_slice(&old);
+            superblock[XFS_FEATURES_INCOMPAT..XFS_FEATURES_INCOMPAT + 4]
+                .copy_from_slice(&(incompat | XFS_INCOMPAT_META_UUID).to_be_bytes());
+        }
+        superblock[XFS_UUID..XFS_UUID + 16].copy_from_slice(&uuid);
+        let checksum = xfs_checksum(&superblock, XFS_CRC);
+        superblock[XFS_CRC..XFS_CRC + 4].copy_from_slice(&checksum.to_le_bytes());
+        write_at(file, offset, &superblock)?;
+    }
+    Ok(None)
+}
+
+/// The internal log of an XFS, by its offset and length on the output.
+struct Log {
+    start: u64,
+    len: u64,
+}
+
+/// A record of an XFS log whose checksum matches.
+struct Record {
+    /// Its first block
+    header: Vec<u8>,
+
+    /// How much of the header the checksum covers
+    header_size: usize,
+
+    /// The extended headers and the data after it
+    extended: Vec<Vec<u8>>,
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn checksum(&self) -> u32 {
+        let mut header = self.header[..self.header_size].to_vec();
+        header[XLOG_CRC..XLOG_CRC + 4].fill(0);
+        let mut crc = crc32c(!0, &header);
+        for extended in &self.extended {
+            crc = crc32c(crc, extended);
+        }
+        !crc32c(crc, &self.data)
+    }
+
+    fn lsn(&self) -> u64 {
+        be64_at(&self.header, XLOG_LSN)
+    }
+
+    /// True if it is the end of a clean unmount: a single operation, of
+    /// unmounting.
+    fn is_unmount(&self) -> bool {
+        be32_at(&self.header, XLOG_NUM_LOGOPS) == 1
+            && self
+                .data
+                .get(9)
+                .is_some_and(|flags| flags & XLOG_UNMOUNT_TRANS != 0)
+    }
+}
+
+impl Log {
+    /// Read `buf.len()` bytes from `offset` into the log, which wraps around.
+    fn read(&self, file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let offset = offset % self.len;
+        let first = (buf.len() as u64).min(self.len - offset) as usize;
+        let (head, tail) = buf.split_at_mut(first);
+        read_at(file, self.start + offset, head)?;
+        if !tail.is_empty() {
+            self.read(file, 0, tail)?;
+        }
+        Ok(())
+    }
+
+    /// The record starting in `block`, if there is one of the filesystem
+    /// with UUID `uuid` there and its checksum matches.
+    fn record(&self, file: &mut File, block: u64, uuid: &[u8; 16]) -> io::Result<Option<Record>> {
+        let mut header = vec![0u8; XLOG_BLOCK as usize];
+        self.read(file, block * XLOG_BLOCK, &mut header)?;
+        if be32_at(&header, 0) != XLOG_MAGIC || header[XLOG_FS_UUID..XLOG_FS_UUID + 16] != *uuid {
+            return Ok(None);
+        }
+        let size = be32_at(&header, XLOG_SIZE);
+        let blocks = match be32_at(&header, XLOG_VERSION) & XLOG_VERSION_2 != 0 {
+            true if size > XLOG_CYCLE_SIZE => u64::from(size.div_ceil(XLOG_CYCLE_SIZE)),
+            _ => 1,
+        };
+        let len = u64::from(be32_at(&header, XLOG_LEN));
+        if blocks * XLOG_BLOCK + len > self.len {
+            return Ok(None);
+        }
+        let mut extended = vec![];
+        for index in 1..blocks {
+            let mut block_header = vec![0u8; XLOG_EXT_HEADER];
+            self.read(file, (block + index) * XLOG_BLOCK, &mut block_header)?;
+            extended.push(block_header);
+        }
+        let mut data = vec![0u8; len as usize];
+        self.read(file, (block + blocks) * XLOG_BLOCK, &mut data)?;
+        let mut record = Record {
+            header,
+            header_size: 0,
+            extended,
+            data,
+        };
+        let stored = u32_at(&record.header, XLOG_CRC);
+        for size in XLOG_HEADER_SIZES {
+            record.header_size = size;
+            if record.checksum() == stored {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The blocks of every record of the filesystem with UUID `uuid` in the
+    /// log, if the newest of them is an unmount: more recent ones whose
+    /// checksums don't match were never completely written, and the
+    /// kernel leaves them out too.
+    fn clean_records(&self, file: &mut File, uuid: &[u8; 16]) -> io::Result<Option<Vec<u64>>> {
+        let mut records = vec![];
+        let mut newest: Option<(u64, bool)> = None;
+        let mut chunk = vec![0u8; 1 << 20];
+        let mut offset = 0;
+        while offset < self.len {
+            let len = chunk.len().min((self.len - offset) as usize);
+            read_at(file, self.start + offset, &mut chunk[..len])?;
+            for at in (0..len).step_by(XLOG_BLOCK as usize) {
+                if be32_at(&chunk, at) != XLOG_MAGIC {
+                    continue;
+                }
+                let block = (offset + at as u64) / XLOG_BLOCK;
+                let Some(record) = self.record(file, block, uuid)? else {
+                    continue;
+                };
+                if newest.is_none_or(|(lsn, _)| record.lsn() > lsn) {
+                    newest = Some((record.lsn(), record.is_unmount()));
+                }
+                records.push(block);
+            }
+            offset += len as u64;
+        }
+        Ok(matches!(newest, Some((_, true))).then_some(records))
+    }
+}
+
+/// CRC-32C of an XFS block as the kernel takes it, with the checksum at
+/// `at` left out.
+fn xfs_checksum(block: &[u8], at: usize) -> u32 {
+    let crc = crc32c(!0, &block[..at]);
+    let crc = crc32c(crc, &[0; 4]);
+    !crc32c(crc, &block[at + 4..])
+}
+
+fn is_power(mut n: u64, base: u64) -> bool {
+    while n > 1 && n.is_multiple_of(base) {
+        n /= base;
+    }
+    n == 1
+}
+
+/// A random (version 4) UUID in the byte order of RFC 4122.
+fn random_uuid() -> io::Result<[u8; 16]> {
+    let mut uuid = [0u8; 16];
+    getrandom::fill(&mut uuid).map_err(io::Error::other)?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    Ok(uuid)
+}
+
+/// CRC-32C from `seed`, without the final inversion, as ext4 takes it.
+fn crc32c(seed: u32, data: &[u8]) -> u32 {
+    let mut crc = seed;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().expect("2 bytes"))
+}
+
+fn be16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes(buf[at..at + 2].try_into().expect("2 bytes"))
+}
+
+fn be32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().expect("4 bytes"))
+}
+
+fn be64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().expect("8 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Offsets of fields laid out one after the other from `start`.
+    fn offsets(start: usize, sizes: &[(&'static str, usize)]) -> Vec<(&'static str, usize)> {
+        let mut offset = start;
+        sizes
+            .iter()
+            .map(|&(name, size)| {
+                let at = offset;
+                offset += size;
+                (name, at)
+            })
+            .collect()
+    }
+
+    fn offset_of(fields: &[(&str, usize)], name: &str) -> usize {
+        fields.iter().find(|(field, _)| *field == name).unwrap().1
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(!crc32c(!0, b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(!0, b""), !0);
+        // Taken in pieces, as the XFS checksums are.
+        let crc = crc32c(crc32c(!0, b"1234"), b"56789");
+        assert_eq!(!crc, 0xe306_9283);
+    }
+
+    #[test]
+    fn xfs_superblock_offsets() {
+        // struct xfs_dsb, from xfs_format.h
+        let fields = offsets(
+            0,
+            &[
+                ("magicnum", 4),
+                ("blocksize", 4),
+                ("dblocks", 8),
+                ("rblocks", 8),
+                ("rextents", 8),
+                ("uuid", 16),
+                ("logstart", 8),
+                ("rootino", 8),
+                ("rbmino", 8),
+                ("rsumino", 8),
+                ("rextsize", 4),
+                ("agblocks", 4),
+                ("agcount", 4),
+                ("rbmblocks", 4),
+                ("logblocks", 4),
+                ("versionnum", 2),
+                ("sectsize", 2),
+                ("inodesize", 2),
+                ("inopblock", 2),
+                ("fname", 12),
+                ("blocklog", 1),
+                ("sectlog", 1),
+                ("inodelog", 1),
+                ("inopblog", 1),
+                ("agblklog", 1),
+                ("rextslog", 1),
+                ("inprogress", 1),
+                ("imax_pct", 1),
+                ("icount", 8),
+                ("ifree", 8),
+                ("fdblocks", 8),
+                ("frextents", 8),
+                ("uquotino", 8),
+                ("gquotino", 8),
+                ("qflags", 2),
+                ("flags", 1),
+                ("shared_vn", 1),
+                ("inoalignmt", 4),
+                ("unit", 4),
+                ("width", 4),
+                ("dirblklog", 1),
+                ("logsectlog", 1),
+                ("logsectsize", 2),
+                ("logsunit", 4),
+                ("features2", 4),
+                ("bad_features2", 4),
+                ("features_compat", 4),
+                ("features_ro_compat", 4),
+                ("features_incompat", 4),
+                ("features_log_incompat", 4),
+                ("crc", 4),
+                ("spino_align", 4),
+                ("pquotino", 8),
+                ("lsn", 8),
+                ("meta_uuid", 16),
+            ],
+        );
+        assert_eq!(offset_of(&fields, "blocksize"), XFS_BLOCKSIZE);
+        assert_eq!(offset_of(&fields, "uuid"), XFS_UUID);
+        assert_eq!(offset_of(&fields, "logstart"), XFS_LOGSTART);
+        assert_eq!(offset_of(&fields, "agblocks"), XFS_AGBLOCKS);
+        assert_eq!(offset_of(&fields, "agcount"), XFS_AGCOUNT);
+        assert_eq!(offset_of(&fields, "logblocks"), XFS_LOGBLOCKS);
+        assert_eq!(offset_of(&fields, "versionnum"), XFS_VERSIONNUM);
+        assert_eq!(offset_of(&fields, "sectsize"), XFS_SECTSIZE);
+        assert_eq!(offset_of(&fields, "agblklog"), XFS_AGBLKLOG);
+        assert_eq!(
+            offset_of(&fields, "features_incompat"),
+            XFS_FEATURES_INCOMPAT
+        );
+        assert_eq!(offset_of(&fields, "crc"), XFS_CRC);
+        assert_eq!(offset_of(&fields, "meta_uuid"), XFS_META_UUID);
+    }
+
+    #[test]
+    fn xlog_header_offsets() {
+        // struct xlog_rec_header, from xfs_log_format.h
+        let fields = offsets(
+            0,
+            &[
+                ("magicno", 4),
+                ("cycle", 4),
+                ("version", 4),
+                ("len", 4),
+                ("lsn", 8),
+                ("tail_lsn", 8),
+                ("crc", 4),
+                ("prev_block", 4),
+                ("num_logops", 4),
+                ("cycle_data", 4 * 64),
+                ("fmt", 4),
+                ("fs_uuid", 16),
+                ("size", 4),
+                ("end", 0),
+            ],
+        );
+        assert_eq!(offset_of(&fields, "version"), XLOG_VERSION);
+        assert_eq!(offset_of(&fields, "len"), XLOG_LEN);
+        assert_eq!(offset_of(&fields, "lsn"), XLOG_LSN);
+        assert_eq!(offset_of(&fields, "crc"), XLOG_CRC);
+        assert_eq!(offset_of(&fields, "num_logops"), XLOG_NUM_LOGOPS);
+        assert_eq!(offset_of(&fields, "fs_uuid"), XLOG_FS_UUID);
+        assert_eq!(offset_of(&fields, "size"), XLOG_SIZE);
+        let end = offset_of(&fields, "end");
+        assert_eq!(XLOG_HEADER_SIZES, [end.next_multiple_of(8), end]);
+        // struct xlog_rec_ext_header: a cycle and the cycles of its blocks
+        assert_eq!(XLOG_EXT_HEADER, 4 + 4 * 64);
+    }
+
+    const BLOCK: u64 = 4096;
+    const AG_BLOCKS: u64 = 64;
+    const LOG_BLOCK: u64 = 16;
+    const LOG_BLOCKS: u64 = 8;
+
+    /// A version 5 XFS of two allocation groups with an internal log, which
+    /// is left clean by an unmount record unless `unmounted` is false.
+    fn xfs_image(name: &str, unmounted: bool) -> (std::path::PathBuf, [u8; 16]) {
+        let path = std::env::temp_dir().join(format!("pdd-ids-{}-{name}", std::process::id()));
+        let mut image = vec![0u8; (2 * AG_BLOCKS * BLOCK) as usize];
+        let uuid = random_uuid().unwrap();
+        for ag in 0..2 {
+            let sb = &mut image[(ag * AG_BLOCKS * BLOCK) as usize..][..512];
+            sb[..4].copy_from_slice(b"XFSB");
+            sb[XFS_BLOCKSIZE..][..4].copy_from_slice(&(BLOCK as u32).to_be_bytes());
+            sb[XFS_UUID..][..16].copy_from_slice(&uuid);
+            sb[XFS_LOGSTART..][..8].copy_from_slice(&LOG_BLOCK.to_be_bytes());
+            sb[XFS_AGBLOCKS..][..4].copy_from_slice(&(AG_BLOCKS as u32).to_be_bytes());
+            sb[XFS_AGCOUNT..][..4].copy_from_slice(&2u32.to_be_bytes());
+            sb[XFS_LOGBLOCKS..][..4].copy_from_slice(&(LOG_BLOCKS as u32).to_be_bytes());
+            sb[XFS_VERSIONNUM..][..2].copy_from_slice(&0xb4a5u16.to_be_bytes());
+            sb[XFS_SECTSIZE..][..2].copy_from_slice(&512u16.to_be_bytes());
+            sb[XFS_AGBLKLOG] = 6;
+            let checksum = xfs_checksum(sb, XFS_CRC);
+            sb[XFS_CRC..][..4].copy_from_slice(&checksum.to_le_bytes());
+        }
+        // One record: its header, then a block with the operation.
+        let log = (LOG_BLOCK * BLOCK) as usize;
+        let (header, data) = image[log..log + 1024].split_at_mut(512);
+        header[..4].copy_from_slice(&XLOG_MAGIC.to_be_bytes());
+        header[4..8].copy_from_slice(&1u32.to_be_bytes());
+        header[XLOG_VERSION..][..4].copy_from_slice(&XLOG_VERSION_2.to_be_bytes());
+        header[XLOG_LEN..][..4].copy_from_slice(&512u32.to_be_bytes());
+        header[XLOG_LSN..][..8].copy_from_slice(&(1u64 << 32).to_be_bytes());
+        header[XLOG_NUM_LOGOPS..][..4].copy_from_slice(&1u32.to_be_bytes());
+        header[XLOG_FS_UUID..][..16].copy_from_slice(&uuid);
+        header[XLOG_SIZE..][..4].copy_from_slice(&XLOG_CYCLE_SIZE.to_be_bytes());
+        data[..4].copy_from_slice(&1u32.to_be_bytes());
+        data[8] = 0xaa;
+        data[9] = if unmounted { XLOG_UNMOUNT_TRANS } else { 0 };
+        let record = Record {
+            header: header.to_vec(),
+            header_size: XLOG_HEADER_SIZES[0],
+            extended: vec![],
+            data: data.to_vec(),
+        };
+        header[XLOG_CRC..][..4].copy_from_slice(&record.checksum().to_le_bytes());
+        std::fs::write(&path, image).unwrap();
+        (path, uuid)
+    }
+
+    #[test]
+    fn xfs_gets_a_new_uuid_and_keeps_the_old_for_metadata() {
+        let (path, old) = xfs_image("clean", true);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        assert_eq!(filesystem(&mut file, 0).unwrap(), Some(Filesystem::Xfs));
+        assert_eq!(xfs(&mut file, &(0..len)).unwrap(), None);
+
+        let image = std::fs::read(&path).unwrap();
+        let new: [u8; 16] = image[XFS_UUID..XFS_UUID + 16].try_into().unwrap();
+        assert_ne!(new, old);
+        for ag in 0..2 {
+            let sb = &image[(ag * AG_BLOCKS * BLOCK) as usize..][..512];
+            assert_eq!(sb[XFS_UUID..XFS_UUID + 16], new);
+            assert_eq!(sb[XFS_META_UUID..XFS_META_UUID + 16], old);
+            assert_ne!(
+                be32_at(sb, XFS_FEATURES_INCOMPAT) & XFS_INCOMPAT_META_UUID,
+                0
+            );
+            assert_eq!(xfs_checksum(sb, XFS_CRC), u32_at(sb, XFS_CRC));
+        }
+        // The log record now carries the new UUID, with a checksum to match.
+        let log = Log {
+            start: LOG_BLOCK * BLOCK,
+            len: LOG_BLOCKS * BLOCK,
+        };
+        assert!(log.record(&mut file, 0, &new).unwrap().is_some());
+        assert!(log.record(&mut file, 0, &old).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn xfs_with_a_dirty_log_is_left_alone() {
+        let (path, _) = xfs_image("dirty", false);
+        let before = std::fs::read(&path).unwrap();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        assert!(xfs(&mut file, &(0..len)).unwrap().is_some());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+use tokio::io::AsyncSeekExt;
+use ureq::BodyReader;
+
+use crate::{
+    arguments::{Input, Operation},
+    cache::{Cache, Version},
+    compress::{self, Decompression, Decompressor},
+    container,
+    diagnostic::{Code, Diagnostic},
+    direct::DirectReader,
+    engine::Source,
+    multicast::MulticastReader,
+    pipe,
+    trailer::Trailer,
+};
+
+/// Times a download is picked up again after its connection drops, in a row.
+const HTTP_RESUMES: u32 = 5;
+
+/// Open the input of an operation, positioned `skip` bytes in.
+///
+/// Files are seeked and downloads start at `skip` where the server allows;
+/// stdin, FIFOs, sockets and multicast groups can't be, so the skipped
+/// bytes are read and thrown away like dd does. Compressed inputs are
+/// decompressed on a thread, the same way stdin is read, and `skip` counts
+/// decompressed bytes. With `iflag=direct` files are read around the page
+/// cache. A file with a `trailer` is only read up to it, and checked
+/// against it. An encrypted input is decrypted before it is decompressed,
+/// and `skip` counts decrypted bytes. A download with `cache=` is read from
+/// the cache if a copy that still matches was kept there, and kept there if
+/// not, so it is read from its start. A `container=pddstream` input is
+/// unpacked, and checked, before anything else unless `decomp=none`.
+pub async fn open(
+    op: &Operation,
+    skip: u64,
+    block_size: usize,
+    trailer: Option<&Trailer>,
+) -> Result<Source> {
+    let input = &op.input;
+    let decomp = op.decomp;
+    let dec = op.dec.as_ref();
+    let direct = op.iflag.direct;
+    let context = |e: io::Error| {
+        eyre!("Failed to open input")
+            .with_error(|| e)
+            .with_note(|| format!("input {input}"))
+    };
+    // Unpacked from a container first, then decrypted, then decompressed,
+    // each on the reading thread. Copied as it is, a container is kept.
+    let decode =
+        |inner: Box<dyn Read + Send>, path: Option<&Path>| -> Result<Box<dyn Read + Send>> {
+            let inner: Box<dyn Read + Send> = match decomp {
+                Decompression::None => inner,
+                _ => Box::new(container::unpack(inner)),
+            };
+            Ok(match dec {
+                Some(dec) => Box::new(Decompressor::new(
+                    dec.reader(inner).map_err(context)?,
+                    path,
+                    decomp,
+                )),
+                None => Box::new(Decompressor::new(inner, path, decomp)),
+            })
+        };
+    let mut skip = skip;
+    let mut reader: Box<dyn Read + Send> = match input {
+        // A FIFO, e.g. if=<(...), is read like stdin, since what was
+        // sniffed of it can't be read again.
+        Input::File(path)
+            if dec.is_some()
+                || !rereadable(path)
+                || (decomp != Decompression::None && container::detect(path)) =>
+        {
+            let file = std::fs::File::open(path).map_err(context)?;
+            let image: Box<dyn Read + Send> = match (trailer, direct) {
+                (Some(trailer), true) => {
+                    Box::new(trailer.reader(open_direct(file, input).map_err(context)?))
+                }
+                (Some(trailer), false) => Box::new(trailer.reader(file)),
+                (None, true) => Box::new(open_direct(file, input).map_err(context)?),
+                (None, false) => Box::new(file),
+            };
+            decode(image, Some(path))?
+        }
+        Input::File(path) => {
+            let mut file = std::fs::File::open(path).map_err(context)?;
+            // Only sniffed when it could be compressed, so a drive whose
+            // first sector won't read can still be rescued.
+            let decomp = if decomp == Decompression::Auto {
+                let mut magic = [0u8; 4];
+                let n = compress::read_full(&mut file, &mut magic).map_err(context)?;
+                file.rewind().map_err(context)?;
+                decomp.resolve(Some(path), &magic[..n])
+            } else {
+                decomp
+            };
+            if let Some(trailer) = trailer {
+                // Read through like a stream, so all of it gets checked.
+                let image: Box<dyn Read + Send> = if direct {
+                    Box::new(trailer.reader(open_direct(file, input).map_err(context)?))
+                } else {
+                    Box::new(trailer.reader(file))
+                };
+                decomp.reader(image).map_err(context)?
+            } else if direct {
+                let mut reader = open_direct(file, input).map_err(context)?;
+                if decomp != Decompression::None {
+                    decomp.reader(reader).map_err(context)?
+                } else {
+                    if skip > 0 {
+                        Seek::seek(&mut reader, SeekFrom::Start(skip)).map_err(context)?;
+                    }
+                    return Ok(Source::seekable(reader));
+                }
+            } else if decomp != Decompression::None {
+                decomp.reader(file).map_err(context)?
+            } else {
+                let mut file = tokio::fs::File::from_std(file);
+                if skip > 0 {
+                    file.seek(SeekFrom::Start(skip)).await?;
+                }
+                return Ok(Source::seekable(file));
+            }
+        }
+        Input::Stdin => decode(Box::new(io::stdin()), None)?,
+        Input::Pipe(name) => decode(Box::new(pipe::reader(name).map_err(context)?), None)?,
+        Input::Socket(hostname, port) => {
+            let stream = tokio::net::TcpStream::connect((hostname.as_str(), *port))
+                .await
+                .map_err(context)?;
+            let stream = stream.into_std().map_err(context)?;
+            stream.set_nonblocking(false).map_err(context)?;
+            decode(Box::new(stream), None)?
+        }
+        Input::Listen(address, port) => {
+            let listener = tokio::net::TcpListener::bind((address.as_str(), *port))
+                .await
+                .map_err(context)?;
+            let (stream, _) = listener.accept().await.map_err(context)?;
+            let stream = stream.into_std().map_err(context)?;
+            stream.set_nonblocking(false).map_err(context)?;
+            decode(Box::new(stream), None)?
+        }
+        Input::Multicast(group) => decode(
+            Box::new(MulticastReader::join(*group).map_err(context)?),
+            None,
+        )?,
+        Input::Join(join) => decode(Box::new(join.open().map_err(context)?), None)?,
+        Input::Generated { generator, len } => Box::new(generator.reader(*len)),
+        Input::Http(url) => {
+            // Skipped bytes can only be left out of the download if they are
+            // those served, which the name has to tell when skipping: the
+            // start of a range has no magic bytes to go by.
+            let path = url.split(['?', '#']).next().map(Path::new);
+            let mut decomp = decomp;
+            if skip > 0 {
+                decomp = decomp.resolve(path, &[]);
+            }
+            let cache = op.cache.as_deref().map(Cache::new);
+            if let Some(cached) = cache
+                .as_ref()
+                .map(|cache| cache.open(url))
+                .transpose()
+                .map_err(context)?
+                .flatten()
+            {
+                decode(Box::new(cached), path)?
+            } else {
+                let offset = if decomp == Decompression::None && dec.is_none() && cache.is_none() {
+                    skip
+                } else {
+                    0
+                };
+                let http = tokio::task::spawn_blocking({
+                    let url = url.clone();
+                    move || HttpReader::open(url, offset)
+                })
+                .await?
+                .map_err(context)?;
+                skip -= http.offset;
+                match &cache {
+                    Some(cache) => {
+                        let version = http.version.clone();
+                        decode(
+                            Box::new(cache.fill(url, http, version).map_err(context)?),
+                            path,
+                        )?
+                    }
+                    None => decode(Box::new(http), path)?,
+                }
+            }
+        }
+    };
+    if skip > 0 {
+        let skipped = io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+        if skipped < skip {
+            return Err(eyre!("Input ended while skipping")
+                .with_note(|| format!("input {input}, skipped {skipped} of {skip} bytes")));
+        }
+    }
+    Ok(Source::threaded(reader, block_size))
+}
+
+/// True if reading `path` again reads the same, as it does from a file or
+/// a disk but not a FIFO or a terminal. A path that can't be looked at is
+/// left for opening it to fail on.
+pub fn rereadable(path: &Path) -> bool {
+    let Ok(metadata) = path.metadata() else {
+        return true;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if metadata.file_type().is_block_device() {
+            return true;
+        }
+    }
+    metadata.is_file()
+}
+
+/// Open a file input for `iflag=direct`, saying so if it can't be.
+fn open_direct(file: std::fs::File, input: &Input) -> io::Result<DirectReader> {
+    let reader = DirectReader::new(file)?;
+    if !reader.is_direct() {
+        Diagnostic::new(Code::DirectUnavailable, "direct I/O isn't possible")
+            .subject(input)
+            .emit();
+    }
+    Ok(reader)
+}
+
+/// A download that asks for a range starting at the bytes it still needs,
+/// both to start part way in and to pick up again after the connection
+/// drops, if the server supports ranges.
+pub struct HttpReader {
+    url: String,
+    body: BodyReader<'static>,
+
+    /// Offset into the resource of the next byte the body yields
+    offset: u64,
+
+    /// True if the server serves ranges
+    ranges: bool,
+
+    /// What the server said identifies the version it served
+    pub version: Version,
+}
+
+impl HttpReader {
+    /// GET `url` from `offset` on. If the server ignores the range, the
+    /// download starts at 0, which `offset` then says.
+    pub fn open(url: String, offset: u64) -> io::Result<Self> {
+        let (body, offset, ranges, version) = get(&url, offset)?;
+        Ok(Self {
+            url,
+            body,
+            offset,
+            ranges,
+            version,
+        })
+    }
+}
+
+/// GET `url` from `offset` on, returning the body, the offset it starts at,
+/// whether it is a range, and the version of the resource served.
+fn get(url: &str, offset: u64) -> io::Result<(BodyReader<'static>, u64, bool, Version)> {
+    let mut request = ureq::get(url);
+    if offset > 0 {
+        request = request.header("Range", format!("bytes={offset}-"));
+    }
+    let response = request.call().map_err(io::Error::other)?;
+    let ranges = response.status().as_u16() == 206
+        || response
+            .headers()
+            .get("accept-ranges")
+            .is_some_and(|value| value == "bytes");
+    let offset = if response.status().as_u16() == 206 {
+        offset
+    } else {
+        0
+    };
+    let version = Version::of(&response);
+    Ok((response.into_body().into_reader(), offset, ranges, version))
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut resumes = 0;
+        loop {
+            match self.body.read(buf) {
+                Ok(n) => {
+                    self.offset += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if self.ranges && resumes < HTTP_RESUMES => {
+                    resumes += 1;
+                    Diagnostic::new(
+                        Code::InputResumed,
+                        format!("connection lost ({e}), resuming"),
+                    )
+                    .subject(format!("ihttp={}", self.url))
+                    .offset(self.offset)
+                    .emit();
+                    let (body, offset, ..) = get(&self.url, self.offset)?;
+                    if offset != self.offset {
+                        return Err(io::Error::other("server no longer serves ranges"));
+                    }
+                    self.body = body;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
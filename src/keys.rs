@@ -0,0 +1,274 @@
+use age::{scrypt, secrecy::SecretString, x25519};
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt, io,
+    path::PathBuf,
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::Arc,
+};
+
+/// Where the keys of `enc=age:SOURCE` and `dec=age:SOURCE` come from, asked
+/// for them once per run, when the input or outputs are opened rather than
+/// when the command line is read, so a passphrase or unsealed key is only
+/// held for as long as it is needed.
+pub trait KeyProvider: fmt::Display + Send + Sync {
+    fn keys(&self) -> io::Result<Keys>;
+}
+
+/// Keys given by a [`KeyProvider`].
+#[derive(Default)]
+pub struct Keys {
+    /// `age1...` public keys, which can only encrypt
+    pub recipients: Vec<x25519::Recipient>,
+
+    /// `AGE-SECRET-KEY-1...` keys, which decrypt, and encrypt to their
+    /// public key
+    pub identities: Vec<x25519::Identity>,
+
+    /// A passphrase, stretched with scrypt, in place of keys
+    pub passphrase: Option<SecretString>,
+}
+
+impl Keys {
+    /// Keys listed one per line, with `#` comments, as `age-keygen` writes
+    /// them or `age -R` reads them, from `origin`.
+    pub fn parse(text: &str, origin: &dyn fmt::Display) -> io::Result<Self> {
+        let mut keys = Keys::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |e: &dyn fmt::Display| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{origin} line {}: {e}", number + 1),
+                )
+            };
+            if line.starts_with("AGE-SECRET-KEY-") {
+                keys.identities
+                    .push(x25519::Identity::from_str(line).map_err(|e| invalid(&e))?);
+            } else {
+                keys.recipients
+                    .push(x25519::Recipient::from_str(line).map_err(|e| invalid(&e))?);
+            }
+        }
+        if keys.recipients.is_empty() && keys.identities.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{origin} holds no age keys"),
+            ));
+        }
+        Ok(keys)
+    }
+
+    /// Who to encrypt to: the public keys, and those of the secret keys.
+    pub fn recipients(self) -> Vec<Box<dyn age::Recipient + Send>> {
+        let mut recipients: Vec<Box<dyn age::Recipient + Send>> = vec![];
+        if let Some(passphrase) = self.passphrase {
+            recipients.push(Box::new(scrypt::Recipient::new(passphrase)));
+        }
+        for recipient in self.recipients {
+            recipients.push(Box::new(recipient));
+        }
+        for identity in self.identities {
+            recipients.push(Box::new(identity.to_public()));
+        }
+        recipients
+    }
+
+    /// What to decrypt with, if anything: public keys can't.
+    pub fn identities(self) -> Vec<Box<dyn age::Identity + Send + Sync>> {
+        let mut identities: Vec<Box<dyn age::Identity + Send + Sync>> = vec![];
+        if let Some(passphrase) = self.passphrase {
+            identities.push(Box::new(scrypt::Identity::new(passphrase)));
+        }
+        for identity in self.identities {
+            identities.push(Box::new(identity));
+        }
+        identities
+    }
+}
+
+/// A public key given on the command line, `enc=age:age1...`.
+struct Literal(x25519::Recipient);
+
+impl KeyProvider for Literal {
+    fn keys(&self) -> io::Result<Keys> {
+        Ok(Keys {
+            recipients: vec![self.0.clone()],
+            ..Keys::default()
+        })
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A key file, `file:PATH` or just the path.
+struct KeyFile(PathBuf);
+
+impl KeyProvider for KeyFile {
+    fn keys(&self) -> io::Result<Keys> {
+        let text = std::fs::read_to_string(&self.0).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to read key file {}, {e}", self.0.display()),
+            )
+        })?;
+        Keys::parse(&text, &self.0.display())
+    }
+}
+
+impl fmt::Display for KeyFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+/// Keys in an environment variable, `env:VAR`.
+struct EnvKey(String);
+
+impl KeyProvider for EnvKey {
+    fn keys(&self) -> io::Result<Keys> {
+        let text = std::env::var(&self.0)
+            .map_err(|e| io::Error::new(io::ErrorKind::NotFound, format!("${}: {e}", self.0)))?;
+        Keys::parse(&text, &format!("${}", self.0))
+    }
+}
+
+impl fmt::Display for EnvKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "env:{}", self.0)
+    }
+}
+
+/// A passphrase in an environment variable, `pass:VAR`, stretched into the
+/// file key with scrypt like `age -p` does.
+struct Passphrase(String);
+
+impl KeyProvider for Passphrase {
+    fn keys(&self) -> io::Result<Keys> {
+        let passphrase = std::env::var(&self.0)
+            .ok()
+            .filter(|passphrase| !passphrase.is_empty())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no passphrase in ${}", self.0),
+                )
+            })?;
+        Ok(Keys {
+            passphrase: Some(SecretString::from(passphrase)),
+            ..Keys::default()
+        })
+    }
+}
+
+impl fmt::Display for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pass:{}", self.0)
+    }
+}
+
+/// Keys printed by a program: a cloud KMS decrypting a wrapped key file
+/// (`kms:FILE`), a TPM unsealing one (`tpm:HANDLE`), or any command that
+/// prints keys (`cmd:COMMAND`), run with `sh -c`.
+struct Program {
+    /// As given, to name it without its arguments
+    source: String,
+    command: Vec<String>,
+}
+
+impl KeyProvider for Program {
+    fn keys(&self) -> io::Result<Keys> {
+        let output = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to run {} for {}, {e}", self.command[0], self.source),
+                )
+            })?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!(
+                "{} failed, {}: {}",
+                self.source,
+                output.status,
+                stderr.trim()
+            )));
+        }
+        let text = String::from_utf8(output.stdout).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} printed something other than age keys", self.source),
+            )
+        })?;
+        Keys::parse(&text, &self.source)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// The provider `source` names, from `enc=age:SOURCE` or `dec=age:SOURCE`:
+/// an `age1...` key, `file:PATH` or a path, `env:VAR`, `pass:VAR`,
+/// `kms:FILE`, `tpm:HANDLE` or `cmd:COMMAND`.
+pub fn provider(source: &str) -> Result<Arc<dyn KeyProvider>> {
+    let (kind, rest) = source.split_once(':').unwrap_or(("", source));
+    let nonempty = |what: &str| {
+        if rest.is_empty() {
+            Err(eyre!("Invalid key source")
+                .with_note(|| format!("key {source}"))
+                .with_suggestion(|| format!("expected {kind}:{what}")))
+        } else {
+            Ok(rest.to_string())
+        }
+    };
+    let program = |command: Vec<&str>| -> Arc<dyn KeyProvider> {
+        Arc::new(Program {
+            source: source.to_string(),
+            command: command.into_iter().map(str::to_string).collect(),
+        })
+    };
+    Ok(match kind {
+        "file" => Arc::new(KeyFile(PathBuf::from(nonempty("PATH")?))),
+        "env" => Arc::new(EnvKey(nonempty("VAR")?)),
+        "pass" => Arc::new(Passphrase(nonempty("VAR")?)),
+        // The AWS CLI prints the plaintext base64 encoded.
+        "kms" => program(vec![
+            "sh",
+            "-c",
+            "plaintext=$(aws kms decrypt --ciphertext-blob \"fileb://$1\" \
+             --query Plaintext --output text) && printf %s \"$plaintext\" | base64 -d",
+            "pdd",
+            &nonempty("FILE")?,
+        ]),
+        "tpm" => program(vec!["tpm2_unseal", "-c", &nonempty("HANDLE")?]),
+        "cmd" => program(vec!["sh", "-c", &nonempty("COMMAND")?]),
+        _ => match x25519::Recipient::from_str(source) {
+            Ok(recipient) => Arc::new(Literal(recipient)),
+            Err(_) if source.starts_with("age1") => {
+                return Err(eyre!("Invalid age public key")
+                    .with_note(|| format!("key {source}"))
+                    .with_suggestion(|| "check it was copied whole"));
+            }
+            Err(_) if source.is_empty() => {
+                return Err(eyre!("Missing key source").with_suggestion(
+                    || "give an age1... key, file:PATH, env:VAR, pass:VAR, kms:FILE, tpm:HANDLE or cmd:COMMAND",
+                ));
+            }
+            Err(_) => Arc::new(KeyFile(PathBuf::from(source))),
+        },
+    })
+}
@@ -0,0 +1,62 @@
+//! Fan-out copying: read one source once and write it to many outputs.
+//!
+//! [`engine::CopyEngine`] is the programmatic entry point; the `pdd` binary
+//! is a thin command line wrapper around it.
+
+pub mod advice;
+pub mod arguments;
+pub mod bmap;
+pub mod cache;
+pub mod carve;
+pub mod catalog;
+pub mod checkpoint;
+pub mod compress;
+pub mod config;
+pub mod container;
+pub mod convert;
+pub mod csv;
+pub mod device;
+pub mod diagnostic;
+pub mod direct;
+pub mod encrypt;
+pub mod engine;
+pub mod generate;
+pub mod gpt;
+pub mod hash;
+pub mod health;
+pub mod history;
+pub mod ids;
+pub mod input;
+pub mod keys;
+pub mod lifecycle;
+pub mod lock;
+pub mod log;
+pub mod multicast;
+pub mod offload;
+pub mod patch;
+pub mod permissions;
+pub mod pipe;
+pub mod plan;
+pub mod print;
+pub mod priority;
+pub mod profile;
+pub mod progress;
+pub mod redact;
+pub mod reframe;
+pub mod rekey;
+pub mod render;
+pub mod report;
+pub mod rescue;
+pub mod s3;
+pub mod scan;
+pub mod selftest;
+pub mod signals;
+pub mod sink;
+pub mod split;
+pub mod summary;
+pub mod template;
+pub mod throttle;
+pub mod trailer;
+pub mod value;
+pub mod verify;
+pub mod wave;
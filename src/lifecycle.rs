@@ -0,0 +1,143 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::log;
+
+/// Where an operation is in its run. Each goes through the states in
+/// order, skipping only those it has no part in, and ends in exactly one of
+/// [`State::Finalized`] or [`State::Failed`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Built, but nothing opened yet
+    Planned,
+
+    /// Reading the input and handing blocks to the outputs
+    Running,
+
+    /// Reading has stopped; every output drains what it was given and is
+    /// finished, once, before the operation moves on
+    Flushing,
+
+    /// Reading the outputs back, and whatever else is done to them once
+    /// they are whole
+    Verifying,
+
+    /// Done, with what each output got in its summary
+    Finalized,
+
+    /// Stopped by an error, an interrupt or an output aborting the copy
+    Failed,
+}
+
+impl State {
+    fn is_terminal(self) -> bool {
+        matches!(self, State::Finalized | State::Failed)
+    }
+
+    /// True if an operation can go from `self` to `next`. Failing is
+    /// possible from anywhere but the end; `Finalized` straight after
+    /// `Planned` is for operations with nothing left to do, like a resumed
+    /// copy that had already finished.
+    fn leads_to(self, next: State) -> bool {
+        use State::*;
+        match (self, next) {
+            (Finalized | Failed, _) => false,
+            (_, Failed) => true,
+            (Planned, Running | Finalized) => true,
+            (Running, Flushing) => true,
+            (Flushing, Verifying | Finalized) => true,
+            (Verifying, Finalized) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Planned => write!(f, "planned"),
+            State::Running => write!(f, "running"),
+            State::Flushing => write!(f, "flushing"),
+            State::Verifying => write!(f, "verifying"),
+            State::Finalized => write!(f, "finalized"),
+            State::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// The state of one operation, shared by what drives it, with a `state`
+/// event for every change (`log=json`).
+///
+/// An operation dropped before it got to the end, e.g. by a panic, fails
+/// then, so that every one ends exactly once.
+pub struct Lifecycle {
+    subject: String,
+    current: Mutex<(State, Instant)>,
+}
+
+impl Lifecycle {
+    /// An operation on `subject`, its input, that was just planned.
+    pub fn new(subject: impl Into<String>) -> Self {
+        let lifecycle = Self {
+            subject: subject.into(),
+            current: Mutex::new((State::Planned, Instant::now())),
+        };
+        lifecycle.emit(None, State::Planned, Duration::ZERO, None);
+        lifecycle
+    }
+
+    pub fn state(&self) -> State {
+        self.current.lock().unwrap_or_else(|e| e.into_inner()).0
+    }
+
+    /// Move on to `next`, which has to follow from the state now.
+    pub fn advance(&self, next: State) -> Result<()> {
+        self.change(next, None)
+    }
+
+    /// Fail with `reason`, unless the operation already ended.
+    pub fn fail(&self, reason: &str) {
+        let _ = self.change(State::Failed, Some(reason));
+    }
+
+    fn change(&self, next: State, reason: Option<&str>) -> Result<()> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let (state, since) = *current;
+        if !state.leads_to(next) {
+            return Err(eyre!("Operation can't go from {state} to {next}")
+                .with_note(|| format!("input {}", self.subject))
+                .with_note(|| "this is a bug in pdd"));
+        }
+        *current = (next, Instant::now());
+        drop(current);
+        self.emit(Some(state), next, since.elapsed(), reason);
+        Ok(())
+    }
+
+    /// The event for entering `to`, after `elapsed` in `from`.
+    fn emit(&self, from: Option<State>, to: State, elapsed: Duration, reason: Option<&str>) {
+        if !log::is_json() {
+            return;
+        }
+        log::event(serde_json::json!({
+            "type": "state",
+            "subject": self.subject,
+            "from": from.map(|from| from.to_string()),
+            "to": to.to_string(),
+            "elapsed": elapsed.as_secs_f64(),
+            "reason": reason,
+        }));
+    }
+}
+
+impl Drop for Lifecycle {
+    fn drop(&mut self) {
+        if !self.state().is_terminal() {
+            self.fail("ended before it finished");
+        }
+    }
+}
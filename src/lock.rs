@@ -0,0 +1,97 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{fs::File, io};
+
+use crate::arguments::Output;
+
+/// Take an exclusive advisory lock on an output file for as long as it stays
+/// open, so two copies can't write to the same target at once. Fails right
+/// away with the holder's PID, where it can be found, if it is locked.
+///
+/// Only other lockers are kept out; programs that don't lock can still
+/// write to it.
+pub fn lock(file: &File, output: &Output) -> Result<()> {
+    match imp::lock(file) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            let holder = imp::holder(file);
+            let mut report = match holder {
+                Some(pid) if pid == std::process::id() => {
+                    eyre!("Output is busy, it is written to more than once")
+                }
+                Some(pid) => eyre!("Output is busy, locked by PID {pid}"),
+                None => eyre!("Output is busy, locked by another process"),
+            };
+            report = report.with_note(|| format!("output {output}"));
+            Err(report.with_suggestion(
+                || "wait for the other copy to finish, or check that this is the right output",
+            ))
+        }
+        // Filesystems without locks, like some network ones, are written
+        // unlocked rather than not at all.
+        Err(_) => Ok(()),
+    }
+}
+
+/// Error for a block device that can't be opened exclusively.
+pub fn busy_device(e: io::Error, output: &Output) -> color_eyre::Report {
+    eyre!("Output is busy, the device is mounted or in use")
+        .with_error(|| e)
+        .with_note(|| format!("output {output}"))
+        .with_suggestion(|| "unmount its filesystems and stop whatever else has it open")
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{fs::File, io, os::fd::AsRawFd};
+
+    pub fn lock(file: &File) -> io::Result<()> {
+        // SAFETY: the descriptor is valid for the lifetime of `file`; the
+        // lock goes away with it.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The PID holding the lock on `file`, from `/proc/locks`, whose lines
+    /// look like `1: FLOCK  ADVISORY  WRITE 4242 08:02:1234 0 EOF`.
+    #[cfg(target_os = "linux")]
+    pub fn holder(file: &File) -> Option<u32> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = file.metadata().ok()?;
+        let dev = metadata.dev();
+        let id = format!(
+            "{:02x}:{:02x}:{}",
+            libc::major(dev),
+            libc::minor(dev),
+            metadata.ino()
+        );
+        let locks = std::fs::read_to_string("/proc/locks").ok()?;
+        locks.lines().find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [_, "FLOCK", _, _, pid, lock, ..] if lock == id => pid.parse().ok(),
+                _ => None,
+            }
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn holder(_file: &File) -> Option<u32> {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::{fs::File, io};
+
+    pub fn lock(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn holder(_file: &File) -> Option<u32> {
+        None
+    }
+}
@@ -0,0 +1,96 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set when pdd reports as JSON.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// How pdd reports what it does on stderr (`log=text|json`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Log {
+    /// Messages and a summary in the style of dd
+    #[default]
+    Text,
+
+    /// One JSON object per line: an event for every block written, every
+    /// diagnostic and every progress update, and a `result` document at the
+    /// end instead of the summary
+    Json,
+}
+
+impl FromStr for Log {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Log::Text),
+            "json" => Ok(Log::Json),
+            _ => Err(eyre!("Invalid log format")
+                .with_note(|| format!("input log={s}"))
+                .with_suggestion(|| "expected one of text, json")),
+        }
+    }
+}
+
+impl fmt::Display for Log {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Log::Text => write!(f, "text"),
+            Log::Json => write!(f, "json"),
+        }
+    }
+}
+
+pub fn set(log: Log) {
+    JSON.store(log == Log::Json, Ordering::Relaxed);
+}
+
+/// True if reporting as JSON (`log=json`).
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Print `event`, an object with a `type`, as one line of JSON to stderr.
+pub fn event(event: serde_json::Value) {
+    eprintln!("{event}");
+}
+
+/// Print the `result` document that ends the event stream, with the status
+/// pdd is about to exit with and the error that ended it, if any.
+pub fn result(
+    exit_status: i32,
+    error: Option<&color_eyre::Report>,
+    operations: Vec<serde_json::Value>,
+) {
+    event(serde_json::json!({
+        "type": "result",
+        "exit_status": exit_status,
+        "error": error.map(|e| {
+            e.chain()
+                .map(|cause| cause.to_string())
+                .collect::<Vec<_>>()
+                .join(": ")
+        }),
+        "operations": operations,
+    }));
+}
+
+/// Print a note about `subject`, as `subject: message` or a `message`
+/// event.
+pub fn message(subject: Option<&str>, message: &str) {
+    if is_json() {
+        event(serde_json::json!({
+            "type": "message",
+            "subject": subject,
+            "message": message,
+        }));
+        return;
+    }
+    match subject {
+        Some(subject) => eprintln!("{subject}: {message}"),
+        None => eprintln!("{message}"),
+    }
+}
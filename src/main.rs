@@ -1,141 +1,1532 @@
 use color_eyre::{Result, Section, eyre::eyre};
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Write},
+    io::Read,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::{Instant, SystemTime},
 };
-use tokio::sync::broadcast::{self, Receiver};
 
-pub mod arguments;
+use pdd::{
+    advice,
+    arguments::{Amount, Arguments, Input, Operation, Output},
+    bmap::{self, Bmap, BmapSink, Mapping},
+    cache::Cache,
+    carve::CarveSink,
+    catalog::{self, Catalog, Entry},
+    checkpoint::{Acked, Checkpoint, OutputCheckpoint, Saver},
+    compress::{self, CompressSink, Decompression, Decompressor},
+    container::{self, ContainerSink},
+    csv,
+    device::{self, DeviceIdentity},
+    diagnostic::{self, Code, Diagnostic},
+    direct::LENGTH_ALIGNMENT,
+    encrypt::EncryptSink,
+    engine::{CopyEngine, ErrorPolicy, Source},
+    gpt::{self, GptFix},
+    health::Health,
+    history,
+    ids::{self, NewIds},
+    input,
+    lifecycle::{Lifecycle, State},
+    log::{self, Log},
+    patch::{self, PatchSink},
+    pipe,
+    plan::{self, VerifyPlan},
+    profile::{OperationProfile, StageKind},
+    progress::Status,
+    reframe::ReframeSink,
+    rekey,
+    render::Renderer,
+    report::{self, Report},
+    rescue::{self, RescueMap, Status as MapStatus},
+    s3,
+    scan::ScanSink,
+    selftest,
+    signals::Signals,
+    sink::{self, Delta},
+    split::{Layout, OpenShard, ShardSink},
+    summary::{OutputSummary, Summary},
+    template::{self, Variables},
+    throttle::ThrottleSink,
+    trailer::{Trailer, TrailerMode, TrailerSink},
+    verify::{self, Chunks, RepairFrom, Verification, VerifySink},
+};
+
+/// Switch disk devices over to their raw nodes, unless `--no-rdisk`, and
+/// check that the operation's I/O is aligned for devices requiring it.
+fn prepare_devices(op: &mut Operation, args: &Arguments) -> Result<()> {
+    if !args.no_rdisk {
+        if let Input::File(path) = &mut op.input {
+            *path = device::raw_path(path);
+        }
+        for output in &mut op.outputs {
+            if let Output::File(path) = output {
+                *path = device::raw_path(path);
+            }
+        }
+    }
+    let mut devices = vec![];
+    if let Input::File(path) = &op.input {
+        devices.push((path.clone(), ("ibs", op.block_size), op.skip_bytes()?, None));
+    }
+    for (index, output) in op.outputs.iter().enumerate() {
+        if let Output::File(path) = output {
+            device::prepare_write(path)?;
+            let block_size = match op.output_frames[index] {
+                Some(frame) => ("oframe", frame),
+                None => ("obs", op.output_block_size),
+            };
+            devices.push((path.clone(), block_size, op.seek_bytes()?, Some(index)));
+        }
+    }
+    for (path, (key, block_size), offset, output) in devices {
+        let Some(sector) = device::sector_size(&path) else {
+            continue;
+        };
+        // An output is written in whole sectors, whatever its blocks, unless
+        // it was given frames of its own.
+        if let Some(index) = output
+            && op.output_frames[index].is_none()
+            && offset.is_multiple_of(sector)
+        {
+            if !block_size.is_multiple_of(sector) {
+                op.output_frames[index] = Some(sector);
+            }
+            continue;
+        }
+        if !block_size.is_multiple_of(sector) || !offset.is_multiple_of(sector) {
+            return Err(eyre!(
+                "{} needs I/O aligned to its {sector} byte sectors",
+                path.display()
+            )
+            .with_note(|| format!("{key}={block_size} at offset {offset}"))
+            .with_suggestion(
+                || "use a block size and skip or seek that are multiples of it, e.g. bs=1M",
+            ));
+        }
+    }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Argument {
-    InputFile(PathBuf),
-    OutputFile(PathBuf),
-    BlockSize(usize),
-    BlockCount(usize),
+    // Generated data without count= fills the smallest output device, so
+    // wiping a disk ends at its end instead of with a write error. With none
+    // it would never end, unless after duration=.
+    if let Input::Generated { len: None, .. } = op.input
+        && op.count.is_zero()
+    {
+        let seek = op.seek_bytes()?;
+        let smallest = op
+            .outputs
+            .iter()
+            .filter_map(|output| match output {
+                Output::File(path) if path.metadata().is_ok_and(|metadata| !metadata.is_file()) => {
+                    device::size(path)
+                }
+                _ => None,
+            })
+            .min();
+        if smallest.is_none() && op.duration.is_none() {
+            return Err(eyre!("{} never ends without count=", op.input)
+                .with_note(|| "no output is a device whose size it could fill")
+                .with_suggestion(|| "say how much to write, e.g. count=1G, or give duration="));
+        }
+        if let Input::Generated { len, .. } = &mut op.input {
+            *len = smallest.map(|size| size.saturating_sub(seek));
+        }
+    }
+
+    // Refuse up front to copy more than an output device can hold, rather
+    // than failing once it runs out of room. A disk image being fixed up
+    // only needs its partitions to fit, so it is cut short at the end of the
+    // smallest device instead.
+    let Some(len) = input_len(op)? else {
+        return Ok(());
+    };
+    if op.fix_gpt && op.count.is_zero() {
+        shrink_to_fit(op, len)?;
+    }
+    let len = input_len(op)?.unwrap_or(len);
+    for output in &op.outputs {
+        if let Output::File(path) = output
+            && path.metadata().is_ok_and(|metadata| !metadata.is_file())
+            && let Some(size) = device::size(path)
+            && op.seek_bytes()?.saturating_add(len) > size
+        {
+            return Err(eyre!("{output} is too small for {}", op.input)
+                .with_note(|| {
+                    format!(
+                        "{len} bytes to copy at offset {} into a {size} byte device",
+                        op.seek_bytes().unwrap_or_default()
+                    )
+                })
+                .with_suggestion(|| "copy less with count=, or choose a larger device"));
+        }
+    }
+    Ok(())
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Argies {
-    pub input_file: Option<PathBuf>,
-    pub output_files: Vec<PathBuf>,
-    pub block_size: usize,
-    pub block_count: usize,
+/// With `fix-gpt=1`, copy a GPT disk image `len` bytes long only as far as
+/// the smallest output device it doesn't fit on, if its partitions do: the
+/// backup table left out is rebuilt at the end of each output afterwards.
+fn shrink_to_fit(op: &mut Operation, len: u64) -> Result<()> {
+    let Input::File(input) = &op.input else {
+        return Ok(());
+    };
+    let seek = op.seek_bytes()?;
+    let Some((output, size)) = op
+        .outputs
+        .iter()
+        .filter_map(|output| match output {
+            Output::File(path) if path.metadata().is_ok_and(|metadata| !metadata.is_file()) => {
+                device::size(path).map(|size| (output, size.saturating_sub(seek)))
+            }
+            _ => None,
+        })
+        .min_by_key(|&(_, size)| size)
+        .filter(|&(_, size)| len > size)
+    else {
+        return Ok(());
+    };
+    let end = match op.skip_bytes()? {
+        0 => gpt::image_end(input).ok().flatten(),
+        _ => None,
+    };
+    if end.is_none_or(|end| end > size) {
+        return Err(eyre!("{output} is too small for {}", op.input)
+            .with_note(|| match end {
+                Some(end) => format!("its partitions need {end} bytes of the {size} there are"),
+                None => format!("{len} bytes to copy into {size}, and no GPT to cut it short at"),
+            })
+            .with_suggestion(|| "choose a larger device, or copy less with count="));
+    }
+    log::message(
+        Some(&op.input.to_string()),
+        &format!(
+            "copying the first {size} bytes, which hold its partitions, for {output}; \
+             fix-gpt=1 rebuilds the backup table at its end"
+        ),
+    );
+    op.count = Amount::Bytes(size);
+    Ok(())
 }
 
-impl Default for Argies {
-    fn default() -> Self {
-        Self {
-            input_file: None,
-            output_files: vec![],
-            block_size: 1024,
-            block_count: 0,
+/// Refuse the operation unless its input file has the digest of the
+/// catalog entry named with `verify-catalog=`, before any output is touched,
+/// and give the entry so the copy can check it again as it reads.
+///
+/// The input is read twice, so a copy reading all of it hashes it again as
+/// it goes and abandons its outputs if it changed in between. One reading
+/// only part of it, with `skip=`, `count=`, `duration=`, a trailer, or
+/// unpacking it, can't, and trusts it not to change after this check.
+fn check_catalog(op: &Operation, args: &Arguments) -> Result<Option<Entry>> {
+    let Some(name) = &op.verify_catalog else {
+        return Ok(None);
+    };
+    let Input::File(path) = &op.input else {
+        return Err(eyre!("verify-catalog= needs a file or device input")
+            .with_note(|| format!("input {}", op.input)));
+    };
+    let catalog_path = args
+        .catalog
+        .clone()
+        .or_else(Catalog::default_path)
+        .ok_or_else(|| {
+            eyre!("No catalog to verify against").with_suggestion(|| "give one with --catalog FILE")
+        })?;
+    let catalog = Catalog::load(&catalog_path)?;
+    let entry = catalog.get(name).ok_or_else(|| {
+        eyre!("No catalog entry named {name}")
+            .with_note(|| format!("catalog {}", catalog_path.display()))
+            .with_suggestion(|| format!("add it with pdd catalog add {name} FILE"))
+    })?;
+    let digest = catalog::digest_file(entry.algorithm, path).map_err(|e| {
+        eyre!("Failed to read input to verify it")
+            .with_error(|| e)
+            .with_note(|| format!("input {}", op.input))
+    })?;
+    if digest != entry.digest {
+        return Err(eyre!(
+            "{} doesn't match the catalog, refusing to write it",
+            path.display()
+        )
+        .with_note(|| format!("expected {name} {}:{}", entry.algorithm, entry.digest))
+        .with_note(|| format!("got {}:{digest}", entry.algorithm)));
+    }
+    if args.status != Status::None {
+        log::message(
+            Some(&op.input.to_string()),
+            &format!("matches catalog entry {name}"),
+        );
+    }
+    Ok(Some(entry.clone()))
+}
+
+/// Bytes the operation will read, if that can be told before copying:
+/// uncompressed, unencrypted file and device inputs, and generated ones with
+/// `count=`.
+fn input_len(op: &Operation) -> Result<Option<u64>> {
+    // Generated data is as long as it is asked to be.
+    if let Input::Generated { len, .. } = op.input {
+        if !op.count.is_zero() {
+            return Ok(Some(op.count.bytes(op.block_size).unwrap_or(u64::MAX)));
         }
+        return Ok(len);
+    }
+    let Input::File(path) = &op.input else {
+        return Ok(None);
+    };
+    if op.dec.is_some() {
+        return Ok(None);
+    }
+    let Some(mut size) = device::size(path) else {
+        return Ok(None);
+    };
+    if let Some(trailer) = input_trailer(op)? {
+        size = trailer.length;
     }
+    let mut magic = [0u8; 4];
+    let n = match std::fs::File::open(path) {
+        Ok(mut file) => compress::read_full(&mut file, &mut magic).unwrap_or(0),
+        Err(_) => return Ok(None),
+    };
+    if op.decomp.resolve(Some(path), &magic[..n]) != Decompression::None {
+        return Ok(None);
+    }
+    // Unpacked, the stream is shorter than the container.
+    if op.decomp != Decompression::None && container::detect(path) {
+        return Ok(None);
+    }
+    let mut len = size.saturating_sub(op.skip_bytes()?);
+    if !op.count.is_zero() {
+        len = len.min(op.count.bytes(op.block_size).unwrap_or(u64::MAX));
+    }
+    Ok(Some(len))
 }
 
-impl Argument {
-    pub fn parse() -> Result<Argies> {
-        let mut args = Argies::default();
-        for arg in std::env::args() {
-            let Some((lhs, rhs)) = arg.split_once("=") else {
-                continue;
+/// The integrity trailer at the end of a file input, unless `trailer=keep`.
+fn input_trailer(op: &Operation) -> Result<Option<Trailer>> {
+    let Input::File(path) = &op.input else {
+        return Ok(None);
+    };
+    if op.trailer == TrailerMode::Keep {
+        return Ok(None);
+    }
+    match Trailer::detect(path) {
+        // A missing input is reported once opening it fails.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        result => result.map_err(|e| {
+            eyre!("Failed to read the trailer of input")
+                .with_error(|| e)
+                .with_note(|| format!("input {}", op.input))
+                .with_suggestion(|| "copy it as it is with trailer=keep")
+        }),
+    }
+}
+
+/// Load the checkpoint of an operation with `resume=`, or start a new one.
+///
+/// Only a seekable input, or a download, and outputs that are written in
+/// place can be picked up part way through.
+fn load_checkpoint(op: &Operation, path: &Path, skip: u64, seek: u64) -> Result<Checkpoint> {
+    let unsupported = |what: &str| {
+        eyre!("resume= can't be used with {what}")
+            .with_note(|| format!("resume={}", path.display()))
+    };
+    if !matches!(op.input, Input::File(_) | Input::Http(_)) {
+        return Err(unsupported(&op.input.to_string()));
+    }
+    if let Some(output) = op
+        .outputs
+        .iter()
+        .find(|output| !matches!(output, Output::File(_)))
+    {
+        return Err(unsupported(&output.to_string()));
+    }
+    if op.comp.is_some() {
+        return Err(unsupported("compressed outputs"));
+    }
+    if op.enc.is_some() {
+        return Err(unsupported("encrypted outputs"));
+    }
+    let fresh = Checkpoint {
+        input: op.input.to_string(),
+        block_size: op.block_size,
+        skip,
+        seek,
+        outputs: op
+            .outputs
+            .iter()
+            .map(|output| OutputCheckpoint {
+                name: output.to_string(),
+                written: 0,
+                complete: false,
+            })
+            .collect(),
+    };
+    let Some(checkpoint) = Checkpoint::load(path)? else {
+        return Ok(fresh);
+    };
+    let same_outputs = checkpoint.outputs.len() == fresh.outputs.len()
+        && checkpoint
+            .outputs
+            .iter()
+            .zip(&fresh.outputs)
+            .all(|(saved, output)| saved.name == output.name);
+    if checkpoint.input != fresh.input
+        || checkpoint.block_size != fresh.block_size
+        || checkpoint.skip != fresh.skip
+        || checkpoint.seek != fresh.seek
+        || !same_outputs
+    {
+        return Err(eyre!("Checkpoint is for a different operation")
+            .with_note(|| format!("resume={}", path.display()))
+            .with_suggestion(|| {
+                "give the same input, outputs, bs, skip and seek, or delete the file to start over"
+            }));
+    }
+    Ok(checkpoint)
+}
+
+/// Read the regions of a rescue map that haven't been read yet again, and
+/// write what reads into the outputs the first pass wrote.
+async fn rescue_again(
+    op: &Operation,
+    mut map: RescueMap,
+    path: &Path,
+    skip: u64,
+    seek: u64,
+    args: &Arguments,
+    signals: &Signals,
+) -> Result<()> {
+    let name = op.input.to_string();
+    let pending = map.pending().count();
+    if pending == 0 {
+        if args.status != Status::None {
+            log::message(
+                Some(&name),
+                &format!("every region in {} has been read", path.display()),
+            );
+        }
+        return Ok(());
+    }
+    let Input::File(input) = &op.input else {
+        return Err(eyre!("rescue= only reads files and devices"));
+    };
+    let input = std::fs::File::open(input).map_err(|e| {
+        eyre!("Failed to open input")
+            .with_error(|| e)
+            .with_note(|| format!("input {name}"))
+    })?;
+    let mut outputs = vec![];
+    for output in &op.outputs {
+        let Output::File(file) = output else {
+            return Err(eyre!("rescue= only writes files and devices"));
+        };
+        outputs.push(
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(file)
+                .map_err(|e| {
+                    eyre!("Failed to open output")
+                        .with_error(|| e)
+                        .with_note(|| format!("output {output}"))
+                        .with_suggestion(|| "a second pass writes into the outputs of the first")
+                })?,
+        );
+    }
+    if args.status != Status::None {
+        log::message(
+            Some(&name),
+            &format!(
+                "reading {} bytes in {pending} regions of {} again",
+                map.pending().map(|extent| extent.len).sum::<u64>(),
+                path.display()
+            ),
+        );
+    }
+    let shift = i64::try_from(seek)? - i64::try_from(skip)?;
+    let block_size = usize::try_from(op.block_size)?;
+    let interrupt = signals.interrupted().clone();
+    let path = path.to_path_buf();
+    let (map, retried) = tokio::task::spawn_blocking(move || {
+        rescue::retry(
+            &mut map, &path, &input, &outputs, shift, block_size, &interrupt,
+        )
+        .map(|retried| (map, retried))
+    })
+    .await??;
+    if args.status != Status::None {
+        log::message(
+            Some(&name),
+            &format!(
+                "rescued {} of {} bytes, {} still unreadable",
+                retried.rescued,
+                retried.tried,
+                map.bytes(MapStatus::Unreadable)
+            ),
+        );
+    }
+    Ok(())
+}
+
+/// Write just the blocks the block map at `path` maps of the input image to
+/// the outputs (`ibmap=FILE`), reading past the rest.
+async fn flash_mapped(
+    op: &Operation,
+    path: &Path,
+    seek: u64,
+    args: &Arguments,
+    signals: &Signals,
+) -> Result<()> {
+    let name = op.input.to_string();
+    let bmap = Bmap::load(path)?;
+    let reader: Box<dyn Read + Send> = match &op.input {
+        Input::File(image) => {
+            let file = std::fs::File::open(image).map_err(|e| {
+                eyre!("Failed to open input")
+                    .with_error(|| e)
+                    .with_note(|| format!("input {name}"))
+            })?;
+            Box::new(Decompressor::new(file, Some(image), op.decomp))
+        }
+        _ => Box::new(Decompressor::new(std::io::stdin(), None, op.decomp)),
+    };
+    let mut outputs = vec![];
+    for output in &op.outputs {
+        let Output::File(file) = output else {
+            return Err(eyre!("ibmap= only writes files and devices"));
+        };
+        outputs.push(
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(file)
+                .map_err(|e| {
+                    eyre!("Failed to open output")
+                        .with_error(|| e)
+                        .with_note(|| format!("output {output}"))
+                })?,
+        );
+    }
+    if args.status != Status::None {
+        log::message(
+            Some(&name),
+            &format!(
+                "writing the {} of {} blocks mapped in {}",
+                bmap.mapped_blocks(),
+                bmap.blocks(),
+                path.display()
+            ),
+        );
+    }
+    let interrupt = signals.interrupted().clone();
+    let start = Instant::now();
+    let flashed = tokio::task::spawn_blocking(move || {
+        let mut reader = reader;
+        bmap::flash(&bmap, &mut reader, &outputs, seek, &interrupt)
+    })
+    .await??;
+    if args.status != Status::None {
+        log::message(
+            Some(&name),
+            &format!(
+                "wrote {} bytes in {} ranges, all matching the map, in {:.3} s",
+                flashed.written,
+                flashed.ranges,
+                start.elapsed().as_secs_f64()
+            ),
+        );
+    }
+    Ok(())
+}
+
+/// Run one operation through its [`Lifecycle`], which ends as the operation
+/// does: failed if it stopped short or with an error, finalized otherwise.
+async fn run(
+    op: Operation,
+    args: &Arguments,
+    signals: &Signals,
+    health: Option<&Health>,
+) -> Result<Option<Report>> {
+    let lifecycle = Arc::new(Lifecycle::new(op.input.to_string()));
+    match run_planned(op, args, signals, health, &lifecycle).await {
+        Ok(Some(report)) if report.summary.interrupted => {
+            lifecycle.fail("interrupted");
+            Ok(Some(report))
+        }
+        Ok(Some(report)) if report.summary.aborted => {
+            lifecycle.fail("an output failed with onerror=abort");
+            Ok(Some(report))
+        }
+        Ok(report) => {
+            lifecycle.advance(State::Finalized)?;
+            Ok(report)
+        }
+        Err(e) => {
+            lifecycle.fail(&e.to_string());
+            Err(e)
+        }
+    }
+}
+
+/// Run one operation, returning its report, or `None` if its checkpoint
+/// shows there is nothing left to do, or it only read the regions of a
+/// rescue map again.
+async fn run_planned(
+    mut op: Operation,
+    args: &Arguments,
+    signals: &Signals,
+    health: Option<&Health>,
+    lifecycle: &Arc<Lifecycle>,
+) -> Result<Option<Report>> {
+    if let Some(wave) = op.wave
+        && args.status != Status::None
+    {
+        let targets = op
+            .outputs
+            .iter()
+            .filter(|output| !matches!(output, Output::Hash { .. }))
+            .count();
+        log::message(
+            Some(&op.input.to_string()),
+            &format!(
+                "writing wave {} of {}, outputs {} to {}",
+                wave.number,
+                wave.count,
+                wave.first + 1,
+                wave.first + targets
+            ),
+        );
+    }
+    let catalogued = check_catalog(&op, args)?;
+    let started = SystemTime::now();
+    let start = Instant::now();
+    let mut variables = Variables::new(&op.input, started);
+    // Outputs named after the digest are renamed once it is known.
+    let mut renames = vec![];
+    for (index, output) in op.outputs.iter_mut().enumerate() {
+        if let Output::Auto(template) = output {
+            let name = if template.needs_hash() {
+                renames.push((index, template.clone()));
+                let mut partial = variables.clone();
+                partial.hash(&template::partial(index));
+                template.render(&partial)
+            } else {
+                template.render(&variables)
             };
-            let (lhs, rhs) = (lhs.to_ascii_lowercase(), rhs.to_ascii_lowercase());
-            match lhs.as_str() {
-                "if" => {
-                    let path = PathBuf::from(rhs);
-                    if !path.exists() {
-                        return Err(eyre!("Input file does not exist")
-                            .with_note(|| format!("input if={}", path.display().to_string())));
+            *output = Output::File(PathBuf::from(name));
+        }
+    }
+    prepare_devices(&mut op, args)?;
+    let mut patches = vec![];
+    for path in &op.patches {
+        patches.extend(patch::load(path)?);
+    }
+    let mut skip = op.skip_bytes()?;
+    let mut seek = op.seek_bytes()?;
+    let block_size = usize::try_from(op.block_size)?;
+    let mut count = op.count;
+
+    // A rescue with a map already made only goes over what it didn't get.
+    if let Some(path) = &op.rescue
+        && let Some(map) = RescueMap::load(path)?
+    {
+        // Each pass syncs the outputs as it ends.
+        lifecycle.advance(State::Running)?;
+        rescue_again(&op, map, path, skip, seek, args, signals).await?;
+        lifecycle.advance(State::Flushing)?;
+        return Ok(None);
+    }
+    if let Some(path) = &op.ibmap {
+        lifecycle.advance(State::Running)?;
+        flash_mapped(&op, path, seek, args, signals).await?;
+        lifecycle.advance(State::Flushing)?;
+        return Ok(None);
+    }
+
+    // Resuming carries on from where the slowest unfinished output stopped.
+    let mut checkpoint = None;
+    let mut resumed = 0;
+    if let Some(path) = &op.resume {
+        let saved = load_checkpoint(&op, path, skip, seek)?;
+        resumed = saved.resume_offset();
+        let blocks = resumed / op.block_size;
+        let counted = match count {
+            Amount::Blocks(count) => count > 0 && blocks >= count,
+            Amount::Bytes(count) => count > 0 && resumed >= count,
+        };
+        if saved.is_complete() || counted {
+            if args.status != Status::None {
+                log::message(
+                    Some(&op.input.to_string()),
+                    "every output is already complete",
+                );
+            }
+            return Ok(None);
+        }
+        count = match count {
+            Amount::Blocks(count) if count > 0 => Amount::Blocks(count - blocks),
+            Amount::Bytes(count) if count > 0 => Amount::Bytes(count - resumed),
+            count => count,
+        };
+        if args.status != Status::None {
+            for output in saved.outputs.iter().filter(|output| output.complete) {
+                log::message(Some(&output.name), "already complete, skipping");
+            }
+        }
+        skip += resumed;
+        seek += resumed;
+        checkpoint = Some(saved);
+    }
+
+    let trailer = input_trailer(&op)?;
+    if trailer.is_some()
+        && op.trailer == TrailerMode::Strip
+        && op
+            .outputs
+            .iter()
+            .any(|output| !matches!(output, Output::Hash { .. }))
+    {
+        Diagnostic::new(
+            Code::TrailerStripped,
+            "the trailer was checked and left out of the copy; trailer=keep copies it and \
+             trailer=add writes a new one",
+        )
+        .subject(&op.input)
+        .emit();
+    }
+    if let (Some(dir), Input::Http(url)) = (&op.cache, &op.input) {
+        let cache = Cache::new(dir);
+        cache.revalidate(url);
+        if args.status != Status::None && cache.contains(url) {
+            log::message(
+                Some(&op.input.to_string()),
+                &format!("reading the copy kept in {}", dir.display()),
+            );
+        }
+    }
+    let source = input::open(&op, skip, block_size, trailer.as_ref()).await?;
+    // Outputs that didn't read back right can have their chunks copied
+    // again from where they came from, when they hold the input as it is:
+    // copied again, patched bytes would be put back as they were read.
+    let repair_from = match &op.input {
+        Input::File(path)
+            if matches!(source, Source::Seekable(_))
+                && op.verify_retries > 0
+                && op.conv.conversion().is_none()
+                && !op.conv.sync
+                && !op.conv.noerror
+                && op.redactions.is_empty()
+                && op.patches.is_empty()
+                && op.injections.is_empty()
+                && op.rescue.is_none()
+                && op.layout == Layout::Mirror
+                && op.comp.is_none()
+                && op.enc.is_none() =>
+        {
+            Some(RepairFrom {
+                input: path.clone(),
+                skip,
+                retries: op.verify_retries,
+            })
+        }
+        _ => None,
+    };
+    // Blocks a file read as it is has allocated are mapped, as bmaptool
+    // maps them, zeros or not.
+    let allocated = match &op.input {
+        Input::File(path)
+            if op.obmap.is_some()
+                && matches!(source, Source::Seekable(_))
+                && op.conv.conversion().is_none()
+                && !op.conv.sync =>
+        {
+            bmap::allocated(&std::fs::File::open(path)?, skip)?
+        }
+        _ => None,
+    };
+    let mut engine = CopyEngine::new(source, op.input.to_string());
+    engine.block_size(block_size);
+    if op.output_block_size != op.block_size {
+        engine.output_block_size(usize::try_from(op.output_block_size)?);
+    }
+    match count {
+        Amount::Blocks(count) => engine.count(count),
+        Amount::Bytes(count) if count > 0 => engine.count_bytes(count),
+        Amount::Bytes(_) => {}
+    }
+    if op.layout == Layout::Split {
+        engine.split(op.split.unwrap_or(op.block_size));
+    }
+    if let Some(duration) = op.duration {
+        engine.duration(duration);
+    }
+    if let Some(idle_timeout) = op.idle_timeout {
+        engine.idle_timeout(idle_timeout);
+    }
+    engine.position(skip);
+    engine.patches(patches.clone());
+    engine.redactions(op.redactions.clone());
+    engine.conversion(op.conv.conversion());
+    engine.pad(op.conv.sync);
+    engine.noerror(op.conv.noerror);
+    engine.fullblock(op.iflag.fullblock);
+    if let Some(limit) = op.limit {
+        engine.limit(limit);
+    }
+    let len = input_len(&op)?;
+    if let Some(len) = len {
+        engine.expect(len.saturating_sub(resumed));
+    }
+    // Checked against the catalog before the copy, the input is checked
+    // again as it is read, if all of it is, in case it changed since.
+    if let (Some(entry), Input::File(path)) = (&catalogued, &op.input)
+        && skip == 0
+        && count.is_zero()
+        && op.duration.is_none()
+        && op.idle_timeout.is_none()
+        && len.is_some_and(|len| Some(len) == device::size(path))
+    {
+        engine.digest(entry.algorithm, entry.digest.clone());
+    }
+    // A file read as it is can be copied by the kernel, if the engine
+    // finds nothing else is done to the stream.
+    if let Input::File(path) = &op.input
+        && len.is_some()
+        && trailer.is_none()
+        && !op.iflag.direct
+        && path.metadata().is_ok_and(|metadata| metadata.is_file())
+    {
+        engine.offload(std::fs::File::open(path).map_err(|e| {
+            eyre!("Failed to open input")
+                .with_error(|| e)
+                .with_note(|| format!("input {}", op.input))
+        })?);
+    }
+    if let (Some(_), Input::File(path)) = (&op.rescue, &op.input) {
+        engine.rescue(std::fs::File::open(path).map_err(|e| {
+            eyre!("Failed to open input")
+                .with_error(|| e)
+                .with_note(|| format!("input {}", op.input))
+        })?);
+    }
+    // Parts big enough that the whole input fits in one upload.
+    for output in &mut op.outputs {
+        if let Output::S3 { part_size, .. } = output {
+            *part_size = s3::part_size(*part_size, len);
+        }
+    }
+    engine.interrupt(signals.interrupted().clone());
+
+    // Hash outputs see the stream as read, every other output is a target
+    // that gets its own injected data.
+    let targets = op
+        .outputs
+        .iter()
+        .filter(|output| !matches!(output, Output::Hash { .. }))
+        .count();
+    let first = op.wave.map_or(0, |wave| wave.first);
+    let mut injections = patch::generate(&op.injections, first, targets)?.into_iter();
+    // Keys are fetched once, however many outputs are encrypted to them.
+    let recipients = op
+        .enc
+        .as_ref()
+        .map(|enc| {
+            enc.recipients().map_err(|e| {
+                eyre!("Failed to get encryption keys")
+                    .with_error(|| e)
+                    .with_note(|| format!("enc={enc}"))
+            })
+        })
+        .transpose()?;
+    let wipe = op.oflag.wipe_tail.then(|| sink::Wipe {
+        report: args.status != Status::None,
+        interrupt: signals.interrupted().clone(),
+    });
+    let mut extras = vec![];
+    let mut planned = None;
+    // Indices into the checkpoint of the outputs being written
+    let mut active = vec![];
+    let outputs = op
+        .outputs
+        .iter()
+        .zip(&op.output_limits)
+        .zip(&op.output_frames)
+        .zip(&op.on_error)
+        .zip(&op.permissions);
+    for (index, ((((output, limit), frame), on_error), permissions)) in outputs.enumerate() {
+        if let Some(checkpoint) = &checkpoint
+            && checkpoint.outputs[index].complete
+        {
+            // Still take its injections so the others keep their own.
+            injections.next();
+            continue;
+        }
+        active.push(index);
+        let delta = (op.conv.delta && matches!(output, Output::File(_)))
+            .then(|| Arc::new(Mutex::new(Delta::default())));
+        let mut writer = match (op.layout, op.split, output) {
+            (Layout::Mirror, Some(size), Output::File(base)) => {
+                let (input, conv, oflag, permissions, delta, wipe) = (
+                    op.input.clone(),
+                    op.conv,
+                    op.oflag,
+                    *permissions,
+                    delta.clone(),
+                    wipe.clone(),
+                );
+                let open: OpenShard = Box::new(move |path: &Path| {
+                    let shard = Output::File(path.to_path_buf());
+                    sink::open(
+                        &shard,
+                        0,
+                        &input,
+                        &conv,
+                        &oflag,
+                        &permissions,
+                        delta.clone(),
+                        wipe.as_ref(),
+                    )
+                });
+                Box::new(ShardSink::new(base.clone(), size, open))
+            }
+            _ => sink::open(
+                output,
+                seek,
+                &op.input,
+                &op.conv,
+                &op.oflag,
+                permissions,
+                delta.clone(),
+                wipe.as_ref(),
+            )?,
+        };
+        // Writes sized for what takes them, so below everything that changes
+        // their size. Direct writes have to be whole sectors.
+        let frame = frame.or_else(|| {
+            (op.oflag.direct && matches!(output, Output::File(_))).then(|| {
+                op.output_block_size
+                    .next_multiple_of(LENGTH_ALIGNMENT as u64)
+            })
+        });
+        if let Some(size) = frame {
+            writer = Box::new(ReframeSink::new(writer, usize::try_from(size)?));
+        }
+        // Over exactly what the file holds, so after compression.
+        if op.trailer == TrailerMode::Add
+            && let Output::File(_) = output
+        {
+            writer = Box::new(TrailerSink::new(writer, op.input.to_string()));
+        }
+        // Framed as it leaves, so the container holds the stream compressed
+        // and encrypted, and a trailer covers the container. A socket is
+        // always framed, for the receiver to tell a stream cut short.
+        if (op.container.is_some() && !matches!(output, Output::Hash { .. }))
+            || matches!(output, Output::Socket(..))
+        {
+            writer = Box::new(ContainerSink::new(
+                writer,
+                &op.input.to_string(),
+                op.comp.is_some(),
+                op.enc.is_some(),
+            )?);
+        }
+        // Throttled on what actually leaves, after compression.
+        if let Some(rate) = *limit {
+            writer = Box::new(ThrottleSink::new(writer, rate));
+        }
+        // Record what actually goes to the file or device, after injection,
+        // so it can be read back and compared once the copy is done.
+        let mut verify = None;
+        if op.verify
+            && let Output::File(path) = output
+        {
+            let chunks = Arc::new(Mutex::new(Chunks::default()));
+            writer = Box::new(VerifySink::new(writer, chunks.clone()));
+            verify = Some((path.clone(), chunks));
+        }
+        // Encrypted after compression, since ciphertext doesn't compress.
+        if let Some(recipients) = &recipients
+            && !matches!(output, Output::Hash { .. })
+        {
+            writer = Box::new(EncryptSink::new(writer, recipients)?);
+        }
+        // Compressed after injection, so the injected data ends up in the
+        // compressed stream, which is then what verification reads back
+        // from the file.
+        if let Some(comp) = op.comp
+            && !matches!(output, Output::Hash { .. })
+        {
+            writer = Box::new(CompressSink::new(writer, comp)?);
+        }
+        // The same, for a receiver elsewhere to compare its copy against
+        // once it has decompressed and decrypted it, so before comp= and
+        // enc=.
+        if op.verify_plan.is_some() && planned.is_none() && output.is_remote() {
+            let chunks = Arc::new(Mutex::new(Chunks::default()));
+            writer = Box::new(VerifySink::new(writer, chunks.clone()));
+            planned = Some((output.to_string(), chunks));
+        }
+        let mut injected = vec![];
+        if !matches!(output, Output::Hash { .. })
+            && let Some(patches) = injections.next()
+            && !patches.is_empty()
+        {
+            injected = patches
+                .iter()
+                .map(|patch| format!("{} at {}", patch.label, patch.offset))
+                .collect();
+            writer = Box::new(PatchSink::new(writer, patches, resumed));
+        }
+        let kind = match output {
+            Output::Hash { .. } => StageKind::Hash,
+            _ => StageKind::Write,
+        };
+        engine.add_sink(kind, output.to_string(), writer, *on_error);
+        let identity = match output {
+            Output::File(path) => device::identify(path),
+            _ => DeviceIdentity::default(),
+        };
+        // The disk as written, for fix-gpt= and new-ids= to work on.
+        let disk = match output {
+            Output::File(path) if op.fix_gpt || op.new_ids => Some(path.clone()),
+            _ => None,
+        };
+        // Patches would be undone by copying chunks again.
+        let verify = verify.map(|(path, chunks)| (path, chunks, injected.is_empty()));
+        extras.push((identity, injected, verify, delta, disk));
+    }
+
+    // Scanning and carving aren't outputs; their sinks are added after the
+    // outputs and left out of the output summaries.
+    let matches = Arc::new(Mutex::new(vec![]));
+    if !op.scans.is_empty() {
+        let scanner = ScanSink::new(op.scans.clone(), skip, matches.clone());
+        engine.add_sink(
+            StageKind::Scan,
+            "scan",
+            Box::new(scanner),
+            ErrorPolicy::Skip,
+        );
+    }
+    if let Some(path) = &op.obmap {
+        let mapping = match allocated {
+            Some(extents) => Mapping::Allocated(extents),
+            None if op.obmap_sparse => Mapping::NonZero,
+            None => Mapping::All,
+        };
+        engine.add_sink(
+            StageKind::Scan,
+            format!("obmap={}", path.display()),
+            Box::new(BmapSink::new(path, mapping)),
+            ErrorPolicy::Skip,
+        );
+    }
+    let carved = Arc::new(Mutex::new(vec![]));
+    if let Some(dir) = &op.carve {
+        let carver = CarveSink::new(dir, skip, carved.clone())?;
+        engine.add_sink(
+            StageKind::Scan,
+            format!("carve={}", dir.display()),
+            Box::new(carver),
+            ErrorPolicy::Skip,
+        );
+    }
+
+    lifecycle.advance(State::Running)?;
+    engine.lifecycle(lifecycle.clone());
+    let copy = engine.start();
+    let saver = match (&checkpoint, &op.resume) {
+        (Some(checkpoint), Some(path)) => {
+            let acked = active
+                .iter()
+                .zip(copy.acked())
+                .map(|(&index, counter)| Acked {
+                    index,
+                    counter: counter.clone(),
+                    file: match &op.outputs[index] {
+                        Output::File(path) => Some(path.clone()),
+                        _ => None,
+                    },
+                });
+            Some(Saver::spawn(
+                checkpoint.clone(),
+                path.clone(),
+                resumed,
+                acked.collect(),
+            ))
+        }
+        _ => None,
+    };
+    let acked = copy.acked().to_vec();
+    let progress = copy.progress().clone();
+    signals.watch(progress.clone());
+    if let Some(health) = health {
+        health.watch(progress.clone());
+    }
+    let reporter = args
+        .renderer()
+        .map(|renderer| progress.spawn_reporter(renderer.build()));
+    let sampler = args.report.is_some().then(|| progress.spawn_sampler());
+    let mut result = copy.await?;
+    if let Some(offload) = result.offload
+        && args.status != Status::None
+    {
+        log::message(
+            Some(&op.input.to_string()),
+            &format!("copied by the kernel with {offload}"),
+        );
+    }
+    let analyzers = result.outputs.split_off(active.len());
+
+    // Whatever made it out before an interrupt should survive it too, as
+    // should what the checkpoint is about to record.
+    let mut unsynced = vec![];
+    if result.interrupted || op.resume.is_some() {
+        for (index, output) in op.outputs.iter().enumerate() {
+            if let Output::File(path) = output
+                && let Err(e) = sink::sync(path)
+            {
+                Diagnostic::new(Code::SyncFailed, format!("failed to sync: {e}"))
+                    .subject(output)
+                    .emit();
+                unsynced.push(index);
+            }
+        }
+    }
+
+    // An output is only complete if the copy ran to the end and every block
+    // made it.
+    if let (Some(saver), Some(path)) = (saver, &op.resume) {
+        let mut checkpoint = saver.finish();
+        let finished = !result.interrupted && !result.expired && !result.aborted;
+        for ((&index, output), acked) in active.iter().zip(&result.outputs).zip(&acked) {
+            // Left where the last sync that worked got it to.
+            if unsynced.contains(&index) {
+                continue;
+            }
+            checkpoint.update(index, resumed, acked.get());
+            checkpoint.outputs[index].complete = finished
+                && output.error.is_none()
+                && acked.get() == output.records.bytes
+                && output.records.full + output.records.partial
+                    == result.records_in.full + result.records_in.partial;
+        }
+        checkpoint.save(path)?;
+    }
+    // Mapped whatever happened, so a second pass can pick up from there.
+    let rescued = match &op.rescue {
+        Some(path) => {
+            RescueMap::from_copy(
+                skip,
+                result.records_in.bytes,
+                &result.unreadable,
+                len.map(|len| skip + len),
+            )
+            .save(path)?;
+            Some((path.clone(), std::mem::take(&mut result.unreadable)))
+        }
+        None => None,
+    };
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
+    let mut timeline = sampler.map(|sampler| sampler.finish()).unwrap_or_default();
+
+    let mut stages = vec![result.read.clone()];
+    let mut outputs = vec![];
+    let finished = !result.interrupted && !result.expired && !result.aborted;
+    let pace = verify::Pace {
+        rate: op.verify_rate,
+        priority: op.verify_priority,
+    };
+    if extras.iter().any(|(_, _, verify, ..)| verify.is_some()) || op.fix_gpt || op.new_ids {
+        lifecycle.advance(State::Verifying)?;
+    }
+    for (output, (identity, injected, verify, delta, disk)) in
+        result.outputs.into_iter().zip(extras)
+    {
+        let mut repaired = vec![];
+        let verification = match verify {
+            Some((path, chunks, repairable)) => {
+                let from = repair_from.clone().filter(|_| repairable);
+                let (verification, ranges) = tokio::task::spawn_blocking(move || {
+                    let chunks = chunks.lock().unwrap();
+                    match verify::verify(&path, seek, &chunks, pace) {
+                        Verification::Mismatch { offset } if let Some(from) = from => {
+                            let repair = verify::repair(&from, &path, seek, &chunks, offset, pace);
+                            (repair.verification, repair.ranges)
+                        }
+                        verification => (verification, vec![]),
                     }
-                    args.input_file = Some(path);
+                })
+                .await?;
+                repaired = ranges;
+                Some(verification)
+            }
+            None => None,
+        };
+        if let Some(diagnostic) = verification
+            .as_ref()
+            .and_then(|verification| verification.diagnostic(&output.name))
+        {
+            diagnostic.emit();
+        }
+        // After verifying, which compares what was written, and the GPT
+        // first so the new GUIDs go to the backup where it now is.
+        let disk = disk.filter(|_| finished && output.error.is_none());
+        let gpt = match &disk {
+            Some(path) if op.fix_gpt => {
+                let path = path.clone();
+                Some(tokio::task::spawn_blocking(move || gpt::fix(&path)).await?)
+            }
+            _ => None,
+        };
+        if let Some(GptFix::Failed(e)) = &gpt {
+            Diagnostic::new(Code::GptNotFixed, format!("GPT not fixed, {e}"))
+                .subject(&output.name)
+                .emit();
+        }
+        let ids = match disk {
+            Some(path) if op.new_ids && gpt.as_ref().is_none_or(GptFix::is_ok) => {
+                Some(tokio::task::spawn_blocking(move || ids::renew(&path)).await?)
+            }
+            _ => None,
+        };
+        match &ids {
+            Some(NewIds::Failed(e)) => {
+                Diagnostic::new(Code::IdsNotChanged, format!("IDs not changed, {e}"))
+                    .subject(&output.name)
+                    .emit();
+            }
+            Some(NewIds::Stamped(stamped)) => {
+                for skipped in &stamped.skipped {
+                    Diagnostic::new(Code::IdKept, skipped)
+                        .subject(&output.name)
+                        .emit();
                 }
-                "of" => {
-                    let path = PathBuf::from(rhs);
-                    args.output_files.push(path);
+            }
+            None => {}
+        }
+        // Split outputs only get their share.
+        let striped = op.layout == Layout::Split && output.profile.kind == StageKind::Write;
+        if output.digest.is_none() && !striped && output.records.bytes < result.records_in.bytes {
+            let message = format!(
+                "output smaller than input, {} of {} bytes written",
+                output.records.bytes, result.records_in.bytes
+            );
+            Diagnostic::new(Code::OutputShort, message)
+                .subject(&output.name)
+                .offset(output.records.bytes)
+                .emit();
+        }
+        stages.push(output.profile);
+        outputs.push(OutputSummary {
+            name: output.name,
+            records: output.records,
+            elapsed: output.elapsed,
+            identity,
+            digest: output.digest,
+            injected,
+            verification,
+            repaired,
+            error: output.error,
+            striped,
+            delta: delta.map(|delta| *delta.lock().unwrap()),
+            gpt,
+            ids,
+        });
+    }
+
+    // Only a stream that went out whole is worth checking copies against.
+    if let (Some(path), Some((name, chunks))) = (&op.verify_plan, planned)
+        && finished
+        && outputs
+            .iter()
+            .any(|output| output.name == name && output.error.is_none())
+    {
+        VerifyPlan::new(&name, &chunks.lock().unwrap()).save(path)?;
+    }
+
+    stages.extend(analyzers.into_iter().map(|analyzer| analyzer.profile));
+    // Nor are they drawn in the timeline.
+    for sample in &mut timeline {
+        sample.outputs.truncate(outputs.len());
+    }
+
+    if let Some(digest) = outputs.iter().find_map(|output| output.digest.clone()) {
+        variables.hash(&digest);
+    }
+    for (index, template) in renames {
+        let Some(position) = active.iter().position(|&active| active == index) else {
+            continue;
+        };
+        let output = &mut outputs[position];
+        let Output::File(partial) = &op.outputs[index] else {
+            continue;
+        };
+        // What didn't finish keeps its partial name rather than get the
+        // digest of data it doesn't hold.
+        if !finished || output.error.is_some() {
+            continue;
+        }
+        let path = PathBuf::from(template.render(&variables));
+        template::rename(partial, &path).map_err(|e| {
+            eyre!("Failed to rename output to its digest")
+                .with_error(|| e)
+                .with_note(|| format!("output {}, left under that name", output.name))
+        })?;
+        output.name = Output::File(path).to_string();
+    }
+
+    let records_in = result.records_in;
+    let elapsed = start.elapsed();
+    Ok(Some(Report {
+        summary: Summary {
+            input: result.read.name,
+            records_in,
+            outputs,
+            started,
+            elapsed,
+            interrupted: result.interrupted,
+            aborted: result.aborted,
+            expired: op.duration.filter(|_| result.expired),
+            idle: op.idle_timeout.filter(|_| result.idle),
+            patched: (
+                patches
+                    .iter()
+                    .filter(|patch| {
+                        patch.offset >= skip
+                            && patch.offset + patch.bytes.len() as u64 <= skip + records_in.bytes
+                    })
+                    .count(),
+                patches.len(),
+            ),
+            read_errors: result.read_errors,
+            rescue: rescued,
+            redacted: result.redacted,
+            matches: std::mem::take(&mut *matches.lock().unwrap()),
+            carved: op
+                .carve
+                .clone()
+                .map(|dir| (dir, std::mem::take(&mut *carved.lock().unwrap()))),
+            trailer,
+        },
+        profile: OperationProfile { elapsed, stages },
+        timeline,
+        variables,
+    }))
+}
+
+/// Run `futures` at once, for their outputs in the order given.
+async fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let mut futures: Vec<_> = futures.into_iter().map(Box::pin).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    std::future::poll_fn(|cx| {
+        let mut pending = false;
+        for (future, output) in futures.iter_mut().zip(&mut outputs) {
+            if output.is_none() {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(value) => *output = Some(value),
+                    Poll::Pending => pending = true,
                 }
-                "bs" => {
-                    let size = rhs.parse::<usize>().map_err(|e| {
-                        eyre!("Invalid block size")
-                            .with_error(|| e)
-                            .with_note(|| format!("input bs={rhs}"))
-                    })?;
-                    args.block_size = size;
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+    .await;
+    outputs.into_iter().flatten().collect()
+}
+
+/// Run every operation in turn, collecting their reports into `reports`
+/// even when one fails, and tell whether the run as a whole succeeded.
+async fn run_all(
+    args: &Arguments,
+    signals: &Signals,
+    health: Option<&Health>,
+    reports: &mut Vec<Report>,
+) -> Result<()> {
+    // Operations joined by pipes run at once, each reading what the other
+    // writes as it goes.
+    for group in pipe::groups(&args.operations) {
+        let ops: Vec<&Operation> = group.iter().map(|&index| &args.operations[index]).collect();
+        pipe::create(&ops);
+        let results = join_all(ops.iter().map(|&op| async move {
+            let result = run(op.clone(), args, signals, health).await;
+            pipe::release(op);
+            result
+        }))
+        .await;
+        let mut stop = false;
+        for (op, result) in ops.into_iter().zip(results) {
+            let report = match result {
+                Ok(Some(report)) => report,
+                Ok(None) => continue,
+                Err(e) => {
+                    if let Some(path) = &args.history {
+                        history::append(path, reports)?;
+                        history::append_failure(path, op, &e)?;
+                    }
+                    return Err(e);
                 }
-                "count" => {
-                    let count = rhs.parse::<usize>().map_err(|e| {
-                        eyre!("Invalid block count")
-                            .with_error(|| e)
-                            .with_note(|| format!("input count={rhs}"))
-                    })?;
-                    args.block_count = count;
+            };
+            if args.status != Status::None && !log::is_json() {
+                report.summary.print();
+            }
+            if args.profile && !log::is_json() {
+                report.profile.print();
+            }
+            if let Some(format) = &args.print {
+                println!("{}", format.render(&report.summary));
+            }
+            if !args.no_advice && args.status != Status::None {
+                for advice in advice::advise(op, &report.profile) {
+                    if log::is_json() {
+                        log::event(serde_json::json!({
+                            "type": "advice",
+                            "subject": report.summary.input,
+                            "message": advice.to_string(),
+                        }));
+                    } else {
+                        eprintln!("advice: {advice}");
+                    }
                 }
-                _ => continue,
             }
+            stop |= report.summary.interrupted || report.summary.aborted;
+            reports.push(report);
         }
-        if args.input_file.is_none() {
-            return Err(eyre!("No input file given"));
+        if stop {
+            break;
         }
-        Ok(args)
     }
-}
 
-pub struct OutFile {
-    pub path: PathBuf,
-    pub file: File,
-    pub rx: Receiver<Vec<u8>>,
-}
+    if let Some(path) = &args.report {
+        report::write_html(path, reports)?;
+    }
+    if let Some(path) = &args.csv {
+        csv::append(path, reports)?;
+    }
+    if let Some(path) = &args.history {
+        history::append(path, reports)?;
+    }
 
-impl OutFile {
-    pub fn new(path: &Path, rx: Receiver<Vec<u8>>) -> Result<Self> {
-        let file = OpenOptions::new().create(true).write(true).open(path)?;
-        Ok(Self {
-            file,
-            path: path.into(),
-            rx,
-        })
+    if reports.iter().any(|report| report.summary.interrupted) {
+        return Err(eyre!("Interrupted"));
     }
 
-    pub fn write_block(&mut self, block: Vec<u8>) {
-        match self.file.write(&block) {
-            Ok(n) => println!("wrote {n} bytes to {}", self.path.display()),
-            Err(e) => eprintln!("failed to write block to {}: {e}", self.path.display()),
-        }
+    let failed: Vec<&str> = reports
+        .iter()
+        .flat_map(|report| report.summary.failed_outputs())
+        .map(|output| output.name.as_str())
+        .collect();
+    if !failed.is_empty() {
+        return Err(eyre!("Writing failed for {}", failed.join(", ")));
+    }
+
+    let failed: Vec<&str> = reports
+        .iter()
+        .flat_map(|report| report.summary.failed_gpt_fixes())
+        .map(|output| output.name.as_str())
+        .collect();
+    if !failed.is_empty() {
+        return Err(eyre!("Fixing the GPT failed for {}", failed.join(", ")));
     }
+
+    let failed: Vec<&str> = reports
+        .iter()
+        .flat_map(|report| report.summary.failed_id_changes())
+        .map(|output| output.name.as_str())
+        .collect();
+    if !failed.is_empty() {
+        return Err(eyre!("Changing IDs failed for {}", failed.join(", ")));
+    }
+
+    let failed: Vec<&str> = reports
+        .iter()
+        .flat_map(|report| report.summary.failed_verifications())
+        .map(|output| output.name.as_str())
+        .collect();
+    if !failed.is_empty() {
+        return Err(eyre!("Verification failed for {}", failed.join(", ")));
+    }
+
+    Ok(())
 }
 
+/// Exit status of a run that failed, as when an error is returned from
+/// `main`
+const FAILED: i32 = 1;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    let args = Argument::parse()?;
-
-    let (tx, _rx) = broadcast::channel::<Vec<u8>>(64);
-    let input_file = args.input_file.unwrap();
-    let mut input = OpenOptions::new().read(true).open(&input_file)?;
-    for output_file in args.output_files {
-        let rx = tx.subscribe();
-        let file = OutFile::new(&output_file, rx)?;
-        tokio::spawn(async move {
-            let mut file = file;
-            while let Ok(block) = file.rx.recv().await {
-                file.write_block(block);
-            }
-        });
+    // From the start, so an error in the arguments ends the stream as well.
+    log::set(Arguments::log());
+    diagnostic::set_json(log::is_json());
+    let args = match Arguments::parse() {
+        Ok(args) => args,
+        Err(e) if log::is_json() => {
+            log::result(FAILED, Some(&e), vec![]);
+            std::process::exit(FAILED);
+        }
+        Err(e) => return Err(e),
+    };
+    if args.self_test {
+        return selftest::run(&std::env::current_exe()?);
+    }
+    if let Some(query) = &args.query {
+        return history::query(query);
+    }
+    if let Some(command) = &args.catalog_command {
+        let path = args
+            .catalog
+            .clone()
+            .or_else(Catalog::default_path)
+            .ok_or_else(|| {
+                eyre!("No catalog to keep").with_suggestion(|| "give one with --catalog FILE")
+            })?;
+        return catalog::run(command, &path);
+    }
+    if let Some(rekey) = &args.rekey {
+        return rekey::run(rekey);
+    }
+    if let Some(check) = &args.plan_check {
+        return plan::run(check);
+    }
+    if let Some(info) = &args.info {
+        return container::info(info);
     }
 
-    let mut buffer = vec![0u8; args.block_size];
-    let mut count = 0;
-    while let Ok(n) = input.read(&mut buffer) {
-        if args.block_count > 0 && count >= args.block_count {
-            break;
+    let signals = Signals::install()?;
+    let health = args.stats.map(Health::spawn);
+    log::set(args.log);
+    diagnostic::set_json(args.log == Log::Json || args.renderer() == Some(Renderer::Json));
+
+    let mut reports = vec![];
+    let result = run_all(&args, &signals, health.as_ref(), &mut reports).await;
+
+    // The result document ends the event stream, and the exit status is all
+    // that is left of an error so nothing but JSON reaches stderr.
+    if log::is_json() {
+        let operations: Vec<_> = reports
+            .iter()
+            .map(|report| {
+                let mut operation = report.summary.to_json();
+                if args.profile {
+                    operation["profile"] = report.profile.to_json();
+                }
+                operation
+            })
+            .collect();
+        let exit_status = if result.is_err() { FAILED } else { 0 };
+        log::result(exit_status, result.as_ref().err(), operations);
+        if result.is_err() {
+            std::process::exit(exit_status);
         }
-        count = count.saturating_add(1);
-        println!("Read {n} bytes from {}", input_file.display());
-        tx.send(buffer)?;
-        buffer = vec![0u8; args.block_size];
     }
-
-    Ok(())
+    result
 }
@@ -0,0 +1,441 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::sink::Sink;
+
+/// Start of every packet, so stray datagrams on the port are ignored.
+const MAGIC: &[u8; 4] = b"PDDM";
+
+/// Magic, kind, session and sequence number.
+const HEADER: usize = 4 + 1 + 4 + 8;
+
+/// Stream bytes per packet, so a packet fits an Ethernet frame unfragmented.
+pub const PAYLOAD: usize = 1400;
+
+/// Packets the sender keeps to send again, about 45 MiB. A receiver that
+/// falls further behind can't catch up and fails.
+const WINDOW: usize = 32 * 1024;
+
+/// Packets sent again for one NAK at most; the receiver asks again for the
+/// rest.
+const RESEND: u64 = 1024;
+
+/// Ranges of missing packets one NAK asks for at most.
+const NAK_RANGES: usize = 64;
+
+/// Time a receiver waits for a missing packet before asking for it again.
+const NAK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time the sender keeps announcing the end and answering NAKs once no
+/// receiver has asked for anything.
+const LINGER: Duration = Duration::from_secs(2);
+
+/// Time a receiver waits for the sender, once it has heard from it, before
+/// giving up.
+const SILENCE: Duration = Duration::from_secs(30);
+
+/// Receive buffer asked for, so a burst isn't dropped while blocks are
+/// written out.
+const RECEIVE_BUFFER: libc::c_int = 8 << 20;
+
+/// What a packet is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// The stream bytes of one sequence number
+    Data = 0,
+
+    /// The stream ended, the sequence number being the packet count
+    End = 1,
+
+    /// A receiver asking for packets again, as ranges after the header
+    Nak = 2,
+
+    /// The sender no longer has packets before the sequence number
+    Lost = 3,
+}
+
+impl Kind {
+    fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Kind::Data),
+            1 => Some(Kind::End),
+            2 => Some(Kind::Nak),
+            3 => Some(Kind::Lost),
+            _ => None,
+        }
+    }
+}
+
+/// A multicast group and port, e.g. `239.1.1.1:9000`.
+pub fn parse_group(key: &str, value: &str) -> Result<SocketAddrV4> {
+    match SocketAddrV4::from_str(value) {
+        Ok(group) if group.ip().is_multicast() => Ok(group),
+        _ => Err(eyre!("Invalid multicast group for {key}")
+            .with_note(|| format!("input {key}={value}"))
+            .with_suggestion(|| {
+                format!("expected an IPv4 multicast address and port, e.g. {key}=239.1.1.1:9000")
+            })),
+    }
+}
+
+fn packet(kind: Kind, session: u32, seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER + payload.len());
+    packet.extend_from_slice(MAGIC);
+    packet.push(kind as u8);
+    packet.extend_from_slice(&session.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Kind, session, sequence number and payload of a packet of ours.
+fn parse(packet: &[u8]) -> Option<(Kind, u32, u64, &[u8])> {
+    if packet.len() < HEADER || &packet[..4] != MAGIC {
+        return None;
+    }
+    let kind = Kind::from_u8(packet[4])?;
+    let session = u32::from_be_bytes(packet[5..9].try_into().ok()?);
+    let seq = u64::from_be_bytes(packet[9..17].try_into().ok()?);
+    Some((kind, session, seq, &packet[HEADER..]))
+}
+
+fn is_would_block(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// An output sent to a multicast group (`omcast=239.1.1.1:9000`), for any
+/// number of `imcast=` receivers to write at once.
+///
+/// The stream goes out in numbered packets of [`PAYLOAD`] bytes. Receivers
+/// ask for those they miss with a NAK, and the sender sends them to the
+/// group again while it still has them, the last [`WINDOW`] packets. Once
+/// the stream ends the sender announces it until receivers have stopped
+/// asking for [`LINGER`]. Nothing slows the sender down but the network, so
+/// give it `olimit=` if receivers can't keep up.
+pub struct MulticastSink {
+    socket: UdpSocket,
+    group: SocketAddr,
+
+    /// Random, so a receiver doesn't take packets of an earlier run
+    session: u32,
+
+    /// Bytes short of a whole packet
+    buffer: Vec<u8>,
+
+    /// Payloads of the packets that can still be sent again, the first being
+    /// `first`
+    sent: VecDeque<Vec<u8>>,
+    first: u64,
+}
+
+impl MulticastSink {
+    pub fn open(group: SocketAddrV4) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+        let mut session = [0u8; 4];
+        getrandom::fill(&mut session).map_err(io::Error::other)?;
+        Ok(Self {
+            socket,
+            group: group.into(),
+            session: u32::from_ne_bytes(session),
+            buffer: vec![],
+            sent: VecDeque::new(),
+            first: 0,
+        })
+    }
+
+    /// Sequence number of the next new packet.
+    fn next(&self) -> u64 {
+        self.first + self.sent.len() as u64
+    }
+
+    fn send_to(&self, packet: &[u8], to: SocketAddr) -> io::Result<()> {
+        loop {
+            match self.socket.send_to(packet, to) {
+                Ok(_) => return Ok(()),
+                Err(e) if is_would_block(&e) => std::thread::sleep(Duration::from_millis(1)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send_data(&self, seq: u64) -> io::Result<()> {
+        let payload = &self.sent[(seq - self.first) as usize];
+        self.send_to(&packet(Kind::Data, self.session, seq, payload), self.group)
+    }
+
+    fn send_packet(&mut self, len: usize) -> io::Result<()> {
+        let payload: Vec<u8> = self.buffer.drain(..len).collect();
+        if self.sent.len() == WINDOW {
+            self.sent.pop_front();
+            self.first += 1;
+        }
+        self.sent.push_back(payload);
+        self.send_data(self.next() - 1)?;
+        self.answer().map(|_| ())
+    }
+
+    /// Send again what receivers asked for, returning true if any did.
+    fn answer(&mut self) -> io::Result<bool> {
+        let mut asked = false;
+        let mut buf = [0u8; HEADER + NAK_RANGES * 16];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if is_would_block(&e) => return Ok(asked),
+                // A receiver gone away shows up as a refused connection.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(e) => return Err(e),
+            };
+            let Some((Kind::Nak, session, _, ranges)) = parse(&buf[..len]) else {
+                continue;
+            };
+            if session != self.session {
+                continue;
+            }
+            asked = true;
+            let mut budget = RESEND;
+            for range in ranges.chunks_exact(16) {
+                let start = u64::from_be_bytes(range[..8].try_into().unwrap());
+                let end = u64::from_be_bytes(range[8..].try_into().unwrap());
+                if start < self.first {
+                    let lost = packet(Kind::Lost, self.session, self.first, &[]);
+                    self.send_to(&lost, from)?;
+                    break;
+                }
+                for seq in start..end.min(self.next()) {
+                    if budget == 0 {
+                        break;
+                    }
+                    self.send_data(seq)?;
+                    budget -= 1;
+                }
+            }
+        }
+    }
+}
+
+impl Write for MulticastSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= PAYLOAD {
+            self.send_packet(PAYLOAD)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Packets are sent once full; a short one can only be the last.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for MulticastSink {
+    /// Send the rest as the last packet and announce the end until
+    /// receivers stop asking for packets.
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        if !self.buffer.is_empty() {
+            self.send_packet(self.buffer.len())?;
+        }
+        let end = packet(Kind::End, self.session, self.next(), &[]);
+        let mut quiet = Instant::now();
+        while quiet.elapsed() < LINGER {
+            self.send_to(&end, self.group)?;
+            let announced = Instant::now();
+            while announced.elapsed() < NAK_INTERVAL * 2 {
+                if self.answer()? {
+                    quiet = Instant::now();
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stop without announcing the end, so receivers time out rather than
+    /// take what they have for the whole stream.
+    fn abandon(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The stream of a [`MulticastSink`] as received from its group
+/// (`imcast=239.1.1.1:9000`), in order and complete.
+///
+/// The receiver may start before or a little after the sender; it asks for
+/// what it missed until it has the stream up to the end. It fails if the
+/// sender no longer has packets it needs, or goes silent for [`SILENCE`].
+pub struct MulticastReader {
+    socket: UdpSocket,
+
+    /// Session and address of the sender, once heard from
+    sender: Option<(u32, SocketAddr)>,
+
+    /// Sequence number of the next packet to read
+    next: u64,
+
+    /// Packets received ahead of `next`
+    ahead: BTreeMap<u64, Vec<u8>>,
+
+    /// Packet count, once the end is announced
+    end: Option<u64>,
+
+    /// Packet being read and how much of it is
+    current: Vec<u8>,
+    position: usize,
+
+    heard: Instant,
+    asked: Instant,
+}
+
+impl MulticastReader {
+    pub fn join(group: SocketAddrV4) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port()))?;
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_read_timeout(Some(NAK_INTERVAL))?;
+        receive_buffer(&socket);
+        Ok(Self {
+            socket,
+            sender: None,
+            next: 0,
+            ahead: BTreeMap::new(),
+            end: None,
+            current: vec![],
+            position: 0,
+            heard: Instant::now(),
+            asked: Instant::now(),
+        })
+    }
+
+    /// Take in one packet, if one comes within [`NAK_INTERVAL`].
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; HEADER + PAYLOAD];
+        let (len, from) = match self.socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if is_would_block(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let Some((kind, session, seq, payload)) = parse(&buf[..len]) else {
+            return Ok(());
+        };
+        match self.sender {
+            Some((known, _)) if known != session => return Ok(()),
+            Some(_) => {}
+            None if matches!(kind, Kind::Data | Kind::End) => {
+                self.sender = Some((session, from));
+            }
+            None => return Ok(()),
+        }
+        self.heard = Instant::now();
+        match kind {
+            Kind::Data if seq >= self.next && self.ahead.len() < WINDOW => {
+                self.ahead.entry(seq).or_insert_with(|| payload.to_vec());
+            }
+            Kind::End => {
+                let _ = self.end.replace(seq);
+            }
+            Kind::Lost if seq > self.next => {
+                return Err(io::Error::other(format!(
+                    "fell behind, the sender no longer has packets {} to {}",
+                    self.next,
+                    seq - 1
+                )));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Ask the sender for the packets missing before the last one known of.
+    fn ask(&mut self) -> io::Result<()> {
+        let Some((session, sender)) = self.sender else {
+            return Ok(());
+        };
+        let last = match (self.end, self.ahead.keys().next_back()) {
+            (Some(end), _) => end,
+            (None, Some(&seq)) => seq,
+            (None, None) => return Ok(()),
+        };
+        let mut ranges = vec![];
+        let mut start = self.next;
+        for &seq in self.ahead.keys().chain(std::iter::once(&last)) {
+            if seq > start && ranges.len() < NAK_RANGES * 16 {
+                ranges.extend_from_slice(&start.to_be_bytes());
+                ranges.extend_from_slice(&seq.to_be_bytes());
+            }
+            start = start.max(seq + 1);
+        }
+        if ranges.is_empty() {
+            return Ok(());
+        }
+        self.asked = Instant::now();
+        self.socket
+            .send_to(&packet(Kind::Nak, session, 0, &ranges), sender)
+            .map(|_| ())
+    }
+}
+
+impl Read for MulticastReader {
+    /// Fill `buf` with the packets received in order so far, waiting only
+    /// while there are none.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        loop {
+            if self.position < self.current.len() {
+                let n = (buf.len() - filled).min(self.current.len() - self.position);
+                buf[filled..filled + n]
+                    .copy_from_slice(&self.current[self.position..self.position + n]);
+                self.position += n;
+                filled += n;
+                if filled == buf.len() {
+                    return Ok(filled);
+                }
+            }
+            if let Some(payload) = self.ahead.remove(&self.next) {
+                self.current = payload;
+                self.position = 0;
+                self.next += 1;
+                continue;
+            }
+            if filled > 0 || self.end == Some(self.next) {
+                return Ok(filled);
+            }
+            if self.sender.is_some() && self.heard.elapsed() > SILENCE {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no packets from the sender for {}s", SILENCE.as_secs()),
+                ));
+            }
+            self.receive()?;
+            if !self.ahead.contains_key(&self.next) && self.asked.elapsed() >= NAK_INTERVAL {
+                self.ask()?;
+            }
+        }
+    }
+}
+
+/// Ask for a larger receive buffer; the default is what the system allows
+/// if it won't.
+fn receive_buffer(socket: &UdpSocket) {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the option value is a c_int that outlives the call.
+    unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            (&RECEIVE_BUFFER as *const libc::c_int).cast(),
+            size_of::<libc::c_int>() as libc::socklen_t,
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use std::{fmt, fs::File, io};
+
+/// How the kernel copied a file to a file output itself, rather than pdd
+/// reading the input and writing the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Offload {
+    /// The output was made to share the input's data on a copy on write
+    /// filesystem, without copying any of it
+    Reflink,
+
+    /// `copy_file_range`, which a filesystem or device may do in place
+    CopyFileRange,
+
+    /// `sendfile`, from the page cache of the input straight to the output
+    Sendfile,
+}
+
+impl fmt::Display for Offload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offload::Reflink => write!(f, "reflink"),
+            Offload::CopyFileRange => write!(f, "copy_file_range"),
+            Offload::Sendfile => write!(f, "sendfile"),
+        }
+    }
+}
+
+/// Make the empty file `dst` share all of the data of `src`; `Ok(false)` if
+/// the filesystem or platform can't.
+#[cfg(target_os = "linux")]
+pub fn reflink(src: &File, dst: &File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: both descriptors are valid for the lifetime of the files.
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY | libc::EBADF) => {
+            Ok(false)
+        }
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_src: &File, _dst: &File) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Copy up to `len` bytes of `src` from `offset` to `dst` at its position,
+/// which moves past them, returning how many were copied, 0 at the end of
+/// `src`. Fails with [`io::ErrorKind::Unsupported`] where `method` can't
+/// copy between these files.
+#[cfg(target_os = "linux")]
+pub fn copy(method: Offload, src: &File, offset: u64, dst: &File, len: usize) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let mut offset = i64::try_from(offset).map_err(io::Error::other)?;
+    // SAFETY: both descriptors are valid for the lifetime of the files, and
+    // the offset outlives the call.
+    let ret = unsafe {
+        match method {
+            Offload::CopyFileRange => libc::copy_file_range(
+                src.as_raw_fd(),
+                &mut offset,
+                dst.as_raw_fd(),
+                std::ptr::null_mut(),
+                len,
+                0,
+            ),
+            Offload::Sendfile => libc::sendfile(dst.as_raw_fd(), src.as_raw_fd(), &mut offset, len),
+            Offload::Reflink => return Err(io::ErrorKind::Unsupported.into()),
+        }
+    };
+    if let Ok(n) = usize::try_from(ret) {
+        return Ok(n);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENOSYS | libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL) => {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn copy(
+    _method: Offload,
+    _src: &File,
+    _offset: u64,
+    _dst: &File,
+    _len: usize,
+) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
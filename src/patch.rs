@@ -0,0 +1,428 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{hash::to_hex, sink::Sink, value::parse_size};
+
+/// Bytes to overwrite at a fixed offset of the stream.
+#[derive(Clone, Debug)]
+pub struct Patch {
+    /// Offset from the start of the stream
+    pub offset: u64,
+
+    pub bytes: Vec<u8>,
+
+    /// What the patch is, for the summary
+    pub label: String,
+}
+
+impl Patch {
+    /// A patch of `bytes` at `offset`, unless it would end past the largest
+    /// offset there is.
+    fn new(offset: u64, bytes: Vec<u8>, label: String) -> Option<Self> {
+        offset.checked_add(bytes.len() as u64)?;
+        Some(Self {
+            offset,
+            bytes,
+            label,
+        })
+    }
+
+    /// Offset just past the last byte it overwrites.
+    fn end(&self) -> u64 {
+        self.offset + self.bytes.len() as u64
+    }
+}
+
+/// Overwrite the parts of `block` covered by `patches`, where `block` starts
+/// `position` bytes into the stream.
+pub fn apply(patches: &[Patch], position: u64, block: &mut [u8]) {
+    let end = position + block.len() as u64;
+    for patch in patches {
+        let patch_end = patch.end();
+        if patch_end <= position || patch.offset >= end {
+            continue;
+        }
+        let from = patch.offset.max(position);
+        let to = patch_end.min(end);
+        let src = (from - patch.offset) as usize..(to - patch.offset) as usize;
+        let dst = (from - position) as usize..(to - position) as usize;
+        block[dst].copy_from_slice(&patch.bytes[src]);
+    }
+}
+
+/// Load a patch file (`patch=FILE`).
+///
+/// Both the IPS format (detected by its `PATCH` magic) and a plain text list
+/// of `OFFSET HEXBYTES` lines are understood. Offsets are relative to the
+/// start of the input.
+pub fn load(path: &Path) -> Result<Vec<Patch>> {
+    let data = std::fs::read(path).map_err(|e| {
+        eyre!("Failed to read patch file")
+            .with_error(|| e)
+            .with_note(|| format!("patch {}", path.display()))
+    })?;
+    let patches = if data.starts_with(IPS_MAGIC) {
+        parse_ips(&data)
+    } else {
+        parse_text(&String::from_utf8_lossy(&data))
+    };
+    patches.map_err(|e| e.with_note(|| format!("patch {}", path.display())))
+}
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+
+/// IPS: `PATCH`, then records of a 3 byte offset and a 2 byte size followed by
+/// the data, or a zero size, a 2 byte run length and a fill byte; `EOF` ends
+/// the records.
+fn parse_ips(data: &[u8]) -> Result<Vec<Patch>> {
+    let truncated = || eyre!("IPS patch is truncated");
+    let mut patches = vec![];
+    let mut at = IPS_MAGIC.len();
+    loop {
+        let header = data.get(at..at + 3).ok_or_else(truncated)?;
+        if header == IPS_EOF {
+            break;
+        }
+        let offset = u64::from(header[0]) << 16 | u64::from(header[1]) << 8 | u64::from(header[2]);
+        let size = data.get(at + 3..at + 5).ok_or_else(truncated)?;
+        let size = usize::from(size[0]) << 8 | usize::from(size[1]);
+        at += 5;
+        let bytes = if size > 0 {
+            let bytes = data.get(at..at + size).ok_or_else(truncated)?.to_vec();
+            at += size;
+            bytes
+        } else {
+            let rle = data.get(at..at + 3).ok_or_else(truncated)?;
+            at += 3;
+            vec![rle[2]; usize::from(rle[0]) << 8 | usize::from(rle[1])]
+        };
+        let label = format!("{} bytes at {offset}", bytes.len());
+        patches.push(Patch::new(offset, bytes, label).expect("a 24 bit offset"));
+    }
+    Ok(patches)
+}
+
+/// One `OFFSET HEXBYTES` pair per line. Offsets are decimal or `0x` hex, the
+/// bytes may be separated by spaces; blank lines and `#` comments are ignored.
+fn parse_text(text: &str) -> Result<Vec<Patch>> {
+    let mut patches = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || {
+            eyre!("Invalid patch line")
+                .with_note(|| format!("line {}: {line}", number + 1))
+                .with_suggestion(|| "expected OFFSET HEXBYTES, e.g. 0x1b8 de ad be ef")
+        };
+        let (offset, hex) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let offset = match offset.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => offset.parse(),
+        }
+        .map_err(|_| invalid())?;
+        let hex: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+        if hex.is_empty()
+            || !hex.len().is_multiple_of(2)
+            || !hex.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Err(invalid());
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        let label = format!("{} bytes at {offset}", bytes.len());
+        let patch = Patch::new(offset, bytes, label).ok_or_else(|| {
+            invalid().with_note(|| "the bytes would end past the largest offset there is")
+        })?;
+        patches.push(patch);
+    }
+    Ok(patches)
+}
+
+/// Sink wrapper applying patches to the stream on its way to `inner`.
+pub struct PatchSink {
+    inner: Box<dyn Sink>,
+    patches: Vec<Patch>,
+    position: u64,
+    scratch: Vec<u8>,
+}
+
+impl PatchSink {
+    /// `position` is the offset of the first byte written from where the
+    /// output's stream starts, which patch offsets are relative to.
+    pub fn new(inner: Box<dyn Sink>, patches: Vec<Patch>, position: u64) -> Self {
+        Self {
+            inner,
+            patches,
+            position,
+            scratch: vec![],
+        }
+    }
+}
+
+impl Write for PatchSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.position + buf.len() as u64;
+        let touched = self
+            .patches
+            .iter()
+            .any(|patch| patch.offset < end && patch.end() > self.position);
+        if touched {
+            self.scratch.clear();
+            self.scratch.extend_from_slice(buf);
+            apply(&self.patches, self.position, &mut self.scratch);
+            self.inner.write_all(&self.scratch)?;
+        } else {
+            self.inner.write_all(buf)?;
+        }
+        self.position = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for PatchSink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.inner.finish()
+    }
+
+    fn abandon(&mut self) -> io::Result<()> {
+        self.inner.abandon()
+    }
+}
+
+/// Content generated per target for `inject=`.
+#[derive(Clone, Debug)]
+pub enum Payload {
+    /// Zero padded decimal serial, counting up from `start` per target
+    Serial { start: u64, width: usize },
+
+    /// Fresh random bytes for every target
+    Random { len: usize },
+
+    /// One MAC address per target, taken in order from a file
+    MacPool(PathBuf),
+}
+
+/// Unique per target data at a fixed offset (`inject=OFFSET,KIND,ARGS`).
+#[derive(Clone, Debug)]
+pub struct Injection {
+    pub offset: u64,
+    pub payload: Payload,
+}
+
+impl FromStr for Injection {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            eyre!("Invalid injection")
+                .with_note(|| format!("input inject={s}"))
+                .with_suggestion(|| {
+                    "expected inject=OFFSET,serial,START[,WIDTH], inject=OFFSET,random,LEN \
+                     or inject=OFFSET,mac,POOL_FILE"
+                })
+        };
+        let parts: Vec<&str> = s.split(',').collect();
+        let (offset, kind, args) = match parts.as_slice() {
+            [offset, kind, args @ ..] => (parse_size("inject", offset)?, *kind, args),
+            _ => return Err(invalid()),
+        };
+        let payload = match (kind, args) {
+            ("serial", [start]) => Payload::Serial {
+                start: start.parse().map_err(|_| invalid())?,
+                width: start.len(),
+            },
+            ("serial", [start, width]) => Payload::Serial {
+                start: start.parse().map_err(|_| invalid())?,
+                width: width.parse().map_err(|_| invalid())?,
+            },
+            ("random", [len]) => Payload::Random {
+                len: usize::try_from(parse_size("inject", len)?)?,
+            },
+            ("mac", [pool]) => Payload::MacPool(PathBuf::from(pool)),
+            _ => return Err(invalid()),
+        };
+        Ok(Self { offset, payload })
+    }
+}
+
+impl fmt::Display for Injection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.payload {
+            Payload::Serial { start, width } => {
+                write!(f, "inject={},serial,{start},{width}", self.offset)
+            }
+            Payload::Random { len } => write!(f, "inject={},random,{len}", self.offset),
+            Payload::MacPool(pool) => {
+                write!(f, "inject={},mac,{}", self.offset, pool.display())
+            }
+        }
+    }
+}
+
+/// Generate the patches for `targets` outputs, one list per target, the
+/// first of them being output number `first` of its operation.
+pub fn generate(injections: &[Injection], first: usize, targets: usize) -> Result<Vec<Vec<Patch>>> {
+    let mut patches = vec![vec![]; targets];
+    for injection in injections {
+        let pool = match &injection.payload {
+            Payload::MacPool(path) => load_mac_pool(path)?,
+            _ => vec![],
+        };
+        if !pool.is_empty() && pool.len() < first + targets {
+            return Err(eyre!("MAC pool is too small")
+                .with_note(|| format!("input {injection}"))
+                .with_note(|| {
+                    format!("{} addresses for {} outputs", pool.len(), first + targets)
+                }));
+        }
+        for (target, list) in (first..).zip(patches.iter_mut()) {
+            let (bytes, label) = match &injection.payload {
+                Payload::Serial { start, width } => {
+                    let serial = format!("{:0width$}", start + target as u64);
+                    (serial.clone().into_bytes(), format!("serial {serial}"))
+                }
+                Payload::Random { len } => {
+                    let mut bytes = vec![0u8; *len];
+                    getrandom::fill(&mut bytes)
+                        .map_err(|e| eyre!("Failed to generate random bytes: {e}"))?;
+                    let label = format!("random {}", to_hex(&bytes));
+                    (bytes, label)
+                }
+                Payload::MacPool(_) => {
+                    let mac = pool[target];
+                    let label = format!("mac {}", format_mac(&mac));
+                    (mac.to_vec(), label)
+                }
+            };
+            let patch = Patch::new(injection.offset, bytes, label).ok_or_else(|| {
+                eyre!("Injection ends past the largest offset there is")
+                    .with_note(|| format!("input {injection}"))
+            })?;
+            list.push(patch);
+        }
+    }
+    Ok(patches)
+}
+
+/// Read MAC addresses, one per line; blank lines and `#` comments are
+/// ignored.
+fn load_mac_pool(path: &PathBuf) -> Result<Vec<[u8; 6]>> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        eyre!("Failed to read MAC pool")
+            .with_error(|| e)
+            .with_note(|| format!("pool {}", path.display()))
+    })?;
+    let mut pool = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        pool.push(parse_mac(line).ok_or_else(|| {
+            eyre!("Invalid MAC address in pool")
+                .with_note(|| format!("{}:{}: {line}", path.display(), number + 1))
+        })?);
+    }
+    Ok(pool)
+}
+
+fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split([':', '-']);
+    for byte in &mut mac {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(patches: &[Patch]) -> Vec<(u64, Vec<u8>)> {
+        patches
+            .iter()
+            .map(|p| (p.offset, p.bytes.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn ips_records_and_runs() {
+        let mut data = IPS_MAGIC.to_vec();
+        data.extend([0, 0, 0x10, 0, 3, 1, 2, 3]);
+        data.extend([0, 1, 0, 0, 0, 0, 4, 0xaa]);
+        data.extend(IPS_EOF);
+        let patches = parse_ips(&data).unwrap();
+        assert_eq!(
+            spans(&patches),
+            [(0x10, vec![1, 2, 3]), (0x100, vec![0xaa; 4])]
+        );
+    }
+
+    #[test]
+    fn truncated_ips_is_refused() {
+        let mut data = IPS_MAGIC.to_vec();
+        data.extend([0, 0, 0x10, 0, 3, 1, 2, 3]);
+        assert!(parse_ips(&data).is_err(), "no EOF");
+        data.truncate(data.len() - 1);
+        data.extend(IPS_EOF);
+        assert!(parse_ips(&data).is_err(), "short record");
+        let mut run = IPS_MAGIC.to_vec();
+        run.extend([0, 0, 0, 0, 0, 0, 4]);
+        assert!(parse_ips(&run).is_err(), "short run");
+    }
+
+    #[test]
+    fn text_lines() {
+        let text = "# boot signature\n0x1b8 de ad be ef  # disk id\n\n512 0102\n";
+        let patches = parse_text(text).unwrap();
+        assert_eq!(
+            spans(&patches),
+            [(0x1b8, vec![0xde, 0xad, 0xbe, 0xef]), (512, vec![1, 2])]
+        );
+    }
+
+    #[test]
+    fn bad_text_lines_are_refused() {
+        for line in ["10 abc", "10", "0xzz 00", "10 0g", "10 +1", "10 aéb"] {
+            assert!(parse_text(line).is_err(), "{line}");
+        }
+        assert!(parse_text(&format!("{} 00 00", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn apply_across_blocks() {
+        let patches = [Patch::new(6, vec![1, 2, 3, 4], String::new()).unwrap()];
+        let mut first = [0; 8];
+        let mut second = [0; 8];
+        apply(&patches, 0, &mut first);
+        apply(&patches, 8, &mut second);
+        assert_eq!(first, [0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(second, [3, 4, 0, 0, 0, 0, 0, 0]);
+        let mut later = [0; 8];
+        apply(&patches, 16, &mut later);
+        assert_eq!(later, [0; 8]);
+    }
+}
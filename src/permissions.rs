@@ -0,0 +1,201 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{fmt, fs::File, str::FromStr};
+
+use crate::arguments::Output;
+
+/// Permissions and owner given to a file output (`mode=`, `owner=`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Permissions {
+    /// Permission bits, e.g. `0o600`
+    ///
+    /// (default = 0666 less the umask, for new files)
+    pub mode: Option<u32>,
+
+    /// (default = whoever runs pdd, for new files)
+    pub owner: Option<Owner>,
+}
+
+impl Permissions {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.owner.is_none()
+    }
+}
+
+/// Parse permission bits in octal, like chmod: `mode=0600` or `mode=600`.
+pub fn parse_mode(value: &str) -> Result<u32> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(eyre!("Invalid file mode")
+            .with_note(|| format!("input mode={value}"))
+            .with_suggestion(|| "expected octal permission bits, e.g. mode=0600")),
+    }
+}
+
+/// A user and optionally a group, as IDs (`owner=user[:group]`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: Option<u32>,
+}
+
+/// Names are looked up when parsing, so a typo fails before anything is
+/// written; numeric IDs are taken as they are.
+impl FromStr for Owner {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        let unknown = |what: &str, name: &str| {
+            eyre!("Unknown {what} {name}")
+                .with_note(|| format!("input owner={s}"))
+                .with_suggestion(|| "expected owner=user or owner=user:group, by name or ID")
+        };
+        let uid = match user.parse() {
+            Ok(uid) => uid,
+            Err(_) => imp::user_id(user).ok_or_else(|| unknown("user", user))?,
+        };
+        let gid = match group {
+            Some(group) => Some(match group.parse() {
+                Ok(gid) => gid,
+                Err(_) => imp::group_id(group).ok_or_else(|| unknown("group", group))?,
+            }),
+            None => None,
+        };
+        Ok(Owner { uid, gid })
+    }
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.gid {
+            Some(gid) => write!(f, "{}:{gid}", self.uid),
+            None => write!(f, "{}", self.uid),
+        }
+    }
+}
+
+/// Give an output file its owner and then its mode. Only regular files are
+/// changed; the nodes of devices are left alone.
+pub fn apply(file: &File, permissions: &Permissions, output: &Output) -> Result<()> {
+    if permissions.is_empty() || !file.metadata()?.is_file() {
+        return Ok(());
+    }
+    imp::apply(file, permissions).map_err(|e| {
+        let report = eyre!("Failed to set the permissions of output file")
+            .with_error(|| e)
+            .with_note(|| format!("output {output}"));
+        match permissions.owner {
+            Some(_) => report.with_suggestion(|| "changing the owner usually needs root"),
+            None => report,
+        }
+    })
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        ffi::CString,
+        fs::File,
+        io,
+        os::{fd::AsRawFd, unix::fs::PermissionsExt},
+    };
+
+    use super::Permissions;
+
+    pub fn apply(file: &File, permissions: &Permissions) -> io::Result<()> {
+        if let Some(owner) = permissions.owner {
+            // -1 leaves the group as it is.
+            let gid = owner.gid.unwrap_or(u32::MAX);
+            // SAFETY: the descriptor is valid for the lifetime of `file`.
+            if unsafe { libc::fchown(file.as_raw_fd(), owner.uid, gid) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // After the owner, since changing it can clear the setuid bits.
+        if let Some(mode) = permissions.mode {
+            file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    /// Size for the buffer of getpwnam_r and getgrnam_r, enough for most
+    /// entries; bigger ones are looked up again with more room.
+    const BUFFER_SIZE: usize = 1024;
+
+    pub fn user_id(name: &str) -> Option<u32> {
+        let name = CString::new(name).ok()?;
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            // SAFETY: passwd is plain data that getpwnam_r fills in.
+            let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+            let mut found = std::ptr::null_mut();
+            // SAFETY: every pointer is valid for the call and the buffer
+            // length is what it holds.
+            let ret = unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut entry,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut found,
+                )
+            };
+            match ret {
+                libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+                0 if !found.is_null() => return Some(entry.pw_uid),
+                _ => return None,
+            }
+        }
+    }
+
+    pub fn group_id(name: &str) -> Option<u32> {
+        let name = CString::new(name).ok()?;
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            // SAFETY: group is plain data that getgrnam_r fills in.
+            let mut entry: libc::group = unsafe { std::mem::zeroed() };
+            let mut found = std::ptr::null_mut();
+            // SAFETY: as for getpwnam_r.
+            let ret = unsafe {
+                libc::getgrnam_r(
+                    name.as_ptr(),
+                    &mut entry,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut found,
+                )
+            };
+            match ret {
+                libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+                0 if !found.is_null() => return Some(entry.gr_gid),
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::{fs::File, io};
+
+    use super::Permissions;
+
+    pub fn apply(_file: &File, _permissions: &Permissions) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "mode= and owner= aren't supported on this platform",
+        ))
+    }
+
+    /// Only numeric IDs can be given here.
+    pub fn user_id(_name: &str) -> Option<u32> {
+        None
+    }
+
+    pub fn group_id(_name: &str) -> Option<u32> {
+        None
+    }
+}
@@ -0,0 +1,357 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::{
+        Mutex,
+        mpsc::{self, Receiver, SyncSender},
+    },
+};
+
+use crate::{
+    arguments::{Input, Operation, Output},
+    sink::Sink,
+};
+
+/// Blocks a pipe holds before its writer waits for the reader to catch up.
+const DEPTH: usize = 16;
+
+/// A message through a pipe: a block, or `None` once the writer finished,
+/// so that a writer that failed isn't taken for one that ended.
+type Message = Option<Vec<u8>>;
+
+/// The ends of the pipes of the operations running, until each is opened.
+static PIPES: Mutex<Option<HashMap<String, Ends>>> = Mutex::new(None);
+
+struct Ends {
+    writer: Option<SyncSender<Message>>,
+    reader: Option<Receiver<Message>>,
+}
+
+/// The pipes an operation writes to and reads from.
+fn names(op: &Operation) -> impl Iterator<Item = &str> {
+    let input = match &op.input {
+        Input::Pipe(name) => Some(name.as_str()),
+        _ => None,
+    };
+    op.outputs
+        .iter()
+        .filter_map(|output| match output {
+            Output::Pipe(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .chain(input)
+}
+
+/// Check that every pipe is written by one operation and read by one that
+/// comes after it, so operations joined by pipes can't wait on each other.
+pub fn check(operations: &[Operation]) -> Result<()> {
+    let mut writers: HashMap<&str, usize> = HashMap::new();
+    for (index, op) in operations.iter().enumerate() {
+        for output in &op.outputs {
+            if let Output::Pipe(name) = output
+                && writers.insert(name, index).is_some()
+            {
+                return Err(eyre!("A pipe can only be written by one operation")
+                    .with_note(|| format!("output {output}"))
+                    .with_suggestion(|| "give each writer a pipe of its own"));
+            }
+        }
+    }
+    let mut read = vec![];
+    for (index, op) in operations.iter().enumerate() {
+        let Input::Pipe(name) = &op.input else {
+            continue;
+        };
+        match writers.get(name.as_str()) {
+            None => {
+                return Err(eyre!("Nothing writes to the pipe read")
+                    .with_note(|| format!("input {}", op.input))
+                    .with_suggestion(|| format!("add of=pipe:{name} to an operation before it")));
+            }
+            Some(writer) if *writer >= index => {
+                return Err(eyre!(
+                    "A pipe has to be written by an operation before the one reading it"
+                )
+                .with_note(|| format!("input {}", op.input)));
+            }
+            Some(_) if read.contains(&name) => {
+                return Err(eyre!("A pipe can only be read by one operation")
+                    .with_note(|| format!("input {}", op.input))
+                    .with_suggestion(|| "write it to another pipe for each reader"));
+            }
+            Some(_) => read.push(name),
+        }
+    }
+    if let Some(name) = writers
+        .keys()
+        .find(|name| !read.iter().any(|read| read == *name))
+    {
+        return Err(eyre!("Nothing reads the pipe written")
+            .with_note(|| format!("output of=pipe:{name}"))
+            .with_suggestion(|| format!("add an operation with if=pipe:{name} after it")));
+    }
+    Ok(())
+}
+
+/// The operations to run at once, by index, for each group joined by
+/// pipes, each in turn when the first of it comes up.
+pub fn groups(operations: &[Operation]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut writers: HashMap<&str, usize> = HashMap::new();
+    for (index, op) in operations.iter().enumerate() {
+        let writer = match &op.input {
+            Input::Pipe(name) => writers.get(name.as_str()).copied(),
+            _ => None,
+        };
+        let group = match writer {
+            Some(group) => {
+                groups[group].push(index);
+                group
+            }
+            None => {
+                groups.push(vec![index]);
+                groups.len() - 1
+            }
+        };
+        for output in &op.outputs {
+            if let Output::Pipe(name) = output {
+                writers.insert(name, group);
+            }
+        }
+    }
+    groups
+}
+
+/// Make the pipes `operations` write to, for them to open as they start.
+pub fn create(operations: &[&Operation]) {
+    let mut pipes = PIPES.lock().unwrap_or_else(|e| e.into_inner());
+    let pipes = pipes.get_or_insert_with(HashMap::new);
+    for op in operations {
+        for output in &op.outputs {
+            if let Output::Pipe(name) = output {
+                let (writer, reader) = mpsc::sync_channel(DEPTH);
+                pipes.insert(
+                    name.clone(),
+                    Ends {
+                        writer: Some(writer),
+                        reader: Some(reader),
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Drop whatever ends of its pipes `op` didn't open, once it is done, so
+/// the operation at the other end isn't left waiting for it.
+pub fn release(op: &Operation) {
+    let mut pipes = PIPES.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(pipes) = pipes.as_mut() else {
+        return;
+    };
+    for name in names(op) {
+        let Some(ends) = pipes.get_mut(name) else {
+            continue;
+        };
+        if matches!(op.input, Input::Pipe(ref input) if input == name) {
+            ends.reader = None;
+        } else {
+            ends.writer = None;
+        }
+        if ends.writer.is_none() && ends.reader.is_none() {
+            pipes.remove(name);
+        }
+    }
+}
+
+fn take<T>(name: &str, end: impl FnOnce(&mut Ends) -> Option<T>) -> io::Result<T> {
+    let mut pipes = PIPES.lock().unwrap_or_else(|e| e.into_inner());
+    pipes
+        .as_mut()
+        .and_then(|pipes| pipes.get_mut(name))
+        .and_then(end)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("pipe:{name} is already open, or its other operation ended"),
+            )
+        })
+}
+
+/// Open the writing end of a pipe (`of=pipe:NAME`).
+pub fn writer(name: &str) -> io::Result<PipeWriter> {
+    let sender = take(name, |ends| ends.writer.take())?;
+    Ok(PipeWriter {
+        name: name.to_string(),
+        sender: Some(sender),
+    })
+}
+
+/// Open the reading end of a pipe (`if=pipe:NAME`).
+pub fn reader(name: &str) -> io::Result<PipeReader> {
+    let receiver = take(name, |ends| ends.reader.take())?;
+    Ok(PipeReader {
+        name: name.to_string(),
+        receiver,
+        pending: vec![],
+        offset: 0,
+        finished: false,
+    })
+}
+
+/// Sends what is written to the operation reading the pipe.
+pub struct PipeWriter {
+    name: String,
+    sender: Option<SyncSender<Message>>,
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let closed = || {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("the operation reading pipe:{} stopped", self.name),
+            )
+        };
+        let sender = self.sender.as_ref().ok_or_else(closed)?;
+        if !buf.is_empty() {
+            sender.send(Some(buf.to_vec())).map_err(|_| closed())?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for PipeWriter {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        if let Some(sender) = self.sender.take() {
+            // The reader may have stopped once it had all it wanted.
+            let _ = sender.send(None);
+        }
+        Ok(None)
+    }
+
+    /// Close the pipe without saying it ended, for the reader to fail.
+    fn abandon(&mut self) -> io::Result<()> {
+        self.sender = None;
+        Ok(())
+    }
+}
+
+/// Reads what the operation writing the pipe sends, ending where it
+/// finished, or failing if it didn't.
+pub struct PipeReader {
+    name: String,
+    receiver: Receiver<Message>,
+    pending: Vec<u8>,
+    offset: usize,
+    finished: bool,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.pending.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(Some(block)) => {
+                    self.pending = block;
+                    self.offset = 0;
+                }
+                Ok(None) => self.finished = true,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("the operation writing pipe:{} failed", self.name),
+                    ));
+                }
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.offset);
+        buf[..n].copy_from_slice(&self.pending[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arguments::OperationBuilder;
+    use std::path::PathBuf;
+
+    /// `/dev/null` to pipe `name`, and pipe `name` to `/dev/null`.
+    fn pair(name: &str) -> (Operation, Operation) {
+        let mut writer = OperationBuilder::default();
+        writer.input_file(PathBuf::from("/dev/null"));
+        writer.output_pipe(name);
+        let mut reader = OperationBuilder::default();
+        reader.input_pipe(name);
+        reader.output_file(PathBuf::from("/dev/null"));
+        (writer.build().unwrap(), reader.build().unwrap())
+    }
+
+    #[test]
+    fn writer_and_reader_are_joined() {
+        let (first, second) = pair("test-joined");
+        let operations = [first, second];
+        check(&operations).unwrap();
+        assert_eq!(groups(&operations), vec![vec![0, 1]]);
+        create(&[&operations[0], &operations[1]]);
+
+        let mut writer = writer("test-joined").unwrap();
+        let mut reader = reader("test-joined").unwrap();
+        let sending = std::thread::spawn(move || {
+            writer.write_all(b"blocks ").unwrap();
+            writer.write_all(b"through a pipe").unwrap();
+            writer.finish().unwrap();
+        });
+        let mut received = vec![];
+        reader.read_to_end(&mut received).unwrap();
+        sending.join().unwrap();
+        assert_eq!(received, b"blocks through a pipe");
+
+        // Each end only opens once.
+        let Err(e) = super::writer("test-joined") else {
+            panic!("opened the writer twice");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+        release(&operations[0]);
+        release(&operations[1]);
+    }
+
+    #[test]
+    fn reader_fails_once_an_abandoned_writer_is_released() {
+        let (first, second) = pair("test-abandoned");
+        create(&[&first, &second]);
+        let mut reader = reader("test-abandoned").unwrap();
+        // The writing operation ended without opening its end.
+        release(&first);
+        let e = reader.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        release(&second);
+        let Err(e) = super::reader("test-abandoned") else {
+            panic!("opened a released pipe");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+        assert!(
+            PIPES
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_none_or(|pipes| !pipes.contains_key("test-abandoned"))
+        );
+    }
+
+    #[test]
+    fn a_pipe_read_before_it_is_written_is_refused() {
+        let (first, second) = pair("test-order");
+        assert!(check(&[second, first]).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    hash::to_hex,
+    throttle::Throttle,
+    verify::{self, CHUNK_SIZE, Chunks, Pace},
+};
+
+/// What an output that pdd can't read back itself was sent, as digests of
+/// [`CHUNK_SIZE`] chunks (`verify-plan=FILE`), for each machine that received
+/// it to check its own copy against with `pdd verify-plan PLAN FILE` instead
+/// of sending the data back to be compared.
+///
+/// The digests are of the stream before `comp=` and `enc=`, as a receiver
+/// has it once it has decompressed and decrypted it.
+///
+/// There is no channel back from `imcast=` receivers to the sender, so what
+/// a receiver found is printed where it ran, with `--json` for whatever
+/// collects it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VerifyPlan {
+    pub version: u32,
+
+    /// The output the stream was sent to, e.g. `omcast=239.1.1.1:9000`
+    pub output: String,
+    pub chunk_size: u64,
+    pub length: u64,
+    pub chunks: Vec<PlannedChunk>,
+}
+
+/// One chunk of a [`VerifyPlan`], at `offset` from the start of the stream.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlannedChunk {
+    pub offset: u64,
+    pub length: u64,
+    pub blake3: String,
+}
+
+/// Check a copy against a plan instead of copying
+/// (`pdd verify-plan PLAN FILE [--seek BYTES] [--json]`).
+#[derive(Clone, Debug)]
+pub struct PlanCheck {
+    pub plan: PathBuf,
+    pub target: PathBuf,
+
+    /// Where the stream starts in the target, as `seek=` put it there
+    pub seek: u64,
+
+    /// Print the report as JSON instead of text
+    pub json: bool,
+    pub pace: Pace,
+}
+
+/// What checking a copy against a plan found.
+#[derive(Clone, Debug, Serialize)]
+pub struct PlanReport {
+    pub plan: PathBuf,
+    pub target: PathBuf,
+    pub output: String,
+    pub length: u64,
+    pub chunks: usize,
+
+    /// Ranges of the target, from its start, that differ from the plan,
+    /// adjacent chunks joined
+    pub mismatched: Vec<Range>,
+
+    /// Where the target ended, if it did before the stream
+    pub short: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Range {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl PlanReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatched.is_empty() && self.short.is_none()
+    }
+}
+
+impl VerifyPlan {
+    const VERSION: u32 = 1;
+
+    /// The plan for what `chunks` recorded going to `output`.
+    pub fn new(output: &str, chunks: &Chunks) -> Self {
+        let mut offset = 0;
+        let chunks = chunks
+            .digests()
+            .iter()
+            .map(|digest| {
+                let length = (chunks.total() - offset).min(CHUNK_SIZE as u64);
+                let chunk = PlannedChunk {
+                    offset,
+                    length,
+                    blake3: to_hex(digest.as_bytes()),
+                };
+                offset += length;
+                chunk
+            })
+            .collect();
+        Self {
+            version: Self::VERSION,
+            output: output.to_string(),
+            chunk_size: CHUNK_SIZE as u64,
+            length: offset,
+            chunks,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let context = |e: &dyn std::fmt::Display| {
+            eyre!("Failed to read verify plan")
+                .with_note(|| e.to_string())
+                .with_note(|| format!("plan {}", path.display()))
+        };
+        let text = std::fs::read_to_string(path).map_err(|e| context(&e))?;
+        let plan: Self = serde_json::from_str(&text).map_err(|e| context(&e))?;
+        if plan.version != Self::VERSION {
+            return Err(context(&format!(
+                "version {} of the format, this pdd reads version {}",
+                plan.version,
+                Self::VERSION
+            )));
+        }
+        if plan.chunk_size > 1 << 30
+            || plan
+                .chunks
+                .iter()
+                .any(|chunk| chunk.length > plan.chunk_size)
+        {
+            return Err(context(&"its chunks are larger than it says they are"));
+        }
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let context = |e: io::Error| {
+            eyre!("Failed to save verify plan")
+                .with_error(|| e)
+                .with_note(|| format!("verify-plan={}", path.display()))
+        };
+        let json = serde_json::to_string_pretty(self)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, json + "\n").map_err(context)?;
+        std::fs::rename(&tmp, path).map_err(context)
+    }
+
+    /// Read `target` from `seek` and compare every chunk, rather than
+    /// stopping at the first that differs, so the report says what needs
+    /// sending again.
+    fn check(&self, target: &Path, seek: u64, pace: Pace) -> io::Result<(Vec<Range>, Option<u64>)> {
+        let _guard = pace.priority.map(|priority| priority.apply()).transpose()?;
+        let mut throttle = pace.rate.map(Throttle::new);
+        let mut file = File::open(target)?;
+        verify::drop_cache(&file)?;
+        file.seek(SeekFrom::Start(seek))?;
+
+        let mut buffer = vec![0u8; self.chunk_size as usize];
+        let mut mismatched: Vec<Range> = vec![];
+        for chunk in &self.chunks {
+            let want = chunk.length as usize;
+            let got = verify::read_full(&mut file, &mut buffer[..want], throttle.as_mut())?;
+            if got < want {
+                return Ok((mismatched, Some(seek + chunk.offset + got as u64)));
+            }
+            if to_hex(blake3::hash(&buffer[..want]).as_bytes()) == chunk.blake3 {
+                continue;
+            }
+            let offset = seek + chunk.offset;
+            match mismatched.last_mut() {
+                Some(last) if last.offset + last.length == offset => last.length += chunk.length,
+                _ => mismatched.push(Range {
+                    offset,
+                    length: chunk.length,
+                }),
+            }
+        }
+        Ok((mismatched, None))
+    }
+}
+
+/// Run `pdd verify-plan`.
+pub fn run(check: &PlanCheck) -> Result<()> {
+    let plan = VerifyPlan::load(&check.plan)?;
+    let (mismatched, short) = plan
+        .check(&check.target, check.seek, check.pace)
+        .map_err(|e| {
+            eyre!("Failed to read the copy back")
+                .with_error(|| e)
+                .with_note(|| format!("file {}", check.target.display()))
+        })?;
+    let report = PlanReport {
+        plan: check.plan.clone(),
+        target: check.target.clone(),
+        output: plan.output.clone(),
+        length: plan.length,
+        chunks: plan.chunks.len(),
+        mismatched,
+        short,
+    };
+    if check.json {
+        println!("{}", serde_json::to_string(&report)?);
+    } else if report.is_ok() {
+        println!(
+            "{}: verified against {}, {} bytes in {} chunks",
+            report.target.display(),
+            report.plan.display(),
+            report.length,
+            report.chunks
+        );
+    } else {
+        let differing: u64 = report.mismatched.iter().map(|range| range.length).sum();
+        println!(
+            "{}: {} bytes in {} ranges differ from {}",
+            report.target.display(),
+            differing,
+            report.mismatched.len(),
+            report.plan.display()
+        );
+        for range in &report.mismatched {
+            println!("  {:#x} +{}", range.offset, range.length);
+        }
+        if let Some(found) = report.short {
+            println!("  ends at {found}, short of {}", check.seek + report.length);
+        }
+    }
+    if !report.is_ok() {
+        return Err(eyre!("The copy doesn't match the plan")
+            .with_note(|| format!("file {}", check.target.display()))
+            .with_suggestion(|| format!("send it to {} again", report.output)));
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{fmt, str::FromStr};
+
+use crate::{report::format_timestamp, summary::Summary};
+
+/// Fields of an operation's summary a format can show.
+pub const FIELDS: [&str; 14] = [
+    "input",
+    "started",
+    "status",
+    "bytes_in",
+    "bytes_out",
+    "records_in",
+    "outputs",
+    "failed",
+    "read_errors",
+    "duration_s",
+    "duration_ms",
+    "rate",
+    "rate_out",
+    "digest",
+];
+
+/// What to print to stdout of each operation once it is done
+/// (`--print="{bytes_out} {duration_s}"`), so a script can capture a value
+/// without taking apart the `log=json` result.
+///
+/// Fields are in braces and everything else is printed as it is, with `{{`
+/// and `}}` for braces of its own; each operation gets a line. Nothing is
+/// worked out between fields, so `{bytes_out}/{duration_s}` prints both
+/// with a slash between them; values derived from others have fields of
+/// their own, like `{rate}` for the bytes read a second and `{rate_out}`
+/// for the bytes written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Format(Vec<Piece>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Field(&'static str),
+}
+
+impl Format {
+    /// The line for `summary`.
+    pub fn render(&self, summary: &Summary) -> String {
+        self.0
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text.clone(),
+                Piece::Field(name) => field(name, summary),
+            })
+            .collect()
+    }
+}
+
+fn field(name: &str, summary: &Summary) -> String {
+    let seconds = summary.elapsed.as_secs_f64();
+    let per_second = |bytes: u64| {
+        if seconds > 0.0 {
+            format!("{:.0}", bytes as f64 / seconds)
+        } else {
+            "0".to_string()
+        }
+    };
+    match name {
+        "input" => summary.input.clone(),
+        "started" => format_timestamp(summary.started),
+        "status" => if summary.is_ok() { "ok" } else { "failed" }.to_string(),
+        "bytes_in" => summary.records_in.bytes.to_string(),
+        "bytes_out" => bytes_out(summary).to_string(),
+        "records_in" => summary.records_in.to_string(),
+        "outputs" => summary.outputs.len().to_string(),
+        "failed" => summary.failed_outputs().count().to_string(),
+        "read_errors" => summary.read_errors.len().to_string(),
+        "duration_s" => format!("{seconds:.3}"),
+        "duration_ms" => summary.elapsed.as_millis().to_string(),
+        "rate" => per_second(summary.records_in.bytes),
+        "rate_out" => per_second(bytes_out(summary)),
+        "digest" => summary
+            .outputs
+            .iter()
+            .find_map(|output| output.digest.clone())
+            .unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+/// What every output got, the least of them.
+fn bytes_out(summary: &Summary) -> u64 {
+    summary
+        .outputs
+        .iter()
+        .map(|output| output.records.bytes)
+        .min()
+        .unwrap_or_default()
+}
+
+impl FromStr for Format {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |what: String| {
+            eyre!("Invalid print format, {what}")
+                .with_note(|| format!("input --print={s}"))
+                .with_suggestion(|| format!("fields are {{{}}}", FIELDS.join("}, {")))
+        };
+        let mut pieces = vec![];
+        let mut text = String::new();
+        let mut rest = s;
+        while let Some(at) = rest.find(['{', '}']) {
+            text.push_str(&rest[..at]);
+            rest = &rest[at..];
+            if let Some(tail) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+                text.push_str(&rest[..1]);
+                rest = tail;
+                continue;
+            }
+            if rest.starts_with('}') {
+                return Err(invalid("} without a {".to_string()));
+            }
+            let Some(len) = rest.find('}') else {
+                return Err(invalid("unclosed {".to_string()));
+            };
+            let key = &rest[1..len];
+            let Some(name) = FIELDS.iter().find(|name| **name == key) else {
+                return Err(invalid(format!("unknown field {{{key}}}")));
+            };
+            if !text.is_empty() {
+                pieces.push(Piece::Text(std::mem::take(&mut text)));
+            }
+            pieces.push(Piece::Field(name));
+            rest = &rest[len + 1..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        if pieces.is_empty() {
+            return Err(invalid("it is empty".to_string()));
+        }
+        Ok(Format(pieces))
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for piece in &self.0 {
+            match piece {
+                Piece::Text(text) => f.write_str(&text.replace('{', "{{").replace('}', "}}"))?,
+                Piece::Field(name) => write!(f, "{{{name}}}")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use color_eyre::{Report, Section, eyre::eyre};
+use std::{fmt, io, str::FromStr};
+
+/// An I/O scheduling class for the calling thread, as `ionice` sets them
+/// (`verify-priority=idle|best-effort:N|realtime:N`).
+///
+/// Only schedulers that share a device between classes, such as BFQ, take
+/// it into account; with `none` or `mq-deadline` it changes nothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Only gets the device when nothing else wants it
+    Idle,
+
+    /// Shares the device by level, 0 first and 7 last
+    BestEffort(u8),
+
+    /// Goes ahead of everything else by level, which needs `CAP_SYS_ADMIN`
+    Realtime(u8),
+}
+
+impl IoPriority {
+    /// The class and level packed as `ioprio_set` takes them.
+    #[cfg(target_os = "linux")]
+    fn value(self) -> libc::c_int {
+        let (class, level) = match self {
+            IoPriority::Realtime(level) => (1, level),
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        (class << 13) | libc::c_int::from(level)
+    }
+
+    /// Give the calling thread this priority until the guard is dropped,
+    /// when it gets back the one it had.
+    #[cfg(target_os = "linux")]
+    pub fn apply(self) -> io::Result<PriorityGuard> {
+        const WHO_PROCESS: libc::c_int = 1;
+
+        // SAFETY: ioprio_get and ioprio_set only take integers; a `who` of
+        // zero is the calling thread.
+        let previous = unsafe { libc::syscall(libc::SYS_ioprio_get, WHO_PROCESS, 0) };
+        if previous < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ret = unsafe { libc::syscall(libc::SYS_ioprio_set, WHO_PROCESS, 0, self.value()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PriorityGuard { previous })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(self) -> io::Result<PriorityGuard> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "I/O priorities are only supported on Linux",
+        ))
+    }
+}
+
+/// Puts back the I/O priority a thread had before [`IoPriority::apply`], as
+/// the blocking threads verification runs on are reused for other work.
+pub struct PriorityGuard {
+    #[cfg(target_os = "linux")]
+    previous: libc::c_long,
+}
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        // SAFETY: as in `IoPriority::apply`.
+        unsafe {
+            libc::syscall(libc::SYS_ioprio_set, 1, 0, self.previous);
+        }
+    }
+}
+
+impl FromStr for IoPriority {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            eyre!("Invalid I/O priority")
+                .with_note(|| format!("priority {s}"))
+                .with_suggestion(|| "expected idle, best-effort:0 to 7 or realtime:0 to 7")
+        };
+        let (class, level) = s.split_once(':').unwrap_or((s, "4"));
+        let level = level
+            .parse::<u8>()
+            .ok()
+            .filter(|level| *level < 8)
+            .ok_or_else(invalid)?;
+        match class.to_ascii_lowercase().as_str() {
+            "idle" if s == class => Ok(IoPriority::Idle),
+            "best-effort" | "be" => Ok(IoPriority::BestEffort(level)),
+            "realtime" | "rt" => Ok(IoPriority::Realtime(level)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoPriority::Idle => write!(f, "idle"),
+            IoPriority::BestEffort(level) => write!(f, "best-effort:{level}"),
+            IoPriority::Realtime(level) => write!(f, "realtime:{level}"),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// What a pipeline stage does with the blocks passing through it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StageKind {
+    Read,
+    Write,
+    Hash,
+    Scan,
+}
+
+impl fmt::Display for StageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StageKind::Read => write!(f, "read"),
+            StageKind::Write => write!(f, "write"),
+            StageKind::Hash => write!(f, "hash"),
+            StageKind::Scan => write!(f, "scan"),
+        }
+    }
+}
+
+/// Time spent in a single pipeline stage, e.g. reading the input or writing
+/// one output.
+#[derive(Clone, Debug)]
+pub struct StageProfile {
+    pub kind: StageKind,
+
+    /// The operand the stage works on, e.g. `if=disk.img`
+    pub name: String,
+
+    /// Total wall time spent inside the stage
+    pub busy: Duration,
+
+    /// Number of times the stage was entered
+    pub calls: u64,
+
+    /// Number of bytes that passed through the stage
+    pub bytes: u64,
+}
+
+impl StageProfile {
+    pub fn new(kind: StageKind, name: impl Into<String>) -> Self {
+        Self {
+            kind,
+            name: name.into(),
+            busy: Duration::ZERO,
+            calls: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Run `f` and charge the time it took to this stage.
+    pub fn time<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.busy += start.elapsed();
+        self.calls += 1;
+        result
+    }
+
+    /// Await `f` and charge the time it took to this stage.
+    pub async fn time_async<T>(&mut self, f: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let result = f.await;
+        self.busy += start.elapsed();
+        self.calls += 1;
+        result
+    }
+
+    pub fn add_bytes(&mut self, n: usize) {
+        self.bytes += n as u64;
+    }
+
+    /// Throughput while the stage was busy, in bytes per second.
+    pub fn rate(&self) -> f64 {
+        let secs = self.busy.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Fraction of `wall` this stage was busy for.
+    pub fn share(&self, wall: Duration) -> f64 {
+        let wall = wall.as_secs_f64();
+        if wall > 0.0 {
+            self.busy.as_secs_f64() / wall
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for StageProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.name)
+    }
+}
+
+/// Stage timings for one operation.
+#[derive(Clone, Debug)]
+pub struct OperationProfile {
+    /// Wall time of the whole operation
+    pub elapsed: Duration,
+
+    /// Every stage of the operation, reader first
+    pub stages: Vec<StageProfile>,
+}
+
+impl OperationProfile {
+    /// The breakdown as a JSON object, for the `result` document of
+    /// `log=json`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let stages: Vec<_> = self
+            .stages
+            .iter()
+            .map(|stage| {
+                serde_json::json!({
+                    "stage": stage.to_string(),
+                    "busy": stage.busy.as_secs_f64(),
+                    "calls": stage.calls,
+                    "bytes": stage.bytes,
+                })
+            })
+            .collect();
+        serde_json::json!({ "elapsed": self.elapsed.as_secs_f64(), "stages": stages })
+    }
+
+    /// Print a per stage breakdown to stderr.
+    pub fn print(&self) {
+        let wall = self.elapsed.as_secs_f64();
+        eprintln!("profile: {:.3}s wall time", wall);
+        eprintln!(
+            "  {:<40} {:>10} {:>7} {:>10} {:>12} {:>12}",
+            "stage", "busy", "% wall", "calls", "bytes", "busy rate"
+        );
+        for stage in &self.stages {
+            eprintln!(
+                "  {:<40} {:>9.3}s {:>6.1}% {:>10} {:>12} {:>12}",
+                stage.to_string(),
+                stage.busy.as_secs_f64(),
+                stage.share(self.elapsed) * 100.0,
+                stage.calls,
+                stage.bytes,
+                format_rate(stage.rate()),
+            );
+        }
+    }
+}
+
+/// Format a byte count with a binary suffix, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{value:.0} {}", UNITS[unit])
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Format a byte count with a decimal suffix, e.g. `3.5 kB`.
+pub fn format_decimal(bytes: f64) -> String {
+    const UNITS: [&str; 6] = ["B", "kB", "MB", "GB", "TB", "PB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{value:.0} {}", UNITS[unit])
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Format a throughput in bytes per second, e.g. `12.0 MiB/s`.
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    format!("{}/s", format_bytes(bytes_per_sec))
+}
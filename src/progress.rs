@@ -0,0 +1,279 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::mpsc::{Sender, WeakSender},
+    task::JoinHandle,
+};
+
+use crate::{
+    profile::{format_bytes, format_rate},
+    render::ProgressRenderer,
+};
+
+/// How often `status=progress` redraws the progress.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the counters are recorded for the throughput timeline.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What pdd prints while and after copying (`status=`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Status {
+    /// Only errors and the end of run output
+    #[default]
+    Default,
+
+    /// Periodically print transfer statistics
+    Progress,
+
+    /// Print nothing but errors
+    None,
+}
+
+impl FromStr for Status {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "default" => Ok(Status::Default),
+            "progress" => Ok(Status::Progress),
+            "none" => Ok(Status::None),
+            _ => Err(eyre!("Invalid status level")
+                .with_note(|| format!("input status={s}"))
+                .with_suggestion(|| "expected one of default, progress, none")),
+        }
+    }
+}
+
+/// A byte counter shared between a pipeline stage and the progress reporter.
+#[derive(Clone, Debug, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn add(&self, n: usize) {
+        self.0.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Live transfer counters for one operation.
+pub struct Progress {
+    start: Instant,
+    input: Counter,
+
+    /// Input blocks that failed to read
+    input_errors: Counter,
+
+    /// Name, bytes written and failed writes of each output
+    outputs: Vec<(String, Counter, Counter)>,
+
+    expected: Option<u64>,
+
+    /// The queue of blocks to each output, once the copy is running
+    queues: Mutex<Vec<WeakSender<Arc<[u8]>>>>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            input: Counter::default(),
+            input_errors: Counter::default(),
+            outputs: vec![],
+            expected: None,
+            queues: Mutex::new(vec![]),
+        }
+    }
+
+    /// Set how many bytes the input is expected to yield, if known.
+    pub fn expect(&mut self, bytes: u64) {
+        let _ = self.expected.replace(bytes);
+    }
+
+    /// Bytes the input is expected to yield, if known.
+    pub fn expected(&self) -> Option<u64> {
+        self.expected
+    }
+
+    /// Counter of bytes read from the input.
+    pub fn input(&self) -> Counter {
+        self.input.clone()
+    }
+
+    /// Counter of input blocks that failed to read.
+    pub fn input_errors(&self) -> Counter {
+        self.input_errors.clone()
+    }
+
+    /// Register an output and return the counters its writer should bump:
+    /// bytes written, and writes that failed, retried ones included.
+    pub fn add_output(&mut self, name: impl Into<String>) -> (Counter, Counter) {
+        let (written, errors) = (Counter::default(), Counter::default());
+        self.outputs
+            .push((name.into(), written.clone(), errors.clone()));
+        (written, errors)
+    }
+
+    /// Keep track of the queues feeding the outputs, in the order they were
+    /// added, without keeping them open.
+    pub fn watch_queues(&self, senders: &[Sender<Arc<[u8]>>]) {
+        *self.queues.lock().unwrap() = senders.iter().map(Sender::downgrade).collect();
+    }
+
+    /// Blocks waiting in each output's queue and how many it can hold, for
+    /// the queues still open.
+    pub fn queued(&self) -> Vec<(String, usize, usize)> {
+        let queues = self.queues.lock().unwrap();
+        self.outputs
+            .iter()
+            .zip(queues.iter())
+            .filter_map(|((name, ..), queue)| {
+                let queue = queue.upgrade()?;
+                let depth = queue.max_capacity();
+                Some((name.clone(), depth - queue.capacity(), depth))
+            })
+            .collect()
+    }
+
+    /// One line summary of the transfer so far.
+    pub fn line(&self) -> String {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let read = self.input.get() as f64;
+        let rate = if elapsed > 0.0 { read / elapsed } else { 0.0 };
+        let mut line = format!("read {}", format_bytes(read));
+        for (name, counter, _) in &self.outputs {
+            line.push_str(&format!(" | {name} {}", format_bytes(counter.get() as f64)));
+        }
+        line.push_str(&format!(" | {elapsed:.1}s | {}", format_rate(rate)));
+        line
+    }
+
+    /// Draw the progress with `renderer` every [`REFRESH_INTERVAL`] until
+    /// the returned reporter is finished.
+    pub fn spawn_reporter(self: &Arc<Self>, renderer: Box<dyn ProgressRenderer>) -> Reporter {
+        let renderer = Arc::new(Mutex::new(renderer));
+        let task = tokio::spawn({
+            let progress = self.clone();
+            let renderer = renderer.clone();
+            async move {
+                let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    renderer.lock().unwrap().update(&progress);
+                }
+            }
+        });
+        Reporter {
+            progress: self.clone(),
+            renderer,
+            task,
+        }
+    }
+
+    /// Snapshot of all counters.
+    pub fn sample(&self) -> Sample {
+        Sample {
+            at: self.start.elapsed(),
+            input: self.input.get(),
+            outputs: self.outputs.iter().map(|(_, c, _)| c.get()).collect(),
+            input_errors: self.input_errors.get(),
+            errors: self.outputs.iter().map(|(_, _, e)| e.get()).collect(),
+        }
+    }
+
+    /// Names of the registered outputs, in the order used by [`Sample`].
+    pub fn output_names(&self) -> Vec<String> {
+        self.outputs.iter().map(|(name, ..)| name.clone()).collect()
+    }
+
+    /// Record a [`Sample`] every [`SAMPLE_INTERVAL`] until the returned
+    /// sampler is finished.
+    pub fn spawn_sampler(self: &Arc<Self>) -> Sampler {
+        let samples = Arc::new(Mutex::new(vec![self.sample()]));
+        let task = tokio::spawn({
+            let progress = self.clone();
+            let samples = samples.clone();
+            async move {
+                let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    samples.lock().unwrap().push(progress.sample());
+                }
+            }
+        });
+        Sampler {
+            progress: self.clone(),
+            samples,
+            task,
+        }
+    }
+}
+
+/// Handle to the background task drawing `status=progress`.
+pub struct Reporter {
+    progress: Arc<Progress>,
+    renderer: Arc<Mutex<Box<dyn ProgressRenderer>>>,
+    task: JoinHandle<()>,
+}
+
+impl Reporter {
+    /// Stop redrawing and leave the final numbers on screen.
+    pub fn finish(self) {
+        self.task.abort();
+        self.renderer.lock().unwrap().finish(&self.progress);
+    }
+}
+
+/// Counter values at one point in time.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    /// Time since the operation started
+    pub at: Duration,
+
+    /// Bytes read so far
+    pub input: u64,
+
+    /// Bytes written so far, per output
+    pub outputs: Vec<u64>,
+
+    /// Input blocks that failed to read so far
+    pub input_errors: u64,
+
+    /// Writes that failed so far, per output
+    pub errors: Vec<u64>,
+}
+
+/// Handle to the background task recording the throughput timeline.
+pub struct Sampler {
+    progress: Arc<Progress>,
+    samples: Arc<Mutex<Vec<Sample>>>,
+    task: JoinHandle<()>,
+}
+
+impl Sampler {
+    /// Stop sampling and return every sample including a final one.
+    pub fn finish(self) -> Vec<Sample> {
+        self.task.abort();
+        let mut samples = std::mem::take(&mut *self.samples.lock().unwrap());
+        samples.push(self.progress.sample());
+        samples
+    }
+}
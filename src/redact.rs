@@ -0,0 +1,216 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{fmt, str::FromStr};
+
+use crate::{hash::to_hex, value::parse_size};
+
+/// What redacted bytes are replaced with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fill {
+    Zero,
+    Random,
+}
+
+impl FromStr for Fill {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "zero" => Ok(Fill::Zero),
+            "random" => Ok(Fill::Random),
+            _ => {
+                Err(eyre!("Unknown redaction fill {s}")
+                    .with_suggestion(|| "expected zero or random"))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Fill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fill::Zero => write!(f, "zero"),
+            Fill::Random => write!(f, "random"),
+        }
+    }
+}
+
+/// Bytes of the stream to redact.
+#[derive(Clone, Debug)]
+pub enum Region {
+    /// `len` bytes at a fixed offset from the start of the input
+    Range { offset: u64, len: u64 },
+
+    /// Every occurrence of a byte pattern
+    Pattern(Vec<u8>),
+}
+
+/// A region wiped from the stream before it reaches the outputs
+/// (`redact=OFFSET,LEN[,FILL]` or `redact=match,HEXBYTES[,FILL]`).
+#[derive(Clone, Debug)]
+pub struct Redaction {
+    pub region: Region,
+    pub fill: Fill,
+}
+
+impl FromStr for Redaction {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            eyre!("Invalid redaction")
+                .with_note(|| format!("input redact={s}"))
+                .with_suggestion(|| {
+                    "expected redact=OFFSET,LEN[,zero|random] or \
+                     redact=match,HEXBYTES[,zero|random]"
+                })
+        };
+        let parts: Vec<&str> = s.split(',').collect();
+        let (region, value, fill) = match parts.as_slice() {
+            [region, value] => (*region, *value, Fill::Zero),
+            [region, value, fill] => (*region, *value, fill.parse()?),
+            _ => return Err(invalid()),
+        };
+        let region = match region {
+            "match" => {
+                let pattern = parse_hex(value).ok_or_else(invalid)?;
+                if pattern.is_empty() {
+                    return Err(invalid());
+                }
+                Region::Pattern(pattern)
+            }
+            offset => Region::Range {
+                offset: parse_size("redact", offset)?,
+                len: parse_size("redact", value)?,
+            },
+        };
+        Ok(Self { region, fill })
+    }
+}
+
+impl fmt::Display for Redaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.region {
+            Region::Range { offset, len } => write!(f, "redact={offset},{len},{}", self.fill),
+            Region::Pattern(pattern) => {
+                write!(f, "redact=match,{},{}", to_hex(pattern), self.fill)
+            }
+        }
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Applies redactions to the stream block by block.
+///
+/// So that pattern matches spanning two blocks are caught, each block is held
+/// back until the next one has been searched; `push` returns the previous
+/// block and `finish` the last one.
+pub struct Redactor {
+    redactions: Vec<Redaction>,
+    pending: Vec<u8>,
+    /// Offset of `pending` from the start of the input
+    position: u64,
+    /// Per redaction, where in `pending` the next pattern search starts
+    cursors: Vec<usize>,
+    /// Regions and bytes redacted so far
+    regions: usize,
+    bytes: u64,
+}
+
+impl Redactor {
+    /// `position` is the offset of the first block from the start of the
+    /// input.
+    pub fn new(redactions: Vec<Redaction>, position: u64) -> Self {
+        Self {
+            cursors: vec![0; redactions.len()],
+            redactions,
+            pending: vec![],
+            position,
+            regions: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn push(&mut self, block: &[u8]) -> Result<Option<Vec<u8>>> {
+        let held = self.pending.len();
+        self.pending.extend_from_slice(block);
+        self.redact(held)?;
+        if held == 0 {
+            return Ok(None);
+        }
+        let rest = self.pending.split_off(held);
+        let done = std::mem::replace(&mut self.pending, rest);
+        self.position += held as u64;
+        for cursor in &mut self.cursors {
+            *cursor = cursor.saturating_sub(held);
+        }
+        Ok(Some(done))
+    }
+
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        self.position += self.pending.len() as u64;
+        (!self.pending.is_empty()).then(|| std::mem::take(&mut self.pending))
+    }
+
+    /// Regions and bytes redacted so far.
+    pub fn redacted(&self) -> (usize, u64) {
+        (self.regions, self.bytes)
+    }
+
+    /// Redact `pending`, of which everything from `new` on has just arrived.
+    fn redact(&mut self, new: usize) -> Result<()> {
+        let start = self.position;
+        let end = start + self.pending.len() as u64;
+        let mut hits = vec![];
+        for (index, redaction) in self.redactions.iter().enumerate() {
+            match &redaction.region {
+                Region::Range { offset, len } => {
+                    let from = (*offset).max(start + new as u64);
+                    let to = offset.saturating_add(*len).min(end);
+                    if from < to {
+                        let first = from == *offset;
+                        hits.push((index, (from - start) as usize, (to - start) as usize, first));
+                    }
+                }
+                Region::Pattern(pattern) => {
+                    let mut at = self.cursors[index];
+                    while let Some(found) = find(&self.pending[at..], pattern) {
+                        let from = at + found;
+                        hits.push((index, from, from + pattern.len(), true));
+                        at = from + pattern.len();
+                    }
+                    // A match could still start in the last `pattern.len() - 1`
+                    // bytes once more data arrives.
+                    self.cursors[index] =
+                        at.max((self.pending.len() + 1).saturating_sub(pattern.len()));
+                }
+            }
+        }
+        // A range split over two blocks only counts as one region.
+        for (index, from, to, first) in hits {
+            let hit = &mut self.pending[from..to];
+            match self.redactions[index].fill {
+                Fill::Zero => hit.fill(0),
+                Fill::Random => getrandom::fill(hit)
+                    .map_err(|e| eyre!("Failed to generate random bytes: {e}"))?,
+            }
+            self.regions += usize::from(first);
+            self.bytes += (to - from) as u64;
+        }
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
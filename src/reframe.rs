@@ -0,0 +1,77 @@
+use std::io::{self, Write};
+
+use crate::sink::Sink;
+
+/// Sink wrapper writing to `inner` in frames of exactly `size` bytes, the
+/// last one possibly short, however the blocks it is given are cut
+/// (`oframe=SIZE`). For outputs that take writes of one size only, like a
+/// tape drive in fixed block mode or a disk written directly, whatever
+/// block size and compression the rest of the operation uses.
+pub struct ReframeSink {
+    inner: Box<dyn Sink>,
+    size: usize,
+
+    /// Start of the next frame, until it is whole
+    buffer: Vec<u8>,
+}
+
+impl ReframeSink {
+    /// `size` must be greater than zero.
+    pub fn new(inner: Box<dyn Sink>, size: usize) -> Self {
+        Self {
+            inner,
+            size,
+            buffer: Vec::with_capacity(size),
+        }
+    }
+
+    /// Write what is left of the stream as a last, short frame.
+    fn drain(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.inner.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Write for ReframeSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        if !self.buffer.is_empty() {
+            let n = rest.len().min(self.size - self.buffer.len());
+            self.buffer.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            if self.buffer.len() < self.size {
+                return Ok(buf.len());
+            }
+            self.inner.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        // Whole frames go through as they are.
+        let mut frames = rest.chunks_exact(self.size);
+        for frame in &mut frames {
+            self.inner.write_all(frame)?;
+        }
+        self.buffer.extend_from_slice(frames.remainder());
+        Ok(buf.len())
+    }
+
+    /// Frames are written once whole; a short one can only be the last.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for ReframeSink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.drain()?;
+        self.inner.finish()
+    }
+
+    /// What was given is still written, as far as it goes.
+    fn abandon(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.abandon()
+    }
+}
@@ -0,0 +1,375 @@
+use age::secrecy::ExposeSecret;
+use age_core::format::{FileKey, Stanza};
+use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
+use color_eyre::{Result, Section, eyre::eyre};
+use hmac::{Hmac, Mac};
+use sha2_hmac::Sha256;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::keys::KeyProvider;
+
+/// First line of the header of every binary age file
+const VERSION_LINE: &str = "age-encryption.org/v1";
+
+/// Stanza bodies are base64 in lines of this many columns, the last shorter.
+const COLUMNS: usize = 64;
+
+/// Who can decrypt an age file, changed by rewriting its header (`pdd rekey
+/// FILE --identity KEY --add RECIPIENT` or `--to RECIPIENT`).
+///
+/// The payload is encrypted with a file key that the header holds wrapped
+/// for each recipient, so the file key is unwrapped with the identity and
+/// wrapped again for the recipients wanted, and the payload is copied over
+/// as it is. Stanzas don't say who they are for, so recipients can't be
+/// removed one at a time: `--to` replaces all of them. Anyone removed who
+/// kept the file key can still decrypt the file; only encrypting it again
+/// stops that.
+#[derive(Clone)]
+pub struct Rekey {
+    pub path: PathBuf,
+
+    /// Keys able to decrypt the file now
+    pub identity: Arc<dyn KeyProvider>,
+
+    /// Recipients to add to those the file has (`--add`)
+    pub add: Vec<Arc<dyn KeyProvider>>,
+
+    /// Recipients to replace those the file has with (`--to`)
+    pub to: Vec<Arc<dyn KeyProvider>>,
+}
+
+/// The header of an age file.
+struct Header {
+    stanzas: Vec<Stanza>,
+
+    /// What the MAC covers: everything up to and including `---`
+    authenticated: Vec<u8>,
+    mac: Vec<u8>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Read the header of an age file, leaving `reader` at the payload.
+fn read_header(reader: &mut impl BufRead) -> io::Result<Header> {
+    let mut authenticated = vec![];
+    let mut line = vec![];
+    let mut next_line = |authenticated: &mut Vec<u8>| -> io::Result<String> {
+        line.clear();
+        reader.read_until(b'\n', &mut line)?;
+        if line.pop() != Some(b'\n') {
+            return Err(invalid("the age header ends early"));
+        }
+        authenticated.extend_from_slice(&line);
+        authenticated.push(b'\n');
+        String::from_utf8(line.clone()).map_err(|_| invalid("the age header isn't text"))
+    };
+    if next_line(&mut authenticated)? != VERSION_LINE {
+        return Err(invalid(
+            "not a binary age file, armored files have to be dearmored first",
+        ));
+    }
+    let mut stanzas = vec![];
+    let mut text = next_line(&mut authenticated)?;
+    while let Some(stanza) = text.strip_prefix("-> ") {
+        let mut args = stanza.split(' ').map(str::to_string);
+        let tag = args.next().unwrap_or_default();
+        let mut body = String::new();
+        loop {
+            let line = next_line(&mut authenticated)?;
+            body.push_str(&line);
+            if line.len() < COLUMNS {
+                break;
+            }
+        }
+        stanzas.push(Stanza {
+            tag,
+            args: args.collect(),
+            body: STANDARD_NO_PAD
+                .decode(body)
+                .map_err(|_| invalid("an age header stanza isn't base64"))?,
+        });
+        text = next_line(&mut authenticated)?;
+    }
+    let Some(mac) = text.strip_prefix("--- ") else {
+        return Err(invalid("the age header has no MAC"));
+    };
+    let mac = STANDARD_NO_PAD
+        .decode(mac)
+        .map_err(|_| invalid("the age header MAC isn't base64"))?;
+    // The MAC covers the `---` but not what follows it.
+    authenticated.truncate(authenticated.len() - text.len() - 1 + 3);
+    Ok(Header {
+        stanzas,
+        authenticated,
+        mac,
+    })
+}
+
+/// A header holding `file_key` wrapped in `stanzas`, MAC and all.
+fn write_header(stanzas: &[Stanza], file_key: &FileKey) -> Vec<u8> {
+    let mut header = format!("{VERSION_LINE}\n");
+    for stanza in stanzas {
+        header.push_str("-> ");
+        header.push_str(&stanza.tag);
+        for arg in &stanza.args {
+            header.push(' ');
+            header.push_str(arg);
+        }
+        header.push('\n');
+        let body = STANDARD_NO_PAD.encode(&stanza.body);
+        for line in body.as_bytes().chunks(COLUMNS) {
+            header.push_str(std::str::from_utf8(line).unwrap_or_default());
+            header.push('\n');
+        }
+        if body.len() % COLUMNS == 0 {
+            header.push('\n');
+        }
+    }
+    header.push_str("---");
+    let mac = mac(file_key, header.as_bytes()).finalize().into_bytes();
+    header.push(' ');
+    header.push_str(&STANDARD_NO_PAD.encode(mac));
+    header.push('\n');
+    header.into_bytes()
+}
+
+/// HMAC-SHA256 of the header under a key derived from the file key.
+fn mac(file_key: &FileKey, header: &[u8]) -> Hmac<Sha256> {
+    let key = age_core::primitives::hkdf(&[], b"header", file_key.expose_secret());
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC takes keys of any length");
+    mac.update(header);
+    mac
+}
+
+/// Stanzas wrapping `file_key` for the keys of `providers`.
+fn wrap(file_key: &FileKey, providers: &[Arc<dyn KeyProvider>]) -> Result<Vec<Stanza>> {
+    let mut stanzas = vec![];
+    let mut all_labels: Option<HashSet<String>> = None;
+    for provider in providers {
+        let keys = provider.keys().map_err(|e| {
+            eyre!("Failed to get encryption keys")
+                .with_error(|| e)
+                .with_note(|| format!("recipient {provider}"))
+        })?;
+        for recipient in keys.recipients() {
+            let (wrapped, labels) = recipient.wrap_file_key(file_key).map_err(|e| {
+                eyre!("Failed to wrap the file key, {e}")
+                    .with_note(|| format!("recipient {provider}"))
+            })?;
+            if all_labels.get_or_insert_with(|| labels.clone()) != &labels {
+                return Err(eyre!("These recipients can't be used together")
+                    .with_note(|| format!("recipient {provider}")));
+            }
+            stanzas.extend(wrapped);
+        }
+    }
+    Ok(stanzas)
+}
+
+/// Run `pdd rekey`.
+pub fn run(rekey: &Rekey) -> Result<()> {
+    let path = &rekey.path;
+    let context = |e: io::Error| {
+        eyre!("Failed to rekey")
+            .with_error(|| e)
+            .with_note(|| format!("file {}", path.display()))
+    };
+    if rekey.add.is_empty() == rekey.to.is_empty() {
+        return Err(
+            eyre!("Rekeying needs the recipients to --add, or to change --to")
+                .with_suggestion(|| "give one of them, e.g. --add age1ql3z7hjy..."),
+        );
+    }
+    let file = File::open(path).map_err(context)?;
+    let permissions = file.metadata().map_err(context)?.permissions();
+    let mut reader = BufReader::new(file);
+    let header = read_header(&mut reader).map_err(context)?;
+
+    let identities = rekey
+        .identity
+        .keys()
+        .map_err(|e| {
+            eyre!("Failed to get decryption keys")
+                .with_error(|| e)
+                .with_note(|| format!("identity {}", rekey.identity))
+        })?
+        .identities();
+    let file_key = identities
+        .iter()
+        .find_map(|identity| identity.unwrap_stanzas(&header.stanzas))
+        .transpose()
+        .map_err(|e| eyre!("Failed to unwrap the file key, {e}"))?
+        .ok_or_else(|| {
+            eyre!("None of the identity's keys can decrypt the file")
+                .with_note(|| format!("identity {}", rekey.identity))
+                .with_note(|| format!("file {}", path.display()))
+        })?;
+    if mac(&file_key, &header.authenticated)
+        .verify_slice(&header.mac)
+        .is_err()
+    {
+        return Err(eyre!("The header of the file doesn't match its MAC")
+            .with_note(|| format!("file {}", path.display()))
+            .with_note(|| "it was altered, so it is left as it is"));
+    }
+
+    let before = header.stanzas.len();
+    let mut stanzas = if rekey.to.is_empty() {
+        header.stanzas
+    } else {
+        vec![]
+    };
+    let added = wrap(
+        &file_key,
+        if rekey.to.is_empty() {
+            &rekey.add
+        } else {
+            &rekey.to
+        },
+    )?;
+    // A passphrase has to be the only way into a file.
+    if !stanzas.is_empty()
+        && stanzas
+            .iter()
+            .chain(&added)
+            .any(|stanza| stanza.tag == "scrypt")
+    {
+        return Err(
+            eyre!("A passphrase can't be used along with other recipients")
+                .with_suggestion(|| "use --to to replace every recipient instead"),
+        );
+    }
+    stanzas.extend(added);
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{name}.rekey"));
+    let write = |tmp: &Path| -> io::Result<()> {
+        let mut out = File::create(tmp)?;
+        out.set_permissions(permissions)?;
+        out.write_all(&write_header(&stanzas, &file_key))?;
+        io::copy(&mut reader, &mut out)?;
+        out.sync_all()
+    };
+    if let Err(e) = write(&tmp) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(context(e));
+    }
+    std::fs::rename(&tmp, path).map_err(context)?;
+    println!(
+        "{}: {} stanzas in the header, {} before",
+        path.display(),
+        stanzas.len(),
+        before
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::{Decryptor, Encryptor, x25519};
+    use std::io::Read;
+
+    use crate::keys;
+
+    /// A key file holding a new identity, and its recipient.
+    fn identity(dir: &Path, name: &str) -> (PathBuf, x25519::Recipient) {
+        let identity = x25519::Identity::generate();
+        let path = dir.join(name);
+        std::fs::write(&path, format!("{}\n", identity.to_string().expose_secret())).unwrap();
+        (path, identity.to_public())
+    }
+
+    fn encrypt(recipient: &x25519::Recipient, plaintext: &[u8]) -> Vec<u8> {
+        let encryptor =
+            Encryptor::with_recipients(std::iter::once(recipient as &dyn age::Recipient)).unwrap();
+        let mut encrypted = vec![];
+        let mut writer = encryptor.wrap_output(&mut encrypted).unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap();
+        encrypted
+    }
+
+    /// The plaintext of the file at `path`, if the identity in `key` can
+    /// decrypt it.
+    fn decrypt(path: &Path, key: &Path) -> Option<Vec<u8>> {
+        let keys = keys::provider(key.to_str().unwrap())
+            .unwrap()
+            .keys()
+            .unwrap();
+        let identities = keys.identities();
+        let decryptor = Decryptor::new(File::open(path).unwrap()).unwrap();
+        let mut reader = decryptor
+            .decrypt(identities.iter().map(|identity| &**identity as _))
+            .ok()?;
+        let mut plaintext = vec![];
+        reader.read_to_end(&mut plaintext).unwrap();
+        Some(plaintext)
+    }
+
+    fn provider(path: &Path) -> Arc<dyn KeyProvider> {
+        keys::provider(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn add_then_replace_recipients() {
+        let dir = std::env::temp_dir().join(format!("pdd-rekey-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (old, old_recipient) = identity(&dir, "old.key");
+        let (new, _) = identity(&dir, "new.key");
+        let (last, _) = identity(&dir, "last.key");
+        let plaintext: Vec<u8> = (0..200_000u32).map(|n| (n % 251) as u8).collect();
+        let path = dir.join("image.age");
+        std::fs::write(&path, encrypt(&old_recipient, &plaintext)).unwrap();
+        assert_eq!(decrypt(&path, &new), None);
+
+        // --add keeps the old recipient.
+        run(&Rekey {
+            path: path.clone(),
+            identity: provider(&old),
+            add: vec![provider(&new)],
+            to: vec![],
+        })
+        .unwrap();
+        assert_eq!(decrypt(&path, &old).as_deref(), Some(&plaintext[..]));
+        assert_eq!(decrypt(&path, &new).as_deref(), Some(&plaintext[..]));
+
+        // --to leaves only the one given, unwrapped with the added identity.
+        run(&Rekey {
+            path: path.clone(),
+            identity: provider(&new),
+            add: vec![],
+            to: vec![provider(&last)],
+        })
+        .unwrap();
+        assert_eq!(decrypt(&path, &last).as_deref(), Some(&plaintext[..]));
+        assert_eq!(decrypt(&path, &old), None);
+        assert_eq!(decrypt(&path, &new), None);
+
+        // An altered header is refused and the file left as it is.
+        let mut altered = std::fs::read(&path).unwrap();
+        let at = altered.windows(3).position(|w| w == b"---").unwrap() - 2;
+        altered[at] ^= 1;
+        std::fs::write(&path, &altered).unwrap();
+        let failed = run(&Rekey {
+            path: path.clone(),
+            identity: provider(&last),
+            add: vec![provider(&old)],
+            to: vec![],
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), altered);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,382 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use ratatui::{
+    Terminal, TerminalOptions, Viewport,
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::{Line, Span},
+    widgets::LineGauge,
+};
+use std::{
+    fmt,
+    io::{self, IsTerminal, Stderr, Write},
+    str::FromStr,
+};
+
+use crate::{
+    profile::{format_bytes, format_rate},
+    progress::{Progress, Sample},
+};
+
+/// Width of the bars drawn by `--progress bars`, in characters.
+const BAR_WIDTH: usize = 30;
+
+/// Draws the progress of a copy while it runs.
+///
+/// The built in renderers are picked with `--progress`; an embedder can
+/// implement this to show progress its own way and hand it to
+/// [`Progress::spawn_reporter`].
+pub trait ProgressRenderer: Send {
+    /// Show the counters as they are now. Called about once a second.
+    fn update(&mut self, progress: &Progress);
+
+    /// Show the final counters once the copy is done.
+    fn finish(&mut self, progress: &Progress) {
+        self.update(progress);
+    }
+}
+
+/// The built in renderers (`--progress`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Renderer {
+    /// One line redrawn in place, like dd's `status=progress`
+    #[default]
+    Plain,
+
+    /// A bar per output
+    Bars,
+
+    /// A terminal dashboard below the command line
+    Tui,
+
+    /// A JSON object per update, one per line
+    Json,
+
+    /// Nothing
+    Quiet,
+}
+
+impl Renderer {
+    pub fn build(self) -> Box<dyn ProgressRenderer> {
+        match self {
+            Renderer::Plain => Box::new(PlainRenderer),
+            Renderer::Bars => Box::new(BarsRenderer::default()),
+            Renderer::Tui => Box::new(TuiRenderer::default()),
+            Renderer::Json => Box::new(JsonRenderer),
+            Renderer::Quiet => Box::new(QuietRenderer),
+        }
+    }
+}
+
+impl FromStr for Renderer {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(Renderer::Plain),
+            "bars" => Ok(Renderer::Bars),
+            "tui" => Ok(Renderer::Tui),
+            "json" => Ok(Renderer::Json),
+            "quiet" => Ok(Renderer::Quiet),
+            _ => Err(eyre!("Invalid progress renderer")
+                .with_note(|| format!("input --progress {s}"))
+                .with_suggestion(|| "expected one of plain, bars, tui, json, quiet")),
+        }
+    }
+}
+
+impl fmt::Display for Renderer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Renderer::Plain => write!(f, "plain"),
+            Renderer::Bars => write!(f, "bars"),
+            Renderer::Tui => write!(f, "tui"),
+            Renderer::Json => write!(f, "json"),
+            Renderer::Quiet => write!(f, "quiet"),
+        }
+    }
+}
+
+/// [`Progress::line`] redrawn in place on stderr.
+pub struct PlainRenderer;
+
+impl ProgressRenderer for PlainRenderer {
+    fn update(&mut self, progress: &Progress) {
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[K{}", progress.line());
+        let _ = stderr.flush();
+    }
+
+    fn finish(&mut self, progress: &Progress) {
+        self.update(progress);
+        eprintln!();
+    }
+}
+
+/// A line for the input and a bar for each output on stderr, redrawn in
+/// place. Bars fill towards the expected size of the input, or towards
+/// what has been read when that isn't known.
+#[derive(Default)]
+pub struct BarsRenderer {
+    /// Lines drawn by the last update, to move back over
+    drawn: usize,
+}
+
+impl ProgressRenderer for BarsRenderer {
+    fn update(&mut self, progress: &Progress) {
+        let sample = progress.sample();
+        let names = progress.output_names();
+        let width = names.iter().map(String::len).max().unwrap_or(0);
+        let total = progress.expected().unwrap_or(sample.input);
+        let elapsed = sample.at.as_secs_f64();
+        let rate = |bytes: u64| {
+            format_rate(if elapsed > 0.0 {
+                bytes as f64 / elapsed
+            } else {
+                0.0
+            })
+        };
+
+        let mut stderr = io::stderr().lock();
+        if self.drawn > 0 {
+            let _ = write!(stderr, "\x1b[{}A", self.drawn);
+        }
+        let _ = writeln!(
+            stderr,
+            "\r\x1b[Kread {} | {elapsed:.1}s | {}",
+            format_bytes(sample.input as f64),
+            rate(sample.input),
+        );
+        for (name, written) in names.iter().zip(&sample.outputs) {
+            let _ = writeln!(
+                stderr,
+                "\r\x1b[K{name:<width$} {} {}",
+                bar(*written, total),
+                format_bytes(*written as f64),
+            );
+        }
+        let _ = stderr.flush();
+        self.drawn = names.len() + 1;
+    }
+}
+
+/// `[#########---------]  50%`
+fn bar(done: u64, total: u64) -> String {
+    let ratio = if total > 0 {
+        (done as f64 / total as f64).min(1.0)
+    } else {
+        0.0
+    };
+    let filled = (ratio * BAR_WIDTH as f64) as usize;
+    format!(
+        "[{}{}] {:>3.0}%",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        ratio * 100.0
+    )
+}
+
+/// A dashboard drawn inline on stderr, so whatever was printed before stays
+/// in the scrollback: the input, then a bar per output with its throughput,
+/// time left and failed writes. Falls back to [`PlainRenderer`] if stderr
+/// isn't a terminal it can draw on.
+#[derive(Default)]
+pub struct TuiRenderer {
+    terminal: Option<Terminal<CrosstermBackend<Stderr>>>,
+    fallback: Option<PlainRenderer>,
+
+    /// Counters at the last update, to tell the current throughput from
+    last: Option<Sample>,
+}
+
+impl TuiRenderer {
+    fn draw(&mut self, progress: &Progress) -> io::Result<()> {
+        let sample = progress.sample();
+        let names = progress.output_names();
+        let terminal = match &mut self.terminal {
+            Some(terminal) => terminal,
+            None if !io::stderr().is_terminal() => {
+                return Err(io::Error::other("stderr is not a terminal"));
+            }
+            None => self.terminal.insert(Terminal::with_options(
+                CrosstermBackend::new(io::stderr()),
+                TerminalOptions {
+                    viewport: Viewport::Inline(names.len() as u16 + 1),
+                },
+            )?),
+        };
+        let expected = progress.expected();
+        let total = expected.unwrap_or(sample.input);
+        let elapsed = sample.at.as_secs_f64();
+        // Bytes per second since the last update, or over the whole copy
+        // before there was one.
+        let last = self.last.replace(sample.clone());
+        let rate = |now: u64, before: Option<u64>| {
+            let (bytes, secs) = match (&last, before) {
+                (Some(last), Some(before)) => (
+                    now.saturating_sub(before),
+                    (sample.at - last.at).as_secs_f64(),
+                ),
+                _ => (now, elapsed),
+            };
+            if secs > 0.0 { bytes as f64 / secs } else { 0.0 }
+        };
+        let name_width = names.iter().map(String::len).max().unwrap_or(0);
+        terminal.draw(|frame| {
+            let rows =
+                Layout::vertical(vec![Constraint::Length(1); names.len() + 1]).split(frame.area());
+            let read_rate = rate(sample.input, last.as_ref().map(|last| last.input));
+            let mut header = match expected {
+                Some(expected) => format!(
+                    "read {} of {} | {elapsed:.1}s | {}",
+                    format_bytes(sample.input as f64),
+                    format_bytes(expected as f64),
+                    format_rate(read_rate),
+                ),
+                None => format!(
+                    "read {} | {elapsed:.1}s | {}",
+                    format_bytes(sample.input as f64),
+                    format_rate(read_rate),
+                ),
+            };
+            if let Some(eta) = eta(sample.input, expected, read_rate) {
+                header.push_str(&format!(" | ETA {eta}"));
+            }
+            let mut header = vec![Span::raw(header)];
+            if sample.input_errors > 0 {
+                header.push(Span::styled(
+                    format!(" | {} read errors", sample.input_errors),
+                    Style::new().fg(Color::Red),
+                ));
+            }
+            frame.render_widget(Line::from(header), rows[0]);
+            let outputs = names
+                .iter()
+                .zip(&sample.outputs)
+                .zip(&sample.errors)
+                .enumerate();
+            for ((index, ((name, written), errors)), row) in outputs.zip(&rows[1..]) {
+                let ratio = if total > 0 {
+                    (*written as f64 / total as f64).min(1.0)
+                } else {
+                    0.0
+                };
+                let before = last
+                    .as_ref()
+                    .and_then(|last| last.outputs.get(index).copied());
+                let output_rate = rate(*written, before);
+                let mut label = vec![Span::raw(format!(
+                    "{name:<name_width$} {} {}",
+                    format_bytes(*written as f64),
+                    format_rate(output_rate),
+                ))];
+                if let Some(eta) = eta(*written, expected, output_rate) {
+                    label.push(Span::raw(format!(" ETA {eta}")));
+                }
+                let color = if *errors > 0 {
+                    label.push(Span::styled(
+                        format!(" {errors} errors"),
+                        Style::new().fg(Color::Red),
+                    ));
+                    Color::Red
+                } else {
+                    Color::Green
+                };
+                let gauge = LineGauge::default()
+                    .ratio(ratio)
+                    .filled_style(Style::new().fg(color))
+                    .label(Line::from(label));
+                frame.render_widget(gauge, *row);
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Time left to get from `done` to `expected` bytes at `rate`, e.g. `1:05`
+/// or `2:03:04`; `None` when finished or when that can't be told.
+fn eta(done: u64, expected: Option<u64>, rate: f64) -> Option<String> {
+    let left = expected?.checked_sub(done).filter(|&left| left > 0)?;
+    if rate <= 0.0 {
+        return None;
+    }
+    let secs = (left as f64 / rate).ceil() as u64;
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    Some(if hours > 0 {
+        format!("{hours}:{minutes:02}:{secs:02}")
+    } else {
+        format!("{minutes}:{secs:02}")
+    })
+}
+
+impl ProgressRenderer for TuiRenderer {
+    fn update(&mut self, progress: &Progress) {
+        if let Some(fallback) = &mut self.fallback {
+            return fallback.update(progress);
+        }
+        if self.draw(progress).is_err() {
+            self.terminal = None;
+            self.fallback.insert(PlainRenderer).update(progress);
+        }
+    }
+
+    fn finish(&mut self, progress: &Progress) {
+        self.update(progress);
+        if let Some(fallback) = &mut self.fallback {
+            return fallback.finish(progress);
+        }
+        // Leave the cursor on the line after the dashboard.
+        if let Some(terminal) = &mut self.terminal {
+            let area = terminal.get_frame().area();
+            let _ = terminal.set_cursor_position((0, area.bottom().saturating_sub(1)));
+            let _ = terminal.show_cursor();
+            eprintln!();
+        }
+    }
+}
+
+/// A JSON object per update on stderr, for wrappers that draw their own
+/// progress:
+/// `{"elapsed":1.0,"read":4096,"read_errors":0,"expected":null,"outputs":[{"name":"of=a","written":4096,"errors":0}],"done":false}`
+pub struct JsonRenderer;
+
+impl JsonRenderer {
+    fn emit(progress: &Progress, done: bool) {
+        let sample = progress.sample();
+        let outputs: Vec<_> = progress
+            .output_names()
+            .into_iter()
+            .zip(sample.outputs)
+            .zip(sample.errors)
+            .map(|((name, written), errors)| {
+                serde_json::json!({ "name": name, "written": written, "errors": errors })
+            })
+            .collect();
+        crate::log::event(serde_json::json!({
+            "type": "progress",
+            "elapsed": sample.at.as_secs_f64(),
+            "read": sample.input,
+            "read_errors": sample.input_errors,
+            "expected": progress.expected(),
+            "outputs": outputs,
+            "done": done,
+        }));
+    }
+}
+
+impl ProgressRenderer for JsonRenderer {
+    fn update(&mut self, progress: &Progress) {
+        Self::emit(progress, false);
+    }
+
+    fn finish(&mut self, progress: &Progress) {
+        Self::emit(progress, true);
+    }
+}
+
+/// Draws nothing.
+pub struct QuietRenderer;
+
+impl ProgressRenderer for QuietRenderer {
+    fn update(&mut self, _progress: &Progress) {}
+}
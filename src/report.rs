@@ -0,0 +1,395 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    profile::{OperationProfile, format_bytes, format_rate},
+    progress::Sample,
+    summary::Summary,
+    template::Variables,
+};
+
+/// Timelines longer than this are thinned out before being drawn.
+const MAX_POINTS: usize = 400;
+
+const CHART_WIDTH: f64 = 800.0;
+const CHART_HEIGHT: f64 = 260.0;
+const MARGIN_LEFT: f64 = 90.0;
+const MARGIN_RIGHT: f64 = 10.0;
+const MARGIN_TOP: f64 = 10.0;
+const MARGIN_BOTTOM: f64 = 30.0;
+
+const COLORS: [&str; 8] = [
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f",
+];
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin:1em 0}\
+th,td{border:1px solid #ccc;padding:4px 10px;text-align:left}\
+th{background:#f0f0f0}\
+td.num{text-align:right;font-family:monospace}\
+.ok{color:#2ca02c}.bad{color:#d62728}";
+
+/// Everything measured while running one operation.
+pub struct Report {
+    pub summary: Summary,
+    pub profile: OperationProfile,
+
+    /// Counter samples over the run, empty unless a report was requested
+    pub timeline: Vec<Sample>,
+
+    /// Values of the variables `of=auto:` names are made from
+    pub variables: Variables,
+}
+
+/// Render every operation of a run into a self-contained HTML file.
+pub fn write_html(path: &Path, reports: &[Report]) -> Result<()> {
+    let html = render(reports);
+    std::fs::write(path, html).map_err(|e| {
+        eyre!("Failed to write report")
+            .with_error(|| e)
+            .with_note(|| format!("report {}", path.display()))
+    })
+}
+
+fn render(reports: &[Report]) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>pdd report</title>\
+         <style>{STYLE}</style></head><body>\n<h1>pdd report</h1>\n<p>Generated {}</p>\n",
+        format_timestamp(SystemTime::now()),
+    );
+    for (i, report) in reports.iter().enumerate() {
+        render_operation(&mut html, i + 1, report);
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn render_operation(html: &mut String, number: usize, report: &Report) {
+    let summary = &report.summary;
+    let secs = summary.elapsed.as_secs_f64();
+    let _ = write!(
+        html,
+        "<h2>Operation {number}: {}</h2>\n<table>\
+         <tr><th>Started</th><td>{}</td></tr>\
+         <tr><th>Elapsed</th><td class=\"num\">{secs:.3} s</td></tr>\
+         <tr><th>Records in</th><td class=\"num\">{}</td></tr>\
+         <tr><th>Bytes read</th><td class=\"num\">{} ({})</td></tr>\
+         <tr><th>Average rate</th><td class=\"num\">{}</td></tr>\
+         <tr><th>Result</th><td>{}</td></tr></table>\n",
+        escape(&summary.input),
+        format_timestamp(summary.started),
+        summary.records_in,
+        summary.records_in.bytes,
+        format_bytes(summary.records_in.bytes as f64),
+        format_rate(rate(summary.records_in.bytes, secs)),
+        if summary.interrupted {
+            "<span class=\"bad\">interrupted</span>"
+        } else if summary.aborted {
+            "<span class=\"bad\">aborted</span>"
+        } else {
+            "<span class=\"ok\">finished</span>"
+        },
+    );
+
+    html.push_str(
+        "<h3>Outputs</h3>\n<table><tr><th>Output</th><th>Records out</th><th>Bytes</th>\
+         <th>Elapsed</th><th>Average rate</th><th>Result</th><th>Verify</th></tr>\n",
+    );
+    for output in summary
+        .outputs
+        .iter()
+        .filter(|output| output.digest.is_none())
+    {
+        let secs = output.elapsed.as_secs_f64();
+        let result = if output.error.is_some() {
+            "<span class=\"bad\">failed</span>"
+        } else if output.striped {
+            "<span class=\"ok\">striped</span>"
+        } else if output.records.bytes == summary.records_in.bytes {
+            "<span class=\"ok\">complete</span>"
+        } else {
+            "<span class=\"bad\">incomplete</span>"
+        };
+        let verify = match &output.verification {
+            Some(v) if v.is_ok() => format!("<span class=\"ok\">{}</span>", escape(&v.to_string())),
+            Some(v) => format!("<span class=\"bad\">{}</span>", escape(&v.to_string())),
+            None => String::new(),
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td>\
+             <td class=\"num\">{secs:.3} s</td><td class=\"num\">{}</td><td>{result}</td>\
+             <td>{verify}</td></tr>",
+            escape(&output.name),
+            output.records,
+            output.records.bytes,
+            format_rate(rate(output.records.bytes, secs)),
+        );
+    }
+    html.push_str("</table>\n");
+
+    if summary.outputs.iter().any(|output| output.delta.is_some()) {
+        html.push_str(
+            "<h3>Delta</h3>\n<table><tr><th>Output</th><th>Blocks unchanged</th>\
+             <th>Blocks rewritten</th></tr>\n",
+        );
+        for output in &summary.outputs {
+            if let Some(delta) = &output.delta {
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td></tr>",
+                    escape(&output.name),
+                    delta.unchanged,
+                    delta.rewritten
+                );
+            }
+        }
+        html.push_str("</table>\n");
+    }
+
+    if summary.digests().next().is_some() {
+        html.push_str("<h3>Hashes</h3>\n<table><tr><th>Output</th><th>Digest</th></tr>\n");
+        for (name, digest) in summary.digests() {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td class=\"num\">{digest}</td></tr>",
+                escape(name)
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("<h3>Variables</h3>\n<table><tr><th>Variable</th><th>Value</th></tr>\n");
+    for (key, value) in &report.variables.values {
+        let _ = writeln!(
+            html,
+            "<tr><td>{{{key}}}</td><td>{}</td></tr>",
+            escape(value)
+        );
+    }
+    html.push_str("</table>\n");
+
+    if !summary.matches.is_empty() {
+        html.push_str(
+            "<h3>Scan matches</h3>\n<table><tr><th>Pattern</th><th>Offset</th><th>Bytes</th></tr>\n",
+        );
+        for m in &summary.matches {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td></tr>",
+                escape(&m.scan),
+                m.offset,
+                m.bytes
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    if summary
+        .outputs
+        .iter()
+        .any(|output| !output.repaired.is_empty())
+    {
+        html.push_str(
+            "<h3>Repaired ranges</h3>\n<p>Read back wrong and copied again</p>\n\
+             <table><tr><th>Output</th><th>Offset</th><th>Bytes</th></tr>\n",
+        );
+        for output in &summary.outputs {
+            for (offset, bytes) in &output.repaired {
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{}</td><td class=\"num\">{offset}</td><td class=\"num\">{bytes}</td></tr>",
+                    escape(&output.name)
+                );
+            }
+        }
+        html.push_str("</table>\n");
+    }
+
+    if let Some((map, unreadable)) = &summary.rescue
+        && !unreadable.is_empty()
+    {
+        let _ = writeln!(
+            html,
+            "<h3>Unreadable regions</h3>\n<p>Replaced with zeros, mapped in {}</p>\n\
+             <table><tr><th>Offset</th><th>Bytes</th></tr>",
+            escape(&map.display().to_string())
+        );
+        for (offset, bytes) in unreadable {
+            let _ = writeln!(
+                html,
+                "<tr><td class=\"num\">{offset}</td><td class=\"num\">{bytes}</td></tr>"
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    if report.timeline.len() >= 2 {
+        html.push_str("<h3>Throughput</h3>\n");
+        let mut names = vec![summary.input.clone()];
+        names.extend(summary.outputs.iter().map(|o| o.name.clone()));
+        html.push_str(&timeline_svg(&names, &report.timeline));
+    }
+}
+
+/// Line chart of the throughput of the input and every output over time.
+fn timeline_svg(names: &[String], samples: &[Sample]) -> String {
+    let step = samples.len().div_ceil(MAX_POINTS).max(1);
+    let mut points: Vec<&Sample> = samples.iter().step_by(step).collect();
+    if let Some(last) = samples.last()
+        && !std::ptr::eq(*points.last().unwrap(), last)
+    {
+        points.push(last);
+    }
+
+    // rates[series][interval] in bytes per second
+    let mut rates = vec![vec![]; names.len()];
+    for pair in points.windows(2) {
+        let dt = (pair[1].at - pair[0].at).as_secs_f64();
+        if dt <= 0.0 {
+            continue;
+        }
+        let x = pair[1].at.as_secs_f64();
+        let mut push = |series: usize, before: u64, after: u64| {
+            rates[series].push((x, after.saturating_sub(before) as f64 / dt));
+        };
+        push(0, pair[0].input, pair[1].input);
+        for (i, (before, after)) in pair[0].outputs.iter().zip(&pair[1].outputs).enumerate() {
+            push(i + 1, *before, *after);
+        }
+    }
+
+    let max_x = points.last().map(|s| s.at.as_secs_f64()).unwrap_or(0.0);
+    let max_y = rates.iter().flatten().map(|(_, y)| *y).fold(0.0, f64::max);
+    let plot_w = CHART_WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+    let plot_h = CHART_HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+    let sx = |x: f64| MARGIN_LEFT + if max_x > 0.0 { x / max_x * plot_w } else { 0.0 };
+    let sy = |y: f64| MARGIN_TOP + plot_h - if max_y > 0.0 { y / max_y * plot_h } else { 0.0 };
+
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{CHART_WIDTH}\" height=\"{CHART_HEIGHT}\" \
+         font-size=\"11\">\
+         <rect x=\"{MARGIN_LEFT}\" y=\"{MARGIN_TOP}\" width=\"{plot_w}\" height=\"{plot_h}\" \
+         fill=\"none\" stroke=\"#ccc\"/>"
+    );
+    for fraction in [0.0, 0.5, 1.0] {
+        let y = sy(max_y * fraction);
+        let _ = write!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>",
+            MARGIN_LEFT - 4.0,
+            y + 4.0,
+            format_rate(max_y * fraction),
+        );
+    }
+    let _ = write!(
+        svg,
+        "<text x=\"{MARGIN_LEFT}\" y=\"{}\">0 s</text>\
+         <text x=\"{}\" y=\"{}\" text-anchor=\"end\">{max_x:.1} s</text>",
+        CHART_HEIGHT - 10.0,
+        CHART_WIDTH - MARGIN_RIGHT,
+        CHART_HEIGHT - 10.0,
+    );
+    for (series, values) in rates.iter().enumerate() {
+        let color = COLORS[series % COLORS.len()];
+        let coords: Vec<String> = values
+            .iter()
+            .map(|(x, y)| format!("{:.1},{:.1}", sx(*x), sy(*y)))
+            .collect();
+        let _ = write!(
+            svg,
+            "<polyline fill=\"none\" stroke=\"{color}\" stroke-width=\"1.5\" points=\"{}\"/>",
+            coords.join(" "),
+        );
+    }
+    svg.push_str("</svg>\n<p>");
+    for (series, name) in names.iter().enumerate() {
+        let color = COLORS[series % COLORS.len()];
+        let _ = write!(
+            svg,
+            "<span style=\"color:{color}\">&#9632;</span> {} &nbsp; ",
+            escape(name)
+        );
+    }
+    svg.push_str("</p>\n");
+    svg
+}
+
+fn rate(bytes: u64, secs: f64) -> f64 {
+    if secs > 0.0 { bytes as f64 / secs } else { 0.0 }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Format a point in time as an RFC 3339 UTC timestamp,
+/// e.g. `2025-06-10T14:03:59Z`.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+    )
+}
+
+/// Read back a timestamp of [`format_timestamp`], or just its date, which
+/// stands for the start of that day.
+pub fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let (date, time) = s.split_once('T').unwrap_or((s, "00:00:00Z"));
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time
+        .strip_suffix('Z')?
+        .splitn(3, ':')
+        .map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// Convert a proleptic Gregorian date into days since 1970-01-01.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Convert days since 1970-01-01 into a proleptic Gregorian date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
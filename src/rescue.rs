@@ -0,0 +1,404 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, ErrorKind},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Smallest read a failing block is split into before what is left of it is
+/// given up on
+pub const SECTOR: usize = 512;
+
+/// What a [`salvage`] got out of a block that failed to read.
+pub struct Salvage {
+    /// The block up to the end of the source, with zeros where it couldn't
+    /// be read
+    pub data: Vec<u8>,
+
+    /// Offsets and lengths of what couldn't be read
+    pub unreadable: Vec<(u64, u64)>,
+}
+
+/// Read `len` bytes of `file` from `offset` however much of it will read:
+/// where a read fails, the range is halved and each half tried again, down
+/// to [`SECTOR`] sized reads, and what still fails is zeroed.
+pub fn salvage(file: &File, len: usize, offset: u64) -> io::Result<Salvage> {
+    let mut data = vec![0u8; len];
+    // Shrinks if the source ends within the block
+    let mut end = len;
+    let mut unreadable = vec![];
+    // Ranges of `data` still to read, the next one last
+    let mut pending = vec![(0, len)];
+    while let Some((start, stop)) = pending.pop() {
+        let stop = stop.min(end);
+        let mut at = start;
+        while at < stop {
+            match read_at(file, &mut data[at..stop], offset + at as u64) {
+                Ok(0) => {
+                    end = at;
+                    break;
+                }
+                Ok(n) => at += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    let left = stop - at;
+                    if left <= SECTOR {
+                        data[at..stop].fill(0);
+                        unreadable.push((offset + at as u64, left as u64));
+                    } else {
+                        let middle = at + (left / 2).div_ceil(SECTOR) * SECTOR;
+                        pending.push((middle, stop));
+                        pending.push((at, middle));
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    data.truncate(end);
+    Ok(Salvage {
+        data,
+        unreadable: merge(unreadable),
+    })
+}
+
+/// Read from `offset` of `file` without moving its position.
+#[cfg(unix)]
+pub fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+pub fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buffer, offset)
+}
+
+/// Write all of `buffer` at `offset` of `file` without moving its
+/// position.
+#[cfg(unix)]
+pub fn write_at(file: &File, buffer: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+pub fn write_at(file: &File, mut buffer: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buffer.is_empty() {
+        let n = std::os::windows::fs::FileExt::seek_write(file, buffer, offset)?;
+        buffer = &buffer[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+/// Sort `extents` and join those that touch.
+pub fn merge(mut extents: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    extents.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = vec![];
+    for (offset, len) in extents {
+        match merged.last_mut() {
+            Some((last, last_len)) if *last + *last_len >= offset => {
+                *last_len = (*last_len).max(offset + len - *last);
+            }
+            _ => merged.push((offset, len)),
+        }
+    }
+    merged
+}
+
+/// How much of a region of the input has been read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Read, `+`
+    Finished,
+
+    /// Failed to read and zeroed in the outputs, `-`; ddrescue's
+    /// non-trimmed `*` and non-scraped `/` regions are read as this too
+    Unreadable,
+
+    /// Not read yet, `?`, e.g. because the copy was interrupted
+    Untried,
+}
+
+impl Status {
+    fn symbol(self) -> char {
+        match self {
+            Status::Finished => '+',
+            Status::Unreadable => '-',
+            Status::Untried => '?',
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+    pub status: Status,
+}
+
+/// Which regions of the input a rescue (`rescue=MAP`) has read and which
+/// it couldn't, kept in the map file format of GNU ddrescue so the map can
+/// be looked at, or carried on with, by ddrescue's tools:
+///
+/// ```text
+/// # Mapfile. Created by pdd 0.1.0
+/// # current_pos  current_status  current_pass
+/// 0x00000000     +               1
+/// #      pos        size  status
+/// 0x00000000  0x0001F000  +
+/// 0x0001F000  0x00000400  -
+/// 0x0001F400  0x00FE0C00  +
+/// ```
+///
+/// Offsets are those of the input, so a map only fits runs with the same
+/// `skip=` and `seek=` as the one that made it.
+#[derive(Clone, Debug, Default)]
+pub struct RescueMap {
+    /// In order, without gaps between those of different runs of reading
+    pub extents: Vec<Extent>,
+}
+
+impl RescueMap {
+    /// The map of a copy that read `read` bytes from `start`, with these
+    /// regions unreadable, and stopped short of `end` if known.
+    pub fn from_copy(start: u64, read: u64, unreadable: &[(u64, u64)], end: Option<u64>) -> Self {
+        let mut map = RescueMap {
+            extents: vec![Extent {
+                offset: start,
+                len: read,
+                status: Status::Finished,
+            }],
+        };
+        for &(offset, len) in unreadable {
+            map.mark(offset, len, Status::Unreadable);
+        }
+        if let Some(end) = end.filter(|&end| end > start + read) {
+            map.mark(start + read, end - start - read, Status::Untried);
+        }
+        map
+    }
+
+    /// Map at `path`, if there is one.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(eyre!("Failed to read rescue map")
+                    .with_error(|| e)
+                    .with_note(|| format!("map {}", path.display())));
+            }
+        };
+        let mut map = RescueMap::default();
+        // The first line that isn't a comment is the position ddrescue was
+        // at, which pdd doesn't need.
+        let lines = text
+            .lines()
+            .enumerate()
+            .map(|(number, line)| (number, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .skip(1);
+        for (number, line) in lines {
+            let invalid = || {
+                eyre!("Invalid rescue map line")
+                    .with_note(|| format!("map {} line {}: {line}", path.display(), number + 1))
+                    .with_suggestion(
+                        || "expected POSITION SIZE STATUS, e.g. 0x0001F000 0x00000400 -",
+                    )
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [offset, len, status] = fields[..] else {
+                return Err(invalid());
+            };
+            let status = match status {
+                "+" => Status::Finished,
+                "-" | "*" | "/" => Status::Unreadable,
+                "?" => Status::Untried,
+                _ => return Err(invalid()),
+            };
+            map.extents.push(Extent {
+                offset: parse_number(offset).ok_or_else(invalid)?,
+                len: parse_number(len).ok_or_else(invalid)?,
+                status,
+            });
+        }
+        map.extents.sort_unstable_by_key(|extent| extent.offset);
+        Ok(Some(map))
+    }
+
+    /// Write the map to `path`, replacing what was there only once all of
+    /// it is written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let context = |e: io::Error| {
+            eyre!("Failed to write rescue map")
+                .with_error(|| e)
+                .with_note(|| format!("map {}", path.display()))
+        };
+        let position = self
+            .pending()
+            .next()
+            .or(self.extents.last())
+            .map_or(0, |extent| extent.offset);
+        let mut text = format!(
+            "# Mapfile. Created by pdd {}\n\
+             # current_pos  current_status  current_pass\n\
+             0x{position:08X}     +               1\n\
+             #      pos        size  status\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        for extent in &self.extents {
+            text.push_str(&format!("{extent}\n"));
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, text).map_err(context)?;
+        std::fs::rename(&tmp, path).map_err(context)
+    }
+
+    /// Regions still to be read.
+    pub fn pending(&self) -> impl Iterator<Item = &Extent> {
+        self.extents
+            .iter()
+            .filter(|extent| extent.status != Status::Finished)
+    }
+
+    /// Bytes of regions in `status`.
+    pub fn bytes(&self, status: Status) -> u64 {
+        self.extents
+            .iter()
+            .filter(|extent| extent.status == status)
+            .map(|extent| extent.len)
+            .sum()
+    }
+
+    /// Set the status of `len` bytes from `offset`.
+    pub fn mark(&mut self, offset: u64, len: u64, status: Status) {
+        let end = offset + len;
+        let mut extents = vec![];
+        for extent in self.extents.drain(..) {
+            let extent_end = extent.offset + extent.len;
+            if extent.offset < offset {
+                extents.push(Extent {
+                    len: extent_end.min(offset) - extent.offset,
+                    ..extent
+                });
+            }
+            if extent_end > end {
+                let start = extent.offset.max(end);
+                extents.push(Extent {
+                    offset: start,
+                    len: extent_end - start,
+                    ..extent
+                });
+            }
+        }
+        extents.push(Extent {
+            offset,
+            len,
+            status,
+        });
+        extents.sort_unstable_by_key(|extent| extent.offset);
+        for extent in extents.into_iter().filter(|extent| extent.len > 0) {
+            match self.extents.last_mut() {
+                Some(last)
+                    if last.status == extent.status && last.offset + last.len == extent.offset =>
+                {
+                    last.len += extent.len;
+                }
+                _ => self.extents.push(extent),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Extent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:08X}  0x{:08X}  {}",
+            self.offset,
+            self.len,
+            self.status.symbol()
+        )
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// What a retry pass over a map got.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retried {
+    /// Bytes that were still to be read
+    pub tried: u64,
+
+    /// Of those, bytes read this time and written to the outputs
+    pub rescued: u64,
+}
+
+/// Read the regions of `map` that haven't been read yet, `block_size` at a
+/// time, salvaging what will read from failing ones, and write what is read
+/// to every output at its offset, `shift` bytes from its offset in the
+/// input. The map is saved to `path` after each region, so an interrupted
+/// pass keeps what it got.
+pub fn retry(
+    map: &mut RescueMap,
+    path: &Path,
+    input: &File,
+    outputs: &[File],
+    shift: i64,
+    block_size: usize,
+    interrupt: &AtomicBool,
+) -> Result<Retried> {
+    let mut retried = Retried::default();
+    let pending: Vec<Extent> = map.pending().copied().collect();
+    retried.tried = pending.iter().map(|extent| extent.len).sum();
+    for extent in pending {
+        let end = extent.offset + extent.len;
+        let mut offset = extent.offset;
+        while offset < end && !interrupt.load(Ordering::Relaxed) {
+            let len = usize::try_from(end - offset).map_or(block_size, |left| left.min(block_size));
+            let salvage = salvage(input, len, offset).map_err(|e| {
+                eyre!("Failed to read input")
+                    .with_error(|| e)
+                    .with_note(|| format!("offset {offset}"))
+            })?;
+            if salvage.data.is_empty() {
+                // The input ends before the map does.
+                break;
+            }
+            let read = salvage.data.len() as u64;
+            let target = offset.checked_add_signed(shift).ok_or_else(|| {
+                eyre!("Rescue map region is before the start of the outputs")
+                    .with_note(|| format!("offset {offset}"))
+                    .with_suggestion(|| "give the skip= and seek= the map was made with")
+            })?;
+            for output in outputs {
+                write_at(output, &salvage.data, target).map_err(|e| {
+                    eyre!("Failed to write output")
+                        .with_error(|| e)
+                        .with_note(|| format!("offset {target}"))
+                })?;
+            }
+            let unreadable: u64 = salvage.unreadable.iter().map(|(_, len)| len).sum();
+            retried.rescued += read - unreadable;
+            map.mark(offset, read, Status::Finished);
+            for (offset, len) in salvage.unreadable {
+                map.mark(offset, len, Status::Unreadable);
+            }
+            offset += read;
+        }
+        map.save(path)?;
+    }
+    for output in outputs {
+        output
+            .sync_all()
+            .map_err(|e| eyre!("Failed to sync output").with_error(|| e))?;
+    }
+    Ok(retried)
+}
@@ -0,0 +1,492 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use crate::{hash::to_hex, report::format_timestamp, sink::Sink};
+
+/// Smallest part S3 takes, except for the last one.
+pub const MIN_PART: u64 = 5 << 20;
+
+/// Part size when neither `s3-part=` nor a bigger `bs=` is given.
+pub const DEFAULT_PART: u64 = 8 << 20;
+
+/// Most parts one upload can have.
+pub const MAX_PARTS: u64 = 10_000;
+
+/// Biggest part S3 takes.
+const MAX_PART: u64 = 5 << 30;
+
+/// Parts sent before the part size doubles, so a stream of unknown length
+/// can go on well past [`MAX_PARTS`] parts of the size it started with.
+const PARTS_PER_SIZE: usize = 1_000;
+
+/// Longest wait to connect, send a request's headers, or read an answer's
+/// body, which is never more than a little XML.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest wait for the headers of an answer; completing an upload can
+/// take the store a while.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Slowest a request body may be sent at on top of [`TIMEOUT`], so a bigger
+/// part gets longer.
+const MIN_RATE: u64 = 64 << 10;
+
+/// Times a failed request is sent again, waiting twice as long each time.
+const RETRIES: u32 = 4;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Region signed for when `AWS_REGION` isn't set, which is also what MinIO
+/// expects by default.
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Where an object goes (`os3=bucket/key`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Object {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl FromStr for Object {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(Object {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+            }),
+            _ => Err(eyre!("Invalid object")
+                .with_note(|| format!("input os3={s}"))
+                .with_suggestion(|| "expected os3=bucket/key, e.g. os3=images/disk.img")),
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.bucket, self.key)
+    }
+}
+
+/// Credentials and endpoint, from the environment the AWS tools use:
+/// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN`,
+/// `AWS_REGION` and, for MinIO and other S3 compatible stores,
+/// `AWS_ENDPOINT_URL`.
+#[derive(Clone)]
+pub struct Credentials {
+    access_key: String,
+    secret_key: String,
+    token: Option<String>,
+    region: String,
+
+    /// Scheme and authority, e.g. `http://localhost:9000`
+    endpoint: String,
+
+    /// Path the store is served under, e.g. `/s3` for
+    /// `http://gateway/s3`, or empty
+    prefix: String,
+}
+
+impl Credentials {
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let (Some(access_key), Some(secret_key)) =
+            (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY"))
+        else {
+            return Err(eyre!("No object storage credentials")
+                .with_suggestion(|| "set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY"));
+        };
+        let region = var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        let endpoint = var("AWS_ENDPOINT_URL")
+            .map(|url| url.trim_end_matches('/').to_string())
+            .unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com"));
+        let Some((scheme, rest)) = endpoint
+            .split_once("://")
+            .filter(|(scheme, _)| ["http", "https"].contains(scheme))
+        else {
+            return Err(eyre!("Invalid object storage endpoint")
+                .with_note(|| format!("AWS_ENDPOINT_URL={endpoint}"))
+                .with_suggestion(|| "expected a URL, e.g. http://localhost:9000"));
+        };
+        let (authority, prefix) = match rest.find('/') {
+            Some(at) => rest.split_at(at),
+            None => (rest, ""),
+        };
+        Ok(Self {
+            access_key,
+            secret_key,
+            token: var("AWS_SESSION_TOKEN"),
+            region,
+            endpoint: format!("{scheme}://{authority}"),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    /// The host the requests go to, as signed.
+    fn host(&self) -> &str {
+        self.endpoint.split_once("://").map_or("", |(_, rest)| rest)
+    }
+}
+
+/// An answer to a request: status, `ETag` header and body.
+struct Answer {
+    status: u16,
+    etag: Option<String>,
+    body: String,
+}
+
+/// Signs and sends requests about one object, path style so any S3
+/// compatible store takes them.
+struct Client {
+    agent: ureq::Agent,
+    credentials: Credentials,
+    object: Object,
+}
+
+impl Client {
+    /// Send a request signed with AWS Signature Version 4, again on
+    /// connection errors and 5xx answers.
+    fn send(&self, method: &str, query: &[(&str, &str)], body: &[u8]) -> io::Result<Answer> {
+        let mut attempt = 0;
+        loop {
+            let answer = self.send_once(method, query, body);
+            let retry = match &answer {
+                Ok(answer) => answer.status >= 500,
+                Err(_) => true,
+            };
+            if !retry || attempt >= RETRIES {
+                return answer;
+            }
+            std::thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt));
+            attempt += 1;
+        }
+    }
+
+    fn send_once(&self, method: &str, query: &[(&str, &str)], body: &[u8]) -> io::Result<Answer> {
+        let credentials = &self.credentials;
+        // Signed as sent, under the endpoint's own path.
+        let path = format!(
+            "{}/{}/{}",
+            encode(&credentials.prefix, true),
+            encode(&self.object.bucket, false),
+            encode(&self.object.key, true)
+        );
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(key, value)| (encode(key, false), encode(value, false)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        // 2026-10-14T12:34:56Z becomes 20261014T123456Z.
+        let timestamp = format_timestamp(SystemTime::now()).replace(['-', ':'], "");
+        let date = &timestamp[..8];
+        let payload = to_hex(&Sha256::digest(body));
+        let mut headers = vec![
+            ("host", credentials.host().to_string()),
+            ("x-amz-content-sha256", payload.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &credentials.token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical = format!(
+            "{method}\n{path}\n{query}\n{}\n{signed}\n{payload}",
+            headers
+                .iter()
+                .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+                .collect::<String>()
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", credentials.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            to_hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let mut key = hmac(
+            format!("AWS4{}", credentials.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [credentials.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed}, Signature={}",
+            credentials.access_key,
+            to_hex(&hmac(&key, to_sign.as_bytes()))
+        );
+
+        let url = format!("{}{path}?{query}", credentials.endpoint);
+        let mut request = ureq::http::Request::builder()
+            .method(method)
+            .uri(url)
+            .header("authorization", authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.header(*name, value);
+        }
+        let request = request.body(body.to_vec()).map_err(io::Error::other)?;
+        let sending = TIMEOUT + Duration::from_secs(body.len() as u64 / MIN_RATE);
+        let request = self
+            .agent
+            .configure_request(request)
+            .timeout_send_body(Some(sending))
+            .build();
+        let response = self.agent.run(request).map_err(io::Error::other)?;
+        let status = response.status().as_u16();
+        let etag = response
+            .headers()
+            .get("etag")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body = response
+            .into_body()
+            .read_to_string()
+            .map_err(io::Error::other)?;
+        Ok(Answer { status, etag, body })
+    }
+
+    /// Like [`send`](Self::send), failing unless the store answered 2xx
+    /// without an error in the body, which S3 does for some failures.
+    fn expect(
+        &self,
+        what: &str,
+        method: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Answer> {
+        let answer = self.send(method, query, body)?;
+        if (200..300).contains(&answer.status) && !answer.body.contains("<Error>") {
+            return Ok(answer);
+        }
+        let reason = tag(&answer.body, "Message")
+            .or_else(|| tag(&answer.body, "Code"))
+            .unwrap_or_default();
+        Err(io::Error::other(format!(
+            "{what} failed with status {}{}{reason}",
+            answer.status,
+            if reason.is_empty() { "" } else { ", " }
+        )))
+    }
+}
+
+/// An output uploaded to S3 compatible object storage as a multipart
+/// upload (`os3=bucket/key`), a part at a time as the stream fills one.
+///
+/// A part is sent again if sending it fails, and an upload given up on is
+/// aborted, so the store doesn't keep its parts. Every [`PARTS_PER_SIZE`]
+/// parts the part size doubles, for a stream whose length wasn't known
+/// when the size was picked.
+pub struct S3Sink {
+    client: Client,
+    upload: String,
+    part_size: usize,
+    buffer: Vec<u8>,
+
+    /// ETag of each part uploaded so far
+    parts: Vec<String>,
+
+    /// True once the upload is completed or aborted
+    closed: bool,
+}
+
+impl S3Sink {
+    /// Start the upload of `object` in parts of `part_size` bytes.
+    pub fn create(object: &Object, part_size: u64) -> Result<Self> {
+        let credentials = Credentials::from_env()?;
+        let config = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_connect(Some(TIMEOUT))
+            .timeout_send_request(Some(TIMEOUT))
+            .timeout_recv_response(Some(ANSWER_TIMEOUT))
+            .timeout_recv_body(Some(TIMEOUT))
+            .build();
+        let client = Client {
+            agent: ureq::Agent::new_with_config(config),
+            credentials,
+            object: object.clone(),
+        };
+        let answer = client
+            .expect("starting the upload", "POST", &[("uploads", "")], &[])
+            .map_err(|e| {
+                eyre!("Failed to start the upload")
+                    .with_error(|| e)
+                    .with_note(|| format!("output os3={object}"))
+            })?;
+        let Some(upload) = tag(&answer.body, "UploadId") else {
+            return Err(
+                eyre!("Failed to start the upload, no upload ID in the answer")
+                    .with_note(|| format!("output os3={object}")),
+            );
+        };
+        Ok(Self {
+            client,
+            upload,
+            part_size: usize::try_from(part_size)?,
+            buffer: vec![],
+            parts: vec![],
+            closed: false,
+        })
+    }
+
+    fn upload_part(&mut self, len: usize) -> io::Result<()> {
+        let number = (self.parts.len() + 1).to_string();
+        let answer = self.client.expect(
+            &format!("uploading part {number}"),
+            "PUT",
+            &[("partNumber", &number), ("uploadId", &self.upload)],
+            &self.buffer[..len],
+        )?;
+        let etag = answer
+            .etag
+            .ok_or_else(|| io::Error::other(format!("part {number} has no ETag")))?;
+        self.parts.push(etag);
+        self.buffer.drain(..len);
+        if self.parts.len().is_multiple_of(PARTS_PER_SIZE) {
+            self.part_size = (self.part_size * 2).min(MAX_PART as usize);
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        if !std::mem::replace(&mut self.closed, true) {
+            let _ = self
+                .client
+                .send("DELETE", &[("uploadId", &self.upload)], &[]);
+        }
+    }
+
+    /// Abort the upload if `result` failed.
+    fn or_abort<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if result.is_err() {
+            self.abort();
+        }
+        result
+    }
+}
+
+impl Write for S3Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::Error::other("the upload was aborted"));
+        }
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= self.part_size {
+            let result = self.upload_part(self.part_size);
+            self.or_abort(result)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Parts are sent once full; a short one can only be the last.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for S3Sink {
+    /// Send the rest as the last part, an empty one for an empty stream, and
+    /// complete the upload.
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        if self.closed {
+            return Err(io::Error::other("the upload was aborted"));
+        }
+        if !self.buffer.is_empty() || self.parts.is_empty() {
+            let result = self.upload_part(self.buffer.len());
+            self.or_abort(result)?;
+        }
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        for (index, etag) in self.parts.iter().enumerate() {
+            complete.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{etag}</ETag></Part>",
+                index + 1
+            ));
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+        let result = self.client.expect(
+            "completing the upload",
+            "POST",
+            &[("uploadId", &self.upload)],
+            complete.as_bytes(),
+        );
+        self.or_abort(result)?;
+        self.closed = true;
+        Ok(None)
+    }
+
+    /// Abort the upload, so no object appears at the key.
+    fn abandon(&mut self) -> io::Result<()> {
+        self.abort();
+        Ok(())
+    }
+}
+
+impl Drop for S3Sink {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+/// Part size for a stream of `len` bytes, if known: `part` unless that would
+/// take more than [`MAX_PARTS`] parts.
+pub fn part_size(part: u64, len: Option<u64>) -> u64 {
+    match len {
+        Some(len) => part.max(len.div_ceil(MAX_PARTS)),
+        None => part,
+    }
+}
+
+/// URI encoding as signing wants it, every byte but the unreserved ones,
+/// and `/` too unless in a path.
+fn encode(s: &str, path: bool) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if path => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+/// Text of the first `<name>` element of an XML answer.
+fn tag(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{name}>"))?;
+    Some(xml[start..start + len].to_string())
+}
@@ -0,0 +1,182 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use regex::bytes::Regex;
+use std::{
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{hash::to_hex, sink::Sink};
+
+/// Bytes kept from the end of one block so matches spanning into the next
+/// one are found. Regex matches longer than this can be missed.
+const OVERLAP: usize = 4096;
+
+/// A byte signature searched for while copying (`scan=match,HEXBYTES` or
+/// `scan=regex,EXPR`).
+#[derive(Clone, Debug)]
+pub struct Scan {
+    /// The operand as given, used to label matches
+    pub label: String,
+    regex: Regex,
+}
+
+impl FromStr for Scan {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            eyre!("Invalid scan pattern")
+                .with_note(|| format!("input scan={s}"))
+                .with_suggestion(|| "expected scan=match,HEXBYTES or scan=regex,EXPR")
+        };
+        let expr = match s.split_once(',').ok_or_else(invalid)? {
+            ("match", hex) => {
+                let hex: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+                if hex.is_empty() || !hex.len().is_multiple_of(2) {
+                    return Err(invalid());
+                }
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .map_err(|_| invalid())?
+                    .iter()
+                    .map(|b| format!("\\x{b:02x}"))
+                    .collect::<String>()
+            }
+            ("regex", expr) if !expr.is_empty() => expr.to_string(),
+            _ => return Err(invalid()),
+        };
+        // Match raw bytes rather than UTF-8 so binary signatures work.
+        let regex = Regex::new(&format!("(?-u){expr}")).map_err(|e| invalid().with_error(|| e))?;
+        if regex.is_match(b"") {
+            return Err(invalid().with_note(|| "the pattern matches the empty string"));
+        }
+        Ok(Self {
+            label: format!("scan={s}"),
+            regex,
+        })
+    }
+}
+
+impl fmt::Display for Scan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.label)
+    }
+}
+
+/// One occurrence of a scan pattern in the stream.
+#[derive(Clone, Debug)]
+pub struct Match {
+    /// The `scan=` operand that matched
+    pub scan: String,
+
+    /// Offset from the start of the input
+    pub offset: u64,
+
+    /// Hex of the matched bytes, shortened if long
+    pub bytes: String,
+}
+
+/// Sink searching the stream for [`Scan`] patterns.
+pub struct ScanSink {
+    scans: Vec<Scan>,
+    window: Vec<u8>,
+    /// Offset of `window` from the start of the input
+    position: u64,
+    /// Per pattern, where in `window` its next search starts
+    cursors: Vec<usize>,
+    matches: Arc<Mutex<Vec<Match>>>,
+}
+
+impl ScanSink {
+    /// `position` is the offset of the first block from the start of the
+    /// input.
+    pub fn new(scans: Vec<Scan>, position: u64, matches: Arc<Mutex<Vec<Match>>>) -> Self {
+        Self {
+            cursors: vec![0; scans.len()],
+            scans,
+            window: vec![],
+            position,
+            matches,
+        }
+    }
+
+    /// Search `window` from each pattern's cursor. Unless `last`, matches
+    /// reaching into the final [`OVERLAP`] bytes are left for the next
+    /// search, once more data has arrived.
+    fn search(&mut self, last: bool) {
+        let limit = if last {
+            self.window.len()
+        } else {
+            self.window.len().saturating_sub(OVERLAP)
+        };
+        let mut found = vec![];
+        for (scan, cursor) in self.scans.iter().zip(&mut self.cursors) {
+            if *cursor >= limit {
+                continue;
+            }
+            let from = *cursor;
+            *cursor = limit;
+            for m in scan.regex.find_iter(&self.window[from..]) {
+                let (start, end) = (from + m.start(), from + m.end());
+                if start >= limit {
+                    break;
+                }
+                if end > limit {
+                    *cursor = start;
+                    break;
+                }
+                found.push(Match {
+                    scan: scan.label.clone(),
+                    offset: self.position + start as u64,
+                    bytes: short_hex(m.as_bytes()),
+                });
+            }
+        }
+        found.sort_by_key(|m| m.offset);
+        self.matches.lock().unwrap().extend(found);
+    }
+}
+
+impl Write for ScanSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.window.extend_from_slice(buf);
+        self.search(false);
+        // Only keep what the next search can still look at.
+        let done = self
+            .cursors
+            .iter()
+            .copied()
+            .min()
+            .unwrap_or(self.window.len());
+        self.window.drain(..done);
+        self.position += done as u64;
+        for cursor in &mut self.cursors {
+            *cursor -= done;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for ScanSink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.search(true);
+        Ok(None)
+    }
+}
+
+fn short_hex(bytes: &[u8]) -> String {
+    const MAX: usize = 32;
+    if bytes.len() > MAX {
+        format!("{}...", to_hex(&bytes[..MAX]))
+    } else {
+        to_hex(bytes)
+    }
+}
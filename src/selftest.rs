@@ -0,0 +1,297 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fs::{self, File},
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::{
+    hash::{HashAlgorithm, HashSink},
+    sink::Sink,
+};
+
+/// Block size the cases are copied with; the input sizes are picked around it.
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Size of the sparse input, big enough to need 64 bit offsets.
+const SPARSE_SIZE: u64 = 4 << 30;
+
+/// A way of copying an input that is checked to give back the same bytes.
+#[derive(Clone, Copy, Debug)]
+enum Transform {
+    /// `of=`
+    File,
+
+    /// Two `of=` and a `hash=` with a sidecar, all compared
+    Fanout,
+
+    /// `if=-`
+    Stdin,
+
+    /// `verify=1`
+    Verify,
+
+    /// `conv=sparse`
+    Sparse,
+
+    /// `iflag=direct oflag=direct`
+    Direct,
+
+    /// `comp=zstd`, then decompressed by a second copy
+    Zstd,
+
+    /// `comp=gzip`, then decompressed by a second copy
+    Gzip,
+
+    /// `limit=`
+    Throttle,
+
+    /// `conv=delta` over a longer, different file, then over its own copy
+    Delta,
+
+    /// `trailer=add`, then checked and stripped by a second copy
+    Trailer,
+
+    /// `container=pddstream`, then checked and unpacked by a second copy
+    Container,
+}
+
+const TRANSFORMS: [Transform; 12] = [
+    Transform::File,
+    Transform::Fanout,
+    Transform::Stdin,
+    Transform::Verify,
+    Transform::Sparse,
+    Transform::Direct,
+    Transform::Zstd,
+    Transform::Gzip,
+    Transform::Throttle,
+    Transform::Delta,
+    Transform::Trailer,
+    Transform::Container,
+];
+
+impl Transform {
+    fn name(self) -> &'static str {
+        match self {
+            Transform::File => "file",
+            Transform::Fanout => "fanout",
+            Transform::Stdin => "stdin",
+            Transform::Verify => "verify",
+            Transform::Sparse => "sparse",
+            Transform::Direct => "direct",
+            Transform::Zstd => "zstd",
+            Transform::Gzip => "gzip",
+            Transform::Throttle => "throttle",
+            Transform::Delta => "delta",
+            Transform::Trailer => "trailer",
+            Transform::Container => "container",
+        }
+    }
+}
+
+/// Copy inputs of awkward sizes through each transform with the `pdd`
+/// binary at `pdd`, check that the outputs hash the same as the inputs, and
+/// print a line per case (`pdd self-test`). Fails if any case does.
+///
+/// Everything happens in a directory under the system's temporary
+/// directory, which is removed afterwards.
+pub fn run(pdd: &Path) -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("pdd-self-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).map_err(|e| {
+        eyre!("Failed to create the self-test directory")
+            .with_error(|| e)
+            .with_note(|| format!("directory {}", dir.display()))
+    })?;
+    let result = run_in(pdd, &dir);
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+fn run_in(pdd: &Path, dir: &Path) -> Result<()> {
+    let inputs = [
+        ("empty", 0),
+        ("bs-1", BLOCK_SIZE - 1),
+        ("bs+1", BLOCK_SIZE + 1),
+        ("many blocks", 64 * BLOCK_SIZE + 7),
+    ];
+    let mut cases = vec![];
+    for (label, size) in inputs {
+        let input = dir.join(format!("in-{size}"));
+        write_input(&input, size)?;
+        for transform in TRANSFORMS {
+            cases.push((label, input.clone(), transform));
+        }
+    }
+    // The sparse input is only copied sparsely, so that a few GB of zeros
+    // aren't written out for real.
+    let input = dir.join("in-sparse");
+    write_sparse_input(&input)?;
+    cases.push(("4 GiB", input, Transform::Sparse));
+
+    let mut failed = 0;
+    for (label, input, transform) in &cases {
+        let name = format!("{} {label}", transform.name());
+        match run_case(pdd, dir, input, *transform) {
+            Ok(()) => println!("{name:<24} ok"),
+            Err(e) => {
+                failed += 1;
+                println!("{name:<24} FAILED: {e:#}");
+            }
+        }
+    }
+    println!("{} passed, {failed} failed", cases.len() - failed);
+
+    if failed > 0 {
+        return Err(eyre!("Self-test failed, {failed} of {} cases", cases.len()));
+    }
+    Ok(())
+}
+
+fn run_case(pdd: &Path, dir: &Path, input: &Path, transform: Transform) -> Result<()> {
+    let expected = digest(input)?;
+    let out = dir.join("out");
+    let operand = |key: &str, path: &Path| format!("{key}={}", path.display());
+    let (ifile, of) = (operand("if", input), operand("of", &out));
+
+    match transform {
+        Transform::File => copy(pdd, &[&ifile, &of], None)?,
+        Transform::Fanout => {
+            let other = dir.join("out-2");
+            let sidecar = dir.join("out.b3");
+            let hash = format!("hash=blake3:{}", sidecar.display());
+            copy(pdd, &[&ifile, &of, &operand("of", &other), &hash], None)?;
+            compare(&other, &expected)?;
+            let line = fs::read_to_string(&sidecar)?;
+            if line.split_whitespace().next() != Some(expected.as_str()) {
+                return Err(eyre!("hash= gave {}, expected {expected}", line.trim()));
+            }
+        }
+        Transform::Stdin => copy(pdd, &["if=-", &of], Some(input))?,
+        Transform::Verify => copy(pdd, &[&ifile, &of, "verify=1"], None)?,
+        Transform::Sparse => copy(pdd, &[&ifile, &of, "conv=sparse"], None)?,
+        Transform::Direct => copy(pdd, &[&ifile, &of, "iflag=direct", "oflag=direct"], None)?,
+        Transform::Zstd | Transform::Gzip => {
+            let (comp, extension) = match transform {
+                Transform::Zstd => ("comp=zstd", "zst"),
+                _ => ("comp=gzip", "gz"),
+            };
+            let compressed = dir.join(format!("out.{extension}"));
+            copy(pdd, &[&ifile, &operand("of", &compressed), comp], None)?;
+            copy(pdd, &[&operand("if", &compressed), &of], None)?;
+        }
+        Transform::Throttle => copy(pdd, &[&ifile, &of, "limit=1G"], None)?,
+        Transform::Delta => {
+            let len = fs::metadata(input)?.len() + BLOCK_SIZE + 1;
+            fs::write(&out, vec![0xa5; len as usize])?;
+            copy(pdd, &[&ifile, &of, "conv=delta"], None)?;
+            copy(pdd, &[&ifile, &of, "conv=delta"], None)?;
+        }
+        Transform::Trailer => {
+            let image = dir.join("out.img");
+            copy(pdd, &[&ifile, &operand("of", &image), "trailer=add"], None)?;
+            copy(pdd, &[&operand("if", &image), &of], None)?;
+        }
+        Transform::Container => {
+            let stream = dir.join("out.pdds");
+            copy(
+                pdd,
+                &[&ifile, &operand("of", &stream), "container=pddstream"],
+                None,
+            )?;
+            copy(pdd, &[&operand("if", &stream), &of], None)?;
+        }
+    }
+    compare(&out, &expected)?;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("out"))
+        {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run one copy with `operands`, feeding it `stdin` if given.
+fn copy(pdd: &Path, operands: &[&str], stdin: Option<&Path>) -> Result<()> {
+    let stdin = match stdin {
+        Some(path) => Stdio::from(File::open(path)?),
+        None => Stdio::null(),
+    };
+    let output = Command::new(pdd)
+        .args(operands)
+        .arg(format!("bs={BLOCK_SIZE}"))
+        .arg("status=none")
+        .stdin(stdin)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| {
+            eyre!("Failed to run pdd")
+                .with_error(|| e)
+                .with_note(|| format!("binary {}", pdd.display()))
+        })?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let last = stderr.lines().rfind(|line| !line.trim().is_empty());
+        return Err(eyre!(
+            "pdd {} failed ({}): {}",
+            operands.join(" "),
+            output.status,
+            last.unwrap_or_default().trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Fail unless `path` hashes to `expected`.
+fn compare(path: &Path, expected: &str) -> Result<()> {
+    let actual = digest(path)?;
+    if actual != expected {
+        return Err(eyre!(
+            "{} differs from the input, hashes to {actual} instead of {expected}",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// BLAKE3 digest of a file, in hex.
+fn digest(path: &Path) -> Result<String> {
+    let mut sink = HashSink::new(HashAlgorithm::Blake3, None, String::new());
+    io::copy(&mut File::open(path)?, &mut sink)?;
+    Ok(sink.finish()?.unwrap_or_default())
+}
+
+/// Write `size` bytes that don't repeat within a block, so misplaced
+/// blocks are caught, and don't compress to nothing.
+fn write_input(path: &Path, size: u64) -> Result<()> {
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64 ^ size;
+    let data: Vec<u8> = (0..size)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/// A file of [`SPARSE_SIZE`] bytes that is a hole except for some data at
+/// the start and an unaligned block half way, so it ends in a hole.
+fn write_sparse_input(path: &Path) -> Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(b"pdd self-test")?;
+    file.seek(SeekFrom::Start(SPARSE_SIZE / 2 + 1))?;
+    file.write_all(&[0xa5; BLOCK_SIZE as usize])?;
+    file.set_len(SPARSE_SIZE)?;
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use color_eyre::{Result, eyre::eyre};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+
+use crate::{log, progress::Progress};
+
+/// Exit status after a second interrupt, as for a process killed by SIGINT.
+const FORCED_EXIT: i32 = 130;
+
+/// Signal handling for a whole run.
+///
+/// SIGINT and SIGTERM stop reading but let the writers drain what was
+/// already read, so the summary says what actually reached the outputs; a
+/// second one exits at once. SIGUSR1, and SIGINFO (Ctrl-T) where there is
+/// one, prints the progress of the copy in flight like dd does.
+pub struct Signals {
+    interrupted: Arc<AtomicBool>,
+    current: Arc<Mutex<Option<Arc<Progress>>>>,
+}
+
+impl Signals {
+    /// Start listening for signals. Needs a running tokio runtime.
+    pub fn install() -> Result<Self> {
+        let signals = Self {
+            interrupted: Arc::new(AtomicBool::new(false)),
+            current: Arc::new(Mutex::new(None)),
+        };
+        imp::spawn_interrupt(signals.interrupted.clone())?;
+        imp::spawn_progress(signals.current.clone())?;
+        Ok(signals)
+    }
+
+    /// Flag set once the run has been interrupted.
+    pub fn interrupted(&self) -> &Arc<AtomicBool> {
+        &self.interrupted
+    }
+
+    /// Print `progress` when progress is asked for, until the next copy
+    /// takes over.
+    pub fn watch(&self, progress: Arc<Progress>) {
+        let _ = self.current.lock().unwrap().replace(progress);
+    }
+}
+
+/// Set `interrupted` on the first interrupt and exit on the second.
+fn interrupt(interrupted: &AtomicBool, signal: &str) {
+    if interrupted.swap(true, Ordering::Relaxed) {
+        log::message(
+            None,
+            &format!("{signal} again, exiting without waiting for the outputs"),
+        );
+        if log::is_json() {
+            log::result(
+                FORCED_EXIT,
+                Some(&eyre!(
+                    "{signal} again, exited without waiting for the outputs"
+                )),
+                vec![],
+            );
+        }
+        std::process::exit(FORCED_EXIT);
+    }
+    log::message(
+        None,
+        &format!("{signal}, stopping once the blocks already read are written"),
+    );
+}
+
+fn print_progress(current: &Mutex<Option<Arc<Progress>>>) {
+    if let Some(progress) = current.lock().unwrap().as_ref() {
+        log::message(None, &progress.line());
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use color_eyre::Result;
+    use std::sync::{Arc, Mutex, atomic::AtomicBool};
+    use tokio::signal::unix::{Signal, SignalKind, signal};
+
+    use crate::progress::Progress;
+
+    pub fn spawn_interrupt(interrupted: Arc<AtomicBool>) -> Result<()> {
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::spawn(async move {
+            loop {
+                let name = tokio::select! {
+                    Some(()) = sigint.recv() => "SIGINT",
+                    Some(()) = sigterm.recv() => "SIGTERM",
+                    else => break,
+                };
+                super::interrupt(&interrupted, name);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn spawn_progress(current: Arc<Mutex<Option<Arc<Progress>>>>) -> Result<()> {
+        let mut sigusr1 = signal(SignalKind::user_defined1())?;
+        let mut siginfo = siginfo()?;
+        tokio::spawn(async move {
+            loop {
+                let info = async {
+                    match &mut siginfo {
+                        Some(siginfo) => siginfo.recv().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    Some(()) = sigusr1.recv() => {}
+                    Some(()) = info => {}
+                    else => break,
+                }
+                super::print_progress(&current);
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    ))]
+    fn siginfo() -> std::io::Result<Option<Signal>> {
+        signal(SignalKind::info()).map(Some)
+    }
+
+    /// Linux has no SIGINFO.
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    )))]
+    fn siginfo() -> std::io::Result<Option<Signal>> {
+        Ok(None)
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use color_eyre::Result;
+    use std::sync::{Arc, Mutex, atomic::AtomicBool};
+
+    use crate::progress::Progress;
+
+    pub fn spawn_interrupt(interrupted: Arc<AtomicBool>) -> Result<()> {
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                super::interrupt(&interrupted, "Ctrl-C");
+            }
+        });
+        Ok(())
+    }
+
+    /// There is no signal to ask for progress with.
+    pub fn spawn_progress(_current: Arc<Mutex<Option<Arc<Progress>>>>) -> Result<()> {
+        Ok(())
+    }
+}
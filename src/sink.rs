@@ -0,0 +1,797 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Stdout, Write},
+    net::{Shutdown, TcpStream},
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    arguments::{Conv, Input, Oflag, Output},
+    diagnostic::{Code, Diagnostic},
+    direct::{self, AlignedBuf, LENGTH_ALIGNMENT},
+    hash::HashSink,
+    lock, log,
+    multicast::MulticastSink,
+    permissions::{self, Permissions},
+    pipe,
+    profile::format_bytes,
+    s3::S3Sink,
+};
+
+/// Where the blocks of one output end up.
+pub trait Sink: Write + Send {
+    /// Called once after the last block has been written.
+    ///
+    /// Sinks that compute something over the stream return it here, e.g. the
+    /// hex digest of a hash output.
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.flush()?;
+        Ok(None)
+    }
+
+    /// Called instead of [`Sink::finish`] when the stream stops short,
+    /// because reading it failed, writing to this output failed or the copy
+    /// was interrupted or aborted, so a sink that marks where a stream ends
+    /// can leave it without an end. Wrappers pass it on to what they wrap
+    /// without ending their own part; others are finished as they are.
+    fn abandon(&mut self) -> io::Result<()> {
+        self.finish().map(|_| ())
+    }
+
+    /// The file blocks are written to as they are, at its position, if
+    /// there is one, so the kernel can be left to copy into it.
+    fn plain_file(&mut self) -> Option<&File> {
+        None
+    }
+}
+
+impl Sink for File {
+    fn plain_file(&mut self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// A sink handed to a writer that owns what it writes to, like an encoder,
+/// and kept hold of too, so it can still be abandoned: the writer only gets
+/// as far as its own end would need by finishing itself, which after
+/// [`Shared::take`] goes nowhere.
+#[derive(Clone)]
+pub struct Shared(Arc<Mutex<Option<Box<dyn Sink>>>>);
+
+impl Shared {
+    pub fn new(inner: Box<dyn Sink>) -> Self {
+        Self(Arc::new(Mutex::new(Some(inner))))
+    }
+
+    /// The sink, leaving every copy of this one without it.
+    pub fn take(&self) -> Option<Box<dyn Sink>> {
+        self.0.lock().unwrap().take()
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut dyn Sink) -> io::Result<T>) -> io::Result<T> {
+        match self.0.lock().unwrap().as_deref_mut() {
+            Some(inner) => f(inner),
+            None => Err(io::Error::other("the output was abandoned")),
+        }
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with(|inner| inner.flush())
+    }
+}
+
+impl Sink for Shared {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.with(|inner| inner.finish())
+    }
+
+    fn abandon(&mut self) -> io::Result<()> {
+        self.with(|inner| inner.abandon())
+    }
+}
+
+/// Sync applied to a file output before it is finished.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// `conv=fsync`: data and metadata
+    All,
+
+    /// `conv=fdatasync`: data only
+    Data,
+}
+
+/// Blocks of an output that were compared with what it already held
+/// before writing (`conv=delta`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Delta {
+    /// Blocks that were already there and so weren't written
+    pub unchanged: u64,
+
+    /// Blocks that differed, or weren't there, and were written
+    pub rewritten: u64,
+}
+
+impl fmt::Display for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} blocks unchanged, {} rewritten",
+            self.unchanged, self.rewritten
+        )
+    }
+}
+
+/// A file or device output with the `conv=` behaviour that needs more than a
+/// plain [`File`].
+pub struct FileSink {
+    file: File,
+
+    /// Seek over all zero blocks instead of writing them (`conv=sparse`)
+    sparse: bool,
+
+    /// Punch a hole where a zero block was skipped, so existing data under
+    /// it reads back as zeros (`conv=punch`)
+    punch: bool,
+
+    sync: Option<SyncMode>,
+
+    /// True if the last block was skipped, so the length must be fixed up
+    skipped: bool,
+
+    /// True while writes bypass the page cache (`oflag=direct`)
+    direct: bool,
+
+    /// Where blocks are copied to for direct writes
+    aligned: AlignedBuf,
+
+    /// Blocks compared before writing, if `conv=delta`
+    delta: Option<Arc<Mutex<Delta>>>,
+
+    /// Where the blocks already on the output are read to for comparing
+    existing: Vec<u8>,
+
+    /// Cut a regular file off where the stream ends once done, as it
+    /// wasn't truncated when opened for `conv=delta`
+    truncate: bool,
+
+    /// Zero the rest of the output, named this, after the stream once done
+    /// (`oflag=wipe-tail`)
+    wipe_tail: Option<(String, Wipe)>,
+}
+
+/// What zeroing the rest of an output after the image (`oflag=wipe-tail`)
+/// reports to and is stopped by.
+#[derive(Clone)]
+pub struct Wipe {
+    /// Say how far it has got, unless `status=none`
+    pub report: bool,
+
+    /// The run's interrupt flag, checked between pieces
+    pub interrupt: Arc<AtomicBool>,
+}
+
+impl FileSink {
+    pub fn new(file: File, conv: &Conv, delta: Option<Arc<Mutex<Delta>>>) -> Self {
+        let sync = if conv.fsync {
+            Some(SyncMode::All)
+        } else if conv.fdatasync {
+            Some(SyncMode::Data)
+        } else {
+            None
+        };
+        Self {
+            file,
+            sparse: conv.sparse || conv.punch,
+            punch: conv.punch,
+            sync,
+            skipped: false,
+            direct: false,
+            aligned: AlignedBuf::default(),
+            delta,
+            existing: vec![],
+            truncate: conv.delta && !conv.notrunc,
+            wipe_tail: None,
+        }
+    }
+
+    /// Zero the rest of the output, named `name`, after the stream once it
+    /// has all been written.
+    pub fn wipe_tail(&mut self, name: String, wipe: Wipe) {
+        self.wipe_tail = Some((name, wipe));
+    }
+
+    /// Zero everything from the current position to the end of the file or
+    /// device, a piece at a time so it can be interrupted and say how far
+    /// it got. The kernel is asked to do it, which devices that guarantee
+    /// discarded blocks read back as zeros do by discarding; otherwise
+    /// zeros are written.
+    fn wipe(&mut self) -> io::Result<()> {
+        let Some((name, wipe)) = self.wipe_tail.clone() else {
+            return Ok(());
+        };
+        let position = self.file.stream_position()?;
+        let end = self.file.seek(SeekFrom::End(0))?;
+        self.file.seek(SeekFrom::Start(position))?;
+        if end <= position {
+            return Ok(());
+        }
+        let total = format_bytes((end - position) as f64);
+        let report = |message: String| {
+            if wipe.report {
+                log::message(Some(&name), &message);
+            }
+        };
+        report(format!("zeroing the {total} after the image"));
+        // Only whole sectors can be zeroed by the kernel; the start of the
+        // first is written.
+        let head = (WIPE_ALIGNMENT - position % WIPE_ALIGNMENT) % WIPE_ALIGNMENT;
+        let head = head.min(end - position);
+        self.write_zeros(head)?;
+        let file = self.file.metadata()?.is_file();
+        let mut kernel = true;
+        let mut at = position + head;
+        let mut reported = Instant::now();
+        while at < end {
+            if wipe.interrupt.load(Ordering::Relaxed) {
+                return Err(io::Error::other(format!(
+                    "interrupted with {} of the tail left to zero",
+                    format_bytes((end - at) as f64)
+                )));
+            }
+            let len = WIPE_STEP.min(end - at);
+            if kernel {
+                kernel = if file {
+                    punch_hole(&self.file, at, len)?
+                } else {
+                    zero_out(&self.file, at, len)?
+                };
+            }
+            if kernel {
+                self.file.seek(SeekFrom::Start(at + len))?;
+            } else {
+                self.write_zeros(len)?;
+            }
+            at += len;
+            if at < end && reported.elapsed() >= WIPE_REPORT {
+                report(format!(
+                    "zeroed {} of the {total} after the image",
+                    format_bytes((at - position) as f64)
+                ));
+                reported = Instant::now();
+            }
+        }
+        report(format!("zeroed the {total} after the image"));
+        Ok(())
+    }
+
+    /// Flush, make the file as long as the stream that reached it and sync
+    /// it. Only once all of the stream has is the rest of it zeroed, or cut
+    /// off with `conv=delta`, so a stream stopped short leaves what is
+    /// past it as it was, for a resumed copy to go on over.
+    fn end(&mut self, complete: bool) -> io::Result<()> {
+        self.file.flush()?;
+        let truncate = complete && self.truncate;
+        // A trailing hole doesn't extend the file by itself, and what was
+        // there past the end of the stream is left from before.
+        if (self.skipped || truncate) && self.file.metadata()?.is_file() {
+            let end = self.file.stream_position()?;
+            let len = self.file.metadata()?.len();
+            if len < end || (truncate && len > end) {
+                self.file.set_len(end)?;
+            }
+        }
+        // Synced even if wiping fails, for what was written.
+        let wiped = if complete { self.wipe() } else { Ok(()) };
+        match self.sync {
+            Some(SyncMode::All) => self.file.sync_all()?,
+            Some(SyncMode::Data) => self.file.sync_data()?,
+            None => {}
+        }
+        wiped
+    }
+
+    /// Write `len` zeros at the current position.
+    fn write_zeros(&mut self, len: u64) -> io::Result<()> {
+        let zeros = vec![0u8; WIPE_CHUNK.min(len) as usize];
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(zeros.len() as u64) as usize;
+            match self.write_file(&zeros[..n])? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => left -= n as u64,
+            }
+        }
+        Ok(())
+    }
+
+    /// Switch the file to direct I/O; `Ok(false)` if that isn't possible
+    /// for it.
+    pub fn direct(&mut self) -> io::Result<bool> {
+        self.direct = match direct::set_direct(&self.file, true) {
+            Ok(direct) => direct,
+            Err(e) if direct::is_refused(&e) => false,
+            Err(e) => return Err(e),
+        };
+        Ok(self.direct)
+    }
+
+    /// Write through the aligned buffer while writing directly. A block
+    /// that isn't a whole number of sectors, like the last one, can't be
+    /// written directly, so from then on writes go through the cache like
+    /// with dd.
+    fn write_file(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.direct && !buf.len().is_multiple_of(LENGTH_ALIGNMENT) {
+            self.stop_direct()?;
+        }
+        if !self.direct {
+            return self.file.write(buf);
+        }
+        let aligned = self.aligned.get(buf.len());
+        aligned.copy_from_slice(buf);
+        match self.file.write(aligned) {
+            Err(e) if direct::is_refused(&e) => {
+                let message = format!("direct write refused ({e}), using the page cache");
+                Diagnostic::new(Code::DirectUnavailable, message)
+                    .offset(self.file.stream_position()?)
+                    .emit();
+                self.stop_direct()?;
+                self.file.write(buf)
+            }
+            result => result,
+        }
+    }
+
+    fn stop_direct(&mut self) -> io::Result<()> {
+        direct::set_direct(&self.file, false)?;
+        self.direct = false;
+        Ok(())
+    }
+
+    /// True if the output already holds `buf` at the current position, in
+    /// which case it is now positioned after it; otherwise the position is
+    /// left where it was.
+    fn holds(&mut self, buf: &[u8]) -> io::Result<bool> {
+        // Reads have the same alignment rules as writes.
+        if self.direct && !buf.len().is_multiple_of(LENGTH_ALIGNMENT) {
+            self.stop_direct()?;
+        }
+        let existing = if self.direct {
+            self.aligned.get(buf.len())
+        } else {
+            self.existing.resize(buf.len(), 0);
+            &mut self.existing[..]
+        };
+        let mut read = 0;
+        while read < existing.len() {
+            match self.file.read(&mut existing[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Whatever can't be read back is written over.
+                Err(_) => break,
+            }
+        }
+        if read == buf.len() && existing[..] == *buf {
+            return Ok(true);
+        }
+        self.file.seek(SeekFrom::Current(-(read as i64)))?;
+        Ok(false)
+    }
+
+    /// Skip `len` zero bytes at the current position.
+    fn skip(&mut self, len: usize) -> io::Result<()> {
+        let position = self.file.stream_position()?;
+        if self.punch && !punch_hole(&self.file, position, len as u64)? {
+            // Holes aren't supported here; zeros have to be written after all.
+            let zeros = vec![0u8; len];
+            let mut written = 0;
+            while written < len {
+                match self.write_file(&zeros[written..])? {
+                    0 => return Err(io::ErrorKind::WriteZero.into()),
+                    n => written += n,
+                }
+            }
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Current(len as i64))?;
+        self.skipped = true;
+        Ok(())
+    }
+}
+
+impl Write for FileSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.sparse && !buf.is_empty() && buf.iter().all(|&b| b == 0) {
+            self.skip(buf.len())?;
+            return Ok(buf.len());
+        }
+        if let Some(delta) = self.delta.clone()
+            && !buf.is_empty()
+        {
+            if self.holds(buf)? {
+                delta.lock().unwrap().unchanged += 1;
+                self.skipped = true;
+                return Ok(buf.len());
+            }
+            delta.lock().unwrap().rewritten += 1;
+            self.skipped = false;
+            // A short write still counts as the one block rewritten.
+            let mut written = 0;
+            while written < buf.len() {
+                match self.write_file(&buf[written..])? {
+                    0 => return Err(io::ErrorKind::WriteZero.into()),
+                    n => written += n,
+                }
+            }
+            return Ok(written);
+        }
+        self.skipped = false;
+        self.write_file(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Sink for FileSink {
+    /// Unless blocks are skipped, compared, written directly or followed by
+    /// zeros.
+    fn plain_file(&mut self) -> Option<&File> {
+        let plain = !self.sparse
+            && self.delta.is_none()
+            && !self.direct
+            && !self.truncate
+            && self.wipe_tail.is_none();
+        plain.then_some(&self.file)
+    }
+
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.end(true)?;
+        Ok(None)
+    }
+
+    fn abandon(&mut self) -> io::Result<()> {
+        self.end(false)
+    }
+}
+
+/// Deallocate `len` bytes at `offset`; `Ok(false)` if the filesystem or
+/// platform can't.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor is valid for the lifetime of `file`.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Sectors the kernel zeroes the tail of a device in (`oflag=wipe-tail`).
+const WIPE_ALIGNMENT: u64 = 512;
+
+/// Bytes of zeros written at a time where the kernel can't zero the tail.
+const WIPE_CHUNK: u64 = 1024 * 1024;
+
+/// Bytes of the tail zeroed between checks for an interrupt, in whole
+/// sectors.
+const WIPE_STEP: u64 = 64 * 1024 * 1024;
+
+/// How often zeroing the tail says how far it has got.
+const WIPE_REPORT: Duration = Duration::from_secs(5);
+
+/// Zero `len` bytes of the block device `file` at `offset`, both whole
+/// sectors; `Ok(false)` if the device or platform can't.
+#[cfg(target_os = "linux")]
+fn zero_out(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    /// `_IO(0x12, 127)` from `<linux/fs.h>`
+    const BLKZEROOUT: libc::c_ulong = 0x127f;
+
+    let range: [u64; 2] = [offset, len];
+    // SAFETY: the descriptor is valid for the lifetime of `file` and the
+    // ioctl only reads the range.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKZEROOUT as _, range.as_ptr()) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENOTTY) | Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn zero_out(_file: &File, _offset: u64, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+impl Sink for Stdout {}
+
+/// The receiving end, e.g. a `listen=` pdd, is told the stream is over by
+/// shutting down the sending side. The stream is framed as a pddstream
+/// before it gets here, so a receiver also has the end of that to go by.
+impl Sink for TcpStream {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.flush()?;
+        self.shutdown(Shutdown::Write)?;
+        Ok(None)
+    }
+
+    /// Reset the connection rather than shut it down once it is closed, so
+    /// even a receiver reading it raw gets an error instead of an end.
+    fn abandon(&mut self) -> io::Result<()> {
+        reset_on_close(self)
+    }
+}
+
+#[cfg(unix)]
+fn reset_on_close(stream: &TcpStream) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    // SAFETY: the descriptor is the stream's own and `linger` outlives the
+    // call.
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            (&linger as *const libc::linger).cast(),
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reset_on_close(_stream: &TcpStream) -> io::Result<()> {
+    Ok(())
+}
+
+/// Flush the data and metadata of the file or device at `path` to storage.
+pub fn sync(path: &Path) -> io::Result<()> {
+    OpenOptions::new().write(true).open(path)?.sync_all()
+}
+
+/// Open flags asking for synchronous writes (`oflag=dsync,sync`), and for a
+/// block device to be opened exclusively, which on Linux fails while it is
+/// mounted or opened exclusively by another copy. New files are created with
+/// `mode=` right away rather than readable by everyone until changed.
+#[cfg(unix)]
+fn open_flags(
+    options: &mut OpenOptions,
+    oflag: &Oflag,
+    permissions: &Permissions,
+    _output: &Output,
+    device: bool,
+) {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if let Some(mode) = permissions.mode {
+        options.mode(mode);
+    }
+    let mut flags = 0;
+    if oflag.sync {
+        flags |= libc::O_SYNC;
+    } else if oflag.dsync {
+        flags |= libc::O_DSYNC;
+    }
+    if device && cfg!(target_os = "linux") {
+        flags |= libc::O_EXCL;
+    }
+    options.custom_flags(flags);
+}
+
+#[cfg(not(unix))]
+fn open_flags(
+    _options: &mut OpenOptions,
+    oflag: &Oflag,
+    _permissions: &Permissions,
+    output: &Output,
+    _device: bool,
+) {
+    if oflag.sync || oflag.dsync {
+        Diagnostic::new(
+            Code::SyncUnsupported,
+            "oflag=sync and dsync aren't supported here, ignored",
+        )
+        .subject(output)
+        .emit();
+    }
+}
+
+/// True if `path` is a block device.
+#[cfg(unix)]
+pub(crate) fn is_block_device(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_block_device(_path: &Path) -> bool {
+    false
+}
+
+/// Open an output for writing, positioned `offset` bytes in.
+///
+/// Regular files are truncated to `offset` like dd does unless
+/// `conv=notrunc` is given; block devices are only seeked. The output is
+/// locked first, so a busy one is left untouched, and then given its
+/// `permissions`. With `conv=delta` the blocks skipped and rewritten are
+/// counted in `delta`, and with `oflag=wipe-tail` the rest of it after a
+/// whole stream is zeroed the way `wipe` says.
+#[allow(clippy::too_many_arguments)]
+pub fn open(
+    output: &Output,
+    offset: u64,
+    input: &Input,
+    conv: &Conv,
+    oflag: &Oflag,
+    permissions: &Permissions,
+    delta: Option<Arc<Mutex<Delta>>>,
+    wipe: Option<&Wipe>,
+) -> Result<Box<dyn Sink>> {
+    match output {
+        Output::File(path) => {
+            let device = is_block_device(path);
+            let mut options = OpenOptions::new();
+            options
+                .create(!device)
+                .read(conv.delta)
+                .write(true)
+                .truncate(false);
+            open_flags(&mut options, oflag, permissions, output, device);
+            let mut file = options.open(path).map_err(|e| {
+                if device && e.raw_os_error() == Some(libc::EBUSY) {
+                    return lock::busy_device(e, output);
+                }
+                eyre!("Failed to open output file")
+                    .with_error(|| e)
+                    .with_note(|| format!("output {output}"))
+            })?;
+            lock::lock(&file, output)?;
+            permissions::apply(&file, permissions, output)?;
+            // With delta what's there is compared first, and cut off after.
+            if !conv.notrunc && !conv.delta && file.metadata()?.is_file() {
+                file.set_len(offset)?;
+            }
+            if offset > 0 {
+                file.seek(SeekFrom::Start(offset))?;
+            }
+            if !(oflag.direct
+                || wipe.is_some()
+                || conv.sparse
+                || conv.punch
+                || conv.fsync
+                || conv.fdatasync
+                || conv.delta)
+            {
+                return Ok(Box::new(file));
+            }
+            let mut sink = FileSink::new(file, conv, delta);
+            if oflag.direct && !sink.direct()? {
+                Diagnostic::new(Code::DirectUnavailable, "direct I/O isn't possible")
+                    .subject(output)
+                    .emit();
+            }
+            if let Some(wipe) = wipe {
+                sink.wipe_tail(output.to_string(), wipe.clone());
+            }
+            Ok(Box::new(sink))
+        }
+        Output::Auto(..) => Err(
+            eyre!("Output templates are made into file names before opening")
+                .with_note(|| format!("output {output}")),
+        ),
+        Output::Stdout => {
+            if offset > 0 {
+                return Err(eyre!("Cannot seek on stdout").with_note(|| format!("output {output}")));
+            }
+            Ok(Box::new(io::stdout()))
+        }
+        Output::Pipe(name) => {
+            if offset > 0 {
+                return Err(eyre!("Cannot seek on a pipe").with_note(|| format!("output {output}")));
+            }
+            let writer = pipe::writer(name).map_err(|e| {
+                eyre!("Failed to open pipe")
+                    .with_error(|| e)
+                    .with_note(|| format!("output {output}"))
+            })?;
+            Ok(Box::new(writer))
+        }
+        Output::Hash { algorithm, sidecar } => {
+            let input = match input {
+                Input::File(path) => path.display().to_string(),
+                Input::Stdin => "-".to_string(),
+                Input::Socket(hostname, port) => format!("{hostname}:{port}"),
+                Input::Listen(address, port) => format!("{address}:{port}"),
+                Input::Http(url) => url.clone(),
+                Input::Multicast(group) => group.to_string(),
+                Input::Generated { generator, .. } => generator.to_string(),
+                Input::Join(join) => join.shards[0].display().to_string(),
+                Input::Pipe(name) => format!("pipe:{name}"),
+            };
+            Ok(Box::new(HashSink::new(*algorithm, sidecar.clone(), input)))
+        }
+        Output::Socket(hostname, port) => {
+            if offset > 0 {
+                return Err(
+                    eyre!("Cannot seek on a socket").with_note(|| format!("output {output}"))
+                );
+            }
+            let stream = TcpStream::connect((hostname.as_str(), *port)).map_err(|e| {
+                eyre!("Failed to connect output")
+                    .with_error(|| e)
+                    .with_note(|| format!("output {output}"))
+            })?;
+            Ok(Box::new(stream))
+        }
+        Output::Multicast(group) => {
+            if offset > 0 {
+                return Err(eyre!("Cannot seek on a multicast group")
+                    .with_note(|| format!("output {output}")));
+            }
+            let sink = MulticastSink::open(*group).map_err(|e| {
+                eyre!("Failed to open multicast output")
+                    .with_error(|| e)
+                    .with_note(|| format!("output {output}"))
+            })?;
+            Ok(Box::new(sink))
+        }
+        Output::S3 { object, part_size } => {
+            if offset > 0 {
+                return Err(
+                    eyre!("Cannot seek on object storage").with_note(|| format!("output {output}"))
+                );
+            }
+            Ok(Box::new(S3Sink::create(object, *part_size)?))
+        }
+        Output::Http { .. } => {
+            Err(eyre!("HTTP outputs are not supported yet")
+                .with_note(|| format!("output {output}")))
+        }
+    }
+}
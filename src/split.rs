@@ -0,0 +1,334 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::sink::Sink;
+
+/// How the stream is laid out over the outputs (`mode=`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// Every output gets all of it
+    #[default]
+    Mirror,
+
+    /// Consecutive chunks go to the outputs in turn
+    Split,
+
+    /// The input is shards, read back into one stream
+    Join,
+}
+
+impl FromStr for Layout {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mirror" => Ok(Layout::Mirror),
+            "split" => Ok(Layout::Split),
+            "join" => Ok(Layout::Join),
+            _ => Err(eyre!("Invalid layout")
+                .with_note(|| format!("input mode={s}"))
+                .with_suggestion(|| "expected one of mirror, split, join")),
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layout::Mirror => write!(f, "mirror"),
+            Layout::Split => write!(f, "split"),
+            Layout::Join => write!(f, "join"),
+        }
+    }
+}
+
+/// Name of shard `index` of the series `base`: `out.000`, `out.001`, ...
+pub fn shard_path(base: &Path, index: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_owned();
+    name.push(format!(".{index:03}"));
+    PathBuf::from(name)
+}
+
+/// Opens the shard at a path, with everything a file output is opened with.
+pub type OpenShard = Box<dyn FnMut(&Path) -> Result<Box<dyn Sink>> + Send>;
+
+/// An output written as a series of shards of `size` bytes each
+/// (`split=SIZE`), each opened once the one before is full.
+pub struct ShardSink {
+    base: PathBuf,
+    size: u64,
+    open: OpenShard,
+
+    /// Shards opened so far
+    opened: usize,
+
+    current: Option<Box<dyn Sink>>,
+
+    /// Bytes the current shard still has room for
+    left: u64,
+}
+
+impl ShardSink {
+    pub fn new(base: PathBuf, size: u64, open: OpenShard) -> Self {
+        Self {
+            base,
+            size,
+            open,
+            opened: 0,
+            current: None,
+            left: 0,
+        }
+    }
+
+    fn next(&mut self) -> io::Result<()> {
+        if let Some(mut shard) = self.current.take() {
+            shard.finish()?;
+        }
+        let path = shard_path(&self.base, self.opened);
+        let shard = (self.open)(&path).map_err(|e| io::Error::other(format!("{e:#}")))?;
+        self.current = Some(shard);
+        self.opened += 1;
+        self.left = self.size;
+        Ok(())
+    }
+}
+
+impl Write for ShardSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.current.is_none() || self.left == 0 {
+            self.next()?;
+        }
+        let n = buf
+            .len()
+            .min(usize::try_from(self.left).unwrap_or(usize::MAX));
+        let shard = self.current.as_mut().expect("a shard was just opened");
+        let n = shard.write(&buf[..n])?;
+        self.left -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(shard) => shard.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Sink for ShardSink {
+    /// An empty input still leaves a first, empty shard for joining.
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        if self.current.is_none() {
+            self.next()?;
+        }
+        match &mut self.current {
+            Some(shard) => shard.finish(),
+            None => Ok(None),
+        }
+    }
+
+    /// The shards before the current one are whole; it is left as it is.
+    fn abandon(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(shard) => shard.abandon(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Shards read back as one input (`mode=join`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Join {
+    /// Each if= given, in order
+    pub shards: Vec<PathBuf>,
+
+    /// Bytes taken from each shard in turn, the reverse of `mode=split`;
+    /// `None` to read the shard series of the one input one after the
+    /// other, the reverse of `split=`
+    pub chunk: Option<u64>,
+}
+
+impl Join {
+    /// Open every shard. A series is found by its numbered names, given
+    /// either its base name or its first shard.
+    pub fn open(&self) -> io::Result<JoinReader> {
+        let paths = match (self.chunk, &self.shards[..]) {
+            (None, [first]) => {
+                let base = match first.to_str().and_then(|name| name.strip_suffix(".000")) {
+                    Some(base) => PathBuf::from(base),
+                    None => first.clone(),
+                };
+                let series: Vec<PathBuf> = (0..)
+                    .map(|index| shard_path(&base, index))
+                    .take_while(|path| path.exists())
+                    .collect();
+                if last_shard(&base).is_some_and(|last| last >= series.len()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "shard {} is missing",
+                            shard_path(&base, series.len()).display()
+                        ),
+                    ));
+                }
+                if series.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no shards {} found", shard_path(&base, 0).display()),
+                    ));
+                }
+                series
+            }
+            _ => self.shards.clone(),
+        };
+        let files = paths
+            .iter()
+            .map(File::open)
+            .collect::<io::Result<Vec<File>>>()?;
+        Ok(JoinReader {
+            files,
+            chunk: self.chunk,
+            current: 0,
+            left: self.chunk.unwrap_or(0),
+        })
+    }
+}
+
+/// Index of the last shard of the series `base` there is, so a gap in it
+/// isn't taken for its end.
+fn last_shard(base: &Path) -> Option<usize> {
+    let name = base.file_name()?.to_str()?;
+    let dir = match base.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| {
+            let file_name = entry.ok()?.file_name();
+            let index = file_name.to_str()?.strip_prefix(name)?.strip_prefix('.')?;
+            if index.len() < 3 || !index.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            index.parse().ok()
+        })
+        .max()
+}
+
+impl fmt::Display for Join {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for shard in &self.shards {
+            write!(f, "if={} ", shard.display())?;
+        }
+        write!(f, "mode=join")
+    }
+}
+
+pub struct JoinReader {
+    files: Vec<File>,
+    chunk: Option<u64>,
+
+    /// Shard being read
+    current: usize,
+
+    /// Bytes left of the current chunk, when taking turns
+    left: u64,
+}
+
+impl Read for JoinReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some(file) = self.files.get_mut(self.current) else {
+                return Ok(0);
+            };
+            let Some(chunk) = self.chunk else {
+                match file.read(buf)? {
+                    0 => self.current += 1,
+                    n => return Ok(n),
+                }
+                continue;
+            };
+            // Taking turns, the stream ends with the first shard to run
+            // out, the one the last chunk went to.
+            let want = buf
+                .len()
+                .min(usize::try_from(self.left).unwrap_or(usize::MAX));
+            let n = file.read(&mut buf[..want])?;
+            self.left -= n as u64;
+            if self.left == 0 {
+                self.current = (self.current + 1) % self.files.len();
+                self.left = chunk;
+            }
+            return Ok(n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pdd-split-{}-{name}", std::process::id()))
+    }
+
+    /// Write `data` as shards of `size` bytes, returning how many there are.
+    fn split(base: &Path, size: u64, data: &[u8]) -> usize {
+        let open: OpenShard = Box::new(|path| Ok(Box::new(File::create(path)?)));
+        let mut sink = ShardSink::new(base.to_path_buf(), size, open);
+        sink.write_all(data).unwrap();
+        sink.finish().unwrap();
+        sink.opened
+    }
+
+    fn remove(base: &Path, count: usize) {
+        for index in 0..count {
+            let _ = std::fs::remove_file(shard_path(base, index));
+        }
+    }
+
+    fn join(first: PathBuf) -> io::Result<Vec<u8>> {
+        let join = Join {
+            shards: vec![first],
+            chunk: None,
+        };
+        let mut data = vec![];
+        join.open()?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn split_and_join_with_a_short_last_shard() {
+        let base = base("short");
+        let data: Vec<u8> = (0..10).collect();
+        let count = split(&base, 4, &data);
+        assert_eq!(count, 3);
+        let sizes: Vec<u64> = (0..count)
+            .map(|index| std::fs::metadata(shard_path(&base, index)).unwrap().len())
+            .collect();
+        assert_eq!(sizes, [4, 4, 2]);
+        assert_eq!(join(base.clone()).unwrap(), data);
+        assert_eq!(join(shard_path(&base, 0)).unwrap(), data);
+        remove(&base, count);
+    }
+
+    #[test]
+    fn joining_with_a_missing_shard_is_refused() {
+        let base = base("missing");
+        let data: Vec<u8> = (0..10).collect();
+        let count = split(&base, 4, &data);
+        std::fs::remove_file(shard_path(&base, 1)).unwrap();
+        let e = join(base.clone()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().contains(".001"), "{e}");
+        std::fs::remove_file(shard_path(&base, 0)).unwrap();
+        assert!(join(base.clone()).is_err(), "the first shard missing");
+        remove(&base, count);
+    }
+}
@@ -0,0 +1,427 @@
+use serde_json::{Value, json};
+use std::{
+    fmt,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    carve::Carved,
+    device::DeviceIdentity,
+    gpt::GptFix,
+    hash::to_hex,
+    ids::NewIds,
+    profile::{format_bytes, format_decimal},
+    report::format_timestamp,
+    scan::Match,
+    sink::Delta,
+    trailer::Trailer,
+    verify::Verification,
+};
+
+/// Scan matches listed in the summary; the rest are only counted.
+const MAX_LISTED_MATCHES: usize = 20;
+
+/// Full and partial block counts, like dd's `N+M records in`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Records {
+    /// Number of blocks of exactly the block size
+    pub full: u64,
+
+    /// Number of shorter blocks
+    pub partial: u64,
+
+    /// Total number of bytes over all blocks
+    pub bytes: u64,
+}
+
+impl Records {
+    /// Account for a block of `n` bytes out of a `block_size` block.
+    pub fn record(&mut self, n: usize, block_size: usize) {
+        if n >= block_size {
+            self.full += 1;
+        } else {
+            self.partial += 1;
+        }
+        self.bytes += n as u64;
+    }
+}
+
+impl Records {
+    fn to_json(self) -> Value {
+        json!({ "full": self.full, "partial": self.partial, "bytes": self.bytes })
+    }
+}
+
+impl fmt::Display for Records {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{}", self.full, self.partial)
+    }
+}
+
+/// What one output received.
+#[derive(Clone, Debug)]
+pub struct OutputSummary {
+    /// The output operand, e.g. `of=/dev/sda1`
+    pub name: String,
+
+    pub records: Records,
+
+    /// Time from the start of the operation until the output was done
+    pub elapsed: Duration,
+
+    /// Serial number and model of the device behind the output, if any
+    pub identity: DeviceIdentity,
+
+    /// Hex digest of the stream, for hash outputs
+    pub digest: Option<String>,
+
+    /// Per target data injected into this output
+    pub injected: Vec<String>,
+
+    /// Result of reading the output back, if `verify=` was given
+    pub verification: Option<Verification>,
+
+    /// Offsets and lengths of the ranges copied again because they didn't
+    /// read back right (`verify-retries=`)
+    pub repaired: Vec<(u64, u64)>,
+
+    /// Why writing the output was given up on, if it was
+    pub error: Option<String>,
+
+    /// True if the output took turns with the others to get the stream
+    /// (`mode=split`), so it holds only part of it
+    pub striped: bool,
+
+    /// Blocks left alone and rewritten, if `conv=delta` was given
+    pub delta: Option<Delta>,
+
+    /// What was done to the disk's GPT, if `fix-gpt=1` was given
+    pub gpt: Option<GptFix>,
+
+    /// What identifiers were replaced, if `new-ids=1` was given
+    pub ids: Option<NewIds>,
+}
+
+/// End of run statistics for one operation.
+#[derive(Clone, Debug)]
+pub struct Summary {
+    /// The input operand, e.g. `if=disk.img`
+    pub input: String,
+
+    pub records_in: Records,
+
+    pub outputs: Vec<OutputSummary>,
+
+    /// When the operation started
+    pub started: SystemTime,
+
+    /// Wall time of the whole operation
+    pub elapsed: Duration,
+
+    /// True if the operation was stopped before reaching the end of its input
+    pub interrupted: bool,
+
+    /// True if a failed write stopped the operation (`onerror=abort`)
+    pub aborted: bool,
+
+    /// Set if the operation stopped because `duration=` ran out
+    pub expired: Option<Duration>,
+
+    /// Set if the operation stopped because the input was idle this long
+    pub idle: Option<Duration>,
+
+    /// Patch records that fell inside the copied range, and the total number
+    pub patched: (usize, usize),
+
+    /// Offsets of input blocks replaced with zeros by `conv=noerror`
+    pub read_errors: Vec<u64>,
+
+    /// Map file given with `rescue=` and the offsets and lengths of input
+    /// regions that couldn't be read and were replaced with zeros
+    pub rescue: Option<(PathBuf, Vec<(u64, u64)>)>,
+
+    /// Regions and bytes redacted from the stream
+    pub redacted: (usize, u64),
+
+    /// Occurrences of `scan=` patterns, by offset
+    pub matches: Vec<Match>,
+
+    /// Directory given with `carve=` and the files carved into it
+    pub carved: Option<(PathBuf, Vec<Carved>)>,
+
+    /// Integrity trailer the input ended in and was stripped of
+    pub trailer: Option<Trailer>,
+}
+
+impl Summary {
+    /// Print the summary to stderr in the style of dd.
+    pub fn print(&self) {
+        if self.interrupted {
+            eprintln!("{}: interrupted", self.input);
+        }
+        if self.aborted {
+            eprintln!("{}: stopped after a write error", self.input);
+        }
+        if let Some(duration) = self.expired {
+            eprintln!("{}: stopped after {duration:?}", self.input);
+        }
+        if let Some(idle) = self.idle {
+            eprintln!("{}: no data for {idle:?}, stopped", self.input);
+        }
+        eprintln!(
+            "{}: {} records in, {}",
+            self.input,
+            self.records_in,
+            transfer(self.records_in.bytes, self.elapsed, "read"),
+        );
+        match &self.trailer {
+            Some(trailer) if trailer.is_verified() => {
+                eprintln!("{}: trailer checked, {trailer}", self.input)
+            }
+            Some(_) => eprintln!(
+                "{}: trailer not checked, the input wasn't read to its end",
+                self.input
+            ),
+            None => {}
+        }
+        if let Some(first) = self.read_errors.first() {
+            eprintln!(
+                "{}: {} unreadable blocks replaced with zeros, the first at {first}",
+                self.input,
+                self.read_errors.len()
+            );
+        }
+        if let Some((map, unreadable)) = &self.rescue {
+            let bytes: u64 = unreadable.iter().map(|(_, len)| len).sum();
+            match unreadable.first() {
+                Some((first, _)) => eprintln!(
+                    "{}: {bytes} unreadable bytes in {} regions replaced with zeros, the first \
+                     at {first}, mapped in {}",
+                    self.input,
+                    unreadable.len(),
+                    map.display()
+                ),
+                None => eprintln!(
+                    "{}: nothing unreadable, mapped in {}",
+                    self.input,
+                    map.display()
+                ),
+            }
+        }
+        if self.patched.1 > 0 {
+            eprintln!(
+                "{}: patched {} of {} patch records",
+                self.input, self.patched.0, self.patched.1
+            );
+        }
+        if self.redacted.0 > 0 {
+            eprintln!(
+                "{}: redacted {} regions, {} bytes",
+                self.input, self.redacted.0, self.redacted.1
+            );
+        }
+        for m in self.matches.iter().take(MAX_LISTED_MATCHES) {
+            eprintln!(
+                "{}: found {} at {}: {}",
+                self.input, m.scan, m.offset, m.bytes
+            );
+        }
+        if self.matches.len() > MAX_LISTED_MATCHES {
+            eprintln!(
+                "{}: {} more scan matches not listed",
+                self.input,
+                self.matches.len() - MAX_LISTED_MATCHES
+            );
+        }
+        if let Some((dir, carved)) = &self.carved {
+            eprintln!(
+                "{}: carved {} files ({} complete) into {}, see manifest.csv",
+                self.input,
+                carved.len(),
+                carved.iter().filter(|carved| carved.complete).count(),
+                dir.display(),
+            );
+        }
+        for output in &self.outputs {
+            match &output.digest {
+                Some(digest) => eprintln!("{}: {digest}", output.name),
+                None => eprintln!(
+                    "{}: {} records out, {}",
+                    output.name,
+                    output.records,
+                    transfer(output.records.bytes, output.elapsed, "copied"),
+                ),
+            }
+            for injected in &output.injected {
+                eprintln!("{}: injected {injected}", output.name);
+            }
+            if let Some(delta) = &output.delta {
+                eprintln!("{}: {delta}", output.name);
+            }
+            if let Some((first, _)) = output.repaired.first() {
+                let bytes: u64 = output.repaired.iter().map(|(_, len)| len).sum();
+                eprintln!(
+                    "{}: {bytes} bytes in {} ranges read back wrong and were copied again, \
+                     the first at {first}",
+                    output.name,
+                    output.repaired.len()
+                );
+            }
+            if let Some(verification) = &output.verification {
+                eprintln!("{}: {verification}", output.name);
+            }
+            if let Some(gpt) = &output.gpt {
+                eprintln!("{}: {gpt}", output.name);
+            }
+            if let Some(ids) = &output.ids {
+                eprintln!("{}: {ids}", output.name);
+            }
+            if let Some(error) = &output.error {
+                eprintln!("{}: failed, {error}", output.name);
+            }
+        }
+    }
+
+    /// The summary as a JSON object, part of the `result` document of
+    /// `log=json`.
+    pub fn to_json(&self) -> Value {
+        let outputs: Vec<Value> = self
+            .outputs
+            .iter()
+            .map(|output| {
+                json!({
+                    "name": output.name,
+                    "records": output.records.to_json(),
+                    "bytes": output.records.bytes,
+                    "elapsed": output.elapsed.as_secs_f64(),
+                    "serial": output.identity.serial,
+                    "model": output.identity.model,
+                    "digest": output.digest,
+                    "injected": output.injected,
+                    "striped": output.striped,
+                    "delta": output.delta.as_ref().map(|delta| json!({
+                        "unchanged": delta.unchanged,
+                        "rewritten": delta.rewritten,
+                    })),
+                    "verification": output.verification.as_ref().map(|verification| json!({
+                        "ok": verification.is_ok(),
+                        "result": verification.to_string(),
+                    })),
+                    "repaired": output.repaired.iter().map(|(offset, bytes)| json!({
+                        "offset": offset,
+                        "bytes": bytes,
+                    })).collect::<Vec<_>>(),
+                    "gpt": output.gpt.as_ref().map(|gpt| json!({
+                        "ok": gpt.is_ok(),
+                        "result": gpt.to_string(),
+                    })),
+                    "ids": output.ids.as_ref().map(|ids| json!({
+                        "ok": ids.is_ok(),
+                        "result": ids.to_string(),
+                    })),
+                    "error": output.error,
+                })
+            })
+            .collect();
+        json!({
+            "input": self.input,
+            "started": format_timestamp(self.started),
+            "elapsed": self.elapsed.as_secs_f64(),
+            "records_in": self.records_in.to_json(),
+            "bytes_in": self.records_in.bytes,
+            "interrupted": self.interrupted,
+            "aborted": self.aborted,
+            "expired": self.expired.map(|duration| duration.as_secs_f64()),
+            "idle": self.idle.map(|idle| idle.as_secs_f64()),
+            "trailer": self.trailer.as_ref().map(|trailer| json!({
+                "checked": trailer.is_verified(),
+                "length": trailer.length,
+                "blake3": to_hex(&trailer.digest),
+            })),
+            "read_errors": self.read_errors,
+            "rescue": self.rescue.as_ref().map(|(map, unreadable)| json!({
+                "map": map,
+                "unreadable": unreadable.iter().map(|(offset, bytes)| json!({
+                    "offset": offset,
+                    "bytes": bytes,
+                })).collect::<Vec<_>>(),
+            })),
+            "patched": { "applied": self.patched.0, "records": self.patched.1 },
+            "redacted": { "regions": self.redacted.0, "bytes": self.redacted.1 },
+            "matches": self.matches.iter().map(|m| json!({
+                "scan": m.scan,
+                "offset": m.offset,
+                "bytes": m.bytes,
+            })).collect::<Vec<_>>(),
+            "carved": self.carved.as_ref().map(|(dir, carved)| json!({
+                "directory": dir,
+                "files": carved.len(),
+                "complete": carved.iter().filter(|carved| carved.complete).count(),
+            })),
+            "outputs": outputs,
+        })
+    }
+
+    /// True if the operation ran to the end and nothing about any output
+    /// failed.
+    pub fn is_ok(&self) -> bool {
+        !self.interrupted
+            && !self.aborted
+            && self.failed_outputs().next().is_none()
+            && self.failed_gpt_fixes().next().is_none()
+            && self.failed_id_changes().next().is_none()
+            && self.failed_verifications().next().is_none()
+    }
+
+    /// Outputs that were given up on after a write error.
+    pub fn failed_outputs(&self) -> impl Iterator<Item = &OutputSummary> {
+        self.outputs.iter().filter(|output| output.error.is_some())
+    }
+
+    /// Outputs whose GPT couldn't be fixed.
+    pub fn failed_gpt_fixes(&self) -> impl Iterator<Item = &OutputSummary> {
+        self.outputs
+            .iter()
+            .filter(|output| output.gpt.as_ref().is_some_and(|gpt| !gpt.is_ok()))
+    }
+
+    /// Outputs whose identifiers couldn't be replaced.
+    pub fn failed_id_changes(&self) -> impl Iterator<Item = &OutputSummary> {
+        self.outputs
+            .iter()
+            .filter(|output| output.ids.as_ref().is_some_and(|ids| !ids.is_ok()))
+    }
+
+    /// Outputs that failed verification.
+    pub fn failed_verifications(&self) -> impl Iterator<Item = &OutputSummary> {
+        self.outputs.iter().filter(|output| {
+            output
+                .verification
+                .as_ref()
+                .is_some_and(|verification| !verification.is_ok())
+        })
+    }
+
+    /// `(output, digest)` of every hash output.
+    pub fn digests(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.outputs.iter().filter_map(|output| {
+            output
+                .digest
+                .as_deref()
+                .map(|digest| (output.name.as_str(), digest))
+        })
+    }
+}
+
+/// `3500 bytes (3.5 kB, 3.4 KiB) copied, 0.0012 s, 2.9 MB/s`
+fn transfer(bytes: u64, elapsed: Duration, verb: &str) -> String {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { bytes as f64 / secs } else { 0.0 };
+    format!(
+        "{bytes} bytes ({}, {}) {verb}, {secs:.4} s, {}/s",
+        format_decimal(bytes as f64),
+        format_bytes(bytes as f64),
+        format_decimal(rate),
+    )
+}
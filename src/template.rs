@@ -0,0 +1,155 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{fmt, io, path::Path, str::FromStr, time::SystemTime};
+
+use crate::{arguments::Input, device, report::format_timestamp};
+
+/// Name of the file an output is written to until its `{hash}` is known.
+pub fn partial(index: usize) -> String {
+    format!("partial-{}-{index}", std::process::id())
+}
+
+/// Rename a file written under its [`partial`] name to the one with its
+/// digest, failing rather than replacing a file that is already there.
+pub fn rename(partial: &Path, path: &Path) -> io::Result<()> {
+    // A hard link never replaces anything; where the filesystem has none,
+    // the name is looked for first instead.
+    match std::fs::hard_link(partial, path) {
+        Ok(()) => std::fs::remove_file(partial),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+        Err(_) if path.symlink_metadata().is_ok() => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", path.display()),
+        )),
+        Err(_) => std::fs::rename(partial, path),
+    }
+}
+
+/// Variables a template can use.
+pub const VARIABLES: [&str; 6] = ["source", "date", "time", "serial", "model", "hash"];
+
+/// An output file name made from variables, e.g.
+/// `of=auto:/backup/{source}-{date}-{serial}.img`.
+///
+/// `{hash}` is the digest of the operation's first `hash=` output, which is
+/// only known once the copy is done; until then the file is written under
+/// the name with `partial-PID-N` in its place, for the process and the
+/// output, and renamed afterwards, unless a file with the digest's name is
+/// already there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template(String);
+
+impl Template {
+    /// True if the name can only be made once the copy is done.
+    pub fn needs_hash(&self) -> bool {
+        self.0.contains("{hash}")
+    }
+
+    /// The name with every variable replaced by its value.
+    pub fn render(&self, variables: &Variables) -> String {
+        let mut name = self.0.clone();
+        for (key, value) in &variables.values {
+            name = name.replace(&format!("{{{key}}}"), value);
+        }
+        name
+    }
+}
+
+impl FromStr for Template {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |what: String| {
+            eyre!("Invalid output template, {what}")
+                .with_note(|| format!("input of=auto:{s}"))
+                .with_suggestion(|| format!("variables are {{{}}}", VARIABLES.join("}, {")))
+        };
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                return Err(invalid("unclosed {".to_string()));
+            };
+            let key = &rest[start + 1..start + len];
+            if !VARIABLES.contains(&key) {
+                return Err(invalid(format!("unknown variable {{{key}}}")));
+            }
+            rest = &rest[start + len + 1..];
+        }
+        if s.is_empty() {
+            return Err(invalid("it is empty".to_string()));
+        }
+        Ok(Template(s.to_string()))
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Values of the template variables for one operation.
+#[derive(Clone, Debug, Default)]
+pub struct Variables {
+    pub values: Vec<(&'static str, String)>,
+}
+
+impl Variables {
+    /// Everything that can be told before copying from `input`, started
+    /// at `started`. Whatever can't be told, like the serial number of a
+    /// plain file, is `unknown`.
+    pub fn new(input: &Input, started: SystemTime) -> Self {
+        let stem = |path: &str| {
+            Path::new(path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        };
+        let source = match input {
+            Input::File(path) => stem(&path.to_string_lossy()),
+            Input::Stdin => Some("stdin".to_string()),
+            Input::Pipe(name) => Some(name.clone()),
+            Input::Join(join) => {
+                let first = join.shards[0].to_string_lossy();
+                stem(first.strip_suffix(".000").unwrap_or(&first))
+            }
+            Input::Socket(hostname, _) => Some(hostname.clone()),
+            Input::Listen(_, port) => Some(format!("port{port}")),
+            Input::Multicast(group) => Some(format!("port{}", group.port())),
+            Input::Http(url) => stem(url.split(['?', '#']).next().unwrap_or_default()),
+            Input::Generated { generator, .. } => Some(
+                generator
+                    .to_string()
+                    .replace(':', "-")
+                    .trim_end_matches('-')
+                    .to_string(),
+            ),
+        };
+        let identity = match input {
+            Input::File(path) => device::identify(path),
+            _ => Default::default(),
+        };
+        // 2026-10-14T12:34:56Z
+        let timestamp = format_timestamp(started);
+        let unknown = || "unknown".to_string();
+        let clean = |value: Option<String>| {
+            value
+                .map(|value| value.trim().replace(['/', ' '], "_"))
+                .filter(|value| !value.is_empty())
+                .unwrap_or_else(unknown)
+        };
+        Self {
+            values: vec![
+                ("source", clean(source)),
+                ("date", timestamp[..10].to_string()),
+                ("time", timestamp[11..19].replace(':', "")),
+                ("serial", clean(identity.serial)),
+                ("model", clean(identity.model)),
+            ],
+        }
+    }
+
+    /// Set `{hash}` once it is known.
+    pub fn hash(&mut self, digest: &str) {
+        self.values.retain(|(key, _)| *key != "hash");
+        self.values.push(("hash", digest.to_string()));
+    }
+}
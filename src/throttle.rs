@@ -0,0 +1,83 @@
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::sink::Sink;
+
+/// Token bucket capping throughput at a number of bytes per second.
+///
+/// The bucket holds a tenth of a second's worth of bytes, so short bursts go
+/// through at full speed; taking more than it holds puts it in debt, which
+/// is what callers then wait out.
+pub struct Throttle {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    /// `rate` is in bytes per second and must be greater than zero.
+    pub fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        let capacity = (rate / 10.0).max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Account for `n` bytes, returning how long to wait before passing
+    /// them on.
+    pub fn take(&mut self, n: usize) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.capacity) - n as f64;
+        self.last = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Sink wrapper holding writes to `inner` to a rate (`olimit=RATE`).
+pub struct ThrottleSink {
+    inner: Box<dyn Sink>,
+    throttle: Throttle,
+}
+
+impl ThrottleSink {
+    pub fn new(inner: Box<dyn Sink>, rate: u64) -> Self {
+        Self {
+            inner,
+            throttle: Throttle::new(rate),
+        }
+    }
+}
+
+impl Write for ThrottleSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        std::thread::sleep(self.throttle.take(n));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for ThrottleSink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.inner.finish()
+    }
+
+    fn abandon(&mut self) -> io::Result<()> {
+        self.inner.abandon()
+    }
+}
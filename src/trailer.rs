@@ -0,0 +1,356 @@
+use color_eyre::{Result, Section, eyre::eyre};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::SystemTime,
+};
+
+use crate::{hash::to_hex, report::format_timestamp, sink::Sink};
+
+/// Last bytes of every trailer.
+const MAGIC: &[u8; 8] = b"PDDTRAIL";
+
+/// Layout version of the trailer.
+const VERSION: u32 = 1;
+
+/// Bytes of the fixed part at the very end: digest, length, metadata length,
+/// version and magic.
+const FOOTER_LEN: u64 = 32 + 8 + 4 + 4 + 8;
+
+/// Most metadata a trailer is read with, so a file that only happens to end
+/// in the magic can't make pdd read all of it.
+const MAX_METADATA: u32 = 64 * 1024;
+
+/// What is done with integrity trailers (`trailer=strip|add|keep`).
+///
+/// A trailer goes at the very end of an image file:
+///
+/// ```text
+/// image | metadata | blake3 (32) | length (u64) | metadata length (u32) | version (u32) | "PDDTRAIL"
+/// ```
+///
+/// with the integers little endian and the metadata `key=value` lines, so a
+/// bare image carries its own checksum without a sidecar file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailerMode {
+    /// Check the trailer of a file input against its data and leave it out
+    /// of the copy, with a warning that the copy has none
+    #[default]
+    Strip,
+
+    /// Like `Strip`, and also end every file output with a trailer of its own
+    Add,
+
+    /// Copy the input as it is, trailer and all, without checking it
+    Keep,
+}
+
+impl FromStr for TrailerMode {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strip" => Ok(TrailerMode::Strip),
+            "add" => Ok(TrailerMode::Add),
+            "keep" => Ok(TrailerMode::Keep),
+            _ => Err(eyre!("Invalid trailer mode")
+                .with_note(|| format!("input trailer={s}"))
+                .with_suggestion(|| "expected one of strip, add, keep")),
+        }
+    }
+}
+
+impl fmt::Display for TrailerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrailerMode::Strip => write!(f, "strip"),
+            TrailerMode::Add => write!(f, "add"),
+            TrailerMode::Keep => write!(f, "keep"),
+        }
+    }
+}
+
+/// The trailer found at the end of a file input.
+#[derive(Clone, Debug)]
+pub struct Trailer {
+    /// Bytes of image before the trailer
+    pub length: u64,
+
+    /// BLAKE3 of those bytes
+    pub digest: [u8; 32],
+
+    /// `key=value` pairs, e.g. when it was made and from what
+    pub metadata: Vec<(String, String)>,
+
+    /// Set once the image was read to the end and matched the digest
+    verified: Arc<AtomicBool>,
+}
+
+impl Trailer {
+    /// Read the trailer of the file at `path`, if it ends in one. Devices
+    /// and files too short to hold one have none.
+    pub fn detect(path: &Path) -> io::Result<Option<Self>> {
+        let mut file = File::open(path)?;
+        if !file.metadata()?.is_file() {
+            return Ok(None);
+        }
+        Self::read(&mut file)
+    }
+
+    fn read(file: &mut File) -> io::Result<Option<Self>> {
+        let len = file.metadata()?.len();
+        if len < FOOTER_LEN {
+            return Ok(None);
+        }
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        if &footer[48..] != MAGIC {
+            return Ok(None);
+        }
+        let length = u64::from_le_bytes(footer[32..40].try_into().expect("8 bytes"));
+        let metadata_len = u32::from_le_bytes(footer[40..44].try_into().expect("4 bytes"));
+        let version = u32::from_le_bytes(footer[44..48].try_into().expect("4 bytes"));
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+        if version != VERSION {
+            return Err(invalid("the trailer is of an unknown version"));
+        }
+        if metadata_len > MAX_METADATA
+            || length.checked_add(u64::from(metadata_len) + FOOTER_LEN) != Some(len)
+        {
+            return Err(invalid("the trailer doesn't match the length of the file"));
+        }
+        let mut metadata = vec![0u8; metadata_len as usize];
+        file.seek(SeekFrom::Start(length))?;
+        file.read_exact(&mut metadata)?;
+        let metadata = String::from_utf8_lossy(&metadata)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Ok(Some(Self {
+            length,
+            digest: footer[..32].try_into().expect("32 bytes"),
+            metadata,
+            verified: Arc::new(AtomicBool::new(false)),
+        }))
+    }
+
+    /// Read the image under this trailer from `inner`, which is at its
+    /// start, and check it once all of it was read.
+    pub fn reader<R: Read>(&self, inner: R) -> TrailerReader<R> {
+        TrailerReader {
+            inner: inner.take(self.length),
+            hasher: blake3::Hasher::new(),
+            trailer: self.clone(),
+        }
+    }
+
+    /// True once the whole image was read and its digest matched.
+    pub fn is_verified(&self) -> bool {
+        self.verified.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Trailer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes, blake3 {}", self.length, to_hex(&self.digest))?;
+        for (key, value) in &self.metadata {
+            write!(f, ", {key} {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the image of a trailer input, hashing it on the way; reading the
+/// last of it fails if the digest doesn't match.
+pub struct TrailerReader<R> {
+    inner: io::Take<R>,
+    hasher: blake3::Hasher,
+    trailer: Trailer,
+}
+
+impl<R: Read> Read for TrailerReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        if n == 0 && !buf.is_empty() && !self.trailer.is_verified() {
+            let digest = self.hasher.finalize();
+            if digest.as_bytes() != &self.trailer.digest {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "the input doesn't match its trailer, blake3 {} instead of {}",
+                        digest.to_hex(),
+                        to_hex(&self.trailer.digest)
+                    ),
+                ));
+            }
+            self.trailer.verified.store(true, Ordering::Relaxed);
+        }
+        Ok(n)
+    }
+}
+
+/// Sink wrapper ending the output with a trailer over everything written to
+/// `inner`.
+pub struct TrailerSink {
+    inner: Box<dyn Sink>,
+    hasher: blake3::Hasher,
+
+    /// Bytes written so far
+    length: u64,
+
+    /// The input operand, recorded as where the image came from
+    source: String,
+}
+
+impl TrailerSink {
+    pub fn new(inner: Box<dyn Sink>, source: String) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+            length: 0,
+            source,
+        }
+    }
+}
+
+impl Write for TrailerSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.length += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for TrailerSink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        let metadata = format!(
+            "created={}\nsource={}\npdd={}\n",
+            format_timestamp(SystemTime::now()),
+            self.source.replace('\n', " "),
+            env!("CARGO_PKG_VERSION"),
+        );
+        let mut trailer = metadata.into_bytes();
+        let metadata_len = trailer.len() as u32;
+        trailer.extend_from_slice(self.hasher.finalize().as_bytes());
+        trailer.extend_from_slice(&self.length.to_le_bytes());
+        trailer.extend_from_slice(&metadata_len.to_le_bytes());
+        trailer.extend_from_slice(&VERSION.to_le_bytes());
+        trailer.extend_from_slice(MAGIC);
+        self.inner.write_all(&trailer)?;
+        self.inner.finish()
+    }
+
+    /// Left without a trailer, as one would vouch for an image cut short.
+    fn abandon(&mut self) -> io::Result<()> {
+        self.inner.abandon()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("pdd-trailer-{}-{name}", std::process::id()))
+    }
+
+    fn image() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// `image` with a trailer added the way an output gets one.
+    fn with_trailer(path: &Path, image: &[u8]) -> Vec<u8> {
+        let mut sink = TrailerSink::new(Box::new(File::create(path).unwrap()), "if=a\nb".into());
+        sink.write_all(image).unwrap();
+        sink.finish().unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    fn read_image(path: &Path) -> io::Result<(Trailer, Vec<u8>)> {
+        let trailer = Trailer::detect(path)?.expect("a trailer");
+        let mut data = vec![];
+        trailer.reader(File::open(path)?).read_to_end(&mut data)?;
+        Ok((trailer, data))
+    }
+
+    #[test]
+    fn add_and_strip() {
+        let path = temp("round-trip");
+        let file = with_trailer(&path, &image());
+        assert!(file.ends_with(MAGIC));
+        let (trailer, data) = read_image(&path).unwrap();
+        assert_eq!(data, image());
+        assert!(trailer.is_verified());
+        assert_eq!(trailer.length, image().len() as u64);
+        assert!(
+            trailer
+                .metadata
+                .contains(&("source".to_string(), "if=a b".to_string()))
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_file_without_one_has_none() {
+        let path = temp("none");
+        std::fs::write(&path, image()).unwrap();
+        assert!(Trailer::detect(&path).unwrap().is_none());
+        std::fs::write(&path, b"PDDTRAIL").unwrap();
+        assert!(Trailer::detect(&path).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_trailer_cut_short_or_altered_is_refused() {
+        let path = temp("altered");
+        let file = with_trailer(&path, &image());
+        let footer = file.len() - FOOTER_LEN as usize;
+        let mut alterations = vec![];
+        let mut short = file.clone();
+        short.remove(image().len());
+        alterations.push(("metadata cut short", short));
+        let mut short = file.clone();
+        short.remove(0);
+        alterations.push(("image cut short", short));
+        let mut length = file.clone();
+        length[footer + 32] ^= 1;
+        alterations.push(("length", length));
+        let mut version = file.clone();
+        version[footer + 44] ^= 1;
+        alterations.push(("version", version));
+        let mut metadata_len = file.clone();
+        metadata_len[footer + 43] = 0xff;
+        alterations.push(("metadata length", metadata_len));
+        for (what, data) in alterations {
+            std::fs::write(&path, data).unwrap();
+            let e = Trailer::detect(&path).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{what}");
+        }
+
+        for at in [0, footer] {
+            let mut data = file.clone();
+            data[at] ^= 1;
+            std::fs::write(&path, data).unwrap();
+            let Err(e) = read_image(&path) else {
+                panic!("a byte at {at} altered, yet the image was read");
+            };
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+use color_eyre::{Report, Result, Section, eyre::eyre};
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::arguments::Amount;
+
+/// The error for a value that can't be parsed as `what`, naming the operand
+/// as given along with the forms `what` takes.
+fn invalid(what: &str, key: &str, value: &str, expected: &str) -> Report {
+    eyre!("Invalid {what} for {key}")
+        .with_note(|| format!("input {key}={value}"))
+        .with_suggestion(|| format!("expected {expected}"))
+}
+
+const SIZES: &str =
+    "a number with an optional suffix, e.g. 512, 4k, 1M, 1MiB, 1MB, 0x1b8 or 34*512";
+const RATES: &str = "bytes per second with an optional suffix, e.g. 512k, 10M, 10MB/s or 1GiB/s";
+const DURATIONS: &str = "a number with an optional suffix, e.g. 500ms, 30s, 90s, 5m, 1h30m or 7d";
+const BOOLS: &str = "true or false, e.g. 1, 0, yes or no";
+
+/// Parse a size operand such as `4096`, `512b`, `4k`, `1MiB` or `1MB`.
+///
+/// Single letter suffixes and the `*iB` forms are binary multiples, `*B`
+/// forms are decimal. `c` is a single byte, `w` two bytes and `b` a 512
+/// byte sector, as in dd.
+///
+/// Numbers can also be hex (`0x1b8`) or octal (`0o755`), where hex digits
+/// take precedence over suffixes, and sizes can be multiplied with `*` or
+/// `x` and added with `+`, e.g. `34*512` or `2048b+0x200`.
+pub fn parse_size(key: &str, value: &str) -> Result<u64> {
+    Ok(sized(key, value)?.0)
+}
+
+fn sized<'a>(key: &str, value: &'a str) -> Result<(u64, &'a str)> {
+    size(value).map_err(|e| invalid("size", key, value, SIZES).with_note(|| e))
+}
+
+/// A size expression in bytes and the suffix it ends in, or what is wrong
+/// with it.
+fn size(value: &str) -> std::result::Result<(u64, &str), String> {
+    let too_large = || format!("{value:?} is more than {} bytes", u64::MAX);
+    let mut total: u64 = 0;
+    let mut last = "";
+    for term in value.split('+') {
+        let mut product: u64 = 1;
+        for factor in factors(term) {
+            let (number, suffix) = parse_factor(factor)?;
+            product = product.checked_mul(number).ok_or_else(too_large)?;
+            last = suffix;
+        }
+        total = total.checked_add(product).ok_or_else(too_large)?;
+    }
+    Ok((total, last))
+}
+
+/// Parse a `count=`, `skip=` or `seek=` operand, in blocks unless it ends in
+/// a `B` suffix, like `1GiB`, `1MB` or `446B`; the digit of a hex number
+/// such as `0x1B` isn't one.
+pub fn parse_amount(key: &str, value: &str) -> Result<Amount> {
+    let (size, suffix) = sized(key, value)?;
+    if suffix.ends_with('B') {
+        Ok(Amount::Bytes(size))
+    } else {
+        Ok(Amount::Blocks(size))
+    }
+}
+
+/// Parse a rate in bytes per second such as `limit=50M` or `limit=50MB/s`.
+pub fn parse_rate(key: &str, value: &str) -> Result<u64> {
+    let bytes = value.strip_suffix("/s").unwrap_or(value);
+    let (rate, _) = size(bytes).map_err(|e| invalid("rate", key, value, RATES).with_note(|| e))?;
+    if rate == 0 {
+        return Err(eyre!("Rate for {key} must be greater than zero")
+            .with_note(|| format!("input {key}={value}")));
+    }
+    Ok(rate)
+}
+
+/// Parse a duration operand such as `duration=30s` or `stats=1h30m`.
+///
+/// A bare number is in seconds; `ms`, `s`, `m`, `h` and `d` suffixes are
+/// understood, the number may have a fraction, e.g. `1.5m`, and parts are
+/// added up, largest first.
+pub fn parse_duration(key: &str, value: &str) -> Result<Duration> {
+    duration(value).map_err(|e| invalid("duration", key, value, DURATIONS).with_note(|| e))
+}
+
+fn duration(value: &str) -> std::result::Result<Duration, String> {
+    const UNITS: [(&str, f64); 5] = [
+        ("d", 86400.0),
+        ("h", 3600.0),
+        ("m", 60.0),
+        ("s", 1.0),
+        ("ms", 0.001),
+    ];
+    let mut rest = value;
+    let mut seconds = 0.0;
+    let mut smallest: Option<usize> = None;
+    loop {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        if number.is_empty() {
+            return Err(format!("missing number in {rest:?}"));
+        }
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("{number:?} isn't a number"))?;
+        let split = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let (suffix, tail) = tail.split_at(split);
+        let unit = match suffix {
+            // A bare number is in seconds, but only on its own.
+            "" if smallest.is_none() => 3,
+            "" => return Err(format!("missing suffix after {number}")),
+            _ => UNITS
+                .iter()
+                .position(|(unit, _)| *unit == suffix)
+                .ok_or_else(|| format!("unknown suffix {suffix:?}"))?,
+        };
+        if smallest.is_some_and(|smallest| unit <= smallest) {
+            return Err(format!("{suffix:?} after a smaller or the same unit"));
+        }
+        seconds += number * UNITS[unit].1;
+        smallest = Some(unit);
+        if tail.is_empty() {
+            break;
+        }
+        rest = tail;
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())
+}
+
+/// Parse a plain number that takes no suffixes, such as a number of
+/// retries.
+pub fn parse_number<T>(key: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| {
+        invalid("number", key, value, "a whole number, e.g. 3").with_note(|| e.to_string())
+    })
+}
+
+/// Parse a `HOST:PORT` operand such as `os=backup:9000`, where the host
+/// may be empty, into the two.
+pub fn parse_address<'a>(key: &str, value: &'a str) -> Result<(&'a str, u16)> {
+    let invalid = || {
+        invalid(
+            "address",
+            key,
+            value,
+            &format!("a host and port, e.g. {key}=localhost:9000"),
+        )
+    };
+    let (host, port) = value.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port
+        .parse()
+        .map_err(|e: std::num::ParseIntError| invalid().with_note(|| format!("port {e}")))?;
+    Ok((host, port))
+}
+
+/// Parse a boolean operand such as `verify=true` or `verify=0`.
+pub fn parse_bool(key: &str, value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(invalid("value", key, value, BOOLS)),
+    }
+}
+
+/// Split a term of a size expression on `*` and `x`, leaving the `x` of a
+/// `0x` prefix alone.
+fn factors(term: &str) -> Vec<&str> {
+    let mut factors = vec![];
+    let mut start = 0;
+    for (i, c) in term.char_indices() {
+        if c == '*' || (c == 'x' && &term[start..i] != "0") {
+            factors.push(&term[start..i]);
+            start = i + 1;
+        }
+    }
+    factors.push(&term[start..]);
+    factors
+}
+
+/// Parse one number of a size expression along with its suffix, returning
+/// the bytes and the suffix.
+fn parse_factor(factor: &str) -> std::result::Result<(u64, &str), String> {
+    let (radix, rest) = if let Some(hex) = factor.strip_prefix("0x") {
+        (16, hex)
+    } else if let Some(octal) = factor.strip_prefix("0o") {
+        (8, octal)
+    } else {
+        (10, factor)
+    };
+    let split = rest
+        .find(|c: char| !c.is_digit(radix))
+        .unwrap_or(rest.len());
+    let (digits, suffix) = rest.split_at(split);
+    if digits.is_empty() {
+        return Err(format!("missing number in {factor:?}"));
+    }
+    let number = u64::from_str_radix(digits, radix).map_err(|e| e.to_string())?;
+
+    let multiplier: u64 = match suffix {
+        "" | "c" | "B" => 1,
+        "w" => 2,
+        "b" => 512,
+        "k" | "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        "P" | "PiB" => 1 << 50,
+        "E" | "EiB" => 1 << 60,
+        "kB" | "KB" => 1000,
+        "MB" => 1000u64.pow(2),
+        "GB" => 1000u64.pow(3),
+        "TB" => 1000u64.pow(4),
+        "PB" => 1000u64.pow(5),
+        "EB" => 1000u64.pow(6),
+        _ => return Err(format!("unknown suffix {suffix:?}")),
+    };
+
+    let bytes = number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{factor:?} is too large"))?;
+    Ok((bytes, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_in_bytes_end_in_a_byte_suffix() {
+        let amount = |value| parse_amount("count", value).unwrap();
+        assert_eq!(amount("446B"), Amount::Bytes(446));
+        assert_eq!(amount("1MB"), Amount::Bytes(1_000_000));
+        assert_eq!(amount("1GiB"), Amount::Bytes(1 << 30));
+        assert_eq!(amount("2*1KiB"), Amount::Bytes(2048));
+        assert_eq!(amount("0x10*1B"), Amount::Bytes(16));
+    }
+
+    #[test]
+    fn hex_digits_are_not_suffixes() {
+        let amount = |value| parse_amount("count", value).unwrap();
+        assert_eq!(amount("0x1B"), Amount::Blocks(27));
+        assert_eq!(amount("0x1A"), Amount::Blocks(26));
+        assert_eq!(amount("0xAB"), Amount::Blocks(171));
+        assert_eq!(amount("4k"), Amount::Blocks(4096));
+        assert_eq!(amount("2048b+0x200"), Amount::Blocks(2048 * 512 + 512));
+    }
+}
@@ -0,0 +1,458 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    diagnostic::{Code, Diagnostic},
+    priority::IoPriority,
+    rescue::{self, read_at, write_at},
+    sink::Sink,
+    throttle::Throttle,
+};
+
+/// Granularity of the digests recorded while writing, and so of the offsets
+/// reported for mismatches.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+/// Digests of consecutive [`CHUNK_SIZE`] chunks of what was written to an
+/// output.
+pub struct Chunks {
+    digests: Vec<blake3::Hash>,
+    hasher: blake3::Hasher,
+    filled: usize,
+    total: u64,
+}
+
+impl Default for Chunks {
+    fn default() -> Self {
+        Self {
+            digests: vec![],
+            hasher: blake3::Hasher::new(),
+            filled: 0,
+            total: 0,
+        }
+    }
+}
+
+impl Chunks {
+    fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+        while !data.is_empty() {
+            let take = (CHUNK_SIZE - self.filled).min(data.len());
+            self.hasher.update(&data[..take]);
+            self.filled += take;
+            data = &data[take..];
+            if self.filled == CHUNK_SIZE {
+                self.digests.push(self.hasher.finalize());
+                self.hasher.reset();
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// Digests of the chunks recorded so far, the last of them short unless
+    /// the stream ended on a chunk boundary.
+    pub fn digests(&self) -> &[blake3::Hash] {
+        &self.digests
+    }
+
+    /// Bytes recorded so far.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    fn finish(&mut self) {
+        if self.filled > 0 {
+            self.digests.push(self.hasher.finalize());
+            self.hasher.reset();
+            self.filled = 0;
+        }
+    }
+}
+
+/// Sink wrapper recording chunk digests of everything passed to `inner`.
+pub struct VerifySink {
+    inner: Box<dyn Sink>,
+    chunks: Arc<Mutex<Chunks>>,
+}
+
+impl VerifySink {
+    pub fn new(inner: Box<dyn Sink>, chunks: Arc<Mutex<Chunks>>) -> Self {
+        Self { inner, chunks }
+    }
+}
+
+impl Write for VerifySink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.chunks.lock().unwrap().update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for VerifySink {
+    fn finish(&mut self) -> io::Result<Option<String>> {
+        self.chunks.lock().unwrap().finish();
+        self.inner.finish()
+    }
+
+    fn abandon(&mut self) -> io::Result<()> {
+        self.chunks.lock().unwrap().finish();
+        self.inner.abandon()
+    }
+}
+
+/// Outcome of reading an output back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// Everything read back matched what was written
+    Verified,
+
+    /// The chunk starting at `offset` of the output differs
+    Mismatch { offset: u64 },
+
+    /// The output ended after `found` of `expected` bytes
+    Short { expected: u64, found: u64 },
+
+    /// The output could not be read back
+    Failed(String),
+}
+
+impl Verification {
+    /// True only if the output was read back and held what was written.
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Verification::Verified)
+    }
+
+    /// The error to report for a failed verification of `output`.
+    pub fn diagnostic(&self, output: &str) -> Option<Diagnostic> {
+        let diagnostic = match self {
+            Verification::Verified => return None,
+            Verification::Mismatch { offset } => {
+                Diagnostic::new(Code::VerifyMismatch, "verification mismatch").offset(*offset)
+            }
+            Verification::Short { found, .. } => {
+                Diagnostic::new(Code::VerifyShort, self.to_string()).offset(*found)
+            }
+            Verification::Failed(_) => Diagnostic::new(Code::VerifyFailed, self.to_string()),
+        };
+        Some(diagnostic.subject(output))
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verification::Verified => write!(f, "verified"),
+            Verification::Mismatch { offset } => write!(f, "mismatch at offset {offset}"),
+            Verification::Short { expected, found } => {
+                write!(f, "short, {found} of {expected} bytes")
+            }
+            Verification::Failed(e) => write!(f, "failed: {e}"),
+        }
+    }
+}
+
+/// How fast, and at what I/O priority, outputs are read back
+/// (`verify-rate=` and `verify-priority=`).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pace {
+    pub rate: Option<u64>,
+    pub priority: Option<IoPriority>,
+}
+
+/// Read `path` back from `offset` and compare it against `chunks`, on the
+/// calling thread, which is blocked while it waits out `pace`.
+pub fn verify(path: &Path, offset: u64, chunks: &Chunks, pace: Pace) -> Verification {
+    let _guard = pace.priority.and_then(|priority| match priority.apply() {
+        Ok(guard) => Some(guard),
+        Err(e) => {
+            Diagnostic::new(Code::PriorityNotSet, format!("failed to set it: {e}"))
+                .subject(format!("verify-priority={priority}"))
+                .emit();
+            None
+        }
+    });
+    let mut throttle = pace.rate.map(Throttle::new);
+    match compare(path, offset, chunks, throttle.as_mut()) {
+        Ok(result) => result,
+        Err(e) => Verification::Failed(e.to_string()),
+    }
+}
+
+fn compare(
+    path: &Path,
+    offset: u64,
+    chunks: &Chunks,
+    mut throttle: Option<&mut Throttle>,
+) -> io::Result<Verification> {
+    let mut file = File::open(path)?;
+    drop_cache(&file)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut position = 0u64;
+    for expected in &chunks.digests {
+        let want = (chunks.total - position).min(CHUNK_SIZE as u64) as usize;
+        let got = read_full(&mut file, &mut buffer[..want], throttle.as_deref_mut())?;
+        if got < want {
+            return Ok(Verification::Short {
+                expected: chunks.total,
+                found: position + got as u64,
+            });
+        }
+        if blake3::hash(&buffer[..want]) != *expected {
+            return Ok(Verification::Mismatch {
+                offset: offset + position,
+            });
+        }
+        position += want as u64;
+    }
+    Ok(Verification::Verified)
+}
+
+/// What repairing an output that didn't read back right came to.
+#[derive(Clone, Debug)]
+pub struct Repair {
+    /// Offsets and lengths of the ranges of the output copied again
+    pub ranges: Vec<(u64, u64)>,
+
+    /// The output read back after the last try
+    pub verification: Verification,
+}
+
+/// Where the chunks of an output that didn't read back right can be copied
+/// from again: a file input, copied as it is from `skip`.
+#[derive(Clone, Debug)]
+pub struct RepairFrom {
+    pub input: PathBuf,
+    pub skip: u64,
+
+    /// Times to try before giving up (`verify-retries=`)
+    pub retries: u32,
+}
+
+/// Copy the chunks of `path` from `mismatch` on that differ from `chunks`
+/// again, the output holding the input at `offset`, and read just them
+/// back, until they match or the retries run out. Those before `mismatch`
+/// were read back fine already.
+pub fn repair(
+    from: &RepairFrom,
+    path: &Path,
+    offset: u64,
+    chunks: &Chunks,
+    mismatch: u64,
+    pace: Pace,
+) -> Repair {
+    let first = ((mismatch - offset) / CHUNK_SIZE as u64) as usize;
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    let mut attempt = || -> io::Result<Vec<usize>> {
+        let mut bad = differing(path, offset, chunks, first..chunks.digests.len(), pace)?;
+        let source = File::open(&from.input)?;
+        let output = OpenOptions::new().write(true).open(path)?;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        for _ in 0..from.retries {
+            if bad.is_empty() {
+                break;
+            }
+            for &index in &bad {
+                let position = (index * CHUNK_SIZE) as u64;
+                let want = (chunks.total - position).min(CHUNK_SIZE as u64) as usize;
+                let got = read_full_at(&source, &mut buffer[..want], from.skip + position)?;
+                write_at(&output, &buffer[..got], offset + position)?;
+                ranges.push((offset + position, want as u64));
+            }
+            output.sync_data()?;
+            bad = differing(path, offset, chunks, bad.into_iter(), pace)?;
+        }
+        Ok(bad)
+    };
+    let verification = match attempt() {
+        Ok(bad) => match bad.first() {
+            None => Verification::Verified,
+            Some(&index) => Verification::Mismatch {
+                offset: offset + (index * CHUNK_SIZE) as u64,
+            },
+        },
+        Err(e) => Verification::Failed(e.to_string()),
+    };
+    Repair {
+        ranges: rescue::merge(ranges),
+        verification,
+    }
+}
+
+/// Which of the chunks `indices` of `path` from `offset` differ from
+/// `chunks`, or are cut short.
+fn differing(
+    path: &Path,
+    offset: u64,
+    chunks: &Chunks,
+    indices: impl Iterator<Item = usize>,
+    pace: Pace,
+) -> io::Result<Vec<usize>> {
+    let _guard = pace.priority.and_then(|priority| priority.apply().ok());
+    let mut throttle = pace.rate.map(Throttle::new);
+    let file = File::open(path)?;
+    drop_cache(&file)?;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut bad = vec![];
+    for index in indices {
+        let position = (index * CHUNK_SIZE) as u64;
+        let want = (chunks.total - position).min(CHUNK_SIZE as u64) as usize;
+        let got = read_full_at(&file, &mut buffer[..want], offset + position)?;
+        if let Some(throttle) = &mut throttle {
+            std::thread::sleep(throttle.take(got));
+        }
+        if got < want || blake3::hash(&buffer[..want]) != chunks.digests[index] {
+            bad.push(index);
+        }
+    }
+    Ok(bad)
+}
+
+/// Like [`read_full`], from `offset` of `file` without moving its position.
+fn read_full_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut got = 0;
+    while got < buffer.len() {
+        match read_at(file, &mut buffer[got..], offset + got as u64) {
+            Ok(0) => break,
+            Ok(n) => got += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(got)
+}
+
+/// Fill `buffer` from `file`, short only at its end, waiting out `throttle`
+/// after each read.
+pub fn read_full(
+    file: &mut File,
+    buffer: &mut [u8],
+    mut throttle: Option<&mut Throttle>,
+) -> io::Result<usize> {
+    let mut got = 0;
+    while got < buffer.len() {
+        match file.read(&mut buffer[got..]) {
+            Ok(0) => break,
+            Ok(n) => {
+                got += n;
+                if let Some(throttle) = throttle.as_deref_mut() {
+                    std::thread::sleep(throttle.take(n));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(got)
+}
+
+/// Make sure the read back comes from the device rather than the page cache.
+#[cfg(target_os = "linux")]
+pub fn drop_cache(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    file.sync_all()?;
+    // SAFETY: the descriptor is valid for the lifetime of `file`.
+    let ret = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_cache(file: &File) -> io::Result<()> {
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three and a half chunks of something that differs from chunk to
+    /// chunk.
+    fn stream() -> Vec<u8> {
+        (0..CHUNK_SIZE * 7 / 2)
+            .map(|i| (i * 7 + i / 4096) as u8)
+            .collect()
+    }
+
+    fn chunks(stream: &[u8]) -> Chunks {
+        let mut chunks = Chunks::default();
+        chunks.update(stream);
+        chunks.finish();
+        chunks
+    }
+
+    fn temp(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pdd-verify-{}-{name}", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn repair_copies_a_bad_chunk_again() {
+        let stream = stream();
+        let input = temp("repair-input", &stream);
+        let mut copy = stream.clone();
+        copy[CHUNK_SIZE * 2 + 12345] ^= 0xff;
+        let output = temp("repair-output", &copy);
+        let chunks = chunks(&stream);
+
+        let verification = verify(&output, 0, &chunks, Pace::default());
+        let Verification::Mismatch { offset } = verification else {
+            panic!("expected a mismatch, got {verification}");
+        };
+        assert_eq!(offset, (CHUNK_SIZE * 2) as u64);
+        let from = RepairFrom {
+            input: input.clone(),
+            skip: 0,
+            retries: 2,
+        };
+        let repair = repair(&from, &output, 0, &chunks, offset, Pace::default());
+        assert_eq!(repair.verification, Verification::Verified);
+        assert_eq!(repair.ranges, vec![(offset, CHUNK_SIZE as u64)]);
+        assert_eq!(std::fs::read(&output).unwrap(), stream);
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+
+    /// What the output was meant to get differs from the input, so copying
+    /// the input again can't put it right, which is why patched copies
+    /// aren't repaired.
+    #[test]
+    fn repair_from_input_cant_restore_patched_bytes() {
+        let stream = stream();
+        let input = temp("patched-input", &stream);
+        let mut patched = stream.clone();
+        patched[CHUNK_SIZE + 10..CHUNK_SIZE + 14].copy_from_slice(b"pdd!");
+        let mut copy = patched.clone();
+        copy[CHUNK_SIZE + 100] ^= 0xff;
+        let output = temp("patched-output", &copy);
+        let chunks = chunks(&patched);
+
+        let from = RepairFrom {
+            input: input.clone(),
+            skip: 0,
+            retries: 2,
+        };
+        let offset = CHUNK_SIZE as u64;
+        let repair = repair(&from, &output, 0, &chunks, offset, Pace::default());
+        assert_eq!(repair.verification, Verification::Mismatch { offset });
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
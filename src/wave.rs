@@ -0,0 +1,187 @@
+use color_eyre::{Result, Section, eyre::eyre};
+
+use crate::{
+    arguments::{Input, Operation, Output},
+    generate::Generator,
+    input::rereadable,
+    split::Layout,
+};
+
+/// Outputs written at once at most, however many files can be open. Each
+/// is written on a thread of the runtime's blocking pool, 512 threads that
+/// also read stream inputs and outputs back, and an output queued for a
+/// thread would never take its blocks, holding up all the others.
+const MAX_OUTPUTS: usize = 256;
+
+/// Open files kept for the input, the standard streams, the runtime and
+/// whatever else a run holds on to.
+const RESERVED_FILES: u64 = 64;
+
+/// Open files an output may take: itself, and another for the likes of a
+/// lock, a sidecar or a connection of its own.
+const FILES_PER_OUTPUT: u64 = 2;
+
+/// The wave of an operation's outputs an operation writes, when there are
+/// too many to write at once and the input is read again for each wave.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Wave {
+    /// From 1
+    pub number: usize,
+
+    /// Waves of the operation given
+    pub count: usize,
+
+    /// Outputs in the waves before this one, hashes aside, for injected
+    /// serials and addresses to carry on where they left off
+    pub first: usize,
+}
+
+/// Most outputs to write at once. The limit on open files is raised as far
+/// as it goes first, as it often is left well below what is allowed.
+pub fn size() -> usize {
+    let files = open_files().unwrap_or(1024);
+    let outputs = files.saturating_sub(RESERVED_FILES) / FILES_PER_OUTPUT;
+    (outputs as usize).clamp(1, MAX_OUTPUTS)
+}
+
+#[cfg(unix)]
+fn open_files() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit for getrlimit to fill in.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } < 0 {
+        return None;
+    }
+    if limit.rlim_cur < limit.rlim_max {
+        let raised = libc::rlimit {
+            rlim_cur: limit.rlim_max,
+            rlim_max: limit.rlim_max,
+        };
+        // SAFETY: as above. An unlimited hard limit can't always be made
+        // the soft one, which then stays as it was.
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            limit = raised;
+        }
+    }
+    // rlim_t isn't u64 everywhere.
+    #[allow(clippy::unnecessary_cast)]
+    Some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+fn open_files() -> Option<u64> {
+    None
+}
+
+/// Split every operation with more outputs than a wave takes (`wave=`, or
+/// [`size`]) into one operation per wave, run one after the other.
+///
+/// Each wave gets its share of the outputs, and every hash output, since
+/// they take the digest of what was read; scans, carving and block maps,
+/// which are about the input, are left to the first.
+pub fn split(operations: Vec<Operation>) -> Result<Vec<Operation>> {
+    let limit = size();
+    let mut split = vec![];
+    for op in operations {
+        let size = op.wave_size.unwrap_or(limit);
+        let (targets, hashes): (Vec<usize>, Vec<usize>) = (0..op.outputs.len())
+            .partition(|&index| !matches!(op.outputs[index], Output::Hash { .. }));
+        if targets.len() <= size {
+            split.push(op);
+            continue;
+        }
+        check(&op, targets.len(), size)?;
+
+        let chunks: Vec<&[usize]> = targets.chunks(size).collect();
+        let mut plan = op.verify_plan.clone();
+        let mut first = 0;
+        for (number, chunk) in chunks.iter().enumerate() {
+            let mut indices: Vec<usize> = chunk.iter().chain(&hashes).copied().collect();
+            indices.sort_unstable();
+            let mut wave = op.clone();
+            wave.outputs = pick(&op.outputs, &indices);
+            wave.output_limits = pick(&op.output_limits, &indices);
+            wave.output_frames = pick(&op.output_frames, &indices);
+            wave.on_error = pick(&op.on_error, &indices);
+            wave.permissions = pick(&op.permissions, &indices);
+            if number > 0 {
+                wave.scans.clear();
+                wave.carve = None;
+                wave.obmap = None;
+            }
+            // Written along with the first output that needs it.
+            wave.verify_plan = if wave.outputs.iter().any(Output::is_remote) {
+                plan.take()
+            } else {
+                None
+            };
+            wave.wave = Some(Wave {
+                number: number + 1,
+                count: chunks.len(),
+                first,
+            });
+            first += chunk.len();
+            split.push(wave);
+        }
+    }
+    Ok(split)
+}
+
+fn pick<T: Clone>(items: &[T], indices: &[usize]) -> Vec<T> {
+    indices.iter().map(|&index| items[index].clone()).collect()
+}
+
+/// Check that `op` can be copied in waves of `size` of its `targets`.
+fn check(op: &Operation, targets: usize, size: usize) -> Result<()> {
+    let waves = || format!("{targets} outputs, written {size} at a time");
+    let again = || {
+        eyre!("Input can't be read again for each wave of outputs")
+            .with_note(|| format!("input {}", op.input))
+            .with_note(waves)
+    };
+    let streamed = match &op.input {
+        Input::File(path) => !rereadable(path),
+        Input::Stdin
+        | Input::Socket(..)
+        | Input::Listen(..)
+        | Input::Multicast(_)
+        | Input::Pipe(_) => true,
+        _ => false,
+    };
+    if streamed {
+        return Err(again().with_suggestion(|| "copy it to a file first and read that"));
+    }
+    match &op.input {
+        Input::Generated {
+            generator: Generator::Random,
+            ..
+        } => {
+            return Err(again().with_suggestion(|| "write random data to a file and copy that"));
+        }
+        Input::Http(_) if op.cache.is_none() => {
+            return Err(eyre!("A download written in waves of outputs needs cache=")
+                .with_note(|| format!("input {}", op.input))
+                .with_note(waves)
+                .with_suggestion(|| "add cache=DIR, so later waves read the copy kept there"));
+        }
+        _ => {}
+    }
+    let invalid = |what: &str| {
+        eyre!("{what} can't be used with outputs written in waves")
+            .with_note(|| format!("input {}", op.input))
+            .with_note(waves)
+            .with_suggestion(|| "split the outputs over operations of their own")
+    };
+    if op.layout == Layout::Split {
+        return Err(invalid("mode=split"));
+    }
+    if op.resume.is_some() {
+        return Err(invalid("resume="));
+    }
+    if op.rescue.is_some() {
+        return Err(invalid("rescue="));
+    }
+    Ok(())
+}